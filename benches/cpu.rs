@@ -0,0 +1,93 @@
+//! Interpreter throughput on the flat memory bus. Throughput is reported in
+//! instructions per second.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use gb::bus::Bus;
+use gb::cpu::Cpu;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// instructions executed per benchmark iteration
+const INSTRS: u64 = 10_000;
+
+/// Build a cpu running the given program from $0100
+fn setup(program: &[u8]) -> Cpu {
+  let bus = Rc::new(RefCell::new(Bus::new_flat()));
+  for (offset, byte) in program.iter().enumerate() {
+    bus
+      .borrow_mut()
+      .write8(0x0100 + offset as u16, *byte)
+      .unwrap();
+  }
+  let mut cpu = Cpu::new();
+  cpu.connect_bus(bus).unwrap();
+  cpu.pc = 0x0100;
+  cpu.sp = 0xdff0;
+  cpu
+}
+
+fn bench_program(c: &mut Criterion, name: &str, program: &[u8]) {
+  let mut cpu = setup(program);
+  let mut group = c.benchmark_group("cpu");
+  group.throughput(Throughput::Elements(INSTRS));
+  group.bench_function(name, |b| {
+    b.iter(|| {
+      for _ in 0..INSTRS {
+        cpu.step().unwrap();
+      }
+    })
+  });
+  group.finish();
+}
+
+fn tight_loop(c: &mut Criterion) {
+  bench_program(
+    c,
+    "tight_loop",
+    &[
+      0x3c, // inc a
+      0x18, 0xfd, // jr -3
+    ],
+  );
+}
+
+fn instr_mix(c: &mut Criterion) {
+  bench_program(
+    c,
+    "instr_mix",
+    &[
+      0x06, 0x10, // ld b 16
+      0x0e, 0x20, // ld c 32
+      0x78, // ld a b
+      0x81, // add a c
+      0xa9, // xor c
+      0xc5, // push bc
+      0xd1, // pop de
+      0x13, // inc de
+      0xcb, 0x37, // swap a
+      0xfe, 0x05, // cp 5
+      0x20, 0x00, // jr nz 0
+      0xc3, 0x00, 0x01, // jp $0100
+    ],
+  );
+}
+
+fn hl_memory_ops(c: &mut Criterion) {
+  bench_program(
+    c,
+    "hl_memory_ops",
+    &[
+      0x21, 0x00, 0xc0, // ld hl $C000
+      0x7e, // ld a (hl)
+      0x34, // inc (hl)
+      0x86, // add a (hl)
+      0x22, // ld (hl+) a
+      0x35, // dec (hl)
+      0xcb, 0x46, // bit 0 (hl)
+      0xc3, 0x00, 0x01, // jp $0100
+    ],
+  );
+}
+
+criterion_group!(benches, tight_loop, instr_mix, hl_memory_ops);
+criterion_main!(benches);
@@ -0,0 +1,654 @@
+//! Audio processing unit for the Gameboy. Four channels: two square waves,
+//! a wave ram player and noise. Stereo samples come out at one per M-cycle
+//! and queue up in a ring buffer for whatever plays them.
+
+pub mod envelope;
+pub mod length;
+pub mod noise;
+pub mod square;
+pub mod wave;
+
+use crate::apu::noise::Noise;
+use crate::apu::square::Square;
+use crate::apu::wave::Wave;
+use crate::err::{GbError, GbErrorType, GbResult};
+use crate::gb_err;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+use log::error;
+use std::collections::VecDeque;
+
+pub const NR10_ADDR: u16 = 0xff10;
+pub const NR50_ADDR: u16 = 0xff24;
+pub const NR51_ADDR: u16 = 0xff25;
+pub const NR52_ADDR: u16 = 0xff26;
+pub const WAVE_RAM_START: u16 = 0xff30;
+pub const WAVE_RAM_END: u16 = 0xff3f;
+// $FF27-$FF2F have nothing behind them
+pub const UNUSED_START: u16 = 0xff27;
+pub const UNUSED_END: u16 = 0xff2f;
+
+// NR10-NR51
+const NUM_REGS: usize = (NR51_ADDR - NR10_ADDR) as usize + 1;
+const WAVE_RAM_SIZE: usize = (WAVE_RAM_END - WAVE_RAM_START) as usize + 1;
+
+// each channel's registers in `regs`
+const CH1_REGS: std::ops::Range<usize> = 0..5;
+const CH2_REGS: std::ops::Range<usize> = 5..10;
+const CH3_REGS: std::ops::Range<usize> = 10..15;
+const CH4_REGS: std::ops::Range<usize> = 15..20;
+const NR50: usize = (NR50_ADDR - NR10_ADDR) as usize;
+const NR51: usize = (NR51_ADDR - NR10_ADDR) as usize;
+// NR50 bits 7 and 3 mix VIN into the left and right side
+const NR50_VIN_LEFT: u8 = 0x80;
+const NR50_VIN_RIGHT: u8 = 0x08;
+
+// cycles per sample, one per M-cycle
+const SAMPLE_CYCLES: u32 = 4;
+/// Rate samples are produced at
+pub const SAMPLE_RATE: u32 = 4_194_304 / SAMPLE_CYCLES;
+/// Samples kept before the oldest get dropped, about a tenth of a second
+pub const SAMPLE_BUFFER_CAP: usize = SAMPLE_RATE as usize / 10;
+
+/// Left and right output, each from -1.0 to 1.0
+pub type StereoSample = (f32, f32);
+
+pub const CHANNEL_NAMES: [&str; 4] = ["Square 1", "Square 2", "Wave", "Noise"];
+
+/// A channel as the debug viewer shows it
+#[derive(Clone, Default)]
+pub struct ChannelStatus {
+  pub enabled: bool,
+  pub dac_on: bool,
+  /// Tone frequency in Hz
+  pub frequency: f32,
+  /// 0.0-1.0
+  pub volume: f32,
+  /// One period of the waveform, each step from 0.0 to 1.0
+  pub waveform: Vec<f32>,
+}
+
+/// 11-bit frequency of a square or wave channel, from NRx3 and the low bits
+/// of NRx4 in the channel's registers
+pub fn frequency(regs: &[u8]) -> u16 {
+  (regs[4] as u16 & 0x07) << 8 | regs[3] as u16
+}
+
+pub struct Apu {
+  /// NR10-NR51 as last written
+  regs: [u8; NUM_REGS],
+  /// NR52 bit 7, everything but wave ram is cleared and locked while off
+  pub powered: bool,
+  pub wave_ram: [u8; WAVE_RAM_SIZE],
+  pub ch1: Square,
+  pub ch2: Square,
+  pub ch3: Wave,
+  pub ch4: Noise,
+  /// Channels left out of the mix. They keep running, so unmuting picks up
+  /// where the sound would be.
+  pub muted: [bool; 4],
+  /// Sound from the cartridge's VIN pin, -1.0 to 1.0. No emulated cartridge
+  /// drives it, so it stays silent unless set from outside.
+  pub vin: f32,
+
+  // next frame sequencer step, 0-7
+  frame_step: u8,
+  // cycles left over from the last sample
+  sample_cycles: u32,
+  /// Mixed samples, oldest first
+  samples: VecDeque<StereoSample>,
+}
+
+impl Apu {
+  pub fn new() -> Self {
+    Self {
+      regs: [0; NUM_REGS],
+      powered: false,
+      wave_ram: [0; WAVE_RAM_SIZE],
+      ch1: Square::new(true),
+      ch2: Square::new(false),
+      ch3: Wave::new(),
+      ch4: Noise::new(),
+      muted: [false; 4],
+      vin: 0.0,
+      frame_step: 0,
+      sample_cycles: 0,
+      samples: VecDeque::with_capacity(SAMPLE_BUFFER_CAP),
+    }
+  }
+
+  /// Return the registers to their power-up values
+  pub fn reset(&mut self) {
+    self.power_off();
+    self.wave_ram = [0; WAVE_RAM_SIZE];
+    self.sample_cycles = 0;
+    self.samples.clear();
+  }
+
+  fn power_off(&mut self) {
+    self.regs = [0; NUM_REGS];
+    self.powered = false;
+    self.ch1.reset();
+    self.ch2.reset();
+    self.ch3.reset();
+    self.ch4.reset();
+    self.frame_step = 0;
+  }
+
+  /// NR52 bits 0-3, which channels are playing
+  pub fn channels_on(&self) -> u8 {
+    self.ch1.enabled as u8
+      | (self.ch2.enabled as u8) << 1
+      | (self.ch3.enabled as u8) << 2
+      | (self.ch4.enabled as u8) << 3
+  }
+
+  /// Advance by cpu cycles at normal speed. The apu keeps the same pace in
+  /// double speed mode.
+  pub fn step(&mut self, cycles: u32) {
+    self.sample_cycles += cycles;
+    while self.sample_cycles >= SAMPLE_CYCLES {
+      self.sample_cycles -= SAMPLE_CYCLES;
+      if self.powered {
+        self.ch1.step(SAMPLE_CYCLES, &self.regs[CH1_REGS]);
+        self.ch2.step(SAMPLE_CYCLES, &self.regs[CH2_REGS]);
+        self
+          .ch3
+          .step(SAMPLE_CYCLES, &self.regs[CH3_REGS], &self.wave_ram);
+        self.ch4.step(SAMPLE_CYCLES, &self.regs[CH4_REGS]);
+      }
+      self.push_sample(self.mix());
+    }
+  }
+
+  /// Clocked at 512 Hz by the timer, on the falling edge of DIV bit 4. Even
+  /// steps clock the length counters at 256 Hz, steps 2 and 6 the sweep at
+  /// 128 Hz and step 7 the envelopes at 64 Hz.
+  pub fn clock_frame_sequencer(&mut self) {
+    if !self.powered {
+      return;
+    }
+    if self.frame_step.is_multiple_of(2) {
+      self.ch1.clock_length(&self.regs[CH1_REGS]);
+      self.ch2.clock_length(&self.regs[CH2_REGS]);
+      self.ch3.clock_length(&self.regs[CH3_REGS]);
+      self.ch4.clock_length(&self.regs[CH4_REGS]);
+    }
+    match self.frame_step {
+      2 | 6 => self.ch1.clock_sweep(&mut self.regs[CH1_REGS]),
+      7 => {
+        self.ch1.clock_envelope();
+        self.ch2.clock_envelope();
+        self.ch4.clock_envelope();
+      }
+      _ => {}
+    }
+    self.frame_step = (self.frame_step + 1) % 8;
+  }
+
+  // a dac maps the digital 0-15 to -1.0-1.0, and outputs nothing while off
+  fn dac(dac_on: bool, output: u8) -> f32 {
+    if dac_on {
+      output as f32 / 7.5 - 1.0
+    } else {
+      0.0
+    }
+  }
+
+  /// Each channel's dac output
+  fn dac_outputs(&self) -> [f32; 4] {
+    [
+      Apu::dac(self.ch1.dac_on, self.ch1.output(&self.regs[CH1_REGS])),
+      Apu::dac(self.ch2.dac_on, self.ch2.output(&self.regs[CH2_REGS])),
+      Apu::dac(self.ch3.dac_on, self.ch3.output(&self.regs[CH3_REGS])),
+      Apu::dac(self.ch4.dac_on, self.ch4.output()),
+    ]
+  }
+
+  /// NR51 picks the channels each side gets, high nibble for the left. NR50
+  /// adds VIN to either side and then scales each by 1/8 to 8/8.
+  fn mix(&self) -> StereoSample {
+    let nr50 = self.regs[NR50];
+    let nr51 = self.regs[NR51];
+    let (mut left, mut right) = (0.0, 0.0);
+    // inputs summed into each side, VIN only counts where it is routed
+    let (mut left_inputs, mut right_inputs) = (4.0, 4.0);
+    for (i, output) in self.dac_outputs().iter().enumerate() {
+      if self.muted[i] {
+        continue;
+      }
+      if nr51 & (0x10 << i) > 0 {
+        left += output;
+      }
+      if nr51 & (0x01 << i) > 0 {
+        right += output;
+      }
+    }
+    if nr50 & NR50_VIN_LEFT > 0 {
+      left += self.vin;
+      left_inputs += 1.0;
+    }
+    if nr50 & NR50_VIN_RIGHT > 0 {
+      right += self.vin;
+      right_inputs += 1.0;
+    }
+    let left_volume = ((nr50 >> 4) & 0x07) as f32 + 1.0;
+    let right_volume = (nr50 & 0x07) as f32 + 1.0;
+    // every input at full volume still fits in -1.0-1.0
+    (
+      left * left_volume / (left_inputs * 8.0),
+      right * right_volume / (right_inputs * 8.0),
+    )
+  }
+
+  /// Channel state for the debug viewer
+  pub fn channel_status(&self) -> [ChannelStatus; 4] {
+    [
+      self.ch1.status(&self.regs[CH1_REGS]),
+      self.ch2.status(&self.regs[CH2_REGS]),
+      self.ch3.status(&self.regs[CH3_REGS], &self.wave_ram),
+      self.ch4.status(&self.regs[CH4_REGS]),
+    ]
+  }
+
+  fn push_sample(&mut self, sample: StereoSample) {
+    if self.samples.len() == SAMPLE_BUFFER_CAP {
+      self.samples.pop_front();
+    }
+    self.samples.push_back(sample);
+  }
+
+  /// Take every queued sample, oldest first
+  pub fn drain_samples(&mut self) -> impl Iterator<Item = StereoSample> + '_ {
+    self.samples.drain(..)
+  }
+
+  pub fn queued_samples(&self) -> usize {
+    self.samples.len()
+  }
+
+  /// Wave ram byte the cpu gets at `addr`. While channel 3 plays, every
+  /// address lands on the byte it is reading, like on the CGB. The DMG only
+  /// allows that on the exact cycle the channel reads and gives $FF
+  /// otherwise, which is not modelled.
+  fn wave_ram_index(&self, addr: u16) -> usize {
+    if self.ch3.enabled {
+      self.ch3.wave_ram_index()
+    } else {
+      (addr - WAVE_RAM_START) as usize
+    }
+  }
+
+  pub fn read(&self, addr: u16) -> GbResult<u8> {
+    match addr {
+      // the bus fills in the write only and unused bits
+      NR10_ADDR..=NR51_ADDR => Ok(self.regs[(addr - NR10_ADDR) as usize]),
+      NR52_ADDR => Ok((self.powered as u8) << 7 | self.channels_on()),
+      UNUSED_START..=UNUSED_END => Ok(0x00),
+      WAVE_RAM_START..=WAVE_RAM_END => Ok(self.wave_ram[self.wave_ram_index(addr)]),
+      _ => {
+        error!("Unknown read from addr ${:04X}", addr);
+        gb_err!(GbErrorType::OutOfBounds)
+      }
+    }
+  }
+
+  pub fn write(&mut self, addr: u16, data: u8) -> GbResult<()> {
+    match addr {
+      // the registers ignore writes while powered off
+      NR10_ADDR..=NR51_ADDR if self.powered => {
+        let index = (addr - NR10_ADDR) as usize;
+        let prev = self.regs[index];
+        self.regs[index] = data;
+        // the length quirks depend on whether the next step clocks lengths
+        let skips_length = !self.frame_step.is_multiple_of(2);
+        if CH1_REGS.contains(&index) {
+          let regs = &mut self.regs[CH1_REGS];
+          self
+            .ch1
+            .write(index - CH1_REGS.start, prev, regs, skips_length);
+        } else if CH2_REGS.contains(&index) {
+          let regs = &mut self.regs[CH2_REGS];
+          self
+            .ch2
+            .write(index - CH2_REGS.start, prev, regs, skips_length);
+        } else if CH3_REGS.contains(&index) {
+          let regs = &self.regs[CH3_REGS];
+          self
+            .ch3
+            .write(index - CH3_REGS.start, prev, regs, skips_length);
+        } else if CH4_REGS.contains(&index) {
+          let regs = &self.regs[CH4_REGS];
+          self
+            .ch4
+            .write(index - CH4_REGS.start, prev, regs, skips_length);
+        }
+      }
+      NR10_ADDR..=NR51_ADDR => {}
+      // only the power bit can be written
+      NR52_ADDR => {
+        let power = data & 0x80 > 0;
+        if self.powered && !power {
+          self.power_off();
+        }
+        self.powered = power;
+      }
+      UNUSED_START..=UNUSED_END => {}
+      WAVE_RAM_START..=WAVE_RAM_END => self.wave_ram[self.wave_ram_index(addr)] = data,
+      _ => {
+        error!("Unknown write: 0x{:02X} -> ${:04X}", data, addr);
+        return gb_err!(GbErrorType::OutOfBounds);
+      }
+    }
+    Ok(())
+  }
+}
+
+impl Default for Apu {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Snapshot for Apu {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    writer.write_bytes(&self.regs);
+    writer.write_bool(self.powered);
+    writer.write_bytes(&self.wave_ram);
+    self.ch1.save(writer);
+    self.ch2.save(writer);
+    self.ch3.save(writer);
+    self.ch4.save(writer);
+    writer.write_u8(self.frame_step);
+    writer.write_u32(self.sample_cycles);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    reader.read_bytes_into(&mut self.regs)?;
+    self.powered = reader.read_bool()?;
+    reader.read_bytes_into(&mut self.wave_ram)?;
+    self.ch1.load(reader)?;
+    self.ch2.load(reader)?;
+    self.ch3.load(reader)?;
+    self.ch4.load(reader)?;
+    self.frame_step = reader.read_u8()? % 8;
+    self.sample_cycles = reader.read_u32()? % SAMPLE_CYCLES;
+    // samples from before the snapshot would only play as a glitch
+    self.samples.clear();
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_power_off_clears_registers() {
+    let mut apu = Apu::new();
+    // locked while off
+    apu.write(NR51_ADDR, 0xff).unwrap();
+    assert_eq!(apu.read(NR51_ADDR).unwrap(), 0x00);
+
+    apu.write(NR52_ADDR, 0x80).unwrap();
+    for addr in NR10_ADDR..=NR51_ADDR {
+      apu.write(addr, 0xff).unwrap();
+    }
+    apu.write(WAVE_RAM_START, 0x5a).unwrap();
+    assert_eq!(apu.read(NR51_ADDR).unwrap(), 0xff);
+    // NR12 has no write only bits
+    apu.write(0xff12, 0xf3).unwrap();
+    assert_eq!(apu.read(0xff12).unwrap(), 0xf3);
+
+    apu.write(NR52_ADDR, 0x00).unwrap();
+    for addr in NR10_ADDR..=NR51_ADDR {
+      assert_eq!(apu.read(addr).unwrap(), 0x00, "${:04X}", addr);
+    }
+    assert_eq!(apu.read(NR52_ADDR).unwrap(), 0x00);
+    // wave ram survives
+    assert_eq!(apu.read(WAVE_RAM_START).unwrap(), 0x5a);
+
+    // and stays cleared after powering back on
+    apu.write(NR52_ADDR, 0x80).unwrap();
+    assert_eq!(apu.read(0xff12).unwrap(), 0x00);
+  }
+
+  #[test]
+  fn test_nr52_status_read_only() {
+    let mut apu = Apu::new();
+    apu.write(NR52_ADDR, 0xff).unwrap();
+    assert_eq!(apu.read(NR52_ADDR).unwrap(), 0x80);
+
+    apu.write(NR52_ADDR, 0x80).unwrap();
+    apu.write(0xff12, 0xf0).unwrap();
+    apu.write(0xff14, 0x80).unwrap();
+    assert_eq!(apu.read(NR52_ADDR).unwrap(), 0x81);
+    // powering off stops every channel
+    apu.write(NR52_ADDR, 0x0f).unwrap();
+    assert_eq!(apu.read(NR52_ADDR).unwrap(), 0x00);
+    assert_eq!(apu.channels_on(), 0);
+  }
+
+  #[test]
+  fn test_channel1_samples() {
+    let mut apu = Apu::new();
+    apu.write(NR52_ADDR, 0x80).unwrap();
+    // full volume, only on the left
+    apu.write(0xff24, 0x77).unwrap();
+    apu.write(NR51_ADDR, 0x10).unwrap();
+    // 50% duty, full volume, fading every 64 Hz tick, frequency 0x7e0
+    apu.write(0xff11, 0x80).unwrap();
+    apu.write(0xff12, 0xf1).unwrap();
+    apu.write(0xff13, 0xe0).unwrap();
+    apu.write(0xff14, 0x87).unwrap();
+    assert!(apu.ch1.enabled);
+
+    // 0x7e0 is 128 cycles per duty step, 1024 per wave, half of it high
+    apu.step(1024);
+    let samples: Vec<StereoSample> = apu.drain_samples().collect();
+    assert_eq!(samples.len(), 256);
+    let high = samples.iter().filter(|(left, _)| *left > 0.0).count();
+    assert_eq!(high, 128);
+    assert!(samples.iter().all(|(_, right)| *right == 0.0));
+    assert_eq!(apu.queued_samples(), 0);
+
+    // the envelope steps on the 8th frame sequencer step
+    assert_eq!(apu.ch1.envelope.volume, 15);
+    for _ in 0..7 {
+      apu.clock_frame_sequencer();
+    }
+    assert_eq!(apu.ch1.envelope.volume, 15);
+    apu.clock_frame_sequencer();
+    assert_eq!(apu.ch1.envelope.volume, 14);
+  }
+
+  #[test]
+  fn test_channel3_waveform() {
+    let mut apu = Apu::new();
+    apu.write(NR52_ADDR, 0x80).unwrap();
+    // a ramp up and back down
+    let wave = [
+      0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0xfe, 0xdc, 0xba, 0x98, 0x76, 0x54, 0x32,
+      0x10,
+    ];
+    for (i, &byte) in wave.iter().enumerate() {
+      apu.write(WAVE_RAM_START + i as u16, byte).unwrap();
+    }
+    // dac on, 100% volume, frequency 0x7fe is one sample per M-cycle
+    apu.write(0xff1a, 0x80).unwrap();
+    apu.write(0xff1c, 0x20).unwrap();
+    apu.write(0xff1d, 0xfe).unwrap();
+    apu.write(0xff1e, 0x87).unwrap();
+    assert!(apu.ch3.enabled);
+    assert_eq!(apu.read(NR52_ADDR).unwrap(), 0x84);
+
+    let regs = apu.regs;
+    let mut played = Vec::new();
+    for _ in 0..32 {
+      apu.step(SAMPLE_CYCLES);
+      played.push(apu.ch3.output(&regs[CH3_REGS]));
+    }
+    // playback starts at sample 1 and wraps around to sample 0
+    let mut expected: Vec<u8> = (1..16).chain((0..16).rev()).collect();
+    expected.push(0);
+    assert_eq!(played, expected);
+
+    // 25% volume
+    apu.write(0xff1c, 0x60).unwrap();
+    apu.step(SAMPLE_CYCLES * 8);
+    assert_eq!(apu.ch3.output(&apu.regs[CH3_REGS]), 8 >> 2);
+
+    // while playing every wave ram address reads the byte being played
+    assert_eq!(apu.read(WAVE_RAM_START).unwrap(), 0x89);
+    assert_eq!(apu.read(WAVE_RAM_END).unwrap(), 0x89);
+    // turning the dac off stops the channel and frees wave ram again
+    apu.write(0xff1a, 0x00).unwrap();
+    assert!(!apu.ch3.enabled);
+    assert_eq!(apu.read(WAVE_RAM_END).unwrap(), 0x10);
+  }
+
+  #[test]
+  fn test_mute_only_affects_mix() {
+    let mut apus = [Apu::new(), Apu::new()];
+    for apu in &mut apus {
+      apu.write(NR52_ADDR, 0x80).unwrap();
+      apu.write(0xff24, 0x77).unwrap();
+      apu.write(NR51_ADDR, 0xff).unwrap();
+      // 25% duty with an odd frequency so the phase matters
+      apu.write(0xff11, 0x40).unwrap();
+      apu.write(0xff12, 0xf0).unwrap();
+      apu.write(0xff13, 0x9d).unwrap();
+      apu.write(0xff14, 0x87).unwrap();
+    }
+    apus[1].muted[0] = true;
+    for apu in &mut apus {
+      apu.step(10_000);
+    }
+    assert!(apus[0].drain_samples().any(|(left, _)| left != 0.0));
+    assert!(apus[1].drain_samples().all(|sample| sample == (0.0, 0.0)));
+    assert!(apus[1].ch1.enabled);
+
+    // unmuted it plays in step with the channel that was never muted
+    apus[1].muted[0] = false;
+    for apu in &mut apus {
+      apu.step(1000);
+    }
+    let played: Vec<Vec<StereoSample>> = apus
+      .iter_mut()
+      .map(|apu| apu.drain_samples().collect())
+      .collect();
+    assert_eq!(played[0], played[1]);
+  }
+
+  #[test]
+  fn test_length_expires_every_channel() {
+    let mut apu = Apu::new();
+    apu.write(NR52_ADDR, 0x80).unwrap();
+    // dacs on, a length of 1 and the length counter on
+    apu.write(0xff12, 0xf0).unwrap();
+    apu.write(0xff17, 0xf0).unwrap();
+    apu.write(0xff1a, 0x80).unwrap();
+    apu.write(0xff21, 0xf0).unwrap();
+    apu.write(0xff11, 63).unwrap();
+    apu.write(0xff16, 63).unwrap();
+    apu.write(0xff1b, 255).unwrap();
+    apu.write(0xff20, 63).unwrap();
+    for nrx4 in [0xff14, 0xff19, 0xff1e, 0xff23] {
+      apu.write(nrx4, 0xc0).unwrap();
+    }
+    assert_eq!(apu.read(NR52_ADDR).unwrap(), 0x8f);
+
+    // step 0 clocks the lengths
+    apu.clock_frame_sequencer();
+    assert_eq!(apu.read(NR52_ADDR).unwrap(), 0x80);
+    // and a channel without the length counter on keeps playing
+    apu.write(0xff11, 63).unwrap();
+    apu.write(0xff14, 0x80).unwrap();
+    for _ in 0..8 {
+      apu.clock_frame_sequencer();
+    }
+    assert_eq!(apu.read(NR52_ADDR).unwrap(), 0x81);
+  }
+
+  #[test]
+  fn test_length_extra_clock() {
+    let mut apu = Apu::new();
+    apu.write(NR52_ADDR, 0x80).unwrap();
+    apu.write(0xff12, 0xf0).unwrap();
+    apu.write(0xff11, 64 - 2).unwrap();
+    apu.write(0xff14, 0x80).unwrap();
+    // step 0 is done, the next one does not clock lengths
+    apu.clock_frame_sequencer();
+    assert_eq!(apu.ch1.length.counter(), 2);
+
+    // turning length on clocks it once
+    apu.write(0xff14, 0x40).unwrap();
+    assert_eq!(apu.ch1.length.counter(), 1);
+    // but only when it was off before
+    apu.write(0xff14, 0x40).unwrap();
+    assert_eq!(apu.ch1.length.counter(), 1);
+    // running out this way turns the channel off
+    apu.write(0xff14, 0x00).unwrap();
+    apu.write(0xff14, 0x40).unwrap();
+    assert_eq!(apu.ch1.length.counter(), 0);
+    assert!(!apu.ch1.enabled);
+    assert_eq!(apu.read(NR52_ADDR).unwrap(), 0x80);
+
+    // a trigger reloading the empty counter takes that clock off too
+    apu.write(0xff14, 0xc0).unwrap();
+    assert!(apu.ch1.enabled);
+    assert_eq!(apu.ch1.length.counter(), 63);
+
+    // no extra clock when the next step clocks lengths anyway
+    apu.clock_frame_sequencer();
+    apu.write(0xff14, 0x00).unwrap();
+    apu.write(0xff14, 0x40).unwrap();
+    assert_eq!(apu.ch1.length.counter(), 63);
+  }
+
+  #[test]
+  fn test_stereo_panning() {
+    let mut apu = Apu::new();
+    apu.write(NR52_ADDR, 0x80).unwrap();
+    // channel 2 holding a high output: 50% duty starts high, and frequency 0
+    // stays there for 8192 cycles
+    apu.write(0xff16, 0x80).unwrap();
+    apu.write(0xff17, 0xf0).unwrap();
+    apu.write(0xff19, 0x80).unwrap();
+    let mix = |apu: &mut Apu| {
+      apu.step(SAMPLE_CYCLES);
+      apu.drain_samples().last().unwrap()
+    };
+
+    // left only, the right side is silent
+    apu.write(NR50_ADDR, 0x77).unwrap();
+    apu.write(NR51_ADDR, 0x20).unwrap();
+    let (left, right) = mix(&mut apu);
+    assert!(left > 0.0);
+    assert_eq!(right, 0.0);
+
+    // and the other way round
+    apu.write(NR51_ADDR, 0x02).unwrap();
+    assert_eq!(mix(&mut apu), (0.0, left));
+
+    // NR50 scales each side, 1/8 to 8/8
+    apu.write(NR51_ADDR, 0x22).unwrap();
+    apu.write(NR50_ADDR, 0x70).unwrap();
+    let (full, eighth) = mix(&mut apu);
+    assert_eq!(full, left);
+    assert_eq!(eighth, left / 8.0);
+
+    // routing VIN makes room for it on that side only
+    apu.write(NR50_ADDR, 0xf7).unwrap();
+    let (with_vin, without_vin) = mix(&mut apu);
+    assert!((with_vin - left * 4.0 / 5.0).abs() < 1e-6);
+    assert_eq!(without_vin, left);
+
+    // VIN only reaches the sides NR50 sends it to
+    apu.write(NR51_ADDR, 0x00).unwrap();
+    apu.vin = 1.0;
+    apu.write(NR50_ADDR, 0xf7).unwrap();
+    let (left, right) = mix(&mut apu);
+    assert!(left > 0.0);
+    assert_eq!(right, 0.0);
+  }
+}
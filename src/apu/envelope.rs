@@ -0,0 +1,98 @@
+//! Volume envelope shared by the square and noise channels, set up by NRx2.
+
+use crate::err::GbResult;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+
+pub const MAX_VOLUME: u8 = 15;
+
+#[derive(Default)]
+pub struct Envelope {
+  /// Current volume, 0-15
+  pub volume: u8,
+  // NRx2 is only latched on trigger
+  increase: bool,
+  period: u8,
+  // envelope clocks until the next volume step
+  timer: u8,
+}
+
+impl Envelope {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Restart the envelope from NRx2
+  pub fn trigger(&mut self, nrx2: u8) {
+    self.volume = nrx2 >> 4;
+    self.increase = nrx2 & 0x08 > 0;
+    self.period = nrx2 & 0x07;
+    self.timer = self.period;
+  }
+
+  /// Clocked at 64 Hz by the frame sequencer. A period of 0 holds the volume.
+  pub fn clock(&mut self) {
+    if self.period == 0 {
+      return;
+    }
+    self.timer = self.timer.saturating_sub(1);
+    if self.timer > 0 {
+      return;
+    }
+    self.timer = self.period;
+    if self.increase && self.volume < MAX_VOLUME {
+      self.volume += 1;
+    } else if !self.increase && self.volume > 0 {
+      self.volume -= 1;
+    }
+  }
+}
+
+impl Snapshot for Envelope {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    writer.write_u8(self.volume);
+    writer.write_bool(self.increase);
+    writer.write_u8(self.period);
+    writer.write_u8(self.timer);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    self.volume = reader.read_u8()? & MAX_VOLUME;
+    self.increase = reader.read_bool()?;
+    self.period = reader.read_u8()? & 0x07;
+    self.timer = reader.read_u8()?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_envelope_steps() {
+    let mut env = Envelope::new();
+    // volume 3, decreasing every 2 clocks
+    env.trigger(0x32);
+    assert_eq!(env.volume, 3);
+    let mut volumes = Vec::new();
+    for _ in 0..8 {
+      env.clock();
+      volumes.push(env.volume);
+    }
+    assert_eq!(volumes, [3, 2, 2, 1, 1, 0, 0, 0]);
+
+    // volume 14, increasing every clock, stops at 15
+    env.trigger(0xe9);
+    env.clock();
+    assert_eq!(env.volume, 15);
+    env.clock();
+    assert_eq!(env.volume, 15);
+
+    // period 0 never steps
+    env.trigger(0x58);
+    for _ in 0..16 {
+      env.clock();
+    }
+    assert_eq!(env.volume, 5);
+  }
+}
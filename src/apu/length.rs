@@ -0,0 +1,80 @@
+//! Length counter shared by all channels. Loaded from NRx1 and clocked at
+//! 256 Hz by the frame sequencer while NRx4 bit 6 is set, the channel turns
+//! off when it runs out.
+
+use crate::err::GbResult;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+
+/// NRx4 bit 6
+pub const LENGTH_ENABLE: u8 = 0x40;
+/// NRx4 bit 7
+pub const TRIGGER: u8 = 0x80;
+
+pub struct Length {
+  counter: u16,
+  // 64 for most channels, 256 for the wave channel
+  max: u16,
+}
+
+impl Length {
+  pub fn new(max: u16) -> Self {
+    Self { counter: 0, max }
+  }
+
+  pub fn reset(&mut self) {
+    self.counter = 0;
+  }
+
+  /// Clocks left before the channel turns off
+  pub fn counter(&self) -> u16 {
+    self.counter
+  }
+
+  /// Take the length bits of NRx1, the counter runs for `max - data` clocks
+  pub fn write(&mut self, data: u8) {
+    self.counter = self.max - (data as u16 % self.max);
+  }
+
+  /// NRx4 was written, `prev` is what it held before. A trigger restarts a
+  /// counter that already ran out at the full length. Returns false when the
+  /// write runs the counter out and the channel has to turn off.
+  ///
+  /// When the frame sequencer's next step does not clock lengths, turning
+  /// length on clocks it once straight away, and a trigger reload counts as
+  /// that clock.
+  pub fn write_nrx4(&mut self, prev: u8, nrx4: u8, skips_length: bool) -> bool {
+    let length_enable = nrx4 & LENGTH_ENABLE > 0;
+    let mut running = true;
+    if skips_length && length_enable && prev & LENGTH_ENABLE == 0 {
+      running = self.clock(true);
+    }
+    if nrx4 & TRIGGER > 0 && self.counter == 0 {
+      self.counter = self.max;
+      if skips_length && length_enable {
+        self.counter -= 1;
+      }
+    }
+    running
+  }
+
+  /// Returns false when the counter just ran out and the channel has to turn
+  /// off
+  pub fn clock(&mut self, length_enable: bool) -> bool {
+    if !length_enable || self.counter == 0 {
+      return true;
+    }
+    self.counter -= 1;
+    self.counter > 0
+  }
+}
+
+impl Snapshot for Length {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    writer.write_u32(self.counter as u32);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    self.counter = (reader.read_u32()? as u16).min(self.max);
+    Ok(())
+  }
+}
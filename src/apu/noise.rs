@@ -0,0 +1,252 @@
+//! Noise channel 4. A linear-feedback shift register gives the pseudo random
+//! output, set up by NR41-NR44 which the apu hands in as a slice. NR40 does
+//! not exist, its slot just goes unused.
+
+use crate::apu::envelope::{Envelope, MAX_VOLUME};
+use crate::apu::length::{Length, LENGTH_ENABLE, TRIGGER};
+use crate::apu::ChannelStatus;
+use crate::err::GbResult;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+
+// register offsets in the channel's slice
+const NRX1: usize = 1;
+const NRX2: usize = 2;
+const NRX3: usize = 3;
+const NRX4: usize = 4;
+
+/// Every bit set, what a trigger loads into the shift register
+pub const LFSR_SEED: u16 = 0x7fff;
+
+// cpu cycles per shift for each NR43 divisor code, before the clock shift
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+pub struct Noise {
+  /// Channel is playing, shows up in NR52
+  pub enabled: bool,
+  /// NR42 bits 3-7 are not all 0
+  pub dac_on: bool,
+  pub envelope: Envelope,
+  pub length: Length,
+  /// 15-bit shift register, the output is the inverted low bit
+  pub lfsr: u16,
+  // cpu cycles until the next shift
+  timer: u32,
+}
+
+impl Noise {
+  pub fn new() -> Self {
+    Self {
+      enabled: false,
+      dac_on: false,
+      envelope: Envelope::new(),
+      length: Length::new(64),
+      lfsr: LFSR_SEED,
+      timer: 0,
+    }
+  }
+
+  pub fn reset(&mut self) {
+    *self = Noise::new();
+  }
+
+  /// Cpu cycles per shift from NR43, None for clock shifts 14 and 15 which
+  /// stop the register
+  fn period(nr43: u8) -> Option<u32> {
+    let shift = nr43 >> 4;
+    (shift < 14).then(|| DIVISORS[(nr43 & 0x07) as usize] << shift)
+  }
+
+  /// NR43 bit 3 narrows the register to 7 bits, which repeats much sooner
+  /// and sounds more like a tone
+  fn short_mode(nr43: u8) -> bool {
+    nr43 & 0x08 > 0
+  }
+
+  /// Shift the register once. The xor of the low two bits goes in at the top,
+  /// and also at bit 6 in 7-bit mode.
+  pub fn clock_lfsr(lfsr: u16, short_mode: bool) -> u16 {
+    let feedback = (lfsr ^ (lfsr >> 1)) & 1;
+    let mut lfsr = (lfsr >> 1) | feedback << 14;
+    if short_mode {
+      lfsr = (lfsr & !(1 << 6)) | feedback << 6;
+    }
+    lfsr
+  }
+
+  /// React to a write that already landed in `regs`. `prev` is what the
+  /// register held before, `skips_length` is set when the frame sequencer's
+  /// next step does not clock lengths.
+  pub fn write(&mut self, reg: usize, prev: u8, regs: &[u8], skips_length: bool) {
+    match reg {
+      NRX1 => self.length.write(regs[NRX1] & 0x3f),
+      NRX2 => {
+        self.dac_on = regs[NRX2] & 0xf8 > 0;
+        if !self.dac_on {
+          self.enabled = false;
+        }
+      }
+      NRX4 => {
+        let running = self.length.write_nrx4(prev, regs[NRX4], skips_length);
+        if regs[NRX4] & TRIGGER > 0 {
+          self.trigger(regs);
+        } else if !running {
+          self.enabled = false;
+        }
+      }
+      _ => {}
+    }
+  }
+
+  fn trigger(&mut self, regs: &[u8]) {
+    self.enabled = self.dac_on;
+    self.lfsr = LFSR_SEED;
+    self.timer = Noise::period(regs[NRX3]).unwrap_or(0);
+    self.envelope.trigger(regs[NRX2]);
+  }
+
+  pub fn step(&mut self, cycles: u32, regs: &[u8]) {
+    if !self.enabled {
+      return;
+    }
+    let nr43 = regs[NRX3];
+    let Some(period) = Noise::period(nr43) else {
+      return;
+    };
+    let mut cycles = cycles;
+    while cycles >= self.timer {
+      cycles -= self.timer;
+      self.timer = period;
+      self.lfsr = Noise::clock_lfsr(self.lfsr, Noise::short_mode(nr43));
+    }
+    self.timer -= cycles;
+  }
+
+  /// Digital output, 0-15
+  pub fn output(&self) -> u8 {
+    if !self.enabled {
+      return 0;
+    }
+    (!self.lfsr & 1) as u8 * self.envelope.volume
+  }
+
+  /// State for the channel viewer. The frequency is how often the register
+  /// shifts and the waveform is the output it has queued up.
+  pub fn status(&self, regs: &[u8]) -> ChannelStatus {
+    let frequency = match Noise::period(regs[NRX3]) {
+      Some(period) => 4_194_304.0 / period as f32,
+      None => 0.0,
+    };
+    ChannelStatus {
+      enabled: self.enabled,
+      dac_on: self.dac_on,
+      frequency,
+      volume: self.envelope.volume as f32 / MAX_VOLUME as f32,
+      waveform: (0..15)
+        .map(|bit| (!(self.lfsr >> bit) & 1) as f32)
+        .collect(),
+    }
+  }
+
+  pub fn clock_length(&mut self, regs: &[u8]) {
+    if !self.length.clock(regs[NRX4] & LENGTH_ENABLE > 0) {
+      self.enabled = false;
+    }
+  }
+
+  pub fn clock_envelope(&mut self) {
+    if self.enabled {
+      self.envelope.clock();
+    }
+  }
+}
+
+impl Default for Noise {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Snapshot for Noise {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    writer.write_bool(self.enabled);
+    writer.write_bool(self.dac_on);
+    self.envelope.save(writer);
+    self.length.save(writer);
+    writer.write_u32(self.lfsr as u32);
+    writer.write_u32(self.timer);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    self.enabled = reader.read_bool()?;
+    self.dac_on = reader.read_bool()?;
+    self.envelope.load(reader)?;
+    self.length.load(reader)?;
+    self.lfsr = reader.read_u32()? as u16 & LFSR_SEED;
+    self.timer = reader.read_u32()?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // clocks until the register comes back around, after settling in
+  fn lfsr_period(short_mode: bool) -> u32 {
+    let mask = if short_mode { 0x7f } else { LFSR_SEED };
+    let mut lfsr = LFSR_SEED;
+    for _ in 0..100 {
+      lfsr = Noise::clock_lfsr(lfsr, short_mode);
+    }
+    let start = lfsr & mask;
+    let mut period = 0;
+    loop {
+      lfsr = Noise::clock_lfsr(lfsr, short_mode);
+      period += 1;
+      if lfsr & mask == start {
+        return period;
+      }
+    }
+  }
+
+  #[test]
+  fn test_lfsr_period() {
+    assert_eq!(lfsr_period(true), 127);
+    assert_eq!(lfsr_period(false), 32767);
+  }
+
+  #[test]
+  fn test_lfsr_from_seed() {
+    // ones shift out until the two low bits differ
+    let mut lfsr = LFSR_SEED;
+    for _ in 0..14 {
+      lfsr = Noise::clock_lfsr(lfsr, false);
+    }
+    assert_eq!(lfsr, 0x0001);
+    assert_eq!(Noise::clock_lfsr(lfsr, false), 0x4000);
+    // 7-bit mode copies the feedback into bit 6 as well
+    assert_eq!(Noise::clock_lfsr(lfsr, true), 0x4040);
+
+    // a triggered channel shifts once per period from the seed
+    let mut regs = [0, 0, 0xf0, 0x00, 0x80];
+    let mut ch = Noise::new();
+    ch.write(NRX2, 0, &regs, false);
+    ch.write(NRX4, 0, &regs, false);
+    assert!(ch.enabled);
+    ch.step(8 * 15, &regs);
+    assert_eq!(ch.lfsr, 0x4000);
+    assert_eq!(ch.output(), 15);
+
+    // retriggering starts over from the same seed
+    regs[NRX3] = 0x18;
+    ch.write(NRX4, 0, &regs, false);
+    assert_eq!(ch.lfsr, LFSR_SEED);
+    assert_eq!(ch.output(), 0);
+    // clock shift 1 doubles the period, 7-bit mode feeds bit 6 from the
+    // first shift on
+    ch.step(16 * 14, &regs);
+    assert_eq!(ch.lfsr, 0x60e0);
+    ch.step(16, &regs);
+    assert_eq!(ch.lfsr, 0x3030);
+  }
+}
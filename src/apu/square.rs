@@ -0,0 +1,345 @@
+//! Square wave channels. Channel 1 is channel 2 plus a frequency sweep.
+//!
+//! Both are set up by five registers, NRx0-NRx4, which the apu owns and hands
+//! in as a slice. Channel 2 has no NRx0, its slot just goes unused.
+
+use crate::apu::envelope::{Envelope, MAX_VOLUME};
+use crate::apu::length::{Length, LENGTH_ENABLE, TRIGGER};
+use crate::apu::{frequency, ChannelStatus};
+use crate::err::GbResult;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+
+// register offsets in the channel's slice
+const NRX0: usize = 0;
+const NRX1: usize = 1;
+const NRX2: usize = 2;
+const NRX3: usize = 3;
+const NRX4: usize = 4;
+
+/// Highest 11-bit frequency, sweeping past it turns the channel off
+pub const MAX_FREQUENCY: u16 = 0x7ff;
+
+/// 12.5%, 25%, 50% and 75% duty, played from the high bit down
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+fn set_frequency(regs: &mut [u8], freq: u16) {
+  regs[NRX3] = freq as u8;
+  regs[NRX4] = (regs[NRX4] & !0x07) | (freq >> 8) as u8 & 0x07;
+}
+
+#[derive(Default)]
+pub struct Sweep {
+  enabled: bool,
+  // frequency the sweep works from, NRx3/NRx4 get a copy of every update
+  shadow: u16,
+  // sweep clocks until the next update
+  timer: u8,
+}
+
+impl Sweep {
+  fn period(nr10: u8) -> u8 {
+    (nr10 >> 4) & 0x07
+  }
+
+  fn shift(nr10: u8) -> u8 {
+    nr10 & 0x07
+  }
+
+  // a period of 0 counts as 8
+  fn reload(&mut self, nr10: u8) {
+    self.timer = match Sweep::period(nr10) {
+      0 => 8,
+      period => period,
+    };
+  }
+
+  /// Frequency the next sweep step lands on, None if it overflows
+  pub fn next_frequency(&self, nr10: u8) -> Option<u16> {
+    let delta = self.shadow >> Sweep::shift(nr10);
+    let freq = if nr10 & 0x08 > 0 {
+      self.shadow - delta
+    } else {
+      self.shadow + delta
+    };
+    (freq <= MAX_FREQUENCY).then_some(freq)
+  }
+
+  /// Restart the sweep. Returns false if the channel has to turn off because
+  /// the first step already overflows.
+  pub fn trigger(&mut self, regs: &[u8]) -> bool {
+    let nr10 = regs[NRX0];
+    self.shadow = frequency(regs);
+    self.reload(nr10);
+    self.enabled = Sweep::period(nr10) != 0 || Sweep::shift(nr10) != 0;
+    Sweep::shift(nr10) == 0 || self.next_frequency(nr10).is_some()
+  }
+
+  /// Clocked at 128 Hz by the frame sequencer. Returns false if the channel
+  /// has to turn off because the frequency overflowed.
+  pub fn clock(&mut self, regs: &mut [u8]) -> bool {
+    let nr10 = regs[NRX0];
+    self.timer = self.timer.saturating_sub(1);
+    if self.timer > 0 {
+      return true;
+    }
+    self.reload(nr10);
+    if !self.enabled || Sweep::period(nr10) == 0 {
+      return true;
+    }
+    let Some(freq) = self.next_frequency(nr10) else {
+      return false;
+    };
+    if Sweep::shift(nr10) == 0 {
+      return true;
+    }
+    self.shadow = freq;
+    set_frequency(regs, freq);
+    // the new frequency gets checked again straight away
+    self.next_frequency(nr10).is_some()
+  }
+}
+
+impl Snapshot for Sweep {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    writer.write_bool(self.enabled);
+    writer.write_u32(self.shadow as u32);
+    writer.write_u8(self.timer);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    self.enabled = reader.read_bool()?;
+    self.shadow = reader.read_u32()? as u16 & MAX_FREQUENCY;
+    self.timer = reader.read_u8()?;
+    Ok(())
+  }
+}
+
+pub struct Square {
+  /// Channel is playing, shows up in NR52
+  pub enabled: bool,
+  /// NRx2 bits 3-7 are not all 0. The channel stays silent without its dac.
+  pub dac_on: bool,
+  pub envelope: Envelope,
+  pub length: Length,
+  /// Only channel 1 has one
+  pub sweep: Option<Sweep>,
+  // cpu cycles until the duty moves on a step
+  timer: u32,
+  duty_pos: u8,
+}
+
+impl Square {
+  pub fn new(with_sweep: bool) -> Self {
+    Self {
+      enabled: false,
+      dac_on: false,
+      envelope: Envelope::new(),
+      length: Length::new(64),
+      sweep: with_sweep.then(Sweep::default),
+      timer: 0,
+      duty_pos: 0,
+    }
+  }
+
+  pub fn reset(&mut self) {
+    *self = Square::new(self.sweep.is_some());
+  }
+
+  // cpu cycles per duty step
+  fn period(regs: &[u8]) -> u32 {
+    (2048 - frequency(regs) as u32) * 4
+  }
+
+  /// React to a write that already landed in `regs`. `prev` is what the
+  /// register held before, `skips_length` is set when the frame sequencer's
+  /// next step does not clock lengths.
+  pub fn write(&mut self, reg: usize, prev: u8, regs: &mut [u8], skips_length: bool) {
+    match reg {
+      NRX1 => self.length.write(regs[NRX1] & 0x3f),
+      NRX2 => {
+        self.dac_on = regs[NRX2] & 0xf8 > 0;
+        if !self.dac_on {
+          self.enabled = false;
+        }
+      }
+      NRX4 => {
+        let running = self.length.write_nrx4(prev, regs[NRX4], skips_length);
+        if regs[NRX4] & TRIGGER > 0 {
+          self.trigger(regs);
+        } else if !running {
+          self.enabled = false;
+        }
+      }
+      _ => {}
+    }
+  }
+
+  fn trigger(&mut self, regs: &mut [u8]) {
+    self.enabled = self.dac_on;
+    self.timer = Square::period(regs);
+    self.envelope.trigger(regs[NRX2]);
+    if let Some(sweep) = &mut self.sweep {
+      if !sweep.trigger(regs) {
+        self.enabled = false;
+      }
+    }
+  }
+
+  pub fn step(&mut self, cycles: u32, regs: &[u8]) {
+    if !self.enabled {
+      return;
+    }
+    let mut cycles = cycles;
+    while cycles >= self.timer {
+      cycles -= self.timer;
+      // frequency changes only take effect once the current step ends
+      self.timer = Square::period(regs);
+      self.duty_pos = (self.duty_pos + 1) % 8;
+    }
+    self.timer -= cycles;
+  }
+
+  /// Digital output, 0-15
+  pub fn output(&self, regs: &[u8]) -> u8 {
+    if !self.enabled {
+      return 0;
+    }
+    let pattern = DUTY_PATTERNS[(regs[NRX1] >> 6) as usize];
+    let high = (pattern >> (7 - self.duty_pos)) & 1;
+    high * self.envelope.volume
+  }
+
+  /// State for the channel viewer, the waveform is the duty pattern
+  pub fn status(&self, regs: &[u8]) -> ChannelStatus {
+    let pattern = DUTY_PATTERNS[(regs[NRX1] >> 6) as usize];
+    ChannelStatus {
+      enabled: self.enabled,
+      dac_on: self.dac_on,
+      frequency: 131072.0 / (2048 - frequency(regs)) as f32,
+      volume: self.envelope.volume as f32 / MAX_VOLUME as f32,
+      waveform: (0..8)
+        .rev()
+        .map(|bit| ((pattern >> bit) & 1) as f32)
+        .collect(),
+    }
+  }
+
+  pub fn clock_length(&mut self, regs: &[u8]) {
+    if !self.length.clock(regs[NRX4] & LENGTH_ENABLE > 0) {
+      self.enabled = false;
+    }
+  }
+
+  pub fn clock_envelope(&mut self) {
+    if self.enabled {
+      self.envelope.clock();
+    }
+  }
+
+  pub fn clock_sweep(&mut self, regs: &mut [u8]) {
+    if let Some(sweep) = &mut self.sweep {
+      if !sweep.clock(regs) {
+        self.enabled = false;
+      }
+    }
+  }
+}
+
+impl Snapshot for Square {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    writer.write_bool(self.enabled);
+    writer.write_bool(self.dac_on);
+    self.envelope.save(writer);
+    self.length.save(writer);
+    if let Some(sweep) = &self.sweep {
+      sweep.save(writer);
+    }
+    writer.write_u32(self.timer);
+    writer.write_u8(self.duty_pos);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    self.enabled = reader.read_bool()?;
+    self.dac_on = reader.read_bool()?;
+    self.envelope.load(reader)?;
+    self.length.load(reader)?;
+    if let Some(sweep) = &mut self.sweep {
+      sweep.load(reader)?;
+    }
+    self.timer = reader.read_u32()?;
+    self.duty_pos = reader.read_u8()? % 8;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // NR10-NR14 with the given sweep and frequency, dac on
+  fn regs(nr10: u8, freq: u16) -> [u8; 5] {
+    let mut regs = [nr10, 0x80, 0xf0, 0, 0];
+    set_frequency(&mut regs, freq);
+    regs
+  }
+
+  fn triggered(regs: &mut [u8]) -> Square {
+    let mut ch = Square::new(true);
+    ch.write(NRX2, 0, regs, false);
+    regs[NRX4] |= 0x80;
+    ch.write(NRX4, 0, regs, false);
+    ch
+  }
+
+  #[test]
+  fn test_sweep_frequency() {
+    // period 1, add, shift 2
+    let mut nr = regs(0x12, 0x100);
+    let mut ch = triggered(&mut nr);
+    assert!(ch.enabled);
+    let sweep = ch.sweep.as_ref().unwrap();
+    assert_eq!(sweep.next_frequency(nr[NRX0]), Some(0x140));
+    ch.clock_sweep(&mut nr);
+    assert_eq!(frequency(&nr), 0x140);
+    ch.clock_sweep(&mut nr);
+    assert_eq!(frequency(&nr), 0x190);
+    assert!(ch.enabled);
+
+    // period 2, subtract, shift 1, only every other clock updates
+    let mut nr = regs(0x29, 0x100);
+    let mut ch = triggered(&mut nr);
+    ch.clock_sweep(&mut nr);
+    assert_eq!(frequency(&nr), 0x100);
+    ch.clock_sweep(&mut nr);
+    assert_eq!(frequency(&nr), 0x080);
+    assert!(ch.enabled);
+  }
+
+  #[test]
+  fn test_sweep_overflow_disables() {
+    // the check on trigger already overflows: 0x7f0 + (0x7f0 >> 1)
+    let mut nr = regs(0x11, 0x7f0);
+    let ch = triggered(&mut nr);
+    assert!(!ch.enabled);
+
+    // period 1, add, shift 2: 0x500 -> 0x640 -> 0x7d0, then the check after
+    // the update overflows and the last frequency is kept
+    let mut nr = regs(0x12, 0x500);
+    let mut ch = triggered(&mut nr);
+    assert!(ch.enabled);
+    ch.clock_sweep(&mut nr);
+    assert_eq!(frequency(&nr), 0x640);
+    assert!(ch.enabled);
+    ch.clock_sweep(&mut nr);
+    assert_eq!(frequency(&nr), 0x7d0);
+    assert!(!ch.enabled);
+
+    // a shift of 0 never updates the frequency but still checks it
+    let mut nr = regs(0x10, 0x500);
+    let mut ch = triggered(&mut nr);
+    assert!(ch.enabled);
+    ch.clock_sweep(&mut nr);
+    assert_eq!(frequency(&nr), 0x500);
+    assert!(!ch.enabled);
+  }
+}
@@ -0,0 +1,171 @@
+//! Wave channel 3. Plays the 32 4-bit samples in wave ram, set up by
+//! NR30-NR34 which the apu hands in as a slice.
+
+use crate::apu::length::{Length, LENGTH_ENABLE, TRIGGER};
+use crate::apu::{frequency, ChannelStatus};
+use crate::err::GbResult;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+
+// register offsets in the channel's slice
+const NRX0: usize = 0;
+const NRX1: usize = 1;
+const NRX2: usize = 2;
+const NRX4: usize = 4;
+
+/// Samples in wave ram, two per byte with the high nibble first
+pub const WAVE_SAMPLES: u8 = 32;
+
+pub struct Wave {
+  /// Channel is playing, shows up in NR52
+  pub enabled: bool,
+  /// NR30 bit 7
+  pub dac_on: bool,
+  pub length: Length,
+  // cpu cycles until the next sample
+  timer: u32,
+  // sample being played, 0-31
+  position: u8,
+  // wave ram byte the sample came from
+  buffer: u8,
+}
+
+impl Wave {
+  pub fn new() -> Self {
+    Self {
+      enabled: false,
+      dac_on: false,
+      length: Length::new(256),
+      timer: 0,
+      position: 0,
+      buffer: 0,
+    }
+  }
+
+  pub fn reset(&mut self) {
+    *self = Wave::new();
+  }
+
+  // cpu cycles per sample, twice as fast as the square channels
+  fn period(regs: &[u8]) -> u32 {
+    (2048 - frequency(regs) as u32) * 2
+  }
+
+  /// Wave ram byte the channel is reading. While it plays the cpu only gets
+  /// at this byte, whatever address it uses.
+  pub fn wave_ram_index(&self) -> usize {
+    (self.position / 2) as usize
+  }
+
+  /// React to a write that already landed in `regs`. `prev` is what the
+  /// register held before, `skips_length` is set when the frame sequencer's
+  /// next step does not clock lengths.
+  pub fn write(&mut self, reg: usize, prev: u8, regs: &[u8], skips_length: bool) {
+    match reg {
+      NRX0 => {
+        self.dac_on = regs[NRX0] & 0x80 > 0;
+        if !self.dac_on {
+          self.enabled = false;
+        }
+      }
+      NRX1 => self.length.write(regs[NRX1]),
+      NRX4 => {
+        let running = self.length.write_nrx4(prev, regs[NRX4], skips_length);
+        if regs[NRX4] & TRIGGER > 0 {
+          self.trigger(regs);
+        } else if !running {
+          self.enabled = false;
+        }
+      }
+      _ => {}
+    }
+  }
+
+  fn trigger(&mut self, regs: &[u8]) {
+    self.enabled = self.dac_on;
+    self.timer = Wave::period(regs);
+    // playback starts at sample 1, the buffer keeps the old byte until then
+    self.position = 0;
+  }
+
+  pub fn step(&mut self, cycles: u32, regs: &[u8], wave_ram: &[u8]) {
+    if !self.enabled {
+      return;
+    }
+    let mut cycles = cycles;
+    while cycles >= self.timer {
+      cycles -= self.timer;
+      self.timer = Wave::period(regs);
+      self.position = (self.position + 1) % WAVE_SAMPLES;
+      self.buffer = wave_ram[self.wave_ram_index()];
+    }
+    self.timer -= cycles;
+  }
+
+  /// State for the channel viewer, the waveform is all of wave ram
+  pub fn status(&self, regs: &[u8], wave_ram: &[u8]) -> ChannelStatus {
+    let volume = match (regs[NRX2] >> 5) & 0x03 {
+      0 => 0.0,
+      code => 1.0 / (1 << (code - 1)) as f32,
+    };
+    ChannelStatus {
+      enabled: self.enabled,
+      dac_on: self.dac_on,
+      frequency: 65536.0 / (2048 - frequency(regs)) as f32,
+      volume,
+      waveform: wave_ram
+        .iter()
+        .flat_map(|byte| [byte >> 4, byte & 0x0f])
+        .map(|sample| sample as f32 / 15.0)
+        .collect(),
+    }
+  }
+
+  pub fn clock_length(&mut self, regs: &[u8]) {
+    if !self.length.clock(regs[NRX4] & LENGTH_ENABLE > 0) {
+      self.enabled = false;
+    }
+  }
+
+  /// Digital output, 0-15. NR32 bits 5-6 pick mute, 100%, 50% or 25%.
+  pub fn output(&self, regs: &[u8]) -> u8 {
+    if !self.enabled {
+      return 0;
+    }
+    let sample = if self.position.is_multiple_of(2) {
+      self.buffer >> 4
+    } else {
+      self.buffer & 0x0f
+    };
+    match (regs[NRX2] >> 5) & 0x03 {
+      0 => 0,
+      code => sample >> (code - 1),
+    }
+  }
+}
+
+impl Default for Wave {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Snapshot for Wave {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    writer.write_bool(self.enabled);
+    writer.write_bool(self.dac_on);
+    self.length.save(writer);
+    writer.write_u32(self.timer);
+    writer.write_u8(self.position);
+    writer.write_u8(self.buffer);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    self.enabled = reader.read_bool()?;
+    self.dac_on = reader.read_bool()?;
+    self.length.load(reader)?;
+    self.timer = reader.read_u32()?;
+    self.position = reader.read_u8()? % WAVE_SAMPLES;
+    self.buffer = reader.read_u8()?;
+    Ok(())
+  }
+}
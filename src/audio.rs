@@ -0,0 +1,217 @@
+//! Host side of the audio. Samples from the apu get resampled to the output
+//! device's rate and queued up for the device callback to pull from.
+
+use crate::apu::{StereoSample, SAMPLE_RATE};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Rate used until a device says otherwise
+pub const DEFAULT_OUTPUT_RATE: u32 = 48_000;
+
+/// Brings samples down from the apu's rate to the output rate. Each output
+/// sample is the average of the input samples it covers, a box filter that
+/// takes out what would alias back into the audible range.
+pub struct Resampler {
+  in_rate: u32,
+  out_rate: u32,
+  // input samples summed for the next output sample
+  sum: StereoSample,
+  count: u32,
+  // how far into the next output sample we are, in units of 1/in_rate
+  phase: u32,
+}
+
+impl Resampler {
+  pub fn new(in_rate: u32, out_rate: u32) -> Resampler {
+    Resampler {
+      in_rate,
+      out_rate,
+      sum: (0.0, 0.0),
+      count: 0,
+      phase: 0,
+    }
+  }
+
+  pub fn out_rate(&self) -> u32 {
+    self.out_rate
+  }
+
+  /// Resample a block, appending to `out`. Leftover input carries over to the
+  /// next block so blocks of any size line up.
+  pub fn process<I>(&mut self, input: I, out: &mut Vec<StereoSample>)
+  where
+    I: IntoIterator<Item = StereoSample>,
+  {
+    for (left, right) in input {
+      self.sum.0 += left;
+      self.sum.1 += right;
+      self.count += 1;
+      self.phase += self.out_rate;
+      if self.phase >= self.in_rate {
+        self.phase -= self.in_rate;
+        let count = self.count as f32;
+        out.push((self.sum.0 / count, self.sum.1 / count));
+        self.sum = (0.0, 0.0);
+        self.count = 0;
+      }
+    }
+  }
+}
+
+/// Resampled samples waiting for the output device, with the master volume.
+/// Cloning shares the same queue, one side for the emulator and one for the
+/// device callback.
+#[derive(Clone)]
+pub struct AudioQueue {
+  inner: Arc<Mutex<QueueInner>>,
+}
+
+struct QueueInner {
+  samples: VecDeque<StereoSample>,
+  cap: usize,
+  volume: f32,
+}
+
+impl AudioQueue {
+  pub fn new(out_rate: u32) -> AudioQueue {
+    // a tenth of a second, older samples get dropped so the latency stays
+    // down when the emulator runs ahead of the device
+    let cap = out_rate as usize / 10;
+    AudioQueue {
+      inner: Arc::new(Mutex::new(QueueInner {
+        samples: VecDeque::with_capacity(cap),
+        cap,
+        volume: 1.0,
+      })),
+    }
+  }
+
+  pub fn push(&self, samples: &[StereoSample]) {
+    let mut inner = self.inner.lock().unwrap();
+    for &sample in samples {
+      if inner.samples.len() == inner.cap {
+        inner.samples.pop_front();
+      }
+      inner.samples.push_back(sample);
+    }
+  }
+
+  /// Fill an interleaved stereo buffer for the device. Runs out into silence
+  /// when the emulator falls behind, e.g. while paused.
+  pub fn fill(&self, out: &mut [f32]) {
+    let mut inner = self.inner.lock().unwrap();
+    let volume = inner.volume;
+    for frame in out.chunks_mut(2) {
+      let (left, right) = inner.samples.pop_front().unwrap_or((0.0, 0.0));
+      frame[0] = left * volume;
+      if let Some(right_out) = frame.get_mut(1) {
+        *right_out = right * volume;
+      }
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.inner.lock().unwrap().samples.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn clear(&self) {
+    self.inner.lock().unwrap().samples.clear();
+  }
+
+  /// Master volume from 0.0 to 1.0
+  pub fn volume(&self) -> f32 {
+    self.inner.lock().unwrap().volume
+  }
+
+  pub fn set_volume(&self, volume: f32) {
+    self.inner.lock().unwrap().volume = volume.clamp(0.0, 1.0);
+  }
+}
+
+/// Takes the apu's samples to the output device
+pub struct AudioOutput {
+  resampler: Resampler,
+  pub queue: AudioQueue,
+  // reused between frames
+  block: Vec<StereoSample>,
+}
+
+impl AudioOutput {
+  pub fn new(out_rate: u32) -> AudioOutput {
+    AudioOutput {
+      resampler: Resampler::new(SAMPLE_RATE, out_rate),
+      queue: AudioQueue::new(out_rate),
+      block: Vec::new(),
+    }
+  }
+
+  /// Switch to a new output rate. Queued samples are dropped, the volume is
+  /// kept.
+  pub fn set_out_rate(&mut self, out_rate: u32) {
+    let volume = self.queue.volume();
+    self.resampler = Resampler::new(SAMPLE_RATE, out_rate);
+    self.queue = AudioQueue::new(out_rate);
+    self.queue.set_volume(volume);
+  }
+
+  /// Resample a block of apu samples and queue them for the device
+  pub fn push<I>(&mut self, samples: I)
+  where
+    I: IntoIterator<Item = StereoSample>,
+  {
+    self.block.clear();
+    self.resampler.process(samples, &mut self.block);
+    self.queue.push(&self.block);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_resampler_length() {
+    // a second of apu samples is a second at the output rate
+    let mut resampler = Resampler::new(SAMPLE_RATE, DEFAULT_OUTPUT_RATE);
+    let mut out = Vec::new();
+    resampler.process(vec![(0.5, -0.5); SAMPLE_RATE as usize], &mut out);
+    assert_eq!(out.len(), DEFAULT_OUTPUT_RATE as usize);
+    assert!(out.iter().all(|&sample| sample == (0.5, -0.5)));
+
+    // one frame's worth, split into uneven blocks, adds up the same
+    let frame_samples = 17556;
+    let mut resampler = Resampler::new(SAMPLE_RATE, DEFAULT_OUTPUT_RATE);
+    let mut out = Vec::new();
+    for block in [1000, 1, 6555, 10000] {
+      resampler.process(vec![(0.0, 0.0); block], &mut out);
+    }
+    let expected = frame_samples * DEFAULT_OUTPUT_RATE as usize / SAMPLE_RATE as usize;
+    assert_eq!(out.len(), expected);
+
+    // a square wave far above the output rate averages out
+    let mut resampler = Resampler::new(8, 2);
+    let mut out = Vec::new();
+    let wave = [(1.0, 1.0), (-1.0, -1.0)].repeat(4);
+    resampler.process(wave, &mut out);
+    assert_eq!(out, [(0.0, 0.0), (0.0, 0.0)]);
+  }
+
+  #[test]
+  fn test_queue_underrun_and_volume() {
+    let queue = AudioQueue::new(DEFAULT_OUTPUT_RATE);
+    queue.push(&[(1.0, -1.0)]);
+    queue.set_volume(0.5);
+    let mut out = [9.0; 6];
+    queue.fill(&mut out);
+    // silence once the queue runs dry
+    assert_eq!(out, [0.5, -0.5, 0.0, 0.0, 0.0, 0.0]);
+    assert!(queue.is_empty());
+
+    queue.set_volume(2.0);
+    assert_eq!(queue.volume(), 1.0);
+  }
+}
@@ -0,0 +1,328 @@
+//! Pc breakpoints with an optional condition on the registers or memory.
+//!
+//! Written as `<addr> [if <condition>]`, e.g. `c100 if A == 0x05`. A condition
+//! compares two values with `==`, `!=`, `<`, `<=`, `>` or `>=`, and
+//! comparisons join with `&&` and `||` (`&&` binds tighter). A value is a
+//! register (A, F, B, C, D, E, H, L, AF, BC, DE, HL, SP, PC), a number
+//! (decimal, `0x` or `$` for hex) or the byte at an address in brackets like
+//! `[HL]` or `[$ff44]`.
+
+use crate::cpu::Cpu;
+use crate::dasm::parse_addr;
+use crate::err::GbResult;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Reg {
+  A,
+  F,
+  B,
+  C,
+  D,
+  E,
+  H,
+  L,
+  AF,
+  BC,
+  DE,
+  HL,
+  SP,
+  PC,
+}
+
+impl Reg {
+  fn parse(name: &str) -> Option<Reg> {
+    let reg = match name.to_uppercase().as_str() {
+      "A" => Reg::A,
+      "F" => Reg::F,
+      "B" => Reg::B,
+      "C" => Reg::C,
+      "D" => Reg::D,
+      "E" => Reg::E,
+      "H" => Reg::H,
+      "L" => Reg::L,
+      "AF" => Reg::AF,
+      "BC" => Reg::BC,
+      "DE" => Reg::DE,
+      "HL" => Reg::HL,
+      "SP" => Reg::SP,
+      "PC" => Reg::PC,
+      _ => return None,
+    };
+    Some(reg)
+  }
+
+  fn read(self, cpu: &Cpu) -> u16 {
+    match self {
+      Reg::A => cpu.af.hi as u16,
+      Reg::F => cpu.af.lo as u16,
+      Reg::B => cpu.bc.hi as u16,
+      Reg::C => cpu.bc.lo as u16,
+      Reg::D => cpu.de.hi as u16,
+      Reg::E => cpu.de.lo as u16,
+      Reg::H => cpu.hl.hi as u16,
+      Reg::L => cpu.hl.lo as u16,
+      Reg::AF => cpu.af.hilo(),
+      Reg::BC => cpu.bc.hilo(),
+      Reg::DE => cpu.de.hilo(),
+      Reg::HL => cpu.hl.hilo(),
+      Reg::SP => cpu.sp,
+      Reg::PC => cpu.pc,
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Value {
+  Num(u16),
+  Reg(Reg),
+  /// Byte at the address the inner value points to
+  Mem(Box<Value>),
+}
+
+impl Value {
+  fn parse(text: &str) -> Result<Value, String> {
+    let text = text.trim();
+    if let Some(inner) = text.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+      return Ok(Value::Mem(Box::new(Value::parse(inner)?)));
+    }
+    if let Some(reg) = Reg::parse(text) {
+      return Ok(Value::Reg(reg));
+    }
+    let num = if text.starts_with('$') || text.starts_with("0x") {
+      parse_addr(text)
+    } else {
+      text.parse().ok()
+    };
+    num
+      .map(Value::Num)
+      .ok_or_else(|| format!("Bad value in condition: {}", text))
+  }
+
+  fn eval<F>(&self, cpu: &Cpu, read: &F) -> GbResult<u16>
+  where
+    F: Fn(u16) -> GbResult<u8>,
+  {
+    match self {
+      Value::Num(num) => Ok(*num),
+      Value::Reg(reg) => Ok(reg.read(cpu)),
+      Value::Mem(addr) => Ok(read(addr.eval(cpu, read)?)? as u16),
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Op {
+  Eq,
+  Ne,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+}
+
+// longer operators first so `<=` is not read as `<`
+const OPS: [(&str, Op); 6] = [
+  ("==", Op::Eq),
+  ("!=", Op::Ne),
+  ("<=", Op::Le),
+  (">=", Op::Ge),
+  ("<", Op::Lt),
+  (">", Op::Gt),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Compare {
+  lhs: Value,
+  op: Op,
+  rhs: Value,
+}
+
+impl Compare {
+  fn parse(text: &str) -> Result<Compare, String> {
+    for (symbol, op) in OPS {
+      if let Some((lhs, rhs)) = text.split_once(symbol) {
+        return Ok(Compare {
+          lhs: Value::parse(lhs)?,
+          op,
+          rhs: Value::parse(rhs)?,
+        });
+      }
+    }
+    Err(format!("No comparison in condition: {}", text.trim()))
+  }
+
+  fn eval<F>(&self, cpu: &Cpu, read: &F) -> GbResult<bool>
+  where
+    F: Fn(u16) -> GbResult<u8>,
+  {
+    let lhs = self.lhs.eval(cpu, read)?;
+    let rhs = self.rhs.eval(cpu, read)?;
+    Ok(match self.op {
+      Op::Eq => lhs == rhs,
+      Op::Ne => lhs != rhs,
+      Op::Lt => lhs < rhs,
+      Op::Le => lhs <= rhs,
+      Op::Gt => lhs > rhs,
+      Op::Ge => lhs >= rhs,
+    })
+  }
+}
+
+/// Comparisons joined by `||` of `&&` groups
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Condition {
+  any: Vec<Vec<Compare>>,
+  text: String,
+}
+
+impl Condition {
+  /// Evaluate against the cpu registers, with `read` for memory values
+  pub fn eval<F>(&self, cpu: &Cpu, read: F) -> GbResult<bool>
+  where
+    F: Fn(u16) -> GbResult<u8>,
+  {
+    for all in &self.any {
+      let mut hit = true;
+      for compare in all {
+        if !compare.eval(cpu, &read)? {
+          hit = false;
+          break;
+        }
+      }
+      if hit {
+        return Ok(true);
+      }
+    }
+    Ok(false)
+  }
+}
+
+impl FromStr for Condition {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let any = s
+      .split("||")
+      .map(|all| all.split("&&").map(Compare::parse).collect())
+      .collect::<Result<_, _>>()?;
+    Ok(Condition {
+      any,
+      text: s.trim().to_string(),
+    })
+  }
+}
+
+impl fmt::Display for Condition {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.text)
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Breakpoint {
+  pub pc: u16,
+  /// Only pause when this holds, None always pauses
+  pub condition: Option<Condition>,
+}
+
+impl Breakpoint {
+  /// Whether the cpu should pause here
+  pub fn hit<F>(&self, cpu: &Cpu, read: F) -> GbResult<bool>
+  where
+    F: Fn(u16) -> GbResult<u8>,
+  {
+    if cpu.pc != self.pc {
+      return Ok(false);
+    }
+    match &self.condition {
+      Some(condition) => condition.eval(cpu, read),
+      None => Ok(true),
+    }
+  }
+}
+
+impl FromStr for Breakpoint {
+  type Err = String;
+  /// `<addr>` or `<addr> if <condition>`
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (addr, condition) = match s.split_once(" if ") {
+      Some((addr, condition)) => (addr, Some(condition.parse()?)),
+      None => (s, None),
+    };
+    let pc = parse_addr(addr).ok_or_else(|| format!("Bad breakpoint address: {}", addr.trim()))?;
+    Ok(Breakpoint { pc, condition })
+  }
+}
+
+impl fmt::Display for Breakpoint {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "${:04X}", self.pc)?;
+    if let Some(condition) = &self.condition {
+      write!(f, " if {}", condition)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // a = $05, hl = $c100, sp = $dff0, with [$c100] = $42
+  fn seeded_cpu() -> Cpu {
+    let mut cpu = Cpu::new();
+    cpu.af.hi = 0x05;
+    cpu.hl.set_u16(0xc100);
+    cpu.sp = 0xdff0;
+    cpu.pc = 0x0150;
+    cpu
+  }
+
+  fn eval(condition: &str) -> bool {
+    let cpu = seeded_cpu();
+    let condition: Condition = condition.parse().unwrap();
+    condition
+      .eval(&cpu, |addr| Ok(if addr == 0xc100 { 0x42 } else { 0 }))
+      .unwrap()
+  }
+
+  #[test]
+  fn test_conditions() {
+    assert!(eval("A == 0x5"));
+    assert!(eval("a == 5"));
+    assert!(!eval("A != $05"));
+    assert!(eval("HL == 0xC100"));
+    assert!(eval("SP > $dfef && SP <= $dff0"));
+    assert!(!eval("B >= 1"));
+    assert!(eval("[HL] == $42"));
+    assert!(eval("[0xc100] > A"));
+    // && binds tighter than ||
+    assert!(eval("B == 1 && C == 1 || A == 5"));
+    assert!(!eval("A == 5 && B == 1 || C == 1"));
+  }
+
+  #[test]
+  fn test_parse_errors() {
+    assert!("A = 5".parse::<Condition>().is_err());
+    assert!("Q == 5".parse::<Condition>().is_err());
+    assert!("A == 0xzz".parse::<Condition>().is_err());
+    assert!("A == 5 &&".parse::<Condition>().is_err());
+    assert!("nowhere if A == 5".parse::<Breakpoint>().is_err());
+  }
+
+  #[test]
+  fn test_breakpoint_hit() {
+    let cpu = seeded_cpu();
+    let read = |_| Ok(0);
+    let bp: Breakpoint = "$0150".parse().unwrap();
+    assert!(bp.hit(&cpu, read).unwrap());
+    let bp: Breakpoint = "0150 if A == 6".parse().unwrap();
+    assert!(!bp.hit(&cpu, read).unwrap());
+    let bp: Breakpoint = "0151 if A == 5".parse().unwrap();
+    assert!(!bp.hit(&cpu, read).unwrap());
+    let bp: Breakpoint = "0x150 if A == 5".parse().unwrap();
+    assert!(bp.hit(&cpu, read).unwrap());
+    assert_eq!(bp.to_string(), "$0150 if A == 5");
+  }
+}
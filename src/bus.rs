@@ -1,19 +1,25 @@
 //! Main Bus for the gameboy emulator. Handles sending reads and writes to the
 //! appropriate location.
 
-use std::{cell::RefCell, rc::Rc};
+use std::{
+  cell::{Cell, RefCell},
+  rc::Rc,
+};
 
 use log::{debug, trace, warn};
 
+use crate::apu::{Apu, NR10_ADDR, NR52_ADDR, UNUSED_END, UNUSED_START};
 use crate::int::Interrupts;
-use crate::timer::Timer;
+use crate::serial::{Serial, SC_ADDR};
+use crate::speed::{SpeedSwitch, KEY1_ADDR};
+use crate::timer::{Timer, TAC_ADDR};
 use crate::{
   cart::Cartridge,
   err::{GbError, GbErrorType, GbResult},
   gb_err,
   joypad::Joypad,
-  ppu::Ppu,
-  ram::Ram,
+  ppu::{Ppu, BCPS_ADDR, OAM_BUG_END, OCPD_ADDR, OCPS_ADDR, STAT_ADDR, VBK_ADDR},
+  ram::{Ram, Wram, SVBK_ADDR},
   util::LazyDref,
 };
 
@@ -32,6 +38,9 @@ pub const OAM_START: u16 = 0xfe00;
 pub const OAM_END: u16 = 0xfe9f;
 pub const WRAM_START: u16 = 0xc000;
 pub const WRAM_END: u16 = 0xdfff;
+/// Mirror of 0xC000-0xDDFF
+pub const ECHO_START: u16 = 0xe000;
+pub const ECHO_END: u16 = 0xfdff;
 pub const TIMER_START: u16 = 0xff04;
 pub const TIMER_END: u16 = 0xff07;
 pub const JOYPAD_EXACT: u16 = 0xff00;
@@ -43,14 +52,72 @@ pub const HRAM_START: u16 = 0xff80;
 pub const HRAM_END: u16 = 0xfffe;
 pub const IE_ADDR: u16 = 0xffff;
 pub const IF_ADDR: u16 = 0xff0f;
+
+// size of the full address space
+const FLAT_MEM_SIZE: usize = 0x10000;
+
+/// Bits of NR10-NR52 that read back as 1. Lengths, frequencies and trigger
+/// bits are write only, and $FF15 and $FF1F are not registers at all.
+#[rustfmt::skip]
+const AUDIO_UNUSED_BITS: [u8; (NR52_ADDR - NR10_ADDR) as usize + 1] = [
+  0x80, 0x3f, 0x00, 0xff, 0xbf, // NR10-NR14
+  0xff, 0x3f, 0x00, 0xff, 0xbf, // NR20-NR24
+  0x7f, 0xff, 0x9f, 0xff, 0xbf, // NR30-NR34
+  0xff, 0xff, 0x00, 0x00, 0xbf, // NR40-NR44
+  0x00, 0x00, 0x70,             // NR50-NR52
+];
+
+/// Bits of an io register that aren't wired to anything and always read
+/// back as 1
+fn unused_bits(addr: u16) -> u8 {
+  match addr {
+    NR10_ADDR..=NR52_ADDR => AUDIO_UNUSED_BITS[(addr - NR10_ADDR) as usize],
+    UNUSED_START..=UNUSED_END => 0xff,
+    JOYPAD_EXACT => 0xc0,
+    SC_ADDR => 0x7e,
+    TAC_ADDR => 0xf8,
+    IF_ADDR => 0xe0,
+    STAT_ADDR => 0x80,
+    VBK_ADDR => 0xfe,
+    KEY1_ADDR => 0x7e,
+    SVBK_ADDR => 0xf8,
+    BCPS_ADDR | OCPS_ADDR => 0x40,
+    _ => 0x00,
+  }
+}
+
+/// One memory cycle of an instruction
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MCycle {
+  Read {
+    addr: u16,
+    val: u8,
+  },
+  Write {
+    addr: u16,
+    val: u8,
+  },
+  /// The cpu is busy with something that doesn't touch the bus
+  Internal,
+}
+
 pub struct Bus {
-  wram: Option<Rc<RefCell<Ram>>>,
+  wram: Option<Rc<RefCell<Wram>>>,
   hram: Option<Rc<RefCell<Ram>>>,
   cart: Option<Rc<RefCell<Cartridge>>>,
   ppu: Option<Rc<RefCell<Ppu>>>,
   ic: Option<Rc<RefCell<Interrupts>>>,
   timer: Option<Rc<RefCell<Timer>>>,
   joypad: Option<Rc<RefCell<Joypad>>>,
+  speed_switch: Option<Rc<RefCell<SpeedSwitch>>>,
+  serial: Option<Rc<RefCell<Serial>>>,
+  apu: Option<Rc<RefCell<Apu>>>,
+  /// Plain ram over the whole address space, replaces all the components
+  flat: Option<Box<[u8]>>,
+  // every access since recording started, None when not recording
+  recording: RefCell<Option<Vec<MCycle>>>,
+  // checked on every access so the log is only borrowed while recording
+  is_recording: Cell<bool>,
 }
 
 impl Bus {
@@ -63,11 +130,27 @@ impl Bus {
       ic: None,
       timer: None,
       joypad: None,
+      speed_switch: None,
+      serial: None,
+      apu: None,
+      flat: None,
+      recording: RefCell::new(None),
+      is_recording: Cell::new(false),
+    }
+  }
+
+  /// Create a bus where the whole address space is plain ram with no side
+  /// effects. Nothing needs to be connected, useful for testing the cpu on
+  /// its own.
+  pub fn new_flat() -> Bus {
+    Bus {
+      flat: Some(vec![0; FLAT_MEM_SIZE].into_boxed_slice()),
+      ..Bus::new()
     }
   }
 
   /// Adds a reference to the working ram to the bus
-  pub fn connect_wram(&mut self, wram: Rc<RefCell<Ram>>) -> GbResult<()> {
+  pub fn connect_wram(&mut self, wram: Rc<RefCell<Wram>>) -> GbResult<()> {
     debug!("Connecting working ram to the bus");
     match self.wram {
       None => self.wram = Some(wram),
@@ -136,91 +219,134 @@ impl Bus {
     Ok(())
   }
 
+  /// Adds a reference to the CGB speed switch to the bus
+  pub fn connect_speed_switch(&mut self, speed_switch: Rc<RefCell<SpeedSwitch>>) -> GbResult<()> {
+    debug!("Connecting speed switch to the bus");
+    match self.speed_switch {
+      None => self.speed_switch = Some(speed_switch),
+      Some(_) => return gb_err!(GbErrorType::AlreadyInitialized),
+    }
+    Ok(())
+  }
+
+  /// Adds a reference to the serial port to the bus
+  pub fn connect_serial(&mut self, serial: Rc<RefCell<Serial>>) -> GbResult<()> {
+    debug!("Connecting serial port to the bus");
+    match self.serial {
+      None => self.serial = Some(serial),
+      Some(_) => return gb_err!(GbErrorType::AlreadyInitialized),
+    }
+    Ok(())
+  }
+
+  /// Adds a reference to the audio unit to the bus
+  pub fn connect_apu(&mut self, apu: Rc<RefCell<Apu>>) -> GbResult<()> {
+    debug!("Connecting apu to the bus");
+    match self.apu {
+      None => self.apu = Some(apu),
+      Some(_) => return gb_err!(GbErrorType::AlreadyInitialized),
+    }
+    Ok(())
+  }
+
   pub fn read8(&self, addr: u16) -> GbResult<u8> {
     #[cfg(debug_assertions)]
     trace!("READ8 ${:04X}", addr);
 
-    // read with relative addressing
-    match addr {
-      CART_ROM_START..=CART_ROM_END => self.cart.lazy_dref().read(addr),
-      CART_RAM_START..=CART_RAM_END => self.cart.lazy_dref().read(addr),
-      CART_IO_START..=CART_IO_END => self.cart.lazy_dref().io_read(addr),
-      PPU_START..=PPU_END | OAM_START..=OAM_END => self.ppu.lazy_dref().read(addr),
-      PPU_IO_START..=PPU_IO_END => self.ppu.lazy_dref().io_read(addr),
-      WRAM_START..=WRAM_END => self.wram.lazy_dref().read(addr - WRAM_START),
-      HRAM_START..=HRAM_END => self.hram.lazy_dref().read(addr - HRAM_START),
-      TIMER_START..=TIMER_END => self.timer.lazy_dref().read(addr),
-      IE_ADDR | IF_ADDR => self.ic.lazy_dref().read(addr),
-      JOYPAD_EXACT => self.joypad.lazy_dref().read(addr),
+    let val = self.read8_inner(addr).map_err(|e| e.with_addr(addr))?;
+    self.record(MCycle::Read { addr, val });
+    Ok(val)
+  }
+
+  /// Start logging every read and write, dropping anything logged before
+  pub fn start_recording(&self) {
+    *self.recording.borrow_mut() = Some(Vec::new());
+    self.is_recording.set(true);
+  }
+
+  /// Stop logging and hand back what was logged in order
+  pub fn take_recording(&self) -> Vec<MCycle> {
+    self.is_recording.set(false);
+    self.recording.borrow_mut().take().unwrap_or_default()
+  }
+
+  fn record(&self, cycle: MCycle) {
+    if !self.is_recording.get() {
+      return;
+    }
+    if let Some(recording) = self.recording.borrow_mut().as_mut() {
+      recording.push(cycle);
+    }
+  }
+
+  fn read8_inner(&self, addr: u16) -> GbResult<u8> {
+    if let Some(mem) = &self.flat {
+      return Ok(mem[addr as usize]);
+    }
+    // read with relative addressing, nothing connected is an error
+    let val = match addr {
+      CART_ROM_START..=CART_ROM_END => self.cart.try_dref()?.read(addr),
+      CART_RAM_START..=CART_RAM_END => self.cart.try_dref()?.read(addr),
+      CART_IO_START..=CART_IO_END => self.cart.try_dref()?.io_read(addr),
+      PPU_START..=PPU_END | OAM_START..=OAM_END => self.ppu.try_dref()?.read(addr),
+      PPU_IO_START..=PPU_IO_END | VBK_ADDR | BCPS_ADDR..=OCPD_ADDR => {
+        self.ppu.try_dref()?.io_read(addr)
+      }
+      WRAM_START..=WRAM_END => self.wram.try_dref()?.read(addr - WRAM_START),
+      ECHO_START..=ECHO_END => self.wram.try_dref()?.read(addr - ECHO_START),
+      SVBK_ADDR => self.wram.try_dref()?.io_read(addr),
+      KEY1_ADDR => self.speed_switch.try_dref()?.read(addr),
+      HRAM_START..=HRAM_END => self.hram.try_dref()?.read(addr - HRAM_START),
+      TIMER_START..=TIMER_END => self.timer.try_dref()?.read(addr),
+      IE_ADDR | IF_ADDR => self.ic.try_dref()?.read(addr),
+      SERIAL_START..=SERIAL_END => self.serial.try_dref()?.read(addr),
+      AUDIO_START..=AUDIO_END => self.apu.try_dref()?.read(addr),
+      JOYPAD_EXACT => self.joypad.try_dref()?.read(addr),
       // unsupported
       _ => {
         warn!("Unsupported read8 address: ${:04X}. Returning 0xff", addr);
         Ok(0xff)
       }
-    }
+    }?;
+    Ok(val | unused_bits(addr))
   }
 
+  /// Little endian, the low byte comes from `addr`. Each byte goes through
+  /// read8 so accesses straddling two regions read both correctly.
   pub fn read16(&self, addr: u16) -> GbResult<u16> {
     #[cfg(debug_assertions)]
     trace!("READ16 ${:04X}", addr);
 
-    // read with relative addressing
-    Ok(match addr {
-      CART_ROM_START..=CART_ROM_END => u16::from_le_bytes([
-        self.cart.lazy_dref().read(addr)?,
-        self.cart.lazy_dref().read(addr + 1)?,
-      ]),
-      CART_RAM_START..=CART_RAM_END => u16::from_le_bytes([
-        self.cart.lazy_dref().read(addr)?,
-        self.cart.lazy_dref().read(addr + 1)?,
-      ]),
-      CART_IO_START..=CART_IO_END => u16::from_le_bytes([
-        self.cart.lazy_dref().io_read(addr)?,
-        self.cart.lazy_dref().io_read(addr + 1)?,
-      ]),
-      PPU_START..=PPU_END | OAM_START..=OAM_END => u16::from_le_bytes([
-        self.ppu.lazy_dref().read(addr)?,
-        self.ppu.lazy_dref().read(addr + 1)?,
-      ]),
-      PPU_IO_START..=PPU_IO_END => u16::from_le_bytes([
-        self.ppu.lazy_dref().io_read(addr)?,
-        self.ppu.lazy_dref().io_read(addr + 1)?,
-      ]),
-      WRAM_START..=WRAM_END => u16::from_le_bytes([
-        self.wram.lazy_dref().read(addr - WRAM_START)?,
-        self.wram.lazy_dref().read(addr - WRAM_START + 1)?,
-      ]),
-      HRAM_START..=HRAM_END => u16::from_le_bytes([
-        self.hram.lazy_dref().read(addr - HRAM_START)?,
-        self.hram.lazy_dref().read(addr - HRAM_START + 1)?,
-      ]),
-      TIMER_START..=TIMER_END => u16::from_le_bytes([
-        self.timer.lazy_dref().read(addr)?,
-        self.timer.lazy_dref().read(addr + 1)?,
-      ]),
-      IF_ADDR | IE_ADDR => u16::from_le_bytes([
-        self.ic.lazy_dref().read(addr)?,
-        self.ic.lazy_dref().read(addr + 1)?,
-      ]),
-
-      // unsupported
-      _ => {
-        warn!("Unsupported read16 address: ${:04X}. Returning 0xff", addr);
-        0xff
-      }
-    })
+    let lo = self.read8(addr)?;
+    let hi = self.read8(addr.wrapping_add(1))?;
+    Ok(u16::from_le_bytes([lo, hi]))
   }
 
   pub fn write8(&mut self, addr: u16, val: u8) -> GbResult<()> {
     #[cfg(debug_assertions)]
     trace!("WRITE8 0x{:02x} ({}) to ${:04X}", val, val, addr);
 
-    // write with relative addressing
+    self
+      .write8_inner(addr, val)
+      .map_err(|e| e.with_addr(addr))?;
+    self.record(MCycle::Write { addr, val });
+    Ok(())
+  }
+
+  fn write8_inner(&mut self, addr: u16, val: u8) -> GbResult<()> {
+    if let Some(mem) = &mut self.flat {
+      mem[addr as usize] = val;
+      return Ok(());
+    }
+    if (OAM_START..=OAM_BUG_END).contains(&addr) {
+      self.ppu.try_dref_mut()?.oam_bug(addr);
+    }
+    // write with relative addressing, nothing connected is an error
     match addr {
-      CART_ROM_START..=CART_ROM_END => self.cart.lazy_dref_mut().write(addr, val),
-      CART_RAM_START..=CART_RAM_END => self.cart.lazy_dref_mut().write(addr, val),
-      CART_IO_START..=CART_IO_END => self.cart.lazy_dref_mut().io_write(addr, val),
-      PPU_START..=PPU_END | OAM_START..=OAM_END => self.ppu.lazy_dref_mut().write(addr, val),
+      CART_ROM_START..=CART_ROM_END => self.cart.try_dref_mut()?.write(addr, val),
+      CART_RAM_START..=CART_RAM_END => self.cart.try_dref_mut()?.write(addr, val),
+      CART_IO_START..=CART_IO_END => self.cart.try_dref_mut()?.io_write(addr, val),
+      PPU_START..=PPU_END | OAM_START..=OAM_END => self.ppu.try_dref_mut()?.write(addr, val),
       PPU_IO_START..=PPU_IO_END => {
         if addr == PPU_IO_DMA {
           debug!("DMA Start");
@@ -229,20 +355,26 @@ impl Bus {
             let src_byte = self.read8(((val as u16) << 8) | offset)?;
             self
               .ppu
-              .lazy_dref_mut()
+              .try_dref_mut()?
               .write(OAM_START + offset, src_byte)?;
           }
           debug!("DMA End");
           Ok(())
         } else {
-          self.ppu.lazy_dref_mut().io_write(addr, val)
+          self.ppu.try_dref_mut()?.io_write(addr, val)
         }
       }
-      WRAM_START..=WRAM_END => self.wram.lazy_dref_mut().write(addr - WRAM_START, val),
-      HRAM_START..=HRAM_END => self.hram.lazy_dref_mut().write(addr - HRAM_START, val),
-      TIMER_START..=TIMER_END => self.timer.lazy_dref_mut().write(addr, val),
-      IE_ADDR | IF_ADDR => self.ic.lazy_dref_mut().write(addr, val),
-      JOYPAD_EXACT => self.joypad.lazy_dref_mut().write(addr, val),
+      WRAM_START..=WRAM_END => self.wram.try_dref_mut()?.write(addr - WRAM_START, val),
+      ECHO_START..=ECHO_END => self.wram.try_dref_mut()?.write(addr - ECHO_START, val),
+      SVBK_ADDR => self.wram.try_dref_mut()?.io_write(addr, val),
+      KEY1_ADDR => self.speed_switch.try_dref_mut()?.write(addr, val),
+      VBK_ADDR | BCPS_ADDR..=OCPD_ADDR => self.ppu.try_dref_mut()?.io_write(addr, val),
+      HRAM_START..=HRAM_END => self.hram.try_dref_mut()?.write(addr - HRAM_START, val),
+      TIMER_START..=TIMER_END => self.timer.try_dref_mut()?.write(addr, val),
+      IE_ADDR | IF_ADDR => self.ic.try_dref_mut()?.write(addr, val),
+      SERIAL_START..=SERIAL_END => self.serial.try_dref_mut()?.write(addr, val),
+      AUDIO_START..=AUDIO_END => self.apu.try_dref_mut()?.write(addr, val),
+      JOYPAD_EXACT => self.joypad.try_dref_mut()?.write(addr, val),
       // unsupported
       _ => {
         warn!("Unsupported write8 address: [{:02X}] -> ${:04X}", val, addr);
@@ -251,68 +383,173 @@ impl Bus {
     }
   }
 
+  /// The cpu put `addr` on the bus without reading or writing it, like
+  /// INC/DEC of a 16-bit register. Only matters for the OAM corruption bug.
+  pub fn idle_addr(&mut self, addr: u16) {
+    if self.flat.is_none() && (OAM_START..=OAM_BUG_END).contains(&addr) {
+      self.ppu.lazy_dref_mut().oam_bug(addr);
+    }
+  }
+
+  /// Little endian, the low byte goes to `addr`. Each byte goes through
+  /// write8 so accesses straddling two regions write both correctly.
   pub fn write16(&mut self, addr: u16, val: u16) -> GbResult<()> {
     #[cfg(debug_assertions)]
     trace!("WRITE16 0x{:04x} ({}) to ${:04X}", val, val, addr);
 
-    // write with relative addressing
-    let bytes = val.to_le_bytes();
-    Ok(match addr {
-      CART_ROM_START..=CART_ROM_END => {
-        self.cart.lazy_dref_mut().write(addr, bytes[0])?;
-        self.cart.lazy_dref_mut().write(addr + 1, bytes[1])?;
-      }
-      CART_RAM_START..=CART_RAM_END => {
-        self.cart.lazy_dref_mut().write(addr, bytes[0])?;
-        self.cart.lazy_dref_mut().write(addr + 1, bytes[1])?;
-      }
-      CART_IO_START..=CART_IO_END => {
-        self.cart.lazy_dref_mut().io_write(addr, bytes[0])?;
-        self.cart.lazy_dref_mut().io_write(addr + 1, bytes[1])?;
-      }
-      PPU_START..=PPU_END | OAM_START..=OAM_END => {
-        self.ppu.lazy_dref_mut().write(addr, bytes[0])?;
-        self.ppu.lazy_dref_mut().write(addr + 1, bytes[1])?;
-      }
-      PPU_IO_START..=PPU_IO_END => {
-        self.ppu.lazy_dref_mut().io_write(addr, bytes[0])?;
-        self.ppu.lazy_dref_mut().io_write(addr + 1, bytes[1])?;
-      }
-      WRAM_START..=WRAM_END => {
-        self
-          .wram
-          .lazy_dref_mut()
-          .write(addr - WRAM_START, bytes[0])?;
-        self
-          .wram
-          .lazy_dref_mut()
-          .write(addr - WRAM_START + 1, bytes[1])?;
-      }
-      HRAM_START..=HRAM_END => {
-        self
-          .hram
-          .lazy_dref_mut()
-          .write(addr - HRAM_START, bytes[0])?;
-        self
-          .hram
-          .lazy_dref_mut()
-          .write(addr - HRAM_START + 1, bytes[1])?;
-      }
-      TIMER_START..=TIMER_END => {
-        self.timer.lazy_dref_mut().write(addr, bytes[0])?;
-        self.timer.lazy_dref_mut().write(addr + 1, bytes[1])?;
-      }
-      IF_ADDR | IE_ADDR => {
-        self.ic.lazy_dref_mut().write(addr, bytes[0])?;
-        self.ic.lazy_dref_mut().write(addr + 1, bytes[1])?;
-      }
-      // unsupported
-      _ => {
-        warn!(
-          "Unsupported write16 address: [{:04X}] -> ${:04X}",
-          val, addr
-        );
-      }
-    })
+    let [lo, hi] = val.to_le_bytes();
+    self.write8(addr, lo)?;
+    self.write8(addr.wrapping_add(1), hi)
+  }
+}
+
+impl Default for Bus {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::screen::Screen;
+  use crate::state::{EmuFlow, GbState};
+
+  #[test]
+  fn test_flat_bus() {
+    let mut bus = Bus::new_flat();
+    // 16 bit accesses straddling region boundaries
+    for addr in [CART_ROM_END, PPU_END, WRAM_END, OAM_END, HRAM_END] {
+      bus.write16(addr, 0xbeef).unwrap();
+      assert_eq!(bus.read8(addr).unwrap(), 0xef);
+      assert_eq!(bus.read8(addr + 1).unwrap(), 0xbe);
+      assert_eq!(bus.read16(addr).unwrap(), 0xbeef);
+    }
+
+    // io registers are plain memory, dma does not copy anything
+    bus.write8(PPU_IO_DMA, 0xc0).unwrap();
+    assert_eq!(bus.read8(PPU_IO_DMA).unwrap(), 0xc0);
+    assert_eq!(bus.read8(OAM_START).unwrap(), 0x00);
+    bus.write8(JOYPAD_EXACT, 0x12).unwrap();
+    assert_eq!(bus.read8(JOYPAD_EXACT).unwrap(), 0x12);
+
+    // wraps around the end of the address space
+    bus.write16(IE_ADDR, 0x1234).unwrap();
+    assert_eq!(bus.read8(IE_ADDR).unwrap(), 0x34);
+    assert_eq!(bus.read8(0x0000).unwrap(), 0x12);
+  }
+
+  #[test]
+  fn test_unused_io_bits() {
+    let mut state = GbState::new(EmuFlow::new(false, false, 1.0));
+    state
+      .init(Rc::new(RefCell::new(Screen::headless())))
+      .unwrap();
+    let mut bus = state.bus.borrow_mut();
+
+    // STAT bit 7 is always set
+    bus.write8(STAT_ADDR, 0x00).unwrap();
+    assert_eq!(bus.read8(STAT_ADDR).unwrap() & 0x80, 0x80);
+
+    // IF has only 5 interrupt bits
+    bus.write8(IF_ADDR, 0x00).unwrap();
+    assert_eq!(bus.read8(IF_ADDR).unwrap(), 0xe0);
+    bus.write8(IF_ADDR, 0x01).unwrap();
+    assert_eq!(bus.read8(IF_ADDR).unwrap(), 0xe1);
+
+    // nothing selected on the joypad reads all 1s
+    bus.write8(JOYPAD_EXACT, 0x30).unwrap();
+    assert_eq!(bus.read8(JOYPAD_EXACT).unwrap(), 0xff);
+    bus.write8(JOYPAD_EXACT, 0x20).unwrap();
+    assert_eq!(bus.read8(JOYPAD_EXACT).unwrap(), 0xef);
+
+    bus.write8(TAC_ADDR, 0x05).unwrap();
+    assert_eq!(bus.read8(TAC_ADDR).unwrap(), 0xfd);
+    assert_eq!(bus.read8(SC_ADDR).unwrap(), 0x7e);
+
+    // cgb registers fill their unused bits too
+    bus.wram.lazy_dref_mut().cgb_mode = true;
+    bus.write8(SVBK_ADDR, 0x02).unwrap();
+    assert_eq!(bus.read8(SVBK_ADDR).unwrap(), 0xfa);
+    bus.write8(BCPS_ADDR, 0x85).unwrap();
+    assert_eq!(bus.read8(BCPS_ADDR).unwrap(), 0xc5);
+    bus.write8(OCPS_ADDR, 0x00).unwrap();
+    assert_eq!(bus.read8(OCPS_ADDR).unwrap(), 0x40);
+
+    // audio registers with the apu off read back only their unused bits
+    bus.write8(NR52_ADDR, 0x00).unwrap();
+    for addr in NR10_ADDR..=NR52_ADDR {
+      let index = (addr - NR10_ADDR) as usize;
+      assert_eq!(
+        bus.read8(addr).unwrap(),
+        AUDIO_UNUSED_BITS[index],
+        "${:04X}",
+        addr
+      );
+    }
+    assert_eq!(bus.read8(NR52_ADDR).unwrap(), 0x70);
+    assert_eq!(bus.read8(UNUSED_START).unwrap(), 0xff);
+  }
+
+  #[test]
+  fn test_read16_write16_little_endian() {
+    let mut state = GbState::new(EmuFlow::new(false, false, 1.0));
+    state
+      .init(Rc::new(RefCell::new(Screen::headless())))
+      .unwrap();
+    let mut bus = state.bus.borrow_mut();
+
+    // low byte first
+    bus.write16(WRAM_START, 0x1234).unwrap();
+    assert_eq!(bus.read8(WRAM_START).unwrap(), 0x34);
+    assert_eq!(bus.read8(WRAM_START + 1).unwrap(), 0x12);
+    bus.write8(HRAM_START, 0xcd).unwrap();
+    bus.write8(HRAM_START + 1, 0xab).unwrap();
+    assert_eq!(bus.read16(HRAM_START).unwrap(), 0xabcd);
+
+    // end of wram into echo, which mirrors the start of wram
+    bus.write16(WRAM_END, 0xbeef).unwrap();
+    assert_eq!(bus.read8(WRAM_END).unwrap(), 0xef);
+    assert_eq!(bus.read8(ECHO_START).unwrap(), 0xbe);
+    assert_eq!(bus.read8(WRAM_START).unwrap(), 0xbe);
+    assert_eq!(bus.read16(WRAM_END).unwrap(), 0xbeef);
+
+    // end of echo into oam
+    bus.write16(ECHO_END, 0x5aa5).unwrap();
+    assert_eq!(
+      bus.read8(ECHO_END - (ECHO_START - WRAM_START)).unwrap(),
+      0xa5
+    );
+    assert_eq!(bus.read8(OAM_START).unwrap(), 0x5a);
+    assert_eq!(bus.read16(ECHO_END).unwrap(), 0x5aa5);
+
+    // end of hram into IE
+    bus.write16(HRAM_END, 0x1f42).unwrap();
+    assert_eq!(bus.read8(HRAM_END).unwrap(), 0x42);
+    assert_eq!(bus.read8(IE_ADDR).unwrap(), 0x1f);
+    assert_eq!(bus.read16(HRAM_END).unwrap(), 0x1f42);
+  }
+
+  #[test]
+  fn test_rom_writes_switch_banks() {
+    // 128KiB mbc1 rom where each bank starts with its number
+    let mut rom = vec![0u8; 0x20000];
+    rom[0x147] = 0x01;
+    rom[0x148] = 0x02;
+    for bank in 0..8 {
+      rom[bank * 0x4000] = bank as u8;
+    }
+    let cart = Cartridge::from_bytes(&rom).unwrap();
+    let mut bus = Bus::new();
+    bus.connect_cartridge(Rc::new(RefCell::new(cart))).unwrap();
+
+    assert_eq!(bus.read8(0x4000).unwrap(), 1);
+    bus.write8(0x2100, 0x02).unwrap();
+    assert_eq!(bus.read8(0x4000).unwrap(), 2);
+
+    // the boot rom doesn't block mapper writes under it
+    bus.write8(0x0000, 0x0a).unwrap();
+    bus.write8(0x2000, 0x03).unwrap();
+    assert_eq!(bus.read8(0x4000).unwrap(), 3);
   }
 }
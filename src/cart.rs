@@ -3,19 +3,27 @@
 mod header;
 mod mapper;
 mod mbc1;
+mod mbc2;
 mod mbc3;
 mod no_mbc;
+mod zip;
 
+pub use mapper::RtcTime;
+
+use crate::bus::CART_ROM_END;
 use crate::cart::mapper::{Mapper, MapperType};
 use crate::cart::mbc1::Mbc1;
+use crate::cart::mbc2::Mbc2;
 use crate::cart::mbc3::Mbc3;
 use crate::cart::no_mbc::NoMbc;
 use crate::err::{GbError, GbErrorType, GbResult};
 use crate::gb_err;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+pub use header::GBCSupport;
 use header::*;
 use log::{error, info};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 // raw dump of the DMG boot rom. This is loaded into addresses 0x00..=0xff until
 // the rom writes to the BANK register at 0xff50
@@ -53,6 +61,29 @@ pub const ROM0_START: u16 = 0x0000;
 pub const ROM0_END: u16 = 0x3fff;
 pub const ROM1_START: u16 = 0x4000;
 pub const ROM1_END: u16 = 0x7fff;
+// the cartridge header ends at $014F
+const ROM_HEADER_END: usize = 0x150;
+
+/// File extensions accepted as roms, zip archives get the rom extracted
+pub const ROM_EXTENSIONS: [&str; 3] = ["gb", "gbc", "zip"];
+
+/// Check if a file looks like a rom by its extension
+pub fn is_rom_file(path: &Path) -> bool {
+  path
+    .extension()
+    .and_then(|ext| ext.to_str())
+    .is_some_and(|ext| ROM_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+/// Byte swapped into rom reads, what a Game Genie does
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RomPatch {
+  pub addr: u16,
+  pub value: u8,
+  /// Only patch while the rom byte at `addr` is this. The address is the same
+  /// in every bank, so this picks out the one the code is meant for.
+  pub compare: Option<u8>,
+}
 
 pub struct Cartridge {
   pub path: PathBuf,
@@ -60,6 +91,11 @@ pub struct Cartridge {
   pub header: Header,
   pub loaded: bool,
   pub boot_mode: bool,
+  /// Checked on every rom read, kept in sync with the cheats by the state
+  pub patches: Vec<RomPatch>,
+  boot_rom: Vec<u8>,
+  // handed to each mapper as it is built, before its battery save loads
+  rtc_time: RtcTime,
 }
 
 impl Cartridge {
@@ -70,11 +106,70 @@ impl Cartridge {
       header: Header::new(),
       loaded: false,
       boot_mode: true,
+      patches: Vec::new(),
+      boot_rom: BOOT_ROM.to_vec(),
+      rtc_time: RtcTime::Wall,
+    }
+  }
+
+  /// Empty cartridge that boots through the same boot rom, for loading a rom
+  /// next to this one
+  pub fn empty_like(&self) -> Cartridge {
+    Cartridge {
+      boot_rom: self.boot_rom.clone(),
+      ..Cartridge::new()
     }
   }
 
+  /// Power-up state for a console reset. The rom and battery ram stay loaded
+  /// and the boot rom is mapped again.
+  pub fn soft_reset(&mut self) {
+    self.boot_mode = true;
+    if let Some(mbc) = self.mbc.as_mut() {
+      mbc.reset();
+    }
+  }
+
+  /// Time for the cartridge clock, kept for carts loaded later
+  pub fn set_rtc_time(&mut self, time: RtcTime) {
+    self.rtc_time = time;
+    if let Some(mbc) = self.mbc.as_mut() {
+      mbc.set_rtc_time(time);
+    }
+  }
+
+  /// Replace the built in boot rom with the one at the given path
+  pub fn load_boot_rom(&mut self, path: PathBuf) -> GbResult<()> {
+    let boot_rom = match fs::read(path.clone()) {
+      Ok(data) => data,
+      Err(why) => {
+        error!("Failed to load boot rom {}: {}", path.display(), why);
+        return gb_err!(GbErrorType::FileError);
+      }
+    };
+    if boot_rom.len() != BOOT_ROM.len() {
+      error!(
+        "Boot rom {} is {} bytes, expected {}",
+        path.display(),
+        boot_rom.len(),
+        BOOT_ROM.len()
+      );
+      return gb_err!(GbErrorType::BadValue);
+    }
+    info!("Loaded boot rom {}", path.display());
+    self.boot_rom = boot_rom;
+    Ok(())
+  }
+
+  /// Build a cartridge from a rom image in memory. There is no file behind
+  /// it, so it has no battery save.
+  pub fn from_bytes(rom: &[u8]) -> GbResult<Cartridge> {
+    let mut cart = Cartridge::new();
+    cart.load_rom(rom.to_vec())?;
+    Ok(cart)
+  }
+
   pub fn load(&mut self, path: PathBuf) -> GbResult<()> {
-    self.loaded = true;
     let rom = match fs::read(path.clone()) {
       Ok(data) => data,
       Err(why) => {
@@ -82,9 +177,33 @@ impl Cartridge {
         return gb_err!(GbErrorType::FileError);
       }
     };
+    let rom = if zip::is_zip(&rom) {
+      zip::extract_rom(&rom)?
+    } else {
+      rom
+    };
     self.path = path.clone();
     info!("Loaded {}", self.path.display());
-    self.header.read_header(&Vec::from(&rom[0x100..]))?;
+    self.load_rom(rom)?;
+    self.load_battery();
+    Ok(())
+  }
+
+  /// Load a rom image that is already in memory
+  pub fn load_rom(&mut self, rom: Vec<u8>) -> GbResult<()> {
+    if rom.len() < ROM_HEADER_END {
+      error!("Rom is {} bytes, too small for a header", rom.len());
+      return gb_err!(GbErrorType::BadValue);
+    }
+    self.header.read_header(&rom[0x100..])?;
+    if rom.len() < self.header.rom_banks * ROM_BANK_SIZE {
+      error!(
+        "Rom is {} bytes, header says {} banks",
+        rom.len(),
+        self.header.rom_banks
+      );
+      return gb_err!(GbErrorType::BadValue);
+    }
     info!("------- HEADER --------");
     info!("{:?}", self.header);
     info!("----- HEADER END ------");
@@ -97,11 +216,13 @@ impl Cartridge {
           self.header.ram_banks,
         )))
       }
+      MapperType::Mbc2 => self.mbc = Some(Box::new(Mbc2::new(rom, self.header.rom_banks))),
       MapperType::Mbc3 => {
         self.mbc = Some(Box::new(Mbc3::new(
           rom,
           self.header.rom_banks,
           self.header.ram_banks,
+          self.header.timer_present,
         )))
       }
       _ => {
@@ -109,11 +230,55 @@ impl Cartridge {
         return gb_err!(GbErrorType::Unsupported);
       }
     }
+    self.set_rtc_time(self.rtc_time);
+    self.loaded = true;
+    Ok(())
+  }
+
+  /// Battery save file kept next to the rom. None if the cartridge has no
+  /// battery backed ram.
+  pub fn battery_path(&self) -> Option<PathBuf> {
+    if self.cart_path().is_some() && self.header.battery_present {
+      Some(self.path.with_extension("sav"))
+    } else {
+      None
+    }
+  }
+
+  /// Write the battery backed ram to the save file
+  pub fn save_battery(&self) -> GbResult<()> {
+    let path = match self.battery_path() {
+      Some(path) => path,
+      None => return Ok(()),
+    };
+    let data = self.mbc.as_ref().unwrap().battery();
+    if let Err(why) = fs::write(&path, data) {
+      error!("Failed to save {}: {}", path.display(), why);
+      return gb_err!(GbErrorType::FileError);
+    }
+    info!("Saved {}", path.display());
     Ok(())
   }
 
+  // A missing or broken save is not fatal, the game starts with empty ram
+  fn load_battery(&mut self) {
+    let path = match self.battery_path() {
+      Some(path) => path,
+      None => return,
+    };
+    let data = match fs::read(&path) {
+      Ok(data) => data,
+      Err(_) => return,
+    };
+    match self.mbc.as_mut().unwrap().load_battery(&data) {
+      Ok(_) => info!("Loaded {}", path.display()),
+      Err(_) => error!("Ignoring battery save {}", path.display()),
+    }
+  }
+
+  /// Path of the loaded rom file, None for roms loaded from memory
   pub fn cart_path(&self) -> Option<PathBuf> {
-    if self.loaded {
+    if self.loaded && !self.path.as_os_str().is_empty() {
       Some(self.path.clone())
     } else {
       None
@@ -124,14 +289,14 @@ impl Cartridge {
     Ok(match addr {
       BOOT_ROM_START..=BOOT_ROM_END => {
         if self.boot_mode {
-          BOOT_ROM[addr as usize]
+          self.boot_rom[addr as usize]
         } else {
-          self.mbc.as_ref().unwrap().read(addr)?
+          self.patch(addr, self.mbc.as_ref().unwrap().read(addr)?)
         }
       }
       _ => {
         if self.loaded {
-          self.mbc.as_ref().unwrap().read(addr)?
+          self.patch(addr, self.mbc.as_ref().unwrap().read(addr)?)
         } else {
           // when no cartridge loaded, returns 0xff
           0xff
@@ -140,24 +305,25 @@ impl Cartridge {
     })
   }
 
+  fn patch(&self, addr: u16, val: u8) -> u8 {
+    if addr > CART_ROM_END {
+      return val;
+    }
+    self
+      .patches
+      .iter()
+      .find(|p| p.addr == addr && p.compare.is_none_or(|c| c == val))
+      .map_or(val, |p| p.value)
+  }
+
+  /// Writes to the rom area are mapper control. The boot rom only overlays
+  /// reads so these go to the mapper even while it is mapped.
   pub fn write(&mut self, addr: u16, val: u8) -> GbResult<()> {
-    match addr {
-      BOOT_ROM_START..=BOOT_ROM_END => {
-        if self.boot_mode {
-          panic!("Writing to BOOT ROM")
-        } else {
-          self.mbc.as_mut().unwrap().write(addr, val)?
-        }
-      }
-      _ => {
-        if self.loaded {
-          self.mbc.as_mut().unwrap().write(addr, val)?
-        } else {
-          panic!("Writing with no cartrige loaded")
-        }
-      }
+    if self.loaded {
+      self.mbc.as_mut().unwrap().write(addr, val)
+    } else {
+      panic!("Writing with no cartrige loaded")
     }
-    Ok(())
   }
 
   pub fn io_read(&self, addr: u16) -> GbResult<u8> {
@@ -169,9 +335,140 @@ impl Cartridge {
 
   pub fn io_write(&mut self, addr: u16, data: u8) -> GbResult<()> {
     match addr {
-      0xff50 => self.boot_mode = data == 0,
+      // any non-zero write unmaps the boot rom until the next reset
+      0xff50 => {
+        if data != 0 && self.boot_mode {
+          info!("Boot rom unmapped, handing off to cartridge");
+          self.boot_mode = false;
+        }
+      }
       _ => return gb_err!(GbErrorType::OutOfBounds),
     }
     Ok(())
   }
 }
+
+impl Default for Cartridge {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// The rom itself is not part of the snapshot, so it has to be loaded into
+/// the same cartridge that was saved
+impl Snapshot for Cartridge {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    writer.write_bool(self.boot_mode);
+    writer.write_bool(self.mbc.is_some());
+    if let Some(mbc) = &self.mbc {
+      mbc.save(writer);
+    }
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    self.boot_mode = reader.read_bool()?;
+    let has_mbc = reader.read_bool()?;
+    match &mut self.mbc {
+      Some(mbc) if has_mbc => mbc.load(reader),
+      None if !has_mbc => Ok(()),
+      _ => {
+        error!("Snapshot does not match the loaded cartridge");
+        gb_err!(GbErrorType::BadValue)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_from_bytes() {
+    // 32KiB mbc1 rom without ram
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x134..0x13b].copy_from_slice(b"TESTROM");
+    rom[0x147] = 0x01;
+    rom[0x150] = 0x3c;
+
+    let mut cart = Cartridge::from_bytes(&rom).unwrap();
+    assert_eq!(cart.header.title.trim_end_matches('\0'), "TESTROM");
+    assert!(matches!(cart.header.mapper, MapperType::Mbc1));
+    assert!(!cart.header.battery_present);
+    assert_eq!(cart.header.rom_banks, 2);
+    assert_eq!(cart.header.ram_banks, 0);
+    assert_eq!(cart.cart_path(), None);
+    assert_eq!(cart.battery_path(), None);
+
+    cart.io_write(0xff50, 0x01).unwrap();
+    assert_eq!(cart.read(0x0150).unwrap(), 0x3c);
+
+    // too small to hold a header
+    let err = Cartridge::from_bytes(&rom[..0x100]).err().unwrap();
+    assert_eq!(*err.error_type(), GbErrorType::BadValue);
+  }
+
+  #[test]
+  fn test_load_zipped_rom() {
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x134..0x13b].copy_from_slice(b"ZIPPED!");
+    rom[0x147] = 0x01;
+    let path = std::env::temp_dir().join("gb_test_load_zipped_rom.zip");
+    fs::write(&path, zip::build_zip(&[("zipped.gb", true, &rom)])).unwrap();
+
+    let mut cart = Cartridge::new();
+    cart.load(path.clone()).unwrap();
+    let _ = fs::remove_file(&path);
+    assert_eq!(cart.header.title.trim_end_matches('\0'), "ZIPPED!");
+    assert!(matches!(cart.header.mapper, MapperType::Mbc1));
+  }
+
+  #[test]
+  fn test_ram_bank_status() {
+    // mbc1 with 4 ram banks
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x147] = 0x02;
+    rom[0x149] = 0x03;
+    let mut cart = Cartridge::from_bytes(&rom).unwrap();
+    let mbc = cart.mbc.as_mut().unwrap();
+    assert!(!mbc.ram_enabled());
+    assert_eq!(mbc.ram_bank(), Some(0));
+
+    mbc.write(0x0000, 0x0a).unwrap();
+    mbc.write(0x4000, 0x02).unwrap();
+    mbc.write(0xa000, 0x42).unwrap();
+    assert!(mbc.ram_enabled());
+    assert_eq!(mbc.ram_bank(), Some(2));
+    assert_eq!(mbc.ram()[2 * RAM_BANK_SIZE], 0x42);
+  }
+
+  #[test]
+  fn test_boot_rom_handoff() {
+    // 32KiB rom with no mapper where every byte in the boot area is its address
+    let mut rom = vec![0u8; 0x8000];
+    for (addr, byte) in rom.iter_mut().enumerate().take(0x100) {
+      *byte = addr as u8;
+    }
+    let path = std::env::temp_dir().join("gb_test_boot_rom_handoff.gb");
+    fs::write(&path, &rom).unwrap();
+
+    let mut cart = Cartridge::new();
+    cart.load(path.clone()).unwrap();
+    fs::remove_file(path).unwrap();
+
+    // boot rom is mapped until $FF50 is written
+    assert_eq!(cart.read(0x0000).unwrap(), BOOT_ROM[0x00]);
+    assert_eq!(cart.read(0x00ff).unwrap(), BOOT_ROM[0xff]);
+    cart.io_write(0xff50, 0x00).unwrap();
+    assert_eq!(cart.read(0x0000).unwrap(), BOOT_ROM[0x00]);
+
+    cart.io_write(0xff50, 0x01).unwrap();
+    for addr in 0x00..=0xff {
+      assert_eq!(cart.read(addr).unwrap(), addr as u8);
+    }
+
+    // can not be mapped back in
+    cart.io_write(0xff50, 0x00).unwrap();
+    assert_eq!(cart.read(0x0000).unwrap(), 0x00);
+  }
+}
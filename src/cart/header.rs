@@ -10,6 +10,13 @@ pub enum GBCSupport {
   Unknown,
 }
 
+impl GBCSupport {
+  /// The cart uses the CGB features, so it runs in CGB mode on a CGB
+  pub fn cgb_mode(&self) -> bool {
+    !matches!(self, GBCSupport::Unknown)
+  }
+}
+
 impl From<u8> for GBCSupport {
   fn from(value: u8) -> Self {
     match value {
@@ -35,6 +42,8 @@ pub struct Header {
   pub mapper: MapperType,
   pub battery_present: bool,
   pub ram_present: bool,
+  /// Mbc3 real time clock
+  pub timer_present: bool,
   pub rom_banks: usize,
   pub ram_banks: usize,
   pub rom_version: u8,
@@ -52,6 +61,7 @@ impl Header {
       mapper: MapperType::None,
       battery_present: false,
       ram_present: false,
+      timer_present: false,
       rom_banks: 0,
       ram_banks: 0,
       rom_version: 0,
@@ -62,7 +72,7 @@ impl Header {
 
   // Reads out the header from the given byte stream. The byte stream should start
   // at 0x100
-  pub fn read_header(&mut self, bytes: &Vec<u8>) -> GbResult<()> {
+  pub fn read_header(&mut self, bytes: &[u8]) -> GbResult<()> {
     // $0134-$0143 Title
     self.title = String::from_utf8(Vec::from(&bytes[0x34..=0x43]))
       // if we fail, try only up to $013e as this is exclusive to the title
@@ -83,8 +93,7 @@ impl Header {
     self.publisher = if code == 0x33 {
       // use new licensee list
       // the code in the new licensee list is a two char ascii code
-      let ascii_code = String::from_utf8(Vec::from(&bytes[0x44..=0x45])).unwrap();
-      get_new_publisher(&*ascii_code)
+      get_new_publisher([bytes[0x44], bytes[0x45]])
     } else {
       // use old licensee list
       get_old_publisher(code)
@@ -96,6 +105,7 @@ impl Header {
     self.battery_present = info.battery_present;
     self.ram_present = info.ram_present;
     self.mapper = info.mapper_type;
+    self.timer_present = matches!(code, 0x0f | 0x10);
 
     // $0148 ROM Size
     let code = bytes[0x48];
@@ -136,7 +146,8 @@ fn get_rom_banks(code: u8) -> usize {
   if code > 0x08 {
     panic!("Unsupported rom banks code [{:02X}]", code);
   }
-  1 << code
+  // 32KiB shifted left by the code, in 16KiB banks
+  2 << code
 }
 
 fn get_cart_type(code: u8) -> CartridgeType {
@@ -196,6 +207,17 @@ fn get_cart_type(code: u8) -> CartridgeType {
       ram_present: true,
       mapper_type: MapperType::Mmm01,
     },
+    // $0F and $10 also have a timer
+    0x0F => CartridgeType {
+      battery_present: true,
+      ram_present: false,
+      mapper_type: MapperType::Mbc3,
+    },
+    0x10 => CartridgeType {
+      battery_present: true,
+      ram_present: true,
+      mapper_type: MapperType::Mbc3,
+    },
     0x11 => CartridgeType {
       battery_present: false,
       ram_present: false,
@@ -241,7 +263,7 @@ fn get_cart_type(code: u8) -> CartridgeType {
       ram_present: true,
       mapper_type: MapperType::HuC1,
     },
-    // Note: Not supporting any carts with timers, sensors, or rumble
+    // Note: Not supporting any carts with sensors or rumble
     _ => panic!("Unsupported cartridge type [{:02X}]", code),
   }
 }
@@ -398,8 +420,13 @@ fn get_old_publisher(byte: u8) -> String {
   }
 }
 
-fn get_new_publisher(code: &str) -> String {
-  match &*code.to_uppercase() {
+/// The new code is two ascii characters. Anything else is shown as hex.
+fn get_new_publisher(code: [u8; 2]) -> String {
+  if !code.iter().all(u8::is_ascii_alphanumeric) {
+    return format!("Unknown (NEW) [{:02X}{:02X}]", code[0], code[1]);
+  }
+  let code = String::from_utf8_lossy(&code).to_uppercase();
+  match &*code {
     "00" => "None".into(),
     "01" => "Nintendo R&D1".into(),
     "08" => "Capcom".into(),
@@ -465,3 +492,39 @@ fn get_new_publisher(code: &str) -> String {
     _ => format!("Unknown (NEW) [\"{}\"]", code),
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // header bytes from $0100 with the given licensee codes, rom only
+  fn header_bytes(old_code: u8, new_code: &[u8; 2]) -> Vec<u8> {
+    let mut bytes = vec![0; 0x50];
+    bytes[0x34..0x38].copy_from_slice(b"TEST");
+    bytes[0x44..=0x45].copy_from_slice(new_code);
+    bytes[0x4b] = old_code;
+    bytes
+  }
+
+  #[test]
+  fn test_publisher() {
+    let mut header = Header::new();
+    header.read_header(&header_bytes(0x33, b"01")).unwrap();
+    assert_eq!(header.publisher, "Nintendo R&D1");
+    header.read_header(&header_bytes(0x33, b"a4")).unwrap();
+    assert_eq!(header.publisher, "Konami (Yu-Gi-Oh!)");
+    // the new code is ignored unless the old one is $33
+    header.read_header(&header_bytes(0x01, b"08")).unwrap();
+    assert_eq!(header.publisher, "Nintendo");
+
+    // unknown codes show the raw code
+    header.read_header(&header_bytes(0x33, b"ZZ")).unwrap();
+    assert_eq!(header.publisher, "Unknown (NEW) [\"ZZ\"]");
+    header
+      .read_header(&header_bytes(0x33, &[0xff, 0x00]))
+      .unwrap();
+    assert_eq!(header.publisher, "Unknown (NEW) [FF00]");
+    header.read_header(&header_bytes(0x02, b"00")).unwrap();
+    assert_eq!(header.publisher, "Unknown (OLD) [02]");
+  }
+}
@@ -1,6 +1,10 @@
 //! Base class for all mappers
 
-use crate::err::GbResult;
+use crate::cart::RAM_BANK_SIZE;
+use crate::err::{GbError, GbErrorType, GbResult};
+use crate::gb_err;
+use crate::snapshot::Snapshot;
+use log::error;
 
 #[derive(Debug)]
 pub enum MapperType {
@@ -19,7 +23,58 @@ pub enum MapperType {
   Other,
 }
 
-pub trait Mapper {
+/// Where a cartridge clock gets the time from
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RtcTime {
+  /// Seconds since the unix epoch, the clock runs even while the emulator is
+  /// closed
+  Wall,
+  /// Seconds of emulated time, so a deterministic run sees the same clock
+  /// every time
+  Emulated(u64),
+}
+
+/// Snapshots only hold the mapper registers and ram, the rom comes from the
+/// loaded cartridge
+pub trait Mapper: Snapshot {
   fn read(&self, addr: u16) -> GbResult<u8>;
   fn write(&mut self, addr: u16, val: u8) -> GbResult<()>;
+  /// Contents of the external ram, as kept in a battery save
+  fn ram(&self) -> Vec<u8>;
+  /// Restore the external ram from a battery save
+  fn load_ram(&mut self, data: &[u8]) -> GbResult<()>;
+  /// Everything the battery keeps, for the save file. That is just the ram
+  /// unless the cart has a clock.
+  fn battery(&self) -> Vec<u8> {
+    self.ram()
+  }
+  /// Restore from a save file written by `battery`
+  fn load_battery(&mut self, data: &[u8]) -> GbResult<()> {
+    self.load_ram(data)
+  }
+  /// Return the mapper registers to their power-up state. The rom and ram
+  /// are kept.
+  fn reset(&mut self);
+  /// Whether the external ram is enabled, reads return $FF while it isn't
+  fn ram_enabled(&self) -> bool;
+  /// Ram bank mapped at $A000, None when something other than ram is mapped
+  fn ram_bank(&self) -> Option<usize>;
+  /// Current time for carts with a clock, the others ignore it
+  fn set_rtc_time(&mut self, _time: RtcTime) {}
+}
+
+/// Copy a battery save into ram banks. The save has to fill every bank.
+pub fn load_ram_banks(banks: &mut [[u8; RAM_BANK_SIZE]], data: &[u8]) -> GbResult<()> {
+  if data.len() != banks.len() * RAM_BANK_SIZE {
+    error!(
+      "Battery save is {} bytes, expected {}",
+      data.len(),
+      banks.len() * RAM_BANK_SIZE
+    );
+    return gb_err!(GbErrorType::BadValue);
+  }
+  for (bank, chunk) in banks.iter_mut().zip(data.chunks(RAM_BANK_SIZE)) {
+    bank.copy_from_slice(chunk);
+  }
+  Ok(())
 }
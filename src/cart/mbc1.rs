@@ -1,11 +1,12 @@
 //! Mbc1 mapper
 
-use crate::cart::mapper::Mapper;
+use crate::cart::mapper::{load_ram_banks, Mapper};
 use crate::cart::{
   ERAM_END, ERAM_START, RAM_BANK_SIZE, ROM0_END, ROM0_START, ROM1_END, ROM1_START, ROM_BANK_SIZE,
 };
 use crate::err::{GbError, GbErrorType, GbResult};
 use crate::gb_err;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
 use log::{error, warn};
 
 const RAM_ENABLE_START: u16 = 0x0000;
@@ -84,7 +85,13 @@ impl Mapper for Mbc1 {
       ROM1_START..=ROM1_END => Ok(self.rom[self.get_mapped_rom_bank1()][rel_rom_addr]),
       ERAM_START..=ERAM_END => {
         if self.ram_enabled {
-          Ok(self.ram[self.get_mapped_ram_bank()][rel_ram_addr])
+          // banks the cart doesn't have read like open bus
+          Ok(
+            self
+              .ram
+              .get(self.get_mapped_ram_bank())
+              .map_or(0xff, |bank| bank[rel_ram_addr]),
+          )
         } else {
           warn!(
             "Reading ERAM @0x{:04x} while disabled! Returning 0xff...",
@@ -122,7 +129,9 @@ impl Mapper for Mbc1 {
       ERAM_START..=ERAM_END => {
         if self.ram_enabled {
           let bank = self.get_mapped_ram_bank();
-          self.ram[bank][rel_ram_addr] = val
+          if let Some(bank) = self.ram.get_mut(bank) {
+            bank[rel_ram_addr] = val;
+          }
         }
       }
       _ => {
@@ -132,4 +141,46 @@ impl Mapper for Mbc1 {
     }
     Ok(())
   }
+
+  fn reset(&mut self) {
+    self.ram_enabled = false;
+    self.rom_bank = 1;
+    self.secondary_bank = 0;
+    self.simple_bank_mode = false;
+  }
+
+  fn ram_enabled(&self) -> bool {
+    self.ram_enabled
+  }
+
+  fn ram_bank(&self) -> Option<usize> {
+    Some(self.get_mapped_ram_bank())
+  }
+
+  fn ram(&self) -> Vec<u8> {
+    self.ram.concat()
+  }
+
+  fn load_ram(&mut self, data: &[u8]) -> GbResult<()> {
+    load_ram_banks(&mut self.ram, data)
+  }
+}
+
+impl Snapshot for Mbc1 {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    writer.write_banks(&self.ram);
+    writer.write_bool(self.ram_enabled);
+    writer.write_u32(self.rom_bank as u32);
+    writer.write_u32(self.secondary_bank as u32);
+    writer.write_bool(self.simple_bank_mode);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    reader.read_banks_into(&mut self.ram)?;
+    self.ram_enabled = reader.read_bool()?;
+    self.rom_bank = reader.read_u32()? as usize % self.num_rom_banks;
+    self.secondary_bank = reader.read_u32()? as usize & 0x3;
+    self.simple_bank_mode = reader.read_bool()?;
+    Ok(())
+  }
 }
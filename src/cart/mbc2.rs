@@ -0,0 +1,213 @@
+//! Mbc2 mapper
+
+use crate::cart::mapper::Mapper;
+use crate::cart::{
+  ERAM_END, ERAM_START, ROM0_END, ROM0_START, ROM1_END, ROM1_START, ROM_BANK_SIZE,
+};
+use crate::err::{GbError, GbErrorType, GbResult};
+use crate::gb_err;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+use log::{error, warn};
+
+// registers
+const REG_START: u16 = 0x0000;
+const REG_END: u16 = 0x3fff;
+
+// built in ram of 512 half bytes
+const RAM_SIZE: usize = 512;
+
+pub struct Mbc2 {
+  rom: Vec<[u8; ROM_BANK_SIZE]>,
+  ram: [u8; RAM_SIZE],
+  ram_enabled: bool,
+  rom_bank: usize,
+  num_rom_banks: usize,
+}
+
+impl Mbc2 {
+  pub fn new(rom: Vec<u8>, num_rom_banks: usize) -> Self {
+    // set up rom
+    let mut rom_banks: Vec<[u8; ROM_BANK_SIZE]> = Vec::new();
+    for bank in 0..num_rom_banks {
+      let bank_offset = bank * ROM_BANK_SIZE;
+      let bank_range = bank_offset..(bank_offset + ROM_BANK_SIZE);
+      rom_banks.push([0u8; ROM_BANK_SIZE]);
+      rom_banks[bank].copy_from_slice(&rom[bank_range]);
+    }
+
+    Self {
+      rom: rom_banks,
+      ram: [0; RAM_SIZE],
+      ram_enabled: false,
+      rom_bank: 1,
+      num_rom_banks,
+    }
+  }
+}
+
+impl Mapper for Mbc2 {
+  fn read(&self, addr: u16) -> GbResult<u8> {
+    let rel_rom_addr = addr as usize % ROM_BANK_SIZE;
+    match addr {
+      ROM0_START..=ROM0_END => Ok(self.rom[0][rel_rom_addr]),
+      ROM1_START..=ROM1_END => Ok(self.rom[self.rom_bank][rel_rom_addr]),
+      ERAM_START..=ERAM_END => {
+        if self.ram_enabled {
+          // only the lower 9 bits are used, so the ram echoes through ERAM.
+          // The upper 4 bits of each byte are undefined and read back as 1s
+          Ok(self.ram[addr as usize % RAM_SIZE] | 0xf0)
+        } else {
+          warn!(
+            "Reading ERAM @0x{:04x} while disabled! Returning 0xff...",
+            addr
+          );
+          Ok(0xff)
+        }
+      }
+      _ => {
+        error!("Invalid Read ${:04X}", addr);
+        gb_err!(GbErrorType::OutOfBounds)
+      }
+    }
+  }
+
+  fn write(&mut self, addr: u16, val: u8) -> GbResult<()> {
+    match addr {
+      // bit 8 of the address selects between ram enable and rom bank number
+      REG_START..=REG_END => {
+        if addr & 0x0100 == 0 {
+          // write $XA to enable ram
+          self.ram_enabled = val & 0x0f == 0xa;
+        } else {
+          // setting to 0 acts as setting to 1
+          let bank = val as usize & 0x0f;
+          self.rom_bank = if bank == 0 {
+            1
+          } else {
+            bank % self.num_rom_banks
+          };
+        }
+      }
+      // writes to the upper rom area are ignored
+      ROM1_START..=ROM1_END => {}
+      ERAM_START..=ERAM_END => {
+        if self.ram_enabled {
+          self.ram[addr as usize % RAM_SIZE] = val & 0x0f;
+        }
+      }
+      _ => {
+        error!("Invalid Write [{:02X}] -> ${:04X}", val, addr);
+        return gb_err!(GbErrorType::OutOfBounds);
+      }
+    }
+    Ok(())
+  }
+
+  fn reset(&mut self) {
+    self.ram_enabled = false;
+    self.rom_bank = 1;
+  }
+
+  fn ram_enabled(&self) -> bool {
+    self.ram_enabled
+  }
+
+  fn ram_bank(&self) -> Option<usize> {
+    // the built in ram is a single bank
+    Some(0)
+  }
+
+  fn ram(&self) -> Vec<u8> {
+    self.ram.to_vec()
+  }
+
+  fn load_ram(&mut self, data: &[u8]) -> GbResult<()> {
+    if data.len() != self.ram.len() {
+      error!(
+        "Battery save is {} bytes, expected {}",
+        data.len(),
+        self.ram.len()
+      );
+      return gb_err!(GbErrorType::BadValue);
+    }
+    self.ram.copy_from_slice(data);
+    Ok(())
+  }
+}
+
+impl Snapshot for Mbc2 {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    writer.write_bytes(&self.ram);
+    writer.write_bool(self.ram_enabled);
+    writer.write_u32(self.rom_bank as u32);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    reader.read_bytes_into(&mut self.ram)?;
+    self.ram_enabled = reader.read_bool()?;
+    self.rom_bank = reader.read_u32()? as usize % self.num_rom_banks;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn new_mbc2() -> Mbc2 {
+    // each bank is filled with its bank number
+    let num_rom_banks = 16;
+    let rom = (0..num_rom_banks)
+      .flat_map(|bank| vec![bank as u8; ROM_BANK_SIZE])
+      .collect();
+    Mbc2::new(rom, num_rom_banks)
+  }
+
+  #[test]
+  fn test_mbc2_address_bit_gating() {
+    let mut mbc = new_mbc2();
+    assert_eq!(mbc.read(ROM1_START).unwrap(), 1);
+
+    // bit 8 clear: ram enable, bank is untouched
+    mbc.write(0x2000, 0x0a).unwrap();
+    assert_eq!(mbc.read(ROM1_START).unwrap(), 1);
+    assert!(mbc.ram_enabled);
+
+    // bit 8 set: rom bank select, ram enable is untouched
+    mbc.write(0x2100, 0x05).unwrap();
+    assert_eq!(mbc.read(ROM1_START).unwrap(), 5);
+    mbc.write(0x0100, 0x03).unwrap();
+    assert_eq!(mbc.read(ROM1_START).unwrap(), 3);
+    assert!(mbc.ram_enabled);
+
+    // bank 0 maps to 1 and only the low 4 bits select the bank
+    mbc.write(0x3fff, 0x00).unwrap();
+    assert_eq!(mbc.read(ROM1_START).unwrap(), 1);
+    mbc.write(0x3fff, 0xf7).unwrap();
+    assert_eq!(mbc.read(ROM1_START).unwrap(), 7);
+
+    mbc.write(0x00ff, 0x00).unwrap();
+    assert!(!mbc.ram_enabled);
+    assert_eq!(mbc.read(ROM0_START).unwrap(), 0);
+  }
+
+  #[test]
+  fn test_mbc2_ram_masking() {
+    let mut mbc = new_mbc2();
+
+    // disabled ram ignores writes
+    mbc.write(ERAM_START, 0x05).unwrap();
+    mbc.write(0x0000, 0x0a).unwrap();
+    assert_eq!(mbc.read(ERAM_START).unwrap(), 0xf0);
+
+    // only the low nibble is stored
+    mbc.write(ERAM_START, 0xab).unwrap();
+    assert_eq!(mbc.read(ERAM_START).unwrap(), 0xfb);
+    mbc.write(0xa1ff, 0x3c).unwrap();
+    assert_eq!(mbc.read(0xa1ff).unwrap(), 0xfc);
+
+    // ram echoes every 512 bytes
+    assert_eq!(mbc.read(0xa200).unwrap(), 0xfb);
+    assert_eq!(mbc.read(ERAM_END).unwrap(), 0xfc);
+  }
+}
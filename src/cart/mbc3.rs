@@ -1,12 +1,21 @@
-//! Mbc2 mapper
+//! Mbc3 mapper, optionally with a real time clock.
+//!
+//! The clock follows the wall clock rather than emulated time, so it keeps
+//! running while the emulator is paused or closed. Deterministic runs hand it
+//! emulated seconds instead. Battery saves append the clock in the layout BGB
+//! and VBA use: the live and latched registers as 32-bit words, then the unix
+//! time they were taken at.
 
-use crate::cart::mapper::Mapper;
+use crate::cart::mapper::{load_ram_banks, Mapper, RtcTime};
 use crate::cart::{
   ERAM_END, ERAM_START, RAM_BANK_SIZE, ROM0_END, ROM0_START, ROM1_END, ROM1_START, ROM_BANK_SIZE,
 };
 use crate::err::{GbError, GbErrorType, GbResult};
 use crate::gb_err;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
 use log::{error, warn};
+use std::mem;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 // registers
 const RAM_TIMER_ENABLE_START: u16 = 0x0000;
@@ -18,6 +27,16 @@ const RAM_BANK_RTC_SELECT_END: u16 = 0x5fff;
 const LATCH_CLOCK_START: u16 = 0x6000;
 const LATCH_CLOCK_END: u16 = 0x7fff;
 
+// rtc registers and timestamp appended to a battery save. Some emulators
+// write a 32-bit timestamp instead of a 64-bit one.
+const RTC_SAVE_SIZE: usize = 48;
+const RTC_SAVE_SIZE_SHORT: usize = 44;
+
+// dh bits
+const DH_DAY_HIGH: u8 = 0x01;
+const DH_HALT: u8 = 0x40;
+const DH_DAY_CARRY: u8 = 0x80;
+
 enum RamRtcSelect {
   RamBank(usize),
   RtcS,
@@ -42,7 +61,7 @@ impl From<u8> for RamRtcSelect {
 }
 
 /// real time clock register
-#[derive(Default, Copy, Clone)]
+#[derive(Default, Copy, Clone, Debug, PartialEq)]
 struct Rtc {
   // sec
   pub s: u8,
@@ -57,8 +76,54 @@ struct Rtc {
   //   Bit 6  Halt (0=Active, 1=Stop Timer)
   //   Bit 7  Day Counter Carry Bit (1=overflow)
   pub dh: u8,
-  pub halt: bool,
-  pub day_carry: bool,
+}
+
+impl Rtc {
+  fn days(&self) -> u64 {
+    (self.dh as u64 & DH_DAY_HIGH as u64) << 8 | self.dl as u64
+  }
+
+  /// Run the clock forward. The day counter wraps after 511 and sets the
+  /// carry bit, which stays set until the game clears it.
+  fn advance(&mut self, secs: u64) {
+    if self.dh & DH_HALT > 0 || secs == 0 {
+      return;
+    }
+    let secs = self.s as u64 + secs;
+    let mins = self.m as u64 + secs / 60;
+    let hours = self.h as u64 + mins / 60;
+    let days = self.days() + hours / 24;
+    self.s = (secs % 60) as u8;
+    self.m = (mins % 60) as u8;
+    self.h = (hours % 24) as u8;
+    self.dl = days as u8;
+    self.dh = (self.dh & !DH_DAY_HIGH) | (days >> 8) as u8 & DH_DAY_HIGH;
+    if days > 0x1ff {
+      self.dh |= DH_DAY_CARRY;
+    }
+  }
+
+  fn registers(&self) -> [u8; 5] {
+    [self.s, self.m, self.h, self.dl, self.dh]
+  }
+
+  fn from_registers(regs: [u8; 5]) -> Rtc {
+    Rtc {
+      s: regs[0] & 0x3f,
+      m: regs[1] & 0x3f,
+      h: regs[2] & 0x1f,
+      dl: regs[3],
+      dh: regs[4] & (DH_DAY_HIGH | DH_HALT | DH_DAY_CARRY),
+    }
+  }
+}
+
+/// Seconds since the unix epoch
+fn unix_time() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|time| time.as_secs())
+    .unwrap_or(0)
 }
 
 pub struct Mbc3 {
@@ -67,12 +132,19 @@ pub struct Mbc3 {
   ram_and_timer_enabled: bool,
   rom_bank: usize,
   ram_rtc_select: RamRtcSelect,
+  /// Cart types $0F and $10 have the clock
+  has_rtc: bool,
   rtc: Rtc,
   latched_rtc: Rtc,
+  time: RtcTime,
+  // time the rtc registers were last brought up to date
+  rtc_anchor: u64,
+  // last value written to the latch register, 00 then 01 latches
+  latch_reg: u8,
 }
 
 impl Mbc3 {
-  pub fn new(rom: Vec<u8>, num_rom_banks: usize, num_ram_banks: usize) -> Self {
+  pub fn new(rom: Vec<u8>, num_rom_banks: usize, num_ram_banks: usize, has_rtc: bool) -> Self {
     // set up rom
     let mut rom_banks: Vec<[u8; ROM_BANK_SIZE]> = Vec::new();
     for bank in 0..num_rom_banks {
@@ -94,36 +166,78 @@ impl Mbc3 {
       ram_and_timer_enabled: false,
       rom_bank: 1,
       ram_rtc_select: RamRtcSelect::RamBank(0),
+      has_rtc,
       rtc: Rtc::default(),
       latched_rtc: Rtc::default(),
+      time: RtcTime::Wall,
+      rtc_anchor: unix_time(),
+      latch_reg: 0xff,
+    }
+  }
+
+  fn now(&self) -> u64 {
+    match self.time {
+      RtcTime::Wall => unix_time(),
+      RtcTime::Emulated(secs) => secs,
     }
   }
 
-  // write to one of the rtc register
+  /// Catch the clock up with the current time
+  fn sync_rtc(&mut self, now: u64) {
+    self.rtc.advance(now.saturating_sub(self.rtc_anchor));
+    self.rtc_anchor = now;
+  }
+
+  // read one of the rtc registers, the game sees the latched copy
   pub fn read_rtc(&self) -> GbResult<u8> {
-    // TODO
     match self.ram_rtc_select {
-      RamRtcSelect::RtcS => Ok(self.rtc.s),
-      RamRtcSelect::RtcM => Ok(self.rtc.m),
-      RamRtcSelect::RtcH => Ok(self.rtc.h),
-      RamRtcSelect::RtcDL => Ok(self.rtc.dl),
-      RamRtcSelect::RtcDH => Ok(self.rtc.dh),
+      RamRtcSelect::RtcS => Ok(self.latched_rtc.s),
+      RamRtcSelect::RtcM => Ok(self.latched_rtc.m),
+      RamRtcSelect::RtcH => Ok(self.latched_rtc.h),
+      RamRtcSelect::RtcDL => Ok(self.latched_rtc.dl),
+      RamRtcSelect::RtcDH => Ok(self.latched_rtc.dh),
       _ => panic!("Unexpected rtc reg"),
     }
   }
 
-  // write to one of the rtc register
+  // write to one of the rtc registers. The latched copy follows so the game
+  // reads back what it wrote.
   pub fn write_rtc(&mut self, val: u8) -> GbResult<()> {
-    match self.ram_rtc_select {
-      RamRtcSelect::RtcS => self.rtc.s = val,
-      RamRtcSelect::RtcM => self.rtc.m = val,
-      RamRtcSelect::RtcH => self.rtc.h = val,
-      RamRtcSelect::RtcDL => self.rtc.dl = val,
-      RamRtcSelect::RtcDH => self.rtc.dh = val,
-      _ => panic!("Unexpected rtc reg"),
-    }
+    self.sync_rtc(self.now());
+    let mut regs = self.rtc.registers();
+    let reg = u8::from(&self.ram_rtc_select) - 0x08;
+    regs[reg as usize] = val;
+    self.rtc = Rtc::from_registers(regs);
+    self.latched_rtc = self.rtc;
     Ok(())
   }
+
+  /// Rtc registers and timestamp for the end of a battery save. The file
+  /// always gets a unix time, emulated time means nothing to the next run.
+  fn rtc_save(&self) -> Vec<u8> {
+    let mut rtc = self.rtc;
+    rtc.advance(self.now().saturating_sub(self.rtc_anchor));
+    let mut data = Vec::with_capacity(RTC_SAVE_SIZE);
+    for reg in rtc.registers().iter().chain(&self.latched_rtc.registers()) {
+      data.extend_from_slice(&(*reg as u32).to_le_bytes());
+    }
+    data.extend_from_slice(&unix_time().to_le_bytes());
+    data
+  }
+
+  /// Restore the rtc from the end of a battery save and run it forward by
+  /// the time that passed since
+  fn load_rtc_save(&mut self, data: &[u8], now: u64) {
+    let word = |i: usize| u32::from_le_bytes(data[i * 4..i * 4 + 4].try_into().unwrap());
+    let regs = |first: usize| [0, 1, 2, 3, 4].map(|i| word(first + i) as u8);
+    self.rtc = Rtc::from_registers(regs(0));
+    self.latched_rtc = Rtc::from_registers(regs(5));
+    self.rtc_anchor = match data.len() {
+      RTC_SAVE_SIZE => u64::from_le_bytes(data[40..48].try_into().unwrap()),
+      _ => word(10) as u64,
+    };
+    self.sync_rtc(now);
+  }
 }
 
 impl Mapper for Mbc3 {
@@ -133,10 +247,22 @@ impl Mapper for Mbc3 {
     match addr {
       ROM0_START..=ROM0_END => Ok(self.rom[0][rel_rom_addr]),
       ROM1_START..=ROM1_END => Ok(self.rom[self.rom_bank][rel_rom_addr]),
-      ERAM_START..=ERAM_END => match self.ram_rtc_select {
-        RamRtcSelect::RamBank(bank) => Ok(self.ram[bank][rel_ram_addr]),
-        _ => self.read_rtc(),
-      },
+      ERAM_START..=ERAM_END => {
+        if !self.ram_and_timer_enabled {
+          warn!(
+            "Reading ERAM @0x{:04x} while disabled! Returning 0xff...",
+            addr
+          );
+          return Ok(0xff);
+        }
+        match self.ram_rtc_select {
+          // banks the cart doesn't have read like open bus
+          RamRtcSelect::RamBank(bank) => {
+            Ok(self.ram.get(bank).map_or(0xff, |bank| bank[rel_ram_addr]))
+          }
+          _ => self.read_rtc(),
+        }
+      }
       _ => {
         error!("Invalid Read ${:04X}", addr);
         gb_err!(GbErrorType::OutOfBounds)
@@ -163,12 +289,20 @@ impl Mapper for Mbc3 {
         self.ram_rtc_select = RamRtcSelect::from(val)
       }
       LATCH_CLOCK_START..=LATCH_CLOCK_END => {
-        // TODO: Should write 00 -> 01 for latch to work
-        self.latched_rtc = self.rtc;
+        // writing 00 then 01 copies the clock into the readable registers
+        if self.latch_reg == 0x00 && val == 0x01 {
+          self.sync_rtc(self.now());
+          self.latched_rtc = self.rtc;
+        }
+        self.latch_reg = val;
       }
+      // the ram and clock ignore writes until enabled
+      ERAM_START..=ERAM_END if !self.ram_and_timer_enabled => {}
       ERAM_START..=ERAM_END => match self.ram_rtc_select {
         RamRtcSelect::RamBank(bank) => {
-          self.ram[bank][rel_ram_addr] = val;
+          if let Some(bank) = self.ram.get_mut(bank) {
+            bank[rel_ram_addr] = val;
+          }
         }
         _ => self.write_rtc(val)?,
       },
@@ -180,4 +314,237 @@ impl Mapper for Mbc3 {
 
     Ok(())
   }
+
+  // switching sources picks up from where the clock is, it doesn't jump by
+  // the difference between the two
+  fn set_rtc_time(&mut self, time: RtcTime) {
+    let switched = mem::discriminant(&time) != mem::discriminant(&self.time);
+    if switched {
+      self.sync_rtc(self.now());
+    }
+    self.time = time;
+    if switched {
+      self.rtc_anchor = self.now();
+    }
+  }
+
+  // the rtc keeps running through a reset like the battery backed clock does
+  fn reset(&mut self) {
+    self.ram_and_timer_enabled = false;
+    self.rom_bank = 1;
+    self.ram_rtc_select = RamRtcSelect::RamBank(0);
+  }
+
+  fn ram_enabled(&self) -> bool {
+    self.ram_and_timer_enabled
+  }
+
+  fn ram_bank(&self) -> Option<usize> {
+    match self.ram_rtc_select {
+      RamRtcSelect::RamBank(bank) => Some(bank),
+      // an rtc register is mapped instead
+      _ => None,
+    }
+  }
+
+  fn ram(&self) -> Vec<u8> {
+    self.ram.concat()
+  }
+
+  fn load_ram(&mut self, data: &[u8]) -> GbResult<()> {
+    load_ram_banks(&mut self.ram, data)
+  }
+
+  fn battery(&self) -> Vec<u8> {
+    let mut data = self.ram();
+    if self.has_rtc {
+      data.extend(self.rtc_save());
+    }
+    data
+  }
+
+  // saves without the clock are fine too, it just starts from zero
+  fn load_battery(&mut self, data: &[u8]) -> GbResult<()> {
+    let ram_size = self.ram.len() * RAM_BANK_SIZE;
+    let rtc_size = data.len().saturating_sub(ram_size);
+    if !self.has_rtc || !matches!(rtc_size, 0 | RTC_SAVE_SIZE | RTC_SAVE_SIZE_SHORT) {
+      return self.load_ram(data);
+    }
+    self.load_ram(&data[..ram_size])?;
+    if rtc_size > 0 {
+      self.load_rtc_save(&data[ram_size..], self.now());
+    }
+    Ok(())
+  }
+}
+
+impl From<&RamRtcSelect> for u8 {
+  fn from(value: &RamRtcSelect) -> u8 {
+    match value {
+      RamRtcSelect::RamBank(bank) => *bank as u8,
+      RamRtcSelect::RtcS => 0x08,
+      RamRtcSelect::RtcM => 0x09,
+      RamRtcSelect::RtcH => 0x0A,
+      RamRtcSelect::RtcDL => 0x0B,
+      RamRtcSelect::RtcDH => 0x0C,
+    }
+  }
+}
+
+impl Snapshot for Rtc {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    writer.write_u8(self.s);
+    writer.write_u8(self.m);
+    writer.write_u8(self.h);
+    writer.write_u8(self.dl);
+    writer.write_u8(self.dh);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    self.s = reader.read_u8()?;
+    self.m = reader.read_u8()?;
+    self.h = reader.read_u8()?;
+    self.dl = reader.read_u8()?;
+    self.dh = reader.read_u8()?;
+    Ok(())
+  }
+}
+
+impl Snapshot for Mbc3 {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    writer.write_banks(&self.ram);
+    writer.write_bool(self.ram_and_timer_enabled);
+    writer.write_u32(self.rom_bank as u32);
+    writer.write_u8((&self.ram_rtc_select).into());
+    self.rtc.save(writer);
+    self.latched_rtc.save(writer);
+    writer.write_u32((self.rtc_anchor >> 32) as u32);
+    writer.write_u32(self.rtc_anchor as u32);
+    writer.write_u8(self.latch_reg);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    reader.read_banks_into(&mut self.ram)?;
+    self.ram_and_timer_enabled = reader.read_bool()?;
+    self.rom_bank = reader.read_u32()? as usize & 0x7f;
+    self.ram_rtc_select = match reader.read_u8()? {
+      select @ (0x00..=0x03 | 0x08..=0x0c) => RamRtcSelect::from(select),
+      select => {
+        error!("Bad ram/rtc select in snapshot: {}", select);
+        return gb_err!(GbErrorType::BadValue);
+      }
+    };
+    self.rtc.load(reader)?;
+    self.latched_rtc.load(reader)?;
+    self.rtc_anchor = (reader.read_u32()? as u64) << 32 | reader.read_u32()? as u64;
+    self.latch_reg = reader.read_u8()?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn new_mbc3() -> Mbc3 {
+    Mbc3::new(vec![0; 2 * ROM_BANK_SIZE], 2, 1, true)
+  }
+
+  fn read_clock(mbc: &mut Mbc3) -> [u8; 5] {
+    mbc.write(LATCH_CLOCK_START, 0x00).unwrap();
+    mbc.write(LATCH_CLOCK_START, 0x01).unwrap();
+    [0x08, 0x09, 0x0a, 0x0b, 0x0c].map(|select| {
+      mbc.write(RAM_BANK_RTC_SELECT_START, select).unwrap();
+      mbc.read(ERAM_START).unwrap()
+    })
+  }
+
+  #[test]
+  fn test_rtc_advance() {
+    let mut rtc = Rtc::from_registers([59, 59, 23, 0xff, 0x00]);
+    rtc.advance(1);
+    assert_eq!(rtc.registers(), [0, 0, 0, 0x00, DH_DAY_HIGH]);
+    rtc.advance(256 * 86400 + 61);
+    assert_eq!(rtc.registers(), [1, 1, 0, 0x00, DH_DAY_CARRY]);
+
+    // a halted clock stands still
+    let mut rtc = Rtc::from_registers([5, 0, 0, 0, DH_HALT]);
+    rtc.advance(100);
+    assert_eq!(rtc.s, 5);
+  }
+
+  #[test]
+  fn test_rtc_emulated_time() {
+    let mut mbc = new_mbc3();
+    mbc.write(RAM_TIMER_ENABLE_START, 0x0a).unwrap();
+    mbc.set_rtc_time(RtcTime::Emulated(0));
+    assert_eq!(read_clock(&mut mbc), [0; 5]);
+
+    // only emulated seconds move the clock
+    mbc.set_rtc_time(RtcTime::Emulated(3661));
+    assert_eq!(read_clock(&mut mbc), [1, 1, 1, 0, 0]);
+
+    // the save still gets a unix time for the next run
+    let save = mbc.battery();
+    let anchor = u64::from_le_bytes(save[RAM_BANK_SIZE + 40..].try_into().unwrap());
+    assert!(anchor.abs_diff(unix_time()) < 5);
+  }
+
+  #[test]
+  fn test_rtc_battery_gap() {
+    let mut mbc = new_mbc3();
+    mbc.write(RAM_TIMER_ENABLE_START, 0x0a).unwrap();
+    mbc.write(ERAM_START, 0x42).unwrap();
+    // day 3, 10:00:00
+    for (select, val) in [(0x08, 0), (0x09, 0), (0x0a, 10), (0x0b, 3), (0x0c, 0)] {
+      mbc.write(RAM_BANK_RTC_SELECT_START, select).unwrap();
+      mbc.write(ERAM_START, val).unwrap();
+    }
+    let mut save = mbc.battery();
+    assert_eq!(save.len(), RAM_BANK_SIZE + RTC_SAVE_SIZE);
+
+    // pretend the save was written two days and an hour ago
+    let anchor_at = RAM_BANK_SIZE + 40;
+    let anchor = u64::from_le_bytes(save[anchor_at..].try_into().unwrap());
+    let gap = 2 * 86400 + 3600;
+    save[anchor_at..].copy_from_slice(&(anchor - gap).to_le_bytes());
+
+    let mut mbc = new_mbc3();
+    mbc.load_battery(&save).unwrap();
+    mbc.write(RAM_TIMER_ENABLE_START, 0x0a).unwrap();
+    let clock = read_clock(&mut mbc);
+    assert_eq!(clock[2], 11);
+    assert_eq!(clock[3], 5);
+    mbc.write(RAM_BANK_RTC_SELECT_START, 0x00).unwrap();
+    assert_eq!(mbc.read(ERAM_START).unwrap(), 0x42);
+
+    // saves from before the clock was kept still load
+    let mut mbc = new_mbc3();
+    mbc.load_battery(&save[..RAM_BANK_SIZE]).unwrap();
+    mbc.write(RAM_TIMER_ENABLE_START, 0x0a).unwrap();
+    assert_eq!(read_clock(&mut mbc)[3], 0);
+  }
+
+  #[test]
+  fn test_ram_enable() {
+    let mut mbc = new_mbc3();
+    // disabled at power up, writes are dropped and reads float
+    mbc.write(ERAM_START, 0x42).unwrap();
+    assert_eq!(mbc.read(ERAM_START).unwrap(), 0xff);
+    mbc.write(RAM_TIMER_ENABLE_START, 0x0a).unwrap();
+    assert_eq!(mbc.read(ERAM_START).unwrap(), 0x00);
+    mbc.write(ERAM_START, 0x42).unwrap();
+    assert_eq!(mbc.read(ERAM_START).unwrap(), 0x42);
+
+    // the clock registers are behind the same switch
+    mbc.write(RAM_BANK_RTC_SELECT_START, 0x0a).unwrap();
+    mbc.write(ERAM_START, 0x05).unwrap();
+    mbc.write(RAM_TIMER_ENABLE_START, 0x00).unwrap();
+    mbc.write(ERAM_START, 0x07).unwrap();
+    assert_eq!(mbc.read(ERAM_START).unwrap(), 0xff);
+    mbc.write(RAM_TIMER_ENABLE_START, 0x0a).unwrap();
+    assert_eq!(mbc.read(ERAM_START).unwrap(), 0x05);
+    mbc.write(RAM_BANK_RTC_SELECT_START, 0x00).unwrap();
+    assert_eq!(mbc.read(ERAM_START).unwrap(), 0x42);
+  }
 }
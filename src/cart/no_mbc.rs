@@ -4,6 +4,7 @@ use crate::cart::mapper::Mapper;
 use crate::cart::{ERAM_END, ERAM_START, RAM_BANK_SIZE, ROM0_START, ROM1_END};
 use crate::err::{GbError, GbErrorType, GbResult};
 use crate::gb_err;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
 use log::error;
 
 pub struct NoMbc {
@@ -24,7 +25,14 @@ impl Mapper for NoMbc {
   fn read(&self, addr: u16) -> GbResult<u8> {
     match addr {
       ROM0_START..=ROM1_END => Ok(self.rom[addr as usize]),
-      ERAM_START..=ERAM_END => Ok(self.ram[addr as usize - ERAM_START as usize]),
+      // carts without ram leave the bus floating
+      ERAM_START..=ERAM_END => Ok(
+        self
+          .ram
+          .get(addr as usize - ERAM_START as usize)
+          .copied()
+          .unwrap_or(0xff),
+      ),
       _ => {
         error!("Invalid Read ${:04X}", addr);
         gb_err!(GbErrorType::OutOfBounds)
@@ -36,7 +44,11 @@ impl Mapper for NoMbc {
     match addr {
       // sometimes games write to rom for some reason, just ignore it :/
       ROM0_START..=ROM1_END => {}
-      ERAM_START..=ERAM_END => self.ram[addr as usize - ERAM_START as usize] = val,
+      ERAM_START..=ERAM_END => {
+        if let Some(byte) = self.ram.get_mut(addr as usize - ERAM_START as usize) {
+          *byte = val;
+        }
+      }
       _ => {
         error!("Invalid Write [{:02X}] -> ${:04X}", val, addr);
         return gb_err!(GbErrorType::OutOfBounds);
@@ -44,4 +56,41 @@ impl Mapper for NoMbc {
     }
     Ok(())
   }
+
+  fn reset(&mut self) {}
+
+  fn ram_enabled(&self) -> bool {
+    true
+  }
+
+  fn ram_bank(&self) -> Option<usize> {
+    Some(0)
+  }
+
+  fn ram(&self) -> Vec<u8> {
+    self.ram.to_vec()
+  }
+
+  fn load_ram(&mut self, data: &[u8]) -> GbResult<()> {
+    if data.len() != self.ram.len() {
+      error!(
+        "Battery save is {} bytes, expected {}",
+        data.len(),
+        self.ram.len()
+      );
+      return gb_err!(GbErrorType::BadValue);
+    }
+    self.ram.copy_from_slice(data);
+    Ok(())
+  }
+}
+
+impl Snapshot for NoMbc {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    writer.write_bytes(&self.ram);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    reader.read_bytes_into(&mut self.ram)
+  }
 }
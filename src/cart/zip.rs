@@ -0,0 +1,238 @@
+//! Reading roms out of .zip archives. Only covers what rom archives use:
+//! stored and deflated entries, no encryption or zip64.
+
+use crate::err::{GbError, GbErrorType, GbResult};
+use crate::gb_err;
+use log::{error, info};
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+
+const LOCAL_HEADER_SIG: u32 = 0x04034b50;
+const CENTRAL_HEADER_SIG: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIR_SIG: u32 = 0x06054b50;
+const LOCAL_HEADER_SIZE: usize = 30;
+const CENTRAL_HEADER_SIZE: usize = 46;
+const END_OF_CENTRAL_DIR_SIZE: usize = 22;
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+/// File in the archive, from its central directory header
+struct Entry {
+  name: String,
+  method: u16,
+  compressed_size: usize,
+  size: usize,
+  local_offset: usize,
+}
+
+/// Zip archives start with a local file header
+pub fn is_zip(data: &[u8]) -> bool {
+  data.starts_with(&LOCAL_HEADER_SIG.to_le_bytes())
+}
+
+/// Pull the first .gb or .gbc file out of a zip archive
+pub fn extract_rom(data: &[u8]) -> GbResult<Vec<u8>> {
+  let entries = match read_entries(data) {
+    Some(entries) => entries,
+    None => {
+      error!("Malformed zip archive");
+      return gb_err!(GbErrorType::BadValue);
+    }
+  };
+  let mut roms = entries.iter().filter(|entry| is_rom_name(&entry.name));
+  let entry = match roms.next() {
+    Some(entry) => entry,
+    None => {
+      error!("No .gb or .gbc rom in the zip archive");
+      return gb_err!(GbErrorType::BadValue);
+    }
+  };
+  let skipped = roms.count();
+  if skipped > 0 {
+    info!(
+      "Zip archive has {} other roms, loading {}",
+      skipped, entry.name
+    );
+  }
+
+  let raw = match entry_data(data, entry) {
+    Some(raw) => raw,
+    None => {
+      error!("Malformed zip entry {}", entry.name);
+      return gb_err!(GbErrorType::BadValue);
+    }
+  };
+  let rom = match entry.method {
+    METHOD_STORED => raw.to_vec(),
+    // the central directory size caps the output, so a bad archive can't
+    // inflate without bound
+    METHOD_DEFLATED => match decompress_to_vec_with_limit(raw, entry.size) {
+      Ok(rom) => rom,
+      Err(why) => {
+        error!("Failed to inflate {}: {:?}", entry.name, why);
+        return gb_err!(GbErrorType::BadValue);
+      }
+    },
+    method => {
+      error!(
+        "Unsupported zip compression method {} for {}",
+        method, entry.name
+      );
+      return gb_err!(GbErrorType::Unsupported);
+    }
+  };
+  if rom.len() != entry.size {
+    error!(
+      "{} is {} bytes, zip archive says {}",
+      entry.name,
+      rom.len(),
+      entry.size
+    );
+    return gb_err!(GbErrorType::BadValue);
+  }
+  info!("Extracted {} from zip archive", entry.name);
+  Ok(rom)
+}
+
+fn is_rom_name(name: &str) -> bool {
+  let name = name.to_lowercase();
+  // skip the resource forks macOS adds next to every file
+  !name.starts_with("__macosx/") && (name.ends_with(".gb") || name.ends_with(".gbc"))
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+  let bytes = data.get(offset..offset + 2)?;
+  Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+  let bytes = data.get(offset..offset + 4)?;
+  Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Entries listed in the central directory. The local headers can leave the
+/// sizes out, so these are the ones to trust.
+fn read_entries(data: &[u8]) -> Option<Vec<Entry>> {
+  // the end record is last, only followed by a comment of up to 64K
+  let last = data.len().checked_sub(END_OF_CENTRAL_DIR_SIZE)?;
+  let end = (0..=last)
+    .rev()
+    .take(u16::MAX as usize + 1)
+    .find(|&offset| u32_at(data, offset) == Some(END_OF_CENTRAL_DIR_SIG))?;
+  let count = u16_at(data, end + 10)?;
+  let mut offset = u32_at(data, end + 16)? as usize;
+
+  let mut entries = Vec::new();
+  for _ in 0..count {
+    if u32_at(data, offset)? != CENTRAL_HEADER_SIG {
+      return None;
+    }
+    let name_len = u16_at(data, offset + 28)? as usize;
+    let extra_len = u16_at(data, offset + 30)? as usize;
+    let comment_len = u16_at(data, offset + 32)? as usize;
+    let name_start = offset + CENTRAL_HEADER_SIZE;
+    let name = data.get(name_start..name_start + name_len)?;
+    entries.push(Entry {
+      name: String::from_utf8_lossy(name).into_owned(),
+      method: u16_at(data, offset + 10)?,
+      compressed_size: u32_at(data, offset + 20)? as usize,
+      size: u32_at(data, offset + 24)? as usize,
+      local_offset: u32_at(data, offset + 42)? as usize,
+    });
+    offset = name_start + name_len + extra_len + comment_len;
+  }
+  Some(entries)
+}
+
+/// Compressed bytes of an entry, found through its local header
+fn entry_data<'a>(data: &'a [u8], entry: &Entry) -> Option<&'a [u8]> {
+  let offset = entry.local_offset;
+  if u32_at(data, offset)? != LOCAL_HEADER_SIG {
+    return None;
+  }
+  let name_len = u16_at(data, offset + 26)? as usize;
+  let extra_len = u16_at(data, offset + 28)? as usize;
+  let start = offset + LOCAL_HEADER_SIZE + name_len + extra_len;
+  data.get(start..start + entry.compressed_size)
+}
+
+/// Minimal archive writer for tests. Entries are (name, deflate, contents).
+#[cfg(test)]
+pub fn build_zip(files: &[(&str, bool, &[u8])]) -> Vec<u8> {
+  use miniz_oxide::deflate::compress_to_vec;
+
+  let mut data = Vec::new();
+  let mut central = Vec::new();
+  for (name, deflate, contents) in files {
+    let (method, body) = if *deflate {
+      (METHOD_DEFLATED, compress_to_vec(contents, 6))
+    } else {
+      (METHOD_STORED, contents.to_vec())
+    };
+    // the crc is left at 0, it isn't checked
+    let mut fields = Vec::new();
+    fields.extend_from_slice(&20u16.to_le_bytes()); // version needed
+    fields.extend_from_slice(&0u16.to_le_bytes()); // flags
+    fields.extend_from_slice(&method.to_le_bytes());
+    fields.extend_from_slice(&[0; 4]); // mod time and date
+    fields.extend_from_slice(&0u32.to_le_bytes()); // crc
+    fields.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    fields.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+    fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    fields.extend_from_slice(&0u16.to_le_bytes()); // extra len
+
+    central.extend_from_slice(&CENTRAL_HEADER_SIG.to_le_bytes());
+    central.extend_from_slice(&20u16.to_le_bytes()); // version made by
+    central.extend_from_slice(&fields);
+    central.extend_from_slice(&[0; 6]); // comment len, disk, internal attributes
+    central.extend_from_slice(&0u32.to_le_bytes()); // external attributes
+    central.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    central.extend_from_slice(name.as_bytes());
+
+    data.extend_from_slice(&LOCAL_HEADER_SIG.to_le_bytes());
+    data.extend_from_slice(&fields);
+    data.extend_from_slice(name.as_bytes());
+    data.extend_from_slice(&body);
+  }
+  let central_offset = data.len() as u32;
+  data.extend_from_slice(&central);
+  data.extend_from_slice(&END_OF_CENTRAL_DIR_SIG.to_le_bytes());
+  data.extend_from_slice(&[0; 4]); // disk numbers
+  data.extend_from_slice(&(files.len() as u16).to_le_bytes());
+  data.extend_from_slice(&(files.len() as u16).to_le_bytes());
+  data.extend_from_slice(&(central.len() as u32).to_le_bytes());
+  data.extend_from_slice(&central_offset.to_le_bytes());
+  data.extend_from_slice(&0u16.to_le_bytes()); // comment len
+  data
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_extract_rom() {
+    let rom: Vec<u8> = (0..0x8000).map(|i| (i % 251) as u8).collect();
+    for deflate in [false, true] {
+      let zip = build_zip(&[
+        ("readme.txt", false, b"not a rom"),
+        ("__MACOSX/._game.gb", false, b"resource fork"),
+        ("game.GB", deflate, &rom),
+        ("other.gbc", false, b"second rom"),
+      ]);
+      assert!(is_zip(&zip));
+      assert_eq!(extract_rom(&zip).unwrap(), rom);
+    }
+
+    assert!(!is_zip(&rom));
+    let no_rom = build_zip(&[("readme.txt", false, b"not a rom")]);
+    assert!(extract_rom(&no_rom).is_err());
+    let zip = build_zip(&[("game.gb", true, &rom)]);
+    assert!(extract_rom(&zip[..zip.len() / 2]).is_err());
+
+    // inflating stops at the size in the central directory
+    let mut zip = build_zip(&[("game.gb", true, &rom)]);
+    let central = u32_at(&zip, zip.len() - END_OF_CENTRAL_DIR_SIZE + 16).unwrap() as usize;
+    zip[central + 24..central + 28].copy_from_slice(&0x100u32.to_le_bytes());
+    assert!(extract_rom(&zip).is_err());
+  }
+}
@@ -0,0 +1,220 @@
+//! Cheats, either entered as `addr=value` or as GameShark and Game Genie
+//! codes.
+//!
+//! Ram cheats hold a byte of memory at a fixed value. The state writes them
+//! through the bus after every frame, so whatever the game wrote in the
+//! meantime gets replaced. Rom cheats (Game Genie) can't be written, so the
+//! cartridge swaps the value into reads instead.
+
+use crate::bus::{
+  Bus, CART_RAM_END, CART_RAM_START, CART_ROM_END, HRAM_END, HRAM_START, OAM_END, OAM_START,
+  PPU_END, PPU_START, WRAM_END, WRAM_START,
+};
+use crate::cart::RomPatch;
+use crate::dasm::parse_addr;
+use crate::err::GbResult;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CheatKind {
+  /// Written to ram after every frame
+  Freeze,
+  /// Replaces rom reads, only while the rom byte is `compare` if given
+  RomPatch { compare: Option<u8> },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cheat {
+  pub addr: u16,
+  pub value: u8,
+  pub enabled: bool,
+  pub kind: CheatKind,
+}
+
+/// Whether a ram cheat may hold `addr`: vram, cart ram, wram, oam or hram.
+/// Writing io registers every frame would set off their side effects, and
+/// rom writes are mapper control.
+pub fn is_freezable(addr: u16) -> bool {
+  matches!(
+    addr,
+    PPU_START..=PPU_END
+      | CART_RAM_START..=CART_RAM_END
+      | WRAM_START..=WRAM_END
+      | OAM_START..=OAM_END
+      | HRAM_START..=HRAM_END
+  )
+}
+
+impl Cheat {
+  /// Ram cheat, only for addresses that are plain writable memory
+  pub fn freeze(addr: u16, value: u8) -> Result<Cheat, String> {
+    if !is_freezable(addr) {
+      return Err(format!("Can't freeze ${:04X}, it isn't ram", addr));
+    }
+    Ok(Cheat {
+      addr,
+      value,
+      enabled: true,
+      kind: CheatKind::Freeze,
+    })
+  }
+
+  pub fn rom_patch(addr: u16, value: u8, compare: Option<u8>) -> Cheat {
+    Cheat {
+      addr,
+      value,
+      enabled: true,
+      kind: CheatKind::RomPatch { compare },
+    }
+  }
+
+  /// Poke the value in if this is a ram cheat and it is on. The write goes
+  /// through the bus like a cpu write.
+  pub fn apply(&self, bus: &mut Bus) -> GbResult<()> {
+    if self.enabled && self.kind == CheatKind::Freeze {
+      bus.write8(self.addr, self.value)?;
+    }
+    Ok(())
+  }
+
+  /// What the cartridge should patch in while this is on, None for ram
+  /// cheats
+  pub fn rom_patch_entry(&self) -> Option<RomPatch> {
+    match self.kind {
+      CheatKind::RomPatch { compare } if self.enabled => Some(RomPatch {
+        addr: self.addr,
+        value: self.value,
+        compare,
+      }),
+      _ => None,
+    }
+  }
+
+  /// GameShark `ttvvaaaa`: type 01, the value, then the address low byte
+  /// first. Other types switch ram banks first on the real thing, which is
+  /// not supported.
+  fn parse_gameshark(code: &str) -> Option<Result<Cheat, String>> {
+    if code.len() != 8 {
+      return None;
+    }
+    let raw = u32::from_str_radix(code, 16).ok()?;
+    let [kind, value, lo, hi] = raw.to_be_bytes();
+    if kind != 0x01 {
+      return Some(Err(format!(
+        "Unsupported GameShark code type {:02X}: {}",
+        kind, code
+      )));
+    }
+    Some(Cheat::freeze(u16::from_le_bytes([lo, hi]), value))
+  }
+
+  /// Game Genie `ABC-DEF` or `ABC-DEF-GHI`. AB is the value and FCDE the
+  /// address with F inverted, which has to land in the rom. The last group
+  /// holds the compare byte, GI rotated right by 2 and xored with $BA; H is
+  /// only a check digit.
+  fn parse_game_genie(code: &str) -> Option<Result<Cheat, String>> {
+    let groups: Vec<&str> = code.split('-').collect();
+    if !matches!(groups.len(), 2 | 3) || groups.iter().any(|g| g.len() != 3) {
+      return None;
+    }
+    let digits: Vec<u8> = groups
+      .concat()
+      .chars()
+      .map(|c| c.to_digit(16).map(|d| d as u8))
+      .collect::<Option<_>>()?;
+    let value = digits[0] << 4 | digits[1];
+    let addr = u16::from_be_bytes([
+      (digits[5] ^ 0xf) << 4 | digits[2],
+      digits[3] << 4 | digits[4],
+    ]);
+    if addr > CART_ROM_END {
+      return Some(Err(format!(
+        "Game Genie code {} is for ${:04X}, outside the rom",
+        code, addr
+      )));
+    }
+    let compare = (digits.len() == 9).then(|| (digits[6] << 4 | digits[8]).rotate_right(2) ^ 0xba);
+    Some(Ok(Cheat::rom_patch(addr, value, compare)))
+  }
+}
+
+impl fmt::Display for Cheat {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.kind {
+      CheatKind::Freeze => write!(f, "{:04X} = {:02X}", self.addr, self.value),
+      CheatKind::RomPatch { compare: None } => {
+        write!(f, "ROM {:04X} = {:02X}", self.addr, self.value)
+      }
+      CheatKind::RomPatch {
+        compare: Some(compare),
+      } => write!(
+        f,
+        "ROM {:04X} = {:02X} if {:02X}",
+        self.addr, self.value, compare
+      ),
+    }
+  }
+}
+
+impl FromStr for Cheat {
+  type Err = String;
+  /// `<addr>=<value>` in hex like `c0a0=63`, a GameShark code like
+  /// `0163a0c0` or a Game Genie code like `00A-17B-C49`
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let s = s.trim();
+    let bad = || format!("Bad cheat, expected addr=value or a code: {}", s);
+    if let Some((addr, value)) = s.split_once('=') {
+      let addr = parse_addr(addr).ok_or_else(bad)?;
+      let value = u8::from_str_radix(value.trim(), 16).map_err(|_| bad())?;
+      return Cheat::freeze(addr, value);
+    }
+    if let Some(cheat) = Cheat::parse_gameshark(s) {
+      return cheat;
+    }
+    Cheat::parse_game_genie(s).unwrap_or_else(|| Err(bad()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_freeze() {
+    assert_eq!("c0a0=63".parse(), Cheat::freeze(0xc0a0, 0x63));
+    assert_eq!(" $d000 = ff ".parse(), Cheat::freeze(0xd000, 0xff));
+    assert!("c0a0".parse::<Cheat>().is_err());
+    assert!("c0a0=100".parse::<Cheat>().is_err());
+    assert!("lives=3".parse::<Cheat>().is_err());
+    // only writable ram can be frozen
+    assert!("ff80=01".parse::<Cheat>().is_ok());
+    for addr in ["0150", "e000", "fea0", "ff40", "ffff"] {
+      assert!(format!("{}=01", addr).parse::<Cheat>().is_err());
+    }
+  }
+
+  #[test]
+  fn test_parse_gameshark() {
+    assert_eq!("010FB5D0".parse(), Cheat::freeze(0xd0b5, 0x0f));
+    assert_eq!("0163a0c0".parse(), Cheat::freeze(0xc0a0, 0x63));
+    // ram bank codes are not supported
+    assert!("910FB5D0".parse::<Cheat>().is_err());
+    assert!("010F40FF".parse::<Cheat>().is_err());
+    assert!("010FB5DZ".parse::<Cheat>().is_err());
+  }
+
+  #[test]
+  fn test_parse_game_genie() {
+    // value $00 at $4A17, patched only where the rom holds $C8
+    let cheat: Cheat = "00A-17B-C49".parse().unwrap();
+    assert_eq!(cheat, Cheat::rom_patch(0x4a17, 0x00, Some(0xc8)));
+    assert_eq!(cheat.to_string(), "ROM 4A17 = 00 if C8");
+    let cheat: Cheat = "3ef-29a".parse().unwrap();
+    assert_eq!(cheat, Cheat::rom_patch(0x5f29, 0x3e, None));
+    assert!("00A-17B-C4".parse::<Cheat>().is_err());
+    assert!("00A-17G".parse::<Cheat>().is_err());
+    // F is inverted, so 0 puts the address at $FA17
+    assert!("00A-170-C49".parse::<Cheat>().is_err());
+  }
+}
@@ -0,0 +1,365 @@
+//! Command line configuration for the gameboy emulator.
+
+use log::LevelFilter;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::joypad::SocdPolicy;
+use crate::logger;
+use crate::ppu::PaletteKind;
+use crate::ram::RamInit;
+
+pub const USAGE: &str = "\
+Usage: gb [OPTIONS] [ROM]
+
+Options:
+  --scale <N>          Initial window scale factor (default: 10)
+  --palette <NAME>     Color palette: gray, green, blue, custom (default: gray)
+  --log-level <LEVEL>  Log level: off, error, warn, info, debug, trace (default: $RUST_LOG or info)
+  --boot-rom <PATH>    Boot rom to run before the cartridge
+  --socd <POLICY>      Opposing directions: off, neutral, last-wins (default: off)
+  --ram-init <PATTERN> Power up wram/hram contents: zeros, ff, random, random:SEED
+                       (default: zeros)
+  --oam-bug            Emulate the DMG OAM corruption bug
+  --scanline-render    Draw whole lines at once, faster but misses mid-line
+                       register writes
+  --dmg                Run CGB carts as a DMG
+  --strict-opcodes     Stop with an error on illegal opcodes instead of
+                       locking up the cpu
+  --volume <PERCENT>   Master audio volume, 0-100 (default: 100)
+  --frames <N>         Run N frames headless and exit
+  --dump-hash          Print a hash of the final framebuffer (with --frames)
+  --no-panic-dump      Do not dump the cpu state when the emulator panics
+  --deterministic      Run a fixed number of cycles per frame instead of
+                       following the wall clock
+  --record <PATH>      Record joypad input to a file (implies --deterministic)
+  --replay <PATH>      Replay joypad input from a file (implies --deterministic)
+  --link-listen <PORT> Wait for a link cable partner on a TCP port
+  --link-connect <ADDR>
+                       Connect the link cable to a partner at HOST:PORT
+  -h, --help           Print this help message";
+
+const DEFAULT_SCALE: u32 = 10;
+const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::Info;
+const DEFAULT_VOLUME: u32 = 100;
+
+/// Link cable connection to another instance
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkConfig {
+  /// Wait for a partner on this port
+  Listen(u16),
+  /// Connect to a partner at this address
+  Connect(String),
+}
+
+/// Options used to configure the emulator at startup
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+  /// Rom to load at startup
+  pub rom: Option<PathBuf>,
+  /// Initial window scale factor
+  pub scale: u32,
+  /// Initial color palette
+  pub palette: PaletteKind,
+  /// Max log level, overrides the RUST_LOG env variable
+  pub log_level: Option<LevelFilter>,
+  /// Optional boot rom
+  pub boot_rom: Option<PathBuf>,
+  /// How to handle opposing dpad directions
+  pub socd: SocdPolicy,
+  /// Power up contents of wram and hram
+  pub ram_init: RamInit,
+  /// Emulate the DMG OAM corruption bug
+  pub oam_bug: bool,
+  /// Draw whole lines at once instead of dot by dot
+  pub scanline_render: bool,
+  /// Never switch into CGB mode, even for CGB carts
+  pub dmg: bool,
+  /// Stop on illegal opcodes instead of locking up the cpu
+  pub strict_opcodes: bool,
+  /// Master volume in percent
+  pub volume: u32,
+  /// Run this many frames headless and exit
+  pub frames: Option<u64>,
+  /// Print the framebuffer hash after a headless run
+  pub dump_hash: bool,
+  /// Skip installing the panic hook that dumps cpu state
+  pub no_panic_dump: bool,
+  /// Run on a fixed schedule so runs can be repeated exactly
+  pub deterministic: bool,
+  /// Save the joypad input to this file when the emulator closes
+  pub record: Option<PathBuf>,
+  /// Play the joypad input from this file instead of the keyboard
+  pub replay: Option<PathBuf>,
+  /// Optional link cable to another instance
+  pub link: Option<LinkConfig>,
+  /// Print the usage and exit
+  pub help: bool,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Config {
+      rom: None,
+      scale: DEFAULT_SCALE,
+      palette: PaletteKind::Gray,
+      log_level: None,
+      boot_rom: None,
+      socd: SocdPolicy::Off,
+      ram_init: RamInit::Zeros,
+      oam_bug: false,
+      scanline_render: false,
+      dmg: false,
+      strict_opcodes: false,
+      volume: DEFAULT_VOLUME,
+      frames: None,
+      dump_hash: false,
+      no_panic_dump: false,
+      deterministic: false,
+      record: None,
+      replay: None,
+      link: None,
+      help: false,
+    }
+  }
+}
+
+impl Config {
+  /// Log level from the command line, falling back to the environment and then
+  /// to the default
+  pub fn resolve_log_level(&self) -> LevelFilter {
+    self
+      .log_level
+      .or_else(logger::env_level)
+      .unwrap_or(DEFAULT_LOG_LEVEL)
+  }
+
+  /// Build a config from the command line arguments. The program name should
+  /// not be included.
+  pub fn from_args<I>(args: I) -> Result<Config, String>
+  where
+    I: IntoIterator<Item = String>,
+  {
+    let mut config = Config::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+      match arg.as_str() {
+        "-h" | "--help" => config.help = true,
+        "--scale" => {
+          let val = next_value(&mut args, &arg)?;
+          config.scale = match val.parse::<u32>() {
+            Ok(scale) if scale > 0 => scale,
+            _ => return Err(format!("Invalid scale: {}", val)),
+          };
+        }
+        "--palette" => {
+          let val = next_value(&mut args, &arg)?;
+          config.palette = PaletteKind::from_str(&val)?;
+        }
+        "--log-level" => {
+          let val = next_value(&mut args, &arg)?;
+          config.log_level =
+            Some(logger::parse_level(&val).ok_or_else(|| format!("Invalid log level: {}", val))?);
+        }
+        "--boot-rom" => config.boot_rom = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+        "--socd" => {
+          let val = next_value(&mut args, &arg)?;
+          config.socd = SocdPolicy::from_str(&val)?;
+        }
+        "--ram-init" => {
+          let val = next_value(&mut args, &arg)?;
+          config.ram_init = RamInit::from_str(&val)?;
+        }
+        "--oam-bug" => config.oam_bug = true,
+        "--scanline-render" => config.scanline_render = true,
+        "--dmg" => config.dmg = true,
+        "--strict-opcodes" => config.strict_opcodes = true,
+        "--volume" => {
+          let val = next_value(&mut args, &arg)?;
+          config.volume = match val.parse::<u32>() {
+            Ok(volume) if volume <= 100 => volume,
+            _ => return Err(format!("Invalid volume: {}", val)),
+          };
+        }
+        "--frames" => {
+          let val = next_value(&mut args, &arg)?;
+          config.frames = Some(
+            val
+              .parse::<u64>()
+              .map_err(|_| format!("Invalid frame count: {}", val))?,
+          );
+        }
+        "--dump-hash" => config.dump_hash = true,
+        "--no-panic-dump" => config.no_panic_dump = true,
+        "--deterministic" => config.deterministic = true,
+        "--record" => {
+          config.record = Some(PathBuf::from(next_value(&mut args, &arg)?));
+          config.deterministic = true;
+        }
+        "--replay" => {
+          config.replay = Some(PathBuf::from(next_value(&mut args, &arg)?));
+          config.deterministic = true;
+        }
+        "--link-listen" | "--link-connect" => {
+          if config.link.is_some() {
+            return Err(String::from("Only one link cable option can be given"));
+          }
+          let val = next_value(&mut args, &arg)?;
+          config.link = Some(if arg == "--link-listen" {
+            LinkConfig::Listen(
+              val
+                .parse::<u16>()
+                .map_err(|_| format!("Invalid port: {}", val))?,
+            )
+          } else {
+            LinkConfig::Connect(val)
+          });
+        }
+        _ if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg)),
+        _ => {
+          if config.rom.is_some() {
+            return Err(format!("Unexpected argument: {}", arg));
+          }
+          config.rom = Some(PathBuf::from(arg));
+        }
+      }
+    }
+    if config.dump_hash && config.frames.is_none() {
+      return Err(String::from("--dump-hash requires --frames"));
+    }
+    if config.deterministic && config.link.is_some() {
+      // the partner's timing can't be repeated
+      return Err(String::from(
+        "--deterministic can't be used with a link cable",
+      ));
+    }
+    Ok(config)
+  }
+}
+
+fn next_value<I>(args: &mut I, option: &str) -> Result<String, String>
+where
+  I: Iterator<Item = String>,
+{
+  args
+    .next()
+    .ok_or_else(|| format!("Missing value for {}", option))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(args: &[&str]) -> Result<Config, String> {
+    Config::from_args(args.iter().map(|arg| String::from(*arg)))
+  }
+
+  #[test]
+  fn test_config_defaults() {
+    assert_eq!(parse(&[]).unwrap(), Config::default());
+  }
+
+  #[test]
+  fn test_config_all_options() {
+    let config = parse(&[
+      "--scale",
+      "3",
+      "--palette",
+      "green",
+      "--log-level",
+      "trace",
+      "--boot-rom",
+      "dmg_boot.bin",
+      "--socd",
+      "last-wins",
+      "--ram-init",
+      "random:9",
+      "--oam-bug",
+      "--scanline-render",
+      "--dmg",
+      "--strict-opcodes",
+      "--volume",
+      "40",
+      "--frames",
+      "600",
+      "--dump-hash",
+      "--no-panic-dump",
+      "--link-listen",
+      "5555",
+      "roms/tetris.gb",
+    ])
+    .unwrap();
+    assert_eq!(
+      config,
+      Config {
+        rom: Some(PathBuf::from("roms/tetris.gb")),
+        scale: 3,
+        palette: PaletteKind::Green,
+        log_level: Some(LevelFilter::Trace),
+        boot_rom: Some(PathBuf::from("dmg_boot.bin")),
+        socd: SocdPolicy::LastWins,
+        ram_init: RamInit::Random(9),
+        oam_bug: true,
+        scanline_render: true,
+        dmg: true,
+        strict_opcodes: true,
+        volume: 40,
+        frames: Some(600),
+        dump_hash: true,
+        no_panic_dump: true,
+        deterministic: false,
+        record: None,
+        replay: None,
+        link: Some(LinkConfig::Listen(5555)),
+        help: false,
+      }
+    );
+  }
+
+  #[test]
+  fn test_config_log_level() {
+    let config = parse(&["--log-level", "debug"]).unwrap();
+    assert_eq!(config.log_level, Some(LevelFilter::Debug));
+    assert_eq!(config.resolve_log_level(), LevelFilter::Debug);
+  }
+
+  #[test]
+  fn test_config_deterministic() {
+    assert!(parse(&["--deterministic"]).unwrap().deterministic);
+    let config = parse(&["--record", "in.txt"]).unwrap();
+    assert_eq!(config.record, Some(PathBuf::from("in.txt")));
+    assert!(config.deterministic);
+    let config = parse(&["--replay", "in.txt"]).unwrap();
+    assert_eq!(config.replay, Some(PathBuf::from("in.txt")));
+    assert!(config.deterministic);
+  }
+
+  #[test]
+  fn test_config_link_connect() {
+    let config = parse(&["--link-connect", "192.168.1.2:5555"]).unwrap();
+    assert_eq!(
+      config.link,
+      Some(LinkConfig::Connect(String::from("192.168.1.2:5555")))
+    );
+  }
+
+  #[test]
+  fn test_config_bad_args() {
+    assert!(parse(&["--scale"]).is_err());
+    assert!(parse(&["--scale", "0"]).is_err());
+    assert!(parse(&["--palette", "purple"]).is_err());
+    assert!(parse(&["--log-level", "loud"]).is_err());
+    assert!(parse(&["--socd", "first-wins"]).is_err());
+    assert!(parse(&["--ram-init", "junk"]).is_err());
+    assert!(parse(&["--volume", "101"]).is_err());
+    assert!(parse(&["--frobnicate"]).is_err());
+    assert!(parse(&["a.gb", "b.gb"]).is_err());
+    assert!(parse(&["--frames", "-1"]).is_err());
+    assert!(parse(&["--dump-hash"]).is_err());
+    assert!(parse(&["--link-listen", "99999"]).is_err());
+    assert!(parse(&["--link-listen", "5555", "--link-connect", "localhost:5555"]).is_err());
+    assert!(parse(&["--deterministic", "--link-listen", "5555"]).is_err());
+    assert!(parse(&["--record"]).is_err());
+    assert!(parse(&["--replay", "in.txt", "--link-connect", "localhost:5555"]).is_err());
+  }
+}
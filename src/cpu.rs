@@ -7,17 +7,22 @@ use log::{debug, error, warn};
 use std::collections::VecDeque;
 #[cfg(feature = "instr-trace")]
 use std::env;
+use std::fmt;
 #[cfg(feature = "instr-trace")]
 use std::fs::File;
 #[cfg(feature = "instr-trace")]
 use std::io::Write;
 use std::{cell::RefCell, rc::Rc};
 
+#[cfg(feature = "instr-trace")]
+use crate::dasm::Dasm;
 use crate::int::Interrupt;
+use crate::speed::SpeedSwitch;
 use crate::{
-  bus::Bus,
+  bus::{Bus, MCycle},
   err::{GbError, GbErrorType, GbResult},
   gb_err,
+  snapshot::{Snapshot, SnapshotReader, SnapshotWriter},
   util::LazyDref,
 };
 
@@ -91,10 +96,23 @@ pub struct Cpu {
   pub pc: u16,
   /// interrupt master enable register
   pub ime: bool,
+  /// set by EI, ime turns on once the next instruction starts
+  pub ime_scheduled: bool,
   /// used for implementing the HALT instruction
   pub halted: bool,
+  /// Hit an illegal opcode and hung like the hardware does. Only a reset gets
+  /// out of this.
+  pub locked: bool,
+  /// Stop with an error on an illegal opcode instead of locking up
+  pub strict_opcodes: bool,
   pub bus: Option<Rc<RefCell<Bus>>>,
+  pub speed_switch: Option<Rc<RefCell<SpeedSwitch>>>,
   pub history: InstrHistory,
+  /// Log the memory cycles of every instruction into `mcycles`
+  pub record_mcycles: bool,
+  /// Memory cycles of the last instruction in order, while recording. The
+  /// cpu doesn't say when its internal cycles happen, they all go at the end.
+  pub mcycles: Vec<MCycle>,
   #[cfg(feature = "instr-trace")]
   trace_file: File,
 
@@ -123,6 +141,39 @@ impl Register {
   }
 }
 
+impl Default for Register {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Copy of every cpu register taken at one point in time
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Registers {
+  pub a: u8,
+  pub f: u8,
+  pub b: u8,
+  pub c: u8,
+  pub d: u8,
+  pub e: u8,
+  pub h: u8,
+  pub l: u8,
+  pub sp: u16,
+  pub pc: u16,
+  pub ime: bool,
+}
+
+impl fmt::Display for Registers {
+  /// Gameboy Doctor style, ime is left out so logs can be diffed against it
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X}",
+      self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l, self.sp, self.pc
+    )
+  }
+}
+
 impl Cpu {
   pub fn new() -> Cpu {
     #[cfg(feature = "instr-trace")]
@@ -140,11 +191,17 @@ impl Cpu {
       sp: 0,
       pc: 0,
       ime: false,
+      ime_scheduled: false,
       halted: false,
+      locked: false,
+      strict_opcodes: false,
       bus: None,
+      speed_switch: None,
       dispatcher: Self::init_dispatcher(),
       dispatcher_cb: Self::init_dispatcher_cb(),
       history: InstrHistory::new(HISTORY_CAP),
+      record_mcycles: false,
+      mcycles: Vec::new(),
       #[cfg(feature = "instr-trace")]
       trace_file,
     }
@@ -159,8 +216,46 @@ impl Cpu {
     Ok(())
   }
 
+  /// Connect the cpu to the CGB speed switch
+  pub fn connect_speed_switch(&mut self, speed_switch: Rc<RefCell<SpeedSwitch>>) -> GbResult<()> {
+    match self.speed_switch {
+      None => self.speed_switch = Some(speed_switch),
+      Some(_) => return gb_err!(GbErrorType::AlreadyInitialized),
+    };
+    Ok(())
+  }
+
+  /// Snapshot of all the registers
+  pub fn registers(&self) -> Registers {
+    Registers {
+      a: self.af.hi,
+      f: self.af.lo,
+      b: self.bc.hi,
+      c: self.bc.lo,
+      d: self.de.hi,
+      e: self.de.lo,
+      h: self.hl.hi,
+      l: self.hl.lo,
+      sp: self.sp,
+      pc: self.pc,
+      ime: self.ime,
+    }
+  }
+
   /// Execute one instruction and return the number of cycles it took
   pub fn step(&mut self) -> GbResult<u32> {
+    if self.locked {
+      return Ok(4);
+    }
+
+    // EI takes effect after the instruction following it. Interrupts are
+    // checked after each step, so turning ime on here means the check right
+    // after EI still sees it off while the next instruction can still DI.
+    if self.ime_scheduled {
+      self.ime = true;
+      self.ime_scheduled = false;
+    }
+
     if self.halted {
       // TODO: what to return for cycles if halted?
       return Ok(4);
@@ -184,29 +279,49 @@ impl Cpu {
           }
           output.push_str(format!("{:9} ", raw_bytes_str).as_str());
           output.push_str(format!("{:12} ", instr).as_str());
+          output.push_str(&self.registers().to_string());
           break;
         }
       }
       self.trace_instr(&output);
     }
 
+    if self.record_mcycles {
+      self.bus.lazy_dref().start_recording();
+    }
+
     // read next instruction
-    self.history.push(self.pc);
-    let instr = self.bus.lazy_dref().read8(self.pc)?;
+    let pc = self.pc;
+    self.history.push(pc);
+    let instr = self.bus.lazy_dref().read8(pc).map_err(|e| e.with_pc(pc))?;
     self.pc = self.pc.wrapping_add(1);
 
     // instruction dispatch
-    let num_cycles = self.dispatcher[instr as usize](self, instr)?;
+    let num_cycles = self.dispatcher[instr as usize](self, instr).map_err(|e| e.with_pc(pc))?;
 
+    if self.record_mcycles {
+      self.mcycles = self.bus.lazy_dref().take_recording();
+      let total = (num_cycles / 4) as usize;
+      if self.mcycles.len() < total {
+        self.mcycles.resize(total, MCycle::Internal);
+      }
+    }
     Ok(num_cycles)
   }
 
-  pub fn interrupt(&mut self, int: Interrupt) -> bool {
+  /// Dispatch an interrupt. Returns true if the interrupt was serviced.
+  pub fn interrupt(&mut self, int: Interrupt) -> GbResult<bool> {
+    // nothing wakes a locked up cpu
+    if self.locked {
+      return Ok(false);
+    }
     self.halted = false;
     if !self.ime {
-      return false;
+      return Ok(false);
     }
+    // an EI right before the interrupt doesn't turn ime back on in the handler
     self.ime = false;
+    self.ime_scheduled = false;
 
     // call appropriate handler
     const VBLANK_HANDLER: u16 = 0x40;
@@ -214,14 +329,15 @@ impl Cpu {
     const TIMER_HANDLER: u16 = 0x50;
     const SERIAL_HANDLER: u16 = 0x58;
     const JOYPAD_HANDLER: u16 = 0x60;
-    match int {
-      Interrupt::Vblank => self.call(VBLANK_HANDLER).unwrap(),
-      Interrupt::Lcd => self.call(LCD_HANDLER).unwrap(),
-      Interrupt::Timer => self.call(TIMER_HANDLER).unwrap(),
-      Interrupt::Serial => self.call(SERIAL_HANDLER).unwrap(),
-      Interrupt::Joypad => self.call(JOYPAD_HANDLER).unwrap(),
+    let handler = match int {
+      Interrupt::Vblank => VBLANK_HANDLER,
+      Interrupt::Lcd => LCD_HANDLER,
+      Interrupt::Timer => TIMER_HANDLER,
+      Interrupt::Serial => SERIAL_HANDLER,
+      Interrupt::Joypad => JOYPAD_HANDLER,
     };
-    return true;
+    self.call(handler).map_err(|e| e.with_pc(self.pc))?;
+    Ok(true)
   }
 
   #[cfg(feature = "instr-trace")]
@@ -430,10 +546,22 @@ impl Cpu {
     Ok(imm8)
   }
 
-  /// Unknown Instruction, returns an error
+  /// Unknown Instruction. Locks up the cpu, or returns an error in strict
+  /// mode.
   fn badi(&mut self, instr: u8) -> GbResult<u32> {
-    error!("Unknown instruction: 0x{:02x}", instr);
-    gb_err!(GbErrorType::InvalidCpuInstruction)
+    if self.strict_opcodes {
+      error!("Unknown instruction: 0x{:02x}", instr);
+      return Err(
+        GbError::new(GbErrorType::InvalidCpuInstruction, file!(), line!()).with_opcode(instr),
+      );
+    }
+    error!(
+      "Unknown instruction: 0x{:02x} at ${:04X}, cpu locked up",
+      instr,
+      self.pc.wrapping_sub(1)
+    );
+    self.locked = true;
+    Ok(4)
   }
 
   /// nop
@@ -454,7 +582,16 @@ impl Cpu {
   ///
   /// Cycles: 4
   fn stop(&mut self, _instr: u8) -> GbResult<u32> {
-    warn!("STOP instruction not implemented!");
+    // STOP is followed by an ignored byte
+    self.pc = self.pc.wrapping_add(1);
+    // nothing connected is a DMG, which has no speed switch
+    let switched = self
+      .speed_switch
+      .try_dref_mut()
+      .is_ok_and(|mut switch| switch.stop());
+    if !switched {
+      warn!("STOP low power mode not implemented!");
+    }
     Ok(4)
   }
 
@@ -1664,7 +1801,7 @@ impl Cpu {
 
   /// Add 2 u8 values, affects Z, N, and H flags
   fn add_hc(&mut self, n1: u8, n2: u8) -> u8 {
-    // remove everything other than carry flag
+    // remove everything other than carry flag, this also resets N
     self.af.lo &= FLAG_C;
     let res = n1.wrapping_add(n2);
 
@@ -1678,8 +1815,6 @@ impl Cpu {
     // check zero
     self.af.lo |= if res == 0 { FLAG_Z } else { 0 };
 
-    // sub flag always zero so don't set it
-
     res
   }
 
@@ -1888,6 +2023,7 @@ impl Cpu {
   ///
   /// Flags: - - - -
   fn inc_bc(&mut self, _instr: u8) -> GbResult<u32> {
+    self.bus.lazy_dref_mut().idle_addr(self.bc.hilo());
     self.bc.set_u16(self.bc.hilo().wrapping_add(1));
     Ok(8)
   }
@@ -1924,6 +2060,7 @@ impl Cpu {
   ///
   /// Flags: - - - -
   fn inc_de(&mut self, _instr: u8) -> GbResult<u32> {
+    self.bus.lazy_dref_mut().idle_addr(self.de.hilo());
     self.de.set_u16(self.de.hilo().wrapping_add(1));
     Ok(8)
   }
@@ -1960,6 +2097,7 @@ impl Cpu {
   ///
   /// Flags: - - - -
   fn inc_hl(&mut self, _instr: u8) -> GbResult<u32> {
+    self.bus.lazy_dref_mut().idle_addr(self.hl.hilo());
     self.hl.set_u16(self.hl.hilo().wrapping_add(1));
     Ok(8)
   }
@@ -2010,6 +2148,7 @@ impl Cpu {
   ///
   /// Flags: - - - -
   fn inc_sp(&mut self, _instr: u8) -> GbResult<u32> {
+    self.bus.lazy_dref_mut().idle_addr(self.sp);
     self.sp = self.sp.wrapping_add(1);
     Ok(8)
   }
@@ -2058,6 +2197,7 @@ impl Cpu {
   ///
   /// Flags: - - - -
   fn dec_bc(&mut self, _instr: u8) -> GbResult<u32> {
+    self.bus.lazy_dref_mut().idle_addr(self.bc.hilo());
     self.bc.set_u16(self.bc.hilo().wrapping_sub(1));
     Ok(8)
   }
@@ -2070,6 +2210,7 @@ impl Cpu {
   ///
   /// Flags: - - - -
   fn dec_sp(&mut self, _instr: u8) -> GbResult<u32> {
+    self.bus.lazy_dref_mut().idle_addr(self.sp);
     self.sp = self.sp.wrapping_sub(1);
     Ok(8)
   }
@@ -2142,6 +2283,7 @@ impl Cpu {
   ///
   /// Flags: - - - -
   fn dec_de(&mut self, _instr: u8) -> GbResult<u32> {
+    self.bus.lazy_dref_mut().idle_addr(self.de.hilo());
     self.de.set_u16(self.de.hilo().wrapping_sub(1));
     Ok(8)
   }
@@ -2154,6 +2296,7 @@ impl Cpu {
   ///
   /// Flags: - - - -
   fn dec_hl(&mut self, _instr: u8) -> GbResult<u32> {
+    self.bus.lazy_dref_mut().idle_addr(self.hl.hilo());
     self.hl.set_u16(self.hl.hilo().wrapping_sub(1));
     Ok(8)
   }
@@ -3897,6 +4040,7 @@ impl Cpu {
   /// Flags: - - - -
   fn di(&mut self, _instr: u8) -> GbResult<u32> {
     self.ime = false;
+    self.ime_scheduled = false;
     Ok(4)
   }
 
@@ -3908,8 +4052,7 @@ impl Cpu {
   ///
   /// Flags: - - - -
   fn ei(&mut self, _instr: u8) -> GbResult<u32> {
-    // TODO: this should be delayed by 1 instruction?
-    self.ime = true;
+    self.ime_scheduled = true;
     Ok(4)
   }
 
@@ -7182,3 +7325,322 @@ impl Cpu {
     Ok(8)
   }
 }
+
+impl Default for Cpu {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Only the registers are saved. The history is debug info and the handles
+/// are kept from the running cpu.
+impl Snapshot for Cpu {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    for reg in [&self.af, &self.bc, &self.de, &self.hl] {
+      writer.write_u8(reg.hi);
+      writer.write_u8(reg.lo);
+    }
+    writer.write_u32(self.sp as u32);
+    writer.write_u32(self.pc as u32);
+    writer.write_bool(self.ime);
+    writer.write_bool(self.ime_scheduled);
+    writer.write_bool(self.halted);
+    writer.write_bool(self.locked);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    for reg in [&mut self.af, &mut self.bc, &mut self.de, &mut self.hl] {
+      reg.hi = reader.read_u8()?;
+      reg.lo = reader.read_u8()?;
+    }
+    self.sp = reader.read_u32()? as u16;
+    self.pc = reader.read_u32()? as u16;
+    self.ime = reader.read_bool()?;
+    self.ime_scheduled = reader.read_bool()?;
+    self.halted = reader.read_bool()?;
+    self.locked = reader.read_bool()?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::screen::Screen;
+  use crate::state::{EmuFlow, GbState};
+
+  /// Cpu on a flat bus running the given program from $C000
+  fn flat_cpu(program: &[u8]) -> Cpu {
+    let bus = Rc::new(RefCell::new(Bus::new_flat()));
+    for (offset, byte) in program.iter().enumerate() {
+      bus
+        .borrow_mut()
+        .write8(0xc000 + offset as u16, *byte)
+        .unwrap();
+    }
+    let mut cpu = Cpu::new();
+    cpu.connect_bus(bus).unwrap();
+    cpu.pc = 0xc000;
+    cpu
+  }
+
+  #[test]
+  fn test_inc_b_flags() {
+    // half carry clears N and keeps C
+    let mut cpu = flat_cpu(&[0x04]);
+    cpu.bc.hi = 0x0f;
+    cpu.af.lo = FLAG_N | FLAG_C;
+    assert_eq!(cpu.step().unwrap(), 4);
+    assert_eq!(cpu.bc.hi, 0x10);
+    assert_eq!(cpu.af.lo, FLAG_H | FLAG_C);
+
+    // no carry in with Z and N set
+    let mut cpu = flat_cpu(&[0x04]);
+    cpu.bc.hi = 0x41;
+    cpu.af.lo = FLAG_Z | FLAG_N;
+    cpu.step().unwrap();
+    assert_eq!(cpu.bc.hi, 0x42);
+    assert_eq!(cpu.af.lo, 0);
+
+    // wrapping to zero sets Z and H, C is left alone either way
+    for carry in [0, FLAG_C] {
+      let mut cpu = flat_cpu(&[0x04]);
+      cpu.bc.hi = 0xff;
+      cpu.af.lo = FLAG_N | carry;
+      cpu.step().unwrap();
+      assert_eq!(cpu.bc.hi, 0x00);
+      assert_eq!(cpu.af.lo, FLAG_Z | FLAG_H | carry);
+    }
+  }
+
+  #[test]
+  fn test_stop_without_speed_switch() {
+    // stop skips the byte after it
+    let mut cpu = flat_cpu(&[0x10, 0x00, 0x00]);
+    assert_eq!(cpu.step().unwrap(), 4);
+    assert_eq!(cpu.pc, 0xc002);
+  }
+
+  #[test]
+  fn test_record_mcycles() {
+    // ld hl,$c100; ld (hl),$42; inc bc
+    let mut cpu = flat_cpu(&[0x21, 0x00, 0xc1, 0x36, 0x42, 0x03]);
+    cpu.step().unwrap();
+    assert!(cpu.mcycles.is_empty());
+
+    cpu.record_mcycles = true;
+    assert_eq!(cpu.step().unwrap(), 12);
+    assert_eq!(
+      cpu.mcycles,
+      [
+        MCycle::Read {
+          addr: 0xc003,
+          val: 0x36
+        },
+        MCycle::Read {
+          addr: 0xc004,
+          val: 0x42
+        },
+        MCycle::Write {
+          addr: 0xc100,
+          val: 0x42
+        },
+      ]
+    );
+
+    // the 16-bit increment keeps the bus idle for its second cycle
+    cpu.step().unwrap();
+    assert_eq!(
+      cpu.mcycles,
+      [
+        MCycle::Read {
+          addr: 0xc005,
+          val: 0x03
+        },
+        MCycle::Internal,
+      ]
+    );
+  }
+
+  #[test]
+  fn test_registers_snapshot() {
+    // ld bc,$1234; ld sp,$dffe; ei; nop
+    let mut cpu = flat_cpu(&[0x01, 0x34, 0x12, 0x31, 0xfe, 0xdf, 0xfb, 0x00]);
+    cpu.af.set_u16(0x01b0);
+    cpu.de.set_u16(0x00d8);
+    cpu.hl.set_u16(0x014d);
+    for _ in 0..4 {
+      cpu.step().unwrap();
+    }
+    let regs = cpu.registers();
+    assert_eq!(
+      regs,
+      Registers {
+        a: 0x01,
+        f: 0xb0,
+        b: 0x12,
+        c: 0x34,
+        d: 0x00,
+        e: 0xd8,
+        h: 0x01,
+        l: 0x4d,
+        sp: 0xdffe,
+        pc: 0xc008,
+        ime: true,
+      }
+    );
+    assert_eq!(
+      regs.to_string(),
+      "A:01 F:B0 B:12 C:34 D:00 E:D8 H:01 L:4D SP:DFFE PC:C008"
+    );
+  }
+
+  #[test]
+  fn test_oam_bug_opt_in() {
+    for enabled in [false, true] {
+      let mut state = GbState::new(EmuFlow::new(false, false, 1.0));
+      state
+        .init(Rc::new(RefCell::new(Screen::headless())))
+        .unwrap();
+      // inc/dec of every 16-bit register pointing at oam, then a write to
+      // the unusable area after it
+      let program = [0x03, 0x0b, 0x13, 0x1b, 0x23, 0x2b, 0x33, 0x3b, 0x02];
+      for (offset, byte) in program.iter().enumerate() {
+        state
+          .bus
+          .borrow_mut()
+          .write8(0xc000 + offset as u16, *byte)
+          .unwrap();
+      }
+      state.bus.borrow_mut().write8(0xff40, 0x91).unwrap();
+      {
+        let mut ppu = state.ppu.borrow_mut();
+        ppu.oam_bug_enabled = enabled;
+        for (i, byte) in ppu.oam.iter_mut().enumerate() {
+          *byte = (i * 37) as u8;
+        }
+        // past the short first line that has no oam scan, then partway
+        // through the next line's scan
+        ppu.step(452 + 40).unwrap();
+      }
+      let before = state.ppu.borrow().oam.clone();
+
+      let mut cpu = state.cpu.borrow_mut();
+      cpu.pc = 0xc000;
+      cpu.bc.set_u16(0xfea0);
+      cpu.de.set_u16(0xfe10);
+      cpu.hl.set_u16(0xfe20);
+      cpu.sp = 0xfe30;
+      for _ in 0..program.len() {
+        cpu.step().unwrap();
+      }
+      assert_eq!(state.ppu.borrow().oam == before, !enabled);
+    }
+  }
+
+  #[test]
+  fn test_daa_vectors() {
+    // (A, flags in, A out, flags out), A is the result of the preceding add
+    // or sub and the flags are the ones it left behind
+    let vectors = [
+      // addition
+      (0x00, 0, 0x00, FLAG_Z),
+      (0x09, 0, 0x09, 0),
+      (0x0a, 0, 0x10, 0),
+      (0x12, FLAG_H, 0x18, 0),          // 09 + 09
+      (0x01, FLAG_Z, 0x01, 0),          // stale Z is cleared
+      (0x9a, 0, 0x00, FLAG_Z | FLAG_C), // > 99 carries out
+      (0xa0, 0, 0x00, FLAG_Z | FLAG_C), // 50 + 50
+      (0xc5, 0, 0x25, FLAG_C),
+      (0x32, FLAG_H | FLAG_C, 0x98, FLAG_C), // 99 + 99
+      (0x00, FLAG_H | FLAG_C, 0x66, FLAG_C),
+      // subtraction only adjusts on borrows
+      (0x0a, FLAG_N, 0x0a, FLAG_N),
+      (0x0f, FLAG_N | FLAG_H, 0x09, FLAG_N),          // 10 - 01
+      (0xf0, FLAG_N | FLAG_C, 0x90, FLAG_N | FLAG_C), // 20 - 30
+      (0xff, FLAG_N | FLAG_H | FLAG_C, 0x99, FLAG_N | FLAG_C), // 00 - 01
+      (0x00, FLAG_N | FLAG_Z, 0x00, FLAG_N | FLAG_Z),
+    ];
+    for (a, flags, a_out, flags_out) in vectors {
+      let mut cpu = flat_cpu(&[0x27]);
+      cpu.af.hi = a;
+      cpu.af.lo = flags;
+      assert_eq!(cpu.step().unwrap(), 4);
+      assert_eq!(
+        (cpu.af.hi, cpu.af.lo),
+        (a_out, flags_out),
+        "daa of {:02X} with flags {:02X}",
+        a,
+        flags
+      );
+    }
+  }
+
+  #[test]
+  fn test_bad_instruction_context() {
+    let mut state = GbState::new(EmuFlow::new(false, false, 1.0));
+    state
+      .init(Rc::new(RefCell::new(Screen::headless())))
+      .unwrap();
+
+    // place an invalid opcode in wram and jump to it
+    state.bus.borrow_mut().write8(0xc123, 0xdd).unwrap();
+    state.cpu.borrow_mut().pc = 0xc123;
+    state.cpu.borrow_mut().strict_opcodes = true;
+
+    let err = state.cpu.borrow_mut().step().unwrap_err();
+    assert_eq!(*err.error_type(), GbErrorType::InvalidCpuInstruction);
+    assert_eq!(err.opcode(), Some(0xdd));
+    assert_eq!(err.pc(), Some(0xc123));
+  }
+
+  #[test]
+  fn test_bad_instruction_lockup() {
+    // ld a,$12; invalid opcode; ld a,$34
+    let mut cpu = flat_cpu(&[0x3e, 0x12, 0xd3, 0x3e, 0x34]);
+    cpu.ime = true;
+    cpu.step().unwrap();
+    cpu.step().unwrap();
+    assert!(cpu.locked);
+
+    // stuck on the byte after the opcode, interrupts can't get it out
+    for _ in 0..10 {
+      assert_eq!(cpu.step().unwrap(), 4);
+      assert!(!cpu.interrupt(Interrupt::Vblank).unwrap());
+    }
+    assert_eq!(cpu.pc, 0xc003);
+    assert_eq!(cpu.af.hi, 0x12);
+  }
+
+  #[test]
+  fn test_interrupt_stack_write_error() {
+    // nothing is connected to the bus, so there is no hram to push into
+    let mut cpu = Cpu::new();
+    cpu.connect_bus(Rc::new(RefCell::new(Bus::new()))).unwrap();
+    cpu.sp = 0xfffe;
+    cpu.pc = 0x1234;
+    cpu.ime = true;
+    let err = cpu.interrupt(Interrupt::Vblank).unwrap_err();
+    assert_eq!(*err.error_type(), GbErrorType::NotInitialized);
+    assert_eq!(err.addr(), Some(0xfffc));
+    assert_eq!(err.pc(), Some(0x1234));
+  }
+
+  #[test]
+  fn test_interrupt_stack_straddles_io() {
+    let mut state = GbState::new(EmuFlow::new(false, false, 1.0));
+    state
+      .init(Rc::new(RefCell::new(Screen::headless())))
+      .unwrap();
+
+    // pushing the return address here writes TAC and the unused byte after it
+    let mut cpu = state.cpu.borrow_mut();
+    cpu.sp = 0xff09;
+    cpu.pc = 0x1234;
+    cpu.ime = true;
+    assert!(cpu.interrupt(Interrupt::Vblank).unwrap());
+    assert_eq!(cpu.pc, 0x40);
+    assert_eq!(cpu.sp, 0xff07);
+    assert_eq!(state.bus.borrow().read8(0xff07).unwrap(), 0xfc);
+  }
+}
@@ -0,0 +1,115 @@
+//! Panic hook that dumps the cpu state when the emulator crashes.
+
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::panic;
+use std::rc::{Rc, Weak};
+
+use crate::bus::Bus;
+use crate::cpu::{Cpu, FLAG_C, FLAG_H, FLAG_N, FLAG_Z};
+use crate::dasm::Dasm;
+
+// max bytes in a single instruction
+const MAX_INSTR_SIZE: u16 = 3;
+
+thread_local! {
+  // cpu of the running emulator, if any
+  static CPU: RefCell<Weak<RefCell<Cpu>>> = const { RefCell::new(Weak::new()) };
+}
+
+/// Register the cpu to dump if the emulator panics
+pub fn register_cpu(cpu: &Rc<RefCell<Cpu>>) {
+  CPU.with(|handle| *handle.borrow_mut() = Rc::downgrade(cpu));
+}
+
+/// Install a panic hook that prints the cpu registers and instruction history
+/// after the default panic message
+pub fn install_panic_hook() {
+  let default_hook = panic::take_hook();
+  panic::set_hook(Box::new(move |info| {
+    default_hook(info);
+    let report = CPU.with(|handle| match handle.borrow().upgrade() {
+      Some(cpu) => match cpu.try_borrow() {
+        Ok(cpu) => format_crash_report(&cpu),
+        Err(_) => String::from("CPU state unavailable: cpu was in use when the panic occurred\n"),
+      },
+      None => String::new(),
+    });
+    eprint!("{}", report);
+  }));
+}
+
+/// Format the cpu registers and instruction history
+pub fn format_crash_report(cpu: &Cpu) -> String {
+  let mut report = String::new();
+  let f = cpu.af.lo;
+  let flag = |mask: u8| (f & mask > 0) as u8;
+  writeln!(report, "------- CPU STATE -------").unwrap();
+  writeln!(report, "[PC] {:04x}  [SP] {:04x}", cpu.pc, cpu.sp).unwrap();
+  writeln!(report, "[A]  {:02x}  [F] {:02x}", cpu.af.hi, cpu.af.lo).unwrap();
+  writeln!(report, "[B]  {:02x}  [C] {:02x}", cpu.bc.hi, cpu.bc.lo).unwrap();
+  writeln!(report, "[D]  {:02x}  [E] {:02x}", cpu.de.hi, cpu.de.lo).unwrap();
+  writeln!(report, "[H]  {:02x}  [L] {:02x}", cpu.hl.hi, cpu.hl.lo).unwrap();
+  writeln!(
+    report,
+    "Z:{}  N:{}  H:{}  C:{}  IME:{}  HALT:{}",
+    flag(FLAG_Z),
+    flag(FLAG_N),
+    flag(FLAG_H),
+    flag(FLAG_C),
+    cpu.ime as u8,
+    cpu.halted as u8
+  )
+  .unwrap();
+
+  writeln!(report, "------- HISTORY ---------").unwrap();
+  if cpu.history.len() == 0 {
+    writeln!(report, " (empty)").unwrap();
+  }
+  // the bus may be busy if we panicked in the middle of an access
+  let bus = cpu.bus.as_ref().and_then(|bus| bus.try_borrow().ok());
+  for pc in cpu.history.entries() {
+    let instr = match &bus {
+      Some(bus) => dasm_at(bus, *pc),
+      None => String::from("??"),
+    };
+    writeln!(report, " PC:{:04X}  {}", pc, instr).unwrap();
+  }
+  report
+}
+
+fn dasm_at(bus: &Bus, pc: u16) -> String {
+  let mut dasm = Dasm::new();
+  for offset in 0..MAX_INSTR_SIZE {
+    match bus.read8(pc.wrapping_add(offset)) {
+      Ok(byte) => {
+        if let Some(instr) = dasm.munch(byte) {
+          return instr;
+        }
+      }
+      Err(_) => break,
+    }
+  }
+  String::from("??")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_crash_report_empty_history() {
+    let cpu = Cpu::new();
+    let report = format_crash_report(&cpu);
+    assert!(report.contains("[PC] 0000"));
+    assert!(report.contains("(empty)"));
+  }
+
+  #[test]
+  fn test_crash_report_no_bus() {
+    let mut cpu = Cpu::new();
+    cpu.history.push(0x0100);
+    let report = format_crash_report(&cpu);
+    assert!(report.contains(" PC:0100  ??"));
+  }
+}
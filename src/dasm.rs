@@ -6,6 +6,8 @@ use crate::err::GbResult;
 use ImmInfo::*;
 
 const PREFIX_CB_OP: u8 = 0xcb;
+// name of the opcodes that don't exist on the gameboy cpu
+const INVALID_INSTR: &str = "???";
 const INSTR_ENTRY_TABLE: [InstrEntry; 256] = [
   /* 00 */ InstrEntry {
     name: "nop",
@@ -1151,7 +1153,7 @@ const INSTR_ENTRY_TABLE: [InstrEntry; 256] = [
     info: Some(A16),
   },
   /* D3 */ InstrEntry {
-    name: "???",
+    name: INVALID_INSTR,
     size: 1,
     info: None,
   },
@@ -1196,7 +1198,7 @@ const INSTR_ENTRY_TABLE: [InstrEntry; 256] = [
     info: Some(A16),
   },
   /* DB */ InstrEntry {
-    name: "???",
+    name: INVALID_INSTR,
     size: 1,
     info: None,
   },
@@ -1207,7 +1209,7 @@ const INSTR_ENTRY_TABLE: [InstrEntry; 256] = [
     info: Some(A16),
   },
   /* DD */ InstrEntry {
-    name: "???",
+    name: INVALID_INSTR,
     size: 1,
     info: None,
   },
@@ -1241,12 +1243,12 @@ const INSTR_ENTRY_TABLE: [InstrEntry; 256] = [
     info: None,
   },
   /* E3 */ InstrEntry {
-    name: "???",
+    name: INVALID_INSTR,
     size: 1,
     info: None,
   },
   /* E4 */ InstrEntry {
-    name: "???",
+    name: INVALID_INSTR,
     size: 1,
     info: None,
   },
@@ -1287,17 +1289,17 @@ const INSTR_ENTRY_TABLE: [InstrEntry; 256] = [
     info: Some(A16),
   },
   /* EB */ InstrEntry {
-    name: "???",
+    name: INVALID_INSTR,
     size: 1,
     info: None,
   },
   /* EC */ InstrEntry {
-    name: "???",
+    name: INVALID_INSTR,
     size: 1,
     info: None,
   },
   /* ED */ InstrEntry {
-    name: "???",
+    name: INVALID_INSTR,
     size: 1,
     info: None,
   },
@@ -1336,7 +1338,7 @@ const INSTR_ENTRY_TABLE: [InstrEntry; 256] = [
     info: None,
   },
   /* F4 */ InstrEntry {
-    name: "???",
+    name: INVALID_INSTR,
     size: 1,
     info: None,
   },
@@ -1382,12 +1384,12 @@ const INSTR_ENTRY_TABLE: [InstrEntry; 256] = [
     info: None,
   },
   /* FC */ InstrEntry {
-    name: "???",
+    name: INVALID_INSTR,
     size: 1,
     info: None,
   },
   /* FD */ InstrEntry {
-    name: "???",
+    name: INVALID_INSTR,
     size: 1,
     info: None,
   },
@@ -2990,7 +2992,12 @@ impl Dasm {
 
       // initialize new state from entry
       self.instr_desc.clear();
-      self.name = String::from(entry.name);
+      self.name = if entry.name == INVALID_INSTR {
+        // keep the byte around so listings show which opcode it was
+        format!("{} ({:02X})", INVALID_INSTR, byte)
+      } else {
+        String::from(entry.name)
+      };
       self.imm16 = 0;
       self.bytes_left = entry.size;
       self.imm_info = entry.info;
@@ -3026,11 +3033,234 @@ impl Dasm {
   }
 }
 
+impl Default for Dasm {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// A single disassembled instruction
+pub struct DasmLine {
+  pub addr: u16,
+  pub bytes: Vec<u8>,
+  pub instr: String,
+}
+
+impl std::fmt::Display for DasmLine {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let raw_bytes: String = self.bytes.iter().map(|b| format!("{:02X} ", b)).collect();
+    write!(f, "{:04X}  {:9} {}", self.addr, raw_bytes, self.instr)
+  }
+}
+
+/// Disassemble `count` instructions starting at `start`, reading memory
+/// through `read`. Addresses wrap around the end of memory.
+pub fn disassemble<F>(start: u16, count: usize, mut read: F) -> GbResult<Vec<DasmLine>>
+where
+  F: FnMut(u16) -> GbResult<u8>,
+{
+  let mut dasm = Dasm::new();
+  let mut lines = Vec::with_capacity(count);
+  let mut addr = start;
+  for _ in 0..count {
+    let mut line = DasmLine {
+      addr,
+      bytes: Vec::new(),
+      instr: String::new(),
+    };
+    loop {
+      let byte = read(addr)?;
+      line.bytes.push(byte);
+      addr = addr.wrapping_add(1);
+      if let Some(instr) = dasm.munch(byte) {
+        line.instr = instr;
+        break;
+      }
+    }
+    lines.push(line);
+  }
+  Ok(lines)
+}
+
+/// `disassemble` for memory that can't fail to read, like a rom image
+pub fn disassemble_with<F>(start: u16, count: usize, read: F) -> Vec<DasmLine>
+where
+  F: Fn(u16) -> u8,
+{
+  // reading never fails, so neither does the disassembly
+  disassemble(start, count, |addr| Ok(read(addr))).unwrap()
+}
+
+/// Disassemble all of `bytes` as if they were loaded at `base`. An
+/// instruction cut off by the end of the slice is left out.
+pub fn disassemble_slice(bytes: &[u8], base: u16) -> Vec<DasmLine> {
+  let mut dasm = Dasm::new();
+  let mut lines = Vec::new();
+  let mut rest = bytes.iter();
+  let mut addr = base;
+  'lines: loop {
+    let mut line = DasmLine {
+      addr,
+      bytes: Vec::new(),
+      instr: String::new(),
+    };
+    loop {
+      let Some(&byte) = rest.next() else {
+        break 'lines;
+      };
+      line.bytes.push(byte);
+      addr = addr.wrapping_add(1);
+      if let Some(instr) = dasm.munch(byte) {
+        line.instr = instr;
+        break;
+      }
+    }
+    lines.push(line);
+  }
+  lines
+}
+
+/// Format disassembled lines as a text listing, one instruction per line
+pub fn format_listing(lines: &[DasmLine]) -> String {
+  lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+/// Where the disassembly window reads from. Following pc keeps the view on
+/// the current instruction, otherwise it stays at a fixed address.
+pub struct DasmView {
+  pub follow_pc: bool,
+  /// Contents of the goto address box
+  pub goto_text: String,
+  /// Contents of the new breakpoint box
+  pub breakpoint_text: String,
+  start: u16,
+}
+
+impl DasmView {
+  pub fn new() -> Self {
+    Self {
+      follow_pc: true,
+      goto_text: String::new(),
+      breakpoint_text: String::new(),
+      start: 0,
+    }
+  }
+
+  /// Address the view should start disassembling from
+  pub fn start_addr(&self, pc: u16) -> u16 {
+    if self.follow_pc {
+      pc
+    } else {
+      self.start
+    }
+  }
+
+  /// Pin or unpin the view. Unpinning keeps the view where it was.
+  pub fn set_follow_pc(&mut self, follow_pc: bool, pc: u16) {
+    if self.follow_pc && !follow_pc {
+      self.start = pc;
+    }
+    self.follow_pc = follow_pc;
+  }
+
+  /// Jump to the address typed in the goto box, unpinning the view. Returns
+  /// false if the text isn't a valid address.
+  pub fn goto(&mut self) -> bool {
+    match parse_addr(&self.goto_text) {
+      Some(addr) => {
+        self.start = addr;
+        self.follow_pc = false;
+        true
+      }
+      None => false,
+    }
+  }
+}
+
+impl Default for DasmView {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Parse a hex address like `0150`, `$0150` or `0x0150`
+pub fn parse_addr(text: &str) -> Option<u16> {
+  let text = text.trim();
+  let digits = text
+    .strip_prefix('$')
+    .or_else(|| text.strip_prefix("0x"))
+    .unwrap_or(text);
+  u16::from_str_radix(digits, 16).ok()
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
   use std::collections::VecDeque;
 
+  #[test]
+  fn test_disassemble_listing() {
+    // nop, jp $0150, cb prefixed swap a, ld h 255
+    let mem = [0x00, 0xc3, 0x50, 0x01, 0xcb, 0x37, 0x26, 0xff];
+    let lines = disassemble(0x0100, 4, |addr| Ok(mem[(addr - 0x0100) as usize])).unwrap();
+    assert_eq!(
+      format_listing(&lines),
+      "0100  00        nop\n\
+       0101  C3 50 01  jp $0150\n\
+       0104  CB 37     swap a\n\
+       0106  26 FF     ld h 255\n"
+    );
+  }
+
+  #[test]
+  fn test_disassemble_slice() {
+    // nop, jp $0150, cb prefixed swap a, then ld a cut off after its opcode
+    let rom: &[u8] = &[0x00, 0xc3, 0x50, 0x01, 0xcb, 0x37, 0x3e];
+    let lines = disassemble_slice(rom, 0x0100);
+    assert_eq!(
+      format_listing(&lines),
+      "0100  00        nop\n\
+       0101  C3 50 01  jp $0150\n\
+       0104  CB 37     swap a\n"
+    );
+    assert!(disassemble_slice(&[], 0x0100).is_empty());
+
+    // the same through a plain reader, wrapping at the end of memory
+    let lines = disassemble_with(0xffff, 2, |addr| rom[addr.wrapping_sub(0xffff) as usize]);
+    assert_eq!(lines[0].instr, "nop");
+    assert_eq!(lines[1].addr, 0x0000);
+    assert_eq!(lines[1].instr, "jp $0150");
+  }
+
+  #[test]
+  fn test_dasm_view_start_addr() {
+    let mut view = DasmView::new();
+    // follows pc by default
+    assert_eq!(view.start_addr(0x0100), 0x0100);
+    assert_eq!(view.start_addr(0x0150), 0x0150);
+
+    // unpinning stays put while pc moves
+    view.set_follow_pc(false, 0x0150);
+    assert_eq!(view.start_addr(0x0200), 0x0150);
+
+    // goto unpins and jumps, bad input leaves the view alone
+    view.set_follow_pc(true, 0x0200);
+    view.goto_text = String::from("$C000");
+    assert!(view.goto());
+    assert!(!view.follow_pc);
+    assert_eq!(view.start_addr(0x0200), 0xc000);
+    view.goto_text = String::from("zz");
+    assert!(!view.goto());
+    assert_eq!(view.start_addr(0x0200), 0xc000);
+    view.goto_text = String::from("0x4a");
+    assert!(view.goto());
+    assert_eq!(view.start_addr(0x0200), 0x004a);
+
+    // pinning again follows pc
+    view.set_follow_pc(true, 0x0200);
+    assert_eq!(view.start_addr(0x0210), 0x0210);
+  }
+
   #[test]
   fn test_dasm_nop() {
     let mut dasm = Dasm::new();
@@ -3071,7 +3301,18 @@ mod tests {
     // invalid
     let instr = dasm.munch(bytes.pop_front().unwrap());
     assert!(instr.is_some());
-    assert_eq!(instr.unwrap(), "???");
+    assert_eq!(instr.unwrap(), "??? (E3)");
+  }
+
+  #[test]
+  fn test_dasm_invalid_opcodes() {
+    let mut dasm = Dasm::new();
+    for op in [
+      0xd3u8, 0xdb, 0xdd, 0xe3, 0xe4, 0xeb, 0xec, 0xed, 0xf4, 0xfc, 0xfd,
+    ] {
+      let instr = dasm.munch(op).unwrap();
+      assert!(instr.contains(&format!("{:02X}", op)), "{}", instr);
+    }
   }
 
   #[test]
@@ -17,15 +17,79 @@ pub struct GbError {
   error: GbErrorType,
   line: u32,
   file: &'static str,
+  // optional context filled in as the error travels up the stack
+  addr: Option<u16>,
+  pc: Option<u16>,
+  opcode: Option<u8>,
 }
 
 impl GbError {
   pub fn new(error: GbErrorType, file: &'static str, line: u32) -> GbError {
-    GbError { error, line, file }
+    GbError {
+      error,
+      line,
+      file,
+      addr: None,
+      pc: None,
+      opcode: None,
+    }
+  }
+
+  /// Attach the bus address being accessed. The innermost address is kept.
+  pub fn with_addr(mut self, addr: u16) -> GbError {
+    self.addr.get_or_insert(addr);
+    self
+  }
+
+  /// Attach the address of the executing instruction. The innermost pc is kept.
+  pub fn with_pc(mut self, pc: u16) -> GbError {
+    self.pc.get_or_insert(pc);
+    self
+  }
+
+  /// Attach the opcode being executed
+  pub fn with_opcode(mut self, opcode: u8) -> GbError {
+    self.opcode.get_or_insert(opcode);
+    self
+  }
+
+  /// Type of error that occurred
+  pub fn error_type(&self) -> &GbErrorType {
+    &self.error
+  }
+
+  pub fn addr(&self) -> Option<u16> {
+    self.addr
+  }
+
+  pub fn pc(&self) -> Option<u16> {
+    self.pc
+  }
+
+  pub fn opcode(&self) -> Option<u8> {
+    self.opcode
   }
 }
 
-#[derive(Debug)]
+impl fmt::Display for GbError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.error)?;
+    if let Some(opcode) = self.opcode {
+      write!(f, " 0x{:02X}", opcode)?;
+    }
+    if let Some(pc) = self.pc {
+      write!(f, " at PC=0x{:04X}", pc)?;
+    }
+    if let Some(addr) = self.addr {
+      write!(f, " accessing ${:04X}", addr)?;
+    }
+    write!(f, " ({}:{})", self.file, self.line)
+  }
+}
+
+impl std::error::Error for GbError {}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub enum GbErrorType {
   NotInitialized,
   AlreadyInitialized,
@@ -34,4 +98,83 @@ pub enum GbErrorType {
   FileError,
   BadValue,
   Unsupported,
+  LinkError,
+  AudioError,
+}
+
+impl fmt::Display for GbErrorType {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let msg = match self {
+      GbErrorType::NotInitialized => "component not initialized",
+      GbErrorType::AlreadyInitialized => "component already initialized",
+      GbErrorType::OutOfBounds => "address out of bounds",
+      GbErrorType::InvalidCpuInstruction => "invalid cpu instruction",
+      GbErrorType::FileError => "file error",
+      GbErrorType::BadValue => "bad value",
+      GbErrorType::Unsupported => "unsupported feature",
+      GbErrorType::LinkError => "link cable error",
+      GbErrorType::AudioError => "audio output error",
+    };
+    write!(f, "{}", msg)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_error_display() {
+    let cases = [
+      (GbErrorType::NotInitialized, "component not initialized"),
+      (
+        GbErrorType::AlreadyInitialized,
+        "component already initialized",
+      ),
+      (GbErrorType::OutOfBounds, "address out of bounds"),
+      (
+        GbErrorType::InvalidCpuInstruction,
+        "invalid cpu instruction",
+      ),
+      (GbErrorType::FileError, "file error"),
+      (GbErrorType::BadValue, "bad value"),
+      (GbErrorType::Unsupported, "unsupported feature"),
+      (GbErrorType::LinkError, "link cable error"),
+      (GbErrorType::AudioError, "audio output error"),
+    ];
+    for (error, msg) in cases {
+      assert_eq!(error.to_string(), msg);
+      let err = GbError::new(error, "src/err.rs", 42);
+      assert_eq!(err.to_string(), format!("{} (src/err.rs:42)", msg));
+    }
+  }
+
+  #[test]
+  fn test_error_context_display() {
+    let err = GbError::new(GbErrorType::InvalidCpuInstruction, "src/cpu.rs", 7)
+      .with_opcode(0xdd)
+      .with_pc(0x1234);
+    assert_eq!(
+      err.to_string(),
+      "invalid cpu instruction 0xDD at PC=0x1234 (src/cpu.rs:7)"
+    );
+
+    // innermost context wins
+    let err = GbError::new(GbErrorType::OutOfBounds, "src/bus.rs", 1)
+      .with_addr(0xa000)
+      .with_addr(0xff46);
+    assert_eq!(err.addr(), Some(0xa000));
+    assert_eq!(err.pc(), None);
+    assert_eq!(err.opcode(), None);
+  }
+
+  #[test]
+  fn test_error_boxed() {
+    fn fails() -> Result<(), Box<dyn std::error::Error>> {
+      let res: GbResult<()> = gb_err!(GbErrorType::BadValue);
+      res?;
+      Ok(())
+    }
+    assert!(fails().unwrap_err().to_string().starts_with("bad value"));
+  }
 }
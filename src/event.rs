@@ -7,7 +7,19 @@ pub enum UserEvent {
   RequestResize(u32, u32),
   EmuPause,
   EmuStep,
+  /// Run one ppu frame while paused
+  EmuStepFrame,
   EmuPlay,
+  /// Resume until the cpu reaches this pc
+  EmuRunTo(u16),
   EmuReset(Option<PathBuf>),
+  /// Reset without reloading the rom from disk
+  EmuSoftReset,
+  /// Save the state to a numbered slot
+  SaveState(u8),
+  /// Load the state from a numbered slot
+  LoadState(u8),
+  /// Change the speed by a number of steps
+  NudgeSpeed(i32),
   RequestRender,
 }
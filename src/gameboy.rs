@@ -0,0 +1,66 @@
+//! Headless gameboy for embedding. Everything is driven by the caller: load a
+//! rom, set the buttons, step a frame and read back the picture and whatever
+//! went out over the link port. None of it touches a window or the gpu.
+
+use crate::err::GbResult;
+use crate::joypad::JoypadInput;
+use crate::screen::{Resolution, Screen, GB_RESOLUTION};
+use crate::state::{EmuFlow, GbState};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub struct GameBoy {
+  state: GbState,
+  screen: Rc<RefCell<Screen>>,
+}
+
+impl GameBoy {
+  pub fn new() -> GbResult<GameBoy> {
+    let mut state = GbState::new(EmuFlow::new(false, false, 1.0));
+    let screen = Rc::new(RefCell::new(Screen::headless()));
+    state.init(screen.clone())?;
+    Ok(GameBoy { state, screen })
+  }
+
+  /// Insert a cartridge from a rom image. The boot rom runs first, so the
+  /// header needs the logo and checksum like on hardware.
+  pub fn load_rom(&mut self, rom: &[u8]) -> GbResult<()> {
+    self.state.load_rom(rom)
+  }
+
+  /// Run until the ppu finishes the next frame. With the lcd off the frames
+  /// are blank but still finish on time.
+  pub fn step_frame(&mut self) -> GbResult<()> {
+    self.state.step_frame()
+  }
+
+  pub fn resolution(&self) -> Resolution {
+    GB_RESOLUTION
+  }
+
+  /// The last frame as 8 bit rgb, three bytes per pixel in row major order
+  pub fn framebuffer(&self) -> Vec<u8> {
+    self
+      .screen
+      .borrow()
+      .get_framebuffer()
+      .iter()
+      .flat_map(|pixel| pixel.to_rgb8())
+      .collect()
+  }
+
+  pub fn set_button(&mut self, button: JoypadInput, pressed: bool) {
+    self.state.joypad_input(button, pressed);
+  }
+
+  /// Every byte the game has sent over the link port, test roms print their
+  /// results this way
+  pub fn serial_output(&self) -> Vec<u8> {
+    self.state.serial.borrow().output.clone()
+  }
+
+  /// The full emulator for anything the facade doesn't cover
+  pub fn state(&mut self) -> &mut GbState {
+    &mut self.state
+  }
+}
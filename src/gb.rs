@@ -1,30 +1,37 @@
 //! Main gameboy system module
 
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use egui_winit::winit::dpi::{LogicalSize, PhysicalSize};
 #[allow(unused)]
 use log::{debug, error, info, trace, warn, LevelFilter};
 
 use std::cell::RefCell;
+use std::path::PathBuf;
 use std::rc::Rc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+use crate::audio::AudioOutput;
 use crate::bus::*;
-use crate::cart::Cartridge;
-use crate::cpu::Cpu;
+use crate::cart::{self, Cartridge};
+use crate::config::{Config, LinkConfig};
+use crate::cpu;
 use crate::err::{GbError, GbErrorType, GbResult};
 use crate::event::UserEvent;
 use crate::gb_err;
+use crate::input_script::InputScript;
 use crate::joypad::JoypadInput;
 use crate::logger::Logger;
 use crate::ram::*;
-use crate::screen::{Color, Pos};
-use crate::state::{EmuFlow, GbState};
-use crate::ui::Ui;
+use crate::screen::{Color, Pos, Screen};
+use crate::serial::link::TcpLink;
+use crate::state::{EmuFlow, GbState, DOTS_PER_FRAME};
+use crate::tick_counter::{wait_until, CycleClock};
+use crate::ui::{self, Ui};
 use crate::video::Video;
 
 use egui;
 use egui_winit::winit;
-use egui_winit::winit::event_loop::{EventLoopBuilder, EventLoopWindowTarget};
+use egui_winit::winit::event_loop::{EventLoopBuilder, EventLoopProxy, EventLoopWindowTarget};
 use egui_winit::winit::{
   event::{self, Event, WindowEvent},
   event_loop::ControlFlow,
@@ -34,30 +41,48 @@ use egui_winit::winit::{
 static mut LOGGER: Logger = Logger::const_default();
 
 // window constants
-const SCALE_FACTOR: u32 = 10;
-const INITIAL_WIDTH: u32 = 160 * SCALE_FACTOR;
-const INITIAL_HEIGHT: u32 = 144 * SCALE_FACTOR;
+const GB_WIDTH: u32 = 160;
+const GB_HEIGHT: u32 = 144;
 
 // target frame time (60 fps)
 const TARGET_FRAME_TIME_MS: u128 = 1000 / 60;
+// smallest slice of emulation worth running, about a scanline. Anything less
+// waits for more time to pass.
+const MIN_RUN_DOTS: u32 = 456;
+// longest to wait for more emulation to be owed, so events are still handled
+const MAX_IDLE_WAIT: Duration = Duration::from_millis(2);
+// slice of emulation to run per loop when uncapped, about a frame
+const UNCAPPED_RUN_DOTS: u32 = 70224;
 
 pub struct Gameboy {
+  config: Config,
   is_init: bool,
   state: GbState,
   last_render: Instant,
+  // paces emulation to wall clock time, independent of rendering
+  clock: CycleClock,
+  // slot used by the save and load state hotkeys
+  state_slot: u8,
+  // plays the queued samples for as long as it lives
+  audio_stream: Option<cpal::Stream>,
+  // None when running headless
+  event_loop_proxy: Option<EventLoopProxy<UserEvent>>,
   // video: Option<Video>,
 }
 
 impl Gameboy {
-  pub fn new(level_filter: LevelFilter) -> Gameboy {
-    init_logging(level_filter);
-
+  pub fn new(config: Config) -> Gameboy {
     let state = GbState::new(EmuFlow::new(false, false, 1.0));
 
     Gameboy {
+      config,
       state,
       is_init: false,
       last_render: Instant::now(),
+      clock: CycleClock::new(cpu::CLOCK_RATE as f64),
+      state_slot: 1,
+      audio_stream: None,
+      event_loop_proxy: None,
     }
   }
 
@@ -72,8 +97,8 @@ impl Gameboy {
       .with_transparent(false)
       .with_title("~ Enter the Gameboy Emulation ~")
       .with_inner_size(winit::dpi::PhysicalSize {
-        width: INITIAL_WIDTH,
-        height: INITIAL_HEIGHT,
+        width: GB_WIDTH * self.config.scale,
+        height: GB_HEIGHT * self.config.scale,
       })
       .build(&event_loop)
       .unwrap();
@@ -85,7 +110,26 @@ impl Gameboy {
     let mut video = pollster::block_on(Video::new(window, ui));
 
     // initialize the gb state
-    self.state.init(video.screen(), event_loop.create_proxy())?;
+    self.state.init(video.screen())?;
+    self.event_loop_proxy = Some(event_loop.create_proxy());
+    let proxy = event_loop.create_proxy();
+    self.state.on_frame = Some(Box::new(move || {
+      // the loop is only gone while shutting down
+      let _ = proxy.send_event(UserEvent::RequestRender);
+    }));
+    self.apply_config()?;
+    self.connect_link()?;
+    // no device just means no sound
+    self.audio_stream = open_audio_stream(&mut self.state.audio)
+      .map_err(|e| warn!("Running without sound: {}", e))
+      .ok();
+    if let Some(rom) = self.config.rom.clone() {
+      self.state.load_cart(rom.clone())?;
+      if let Some(warning) = self.state.model_warning() {
+        video.show_toast(warning);
+      }
+      video.add_recent_rom(rom);
+    }
 
     self.last_render = Instant::now();
     // run as fast as possible
@@ -93,10 +137,14 @@ impl Gameboy {
       // run as fast as possible
       control_flow.set_poll();
 
-      self.handle_events(event, control_flow, &mut video).unwrap();
+      if let Err(e) = self.handle_events(event, control_flow, &mut video) {
+        panic!("Failed to handle event: {}", e);
+      }
 
-      // system step
-      self.state.step().unwrap();
+      // run the emulation owed for the time since the last loop
+      if let Err(e) = self.run_emulation(Instant::now()) {
+        panic!("Emulation failed: {}", e);
+      }
 
       // draw the window at least every 1/60 of a second
       let now = Instant::now();
@@ -110,6 +158,69 @@ impl Gameboy {
     // no return
   }
 
+  /// Run the given number of frames without a window and return the hash of
+  /// the final framebuffer
+  pub fn run_headless(mut self, frames: u64) -> GbResult<u64> {
+    info!("Running {} frames headless", frames);
+    let screen = Rc::new(RefCell::new(Screen::headless()));
+    self.state.init(screen.clone())?;
+    self.apply_config()?;
+    if let Some(rom) = self.config.rom.clone() {
+      self.state.load_cart(rom)?;
+    }
+
+    for _ in 0..frames {
+      if self.state.flow.deterministic {
+        self.state.run_fixed_frame()?;
+      } else {
+        self.state.step_frame()?;
+      }
+    }
+
+    let hash = screen.borrow().framebuffer_hash();
+    Ok(hash)
+  }
+
+  /// Plug in the link cable from the config. This stays connected across
+  /// resets.
+  fn connect_link(&mut self) -> GbResult<()> {
+    let link = match &self.config.link {
+      Some(LinkConfig::Listen(port)) => TcpLink::listen(*port)?,
+      Some(LinkConfig::Connect(addr)) => TcpLink::connect(addr.as_str())?,
+      None => return Ok(()),
+    };
+    self.state.serial.borrow_mut().connect_link(link)
+  }
+
+  /// Apply the startup config to a freshly initialized state
+  fn apply_config(&mut self) -> GbResult<()> {
+    self
+      .state
+      .ppu
+      .borrow_mut()
+      .set_palette(ui::palette_colors(self.config.palette));
+    self.state.ppu.borrow_mut().oam_bug_enabled = self.config.oam_bug;
+    self.state.ppu.borrow_mut().scanline_render = self.config.scanline_render;
+    self.state.force_dmg = self.config.dmg;
+    self.state.cpu.borrow_mut().strict_opcodes = self.config.strict_opcodes;
+    let volume = self.config.volume as f32 / 100.0;
+    self.state.audio.queue.set_volume(volume);
+    self.state.joypad.borrow_mut().set_socd(self.config.socd);
+    self.state.flow.deterministic = self.config.deterministic;
+    self.state.set_ram_init(self.config.ram_init);
+    if let Some(replay) = &self.config.replay {
+      self.state.replay = Some(InputScript::load(replay)?);
+      info!("Replaying input from {}", replay.display());
+    }
+    if self.config.record.is_some() {
+      self.state.recording = Some(InputScript::new());
+    }
+    if let Some(boot_rom) = self.config.boot_rom.clone() {
+      self.state.cart.borrow_mut().load_boot_rom(boot_rom)?;
+    }
+    Ok(())
+  }
+
   fn handle_events(
     &mut self,
     event: Event<UserEvent>,
@@ -127,8 +238,15 @@ impl Gameboy {
             self.handle_keyboard_input(input);
           }
           WindowEvent::CloseRequested => {
+            self.on_close();
+            video.on_close();
             control_flow.set_exit();
           }
+          WindowEvent::DroppedFile(ref path) => {
+            if let Some(event) = dropped_file_event(path.clone()) {
+              self.send_event(event);
+            }
+          }
           _ => (),
         };
         video.handle_window_event(event);
@@ -145,15 +263,43 @@ impl Gameboy {
         }
         UserEvent::EmuPause => self.state.flow.paused = true,
         UserEvent::EmuPlay => self.state.flow.paused = false,
+        UserEvent::EmuRunTo(pc) => {
+          self.state.flow.run_to = Some(pc);
+          self.state.flow.paused = false;
+        }
         UserEvent::EmuStep => self.state.flow.step = true,
-        UserEvent::EmuReset(path) => {
-          let flow = self.state.flow;
-          let elp = self.state.event_loop_proxy.clone();
-          self.state = GbState::new(flow);
-          self.state.init(video.screen(), elp.unwrap())?;
-          if let Some(path_unwrapped) = path {
-            self.state.cart.borrow_mut().load(path_unwrapped)?;
+        UserEvent::EmuStepFrame => self.step_frame()?,
+        UserEvent::EmuReset(None) => self.power_cycle(None)?,
+        // the rom is read before the reset, a bad file keeps the game going
+        UserEvent::EmuReset(Some(path)) => match self.state.open_cart(path.clone()) {
+          Ok(cart) => {
+            self.power_cycle(Some(cart))?;
+            if let Some(warning) = self.state.model_warning() {
+              video.show_toast(warning);
+            }
+            video.add_recent_rom(path);
           }
+          Err(e) => {
+            error!("Failed to load {}: {}", path.display(), e);
+            video.show_toast(format!("Failed to load {}", path.display()));
+            video.remove_recent_rom(&path);
+          }
+        },
+        UserEvent::EmuSoftReset => {
+          self.state.soft_reset()?;
+          self.apply_config()?;
+        }
+        UserEvent::SaveState(slot) => {
+          let msg = self.save_state(slot);
+          video.show_toast(msg);
+        }
+        UserEvent::LoadState(slot) => {
+          let msg = self.load_state(slot);
+          video.show_toast(msg);
+        }
+        UserEvent::NudgeSpeed(steps) => {
+          self.state.flow.nudge_speed(steps);
+          video.show_toast(format!("Speed {:.0}%", self.state.flow.speed * 100.0));
         }
         _ => {}
       },
@@ -162,127 +308,255 @@ impl Gameboy {
     Ok(())
   }
 
-  fn handle_keyboard_input(&self, keyboard_input: event::KeyboardInput) {
+  /// Advance the emulation by the wall clock time between the last call and
+  /// `now`. Time spent rendering or handling events is caught up on here, up
+  /// to a limit. How often this gets called, or how often the display
+  /// refreshes, doesn't change how fast the game runs.
+  fn run_emulation(&mut self, now: Instant) -> GbResult<()> {
+    let EmuFlow {
+      deterministic,
+      paused,
+      uncapped,
+      speed,
+      ..
+    } = self.state.flow;
+    if deterministic && !paused {
+      if uncapped {
+        self.clock.resync(now);
+        return self.state.run_fixed_frame();
+      }
+      // the schedule only runs whole frames, wait until one is owed
+      if self.clock.advance_for(now, speed, DOTS_PER_FRAME) < DOTS_PER_FRAME {
+        wait_until(self.clock.due(speed, DOTS_PER_FRAME), MAX_IDLE_WAIT);
+        return Ok(());
+      }
+      self.state.run_fixed_frame()?;
+      self.clock.spend(DOTS_PER_FRAME);
+      return Ok(());
+    }
+    if paused || uncapped {
+      // nothing is owed for time spent paused or running flat out
+      self.clock.resync(now);
+      let dots = if uncapped { UNCAPPED_RUN_DOTS } else { 0 };
+      self.state.run(dots)?;
+      return Ok(());
+    }
+
+    let budget = self.clock.advance(now, speed);
+    if budget < MIN_RUN_DOTS {
+      wait_until(self.clock.due(speed, MIN_RUN_DOTS), MAX_IDLE_WAIT);
+      return Ok(());
+    }
+    let ran = self.state.run(budget)?;
+    self.clock.spend(ran);
+    Ok(())
+  }
+
+  /// Reset everything with `cart` inserted, or with the slot empty
+  fn power_cycle(&mut self, cart: Option<Cartridge>) -> GbResult<()> {
+    self.save_battery();
+    self.state.reset()?;
+    self.apply_config()?;
+    if let Some(cart) = cart {
+      self.state.insert_cart(cart);
+    }
+    Ok(())
+  }
+
+  /// Run exactly one ppu frame, only while paused. The emulator stays paused
+  /// afterwards.
+  fn step_frame(&mut self) -> GbResult<()> {
+    if !self.state.flow.paused {
+      return Ok(());
+    }
+    self.state.step_frame()
+  }
+
+  /// Flush anything that would be lost when the window closes
+  fn on_close(&mut self) {
+    info!("Closing emulator");
+    self.save_battery();
+    self.save_recording();
+  }
+
+  /// Write the recorded input to the file from the config
+  fn save_recording(&self) {
+    if let (Some(recording), Some(path)) = (&self.state.recording, &self.config.record) {
+      if recording.save(path).is_ok() {
+        info!("Saved input recording to {}", path.display());
+      }
+    }
+  }
+
+  /// Write battery backed ram to the save file. Failing to save is logged
+  /// but not fatal.
+  fn save_battery(&self) {
+    let _ = self.state.cart.borrow().save_battery();
+  }
+
+  /// Save to a state slot and return a message for the user. Failing to save
+  /// is not fatal.
+  fn save_state(&mut self, slot: u8) -> String {
+    self.state_slot = slot;
+    match self.state.save_state_slot(slot) {
+      Ok(path) => {
+        info!("Saved state to {}", path.display());
+        format!("Saved state {}", slot)
+      }
+      Err(e) => {
+        error!("Failed to save state {}: {}", slot, e);
+        format!("Failed to save state {}", slot)
+      }
+    }
+  }
+
+  /// Load a state slot and return a message for the user. Failing to load
+  /// leaves the emulator as it was.
+  fn load_state(&mut self, slot: u8) -> String {
+    self.state_slot = slot;
+    match self.state.load_state_slot(slot) {
+      Ok(path) => {
+        info!("Loaded state from {}", path.display());
+        format!("Loaded state {}", slot)
+      }
+      Err(e) => {
+        error!("Failed to load state {}: {}", slot, e);
+        format!("Failed to load state {}", slot)
+      }
+    }
+  }
+
+  fn send_event(&self, event: UserEvent) {
+    if let Some(elp) = &self.event_loop_proxy {
+      elp.send_event(event).unwrap();
+    }
+  }
+
+  fn handle_keyboard_input(&mut self, keyboard_input: event::KeyboardInput) {
     match keyboard_input {
       // Up
       event::KeyboardInput {
         virtual_keycode: Some(event::VirtualKeyCode::W),
         state: event::ElementState::Pressed,
         ..
-      } => self.state.joypad.borrow_mut().set_input(JoypadInput::Up),
+      } => self.state.joypad_input(JoypadInput::Up, true),
       event::KeyboardInput {
         virtual_keycode: Some(event::VirtualKeyCode::W),
         state: event::ElementState::Released,
         ..
-      } => self.state.joypad.borrow_mut().clear_input(JoypadInput::Up),
+      } => self.state.joypad_input(JoypadInput::Up, false),
       // Down
       event::KeyboardInput {
         virtual_keycode: Some(event::VirtualKeyCode::S),
         state: event::ElementState::Pressed,
         ..
-      } => self.state.joypad.borrow_mut().set_input(JoypadInput::Down),
+      } => self.state.joypad_input(JoypadInput::Down, true),
       event::KeyboardInput {
         virtual_keycode: Some(event::VirtualKeyCode::S),
         state: event::ElementState::Released,
         ..
-      } => self
-        .state
-        .joypad
-        .borrow_mut()
-        .clear_input(JoypadInput::Down),
+      } => self.state.joypad_input(JoypadInput::Down, false),
       // Left
       event::KeyboardInput {
         virtual_keycode: Some(event::VirtualKeyCode::A),
         state: event::ElementState::Pressed,
         ..
-      } => self.state.joypad.borrow_mut().set_input(JoypadInput::Left),
+      } => self.state.joypad_input(JoypadInput::Left, true),
       event::KeyboardInput {
         virtual_keycode: Some(event::VirtualKeyCode::A),
         state: event::ElementState::Released,
         ..
-      } => self
-        .state
-        .joypad
-        .borrow_mut()
-        .clear_input(JoypadInput::Left),
+      } => self.state.joypad_input(JoypadInput::Left, false),
       // Right
       event::KeyboardInput {
         virtual_keycode: Some(event::VirtualKeyCode::D),
         state: event::ElementState::Pressed,
         ..
-      } => self.state.joypad.borrow_mut().set_input(JoypadInput::Right),
+      } => self.state.joypad_input(JoypadInput::Right, true),
       event::KeyboardInput {
         virtual_keycode: Some(event::VirtualKeyCode::D),
         state: event::ElementState::Released,
         ..
-      } => self
-        .state
-        .joypad
-        .borrow_mut()
-        .clear_input(JoypadInput::Right),
+      } => self.state.joypad_input(JoypadInput::Right, false),
       // A
       event::KeyboardInput {
         virtual_keycode: Some(event::VirtualKeyCode::J),
         state: event::ElementState::Pressed,
         ..
-      } => self.state.joypad.borrow_mut().set_input(JoypadInput::A),
+      } => self.state.joypad_input(JoypadInput::A, true),
       event::KeyboardInput {
         virtual_keycode: Some(event::VirtualKeyCode::J),
         state: event::ElementState::Released,
         ..
-      } => self.state.joypad.borrow_mut().clear_input(JoypadInput::A),
+      } => self.state.joypad_input(JoypadInput::A, false),
       // B
       event::KeyboardInput {
         virtual_keycode: Some(event::VirtualKeyCode::I),
         state: event::ElementState::Pressed,
         ..
-      } => self.state.joypad.borrow_mut().set_input(JoypadInput::B),
+      } => self.state.joypad_input(JoypadInput::B, true),
       event::KeyboardInput {
         virtual_keycode: Some(event::VirtualKeyCode::I),
         state: event::ElementState::Released,
         ..
-      } => self.state.joypad.borrow_mut().clear_input(JoypadInput::B),
+      } => self.state.joypad_input(JoypadInput::B, false),
       // Start
       event::KeyboardInput {
         virtual_keycode: Some(event::VirtualKeyCode::Return),
         state: event::ElementState::Pressed,
         ..
-      } => self.state.joypad.borrow_mut().set_input(JoypadInput::Start),
+      } => self.state.joypad_input(JoypadInput::Start, true),
       event::KeyboardInput {
         virtual_keycode: Some(event::VirtualKeyCode::Return),
         state: event::ElementState::Released,
         ..
-      } => self
-        .state
-        .joypad
-        .borrow_mut()
-        .clear_input(JoypadInput::Start),
+      } => self.state.joypad_input(JoypadInput::Start, false),
       // Select
       event::KeyboardInput {
         virtual_keycode: Some(event::VirtualKeyCode::Space),
         state: event::ElementState::Pressed,
         ..
-      } => self
-        .state
-        .joypad
-        .borrow_mut()
-        .set_input(JoypadInput::Select),
+      } => self.state.joypad_input(JoypadInput::Select, true),
       event::KeyboardInput {
         virtual_keycode: Some(event::VirtualKeyCode::Space),
         state: event::ElementState::Released,
         ..
-      } => self
-        .state
-        .joypad
-        .borrow_mut()
-        .clear_input(JoypadInput::Select),
+      } => self.state.joypad_input(JoypadInput::Select, false),
+      // save states
+      event::KeyboardInput {
+        virtual_keycode: Some(event::VirtualKeyCode::F5),
+        state: event::ElementState::Pressed,
+        ..
+      } => self.send_event(UserEvent::SaveState(self.state_slot)),
+      event::KeyboardInput {
+        virtual_keycode: Some(event::VirtualKeyCode::F9),
+        state: event::ElementState::Pressed,
+        ..
+      } => self.send_event(UserEvent::LoadState(self.state_slot)),
+      // frame step
+      event::KeyboardInput {
+        virtual_keycode: Some(event::VirtualKeyCode::N),
+        state: event::ElementState::Pressed,
+        ..
+      } => self.send_event(UserEvent::EmuStepFrame),
+      // speed
+      event::KeyboardInput {
+        virtual_keycode: Some(event::VirtualKeyCode::Equals),
+        state: event::ElementState::Pressed,
+        ..
+      } => self.send_event(UserEvent::NudgeSpeed(1)),
+      event::KeyboardInput {
+        virtual_keycode: Some(event::VirtualKeyCode::Minus),
+        state: event::ElementState::Pressed,
+        ..
+      } => self.send_event(UserEvent::NudgeSpeed(-1)),
       _ => {}
     }
   }
 }
 
 // Initialize logging and set the level filter
-fn init_logging(level_filter: LevelFilter) {
+pub fn init_logging(level_filter: LevelFilter) {
   log::set_max_level(level_filter);
   unsafe {
     LOGGER = Logger::new(level_filter);
@@ -297,3 +571,283 @@ fn init_logging(level_filter: LevelFilter) {
   debug!("Log Level DEBUG Enabled!");
   trace!("Log Level TRACE Enabled!");
 }
+
+/// Open the default output device at its own sample rate and start a stream
+/// that pulls from the output's queue. Sound plays until the stream is
+/// dropped.
+fn open_audio_stream(output: &mut AudioOutput) -> GbResult<cpal::Stream> {
+  let device = match cpal::default_host().default_output_device() {
+    Some(device) => device,
+    None => {
+      error!("No audio output device");
+      return gb_err!(GbErrorType::AudioError);
+    }
+  };
+  let sample_rate = match device.default_output_config() {
+    Ok(config) => config.sample_rate(),
+    Err(e) => {
+      error!("Failed to query the audio device: {}", e);
+      return gb_err!(GbErrorType::AudioError);
+    }
+  };
+  output.set_out_rate(sample_rate.0);
+
+  // the queue hands out interleaved stereo
+  let config = cpal::StreamConfig {
+    channels: 2,
+    sample_rate,
+    buffer_size: cpal::BufferSize::Default,
+  };
+  let queue = output.queue.clone();
+  let stream = match device.build_output_stream(
+    &config,
+    move |data: &mut [f32], _| queue.fill(data),
+    |e| error!("Audio stream error: {}", e),
+    None,
+  ) {
+    Ok(stream) => stream,
+    Err(e) => {
+      error!("Failed to open the audio stream: {}", e);
+      return gb_err!(GbErrorType::AudioError);
+    }
+  };
+  if let Err(e) = stream.play() {
+    error!("Failed to start the audio stream: {}", e);
+    return gb_err!(GbErrorType::AudioError);
+  }
+  info!("Audio output at {} Hz", sample_rate.0);
+  Ok(stream)
+}
+
+/// Event for a file dropped on the window. Only roms are loaded.
+fn dropped_file_event(path: PathBuf) -> Option<UserEvent> {
+  if !cart::is_rom_file(&path) {
+    warn!("Ignoring dropped file {}, not a rom", path.display());
+    return None;
+  }
+  info!("Loading dropped rom {}", path.display());
+  Some(UserEvent::EmuReset(Some(path)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::int::Interrupt;
+  use crate::state::{FRAME_RATE, MIN_SPEED};
+
+  /// Frames run for `secs` of made up wall clock time, polled at the rate of
+  /// a 144 Hz display
+  fn frames_in(deterministic: bool, speed: f32, secs: u32) -> u32 {
+    let mut gb = Gameboy::new(Config::default());
+    let screen = Rc::new(RefCell::new(Screen::headless()));
+    gb.state.init(screen).unwrap();
+    gb.apply_config().unwrap();
+    gb.state.flow.deterministic = deterministic;
+    gb.state.flow.set_speed(speed);
+    // the boot rom starts with the lcd off, count from when it is on
+    while !gb.state.ppu.borrow().lcdc.ppu_enabled {
+      gb.state.step_frame().unwrap();
+    }
+    let frames = Rc::new(RefCell::new(0));
+    let frame_count = frames.clone();
+    gb.state
+      .ppu
+      .borrow_mut()
+      .set_frame_callback(move |_| *frame_count.borrow_mut() += 1);
+
+    let start = Instant::now();
+    gb.clock.resync(start);
+    let refresh = Duration::from_secs(1) / 144;
+    for i in 1..=secs * 144 {
+      gb.run_emulation(start + refresh * i).unwrap();
+    }
+    frames.take()
+  }
+
+  #[test]
+  fn test_frame_rate_ignores_refresh_rate() {
+    // the boot rom stalls with the lcd on when there is no cart, the clock
+    // sees a second go by while the test takes however long it takes
+    for deterministic in [false, true] {
+      let frames = frames_in(deterministic, 1.0, 1) as f64;
+      let expected = FRAME_RATE;
+      assert!(
+        (frames - expected).abs() <= 1.0,
+        "{} frames, expected {:.1}",
+        frames,
+        expected
+      );
+    }
+  }
+
+  #[test]
+  fn test_frame_rate_at_min_speed() {
+    // less than a frame fits in the catch up limit at this speed
+    for deterministic in [false, true] {
+      let frames = frames_in(deterministic, MIN_SPEED, 1) as f64;
+      let expected = FRAME_RATE * MIN_SPEED as f64;
+      assert!(
+        (frames - expected).abs() <= 1.0,
+        "{} frames, expected {:.1}",
+        frames,
+        expected
+      );
+    }
+  }
+
+  #[test]
+  fn test_headless_hash_is_stable() {
+    let run = || Gameboy::new(Config::default()).run_headless(30).unwrap();
+    assert_eq!(run(), run());
+  }
+
+  #[test]
+  fn test_step_frame() {
+    let mut gb = Gameboy::new(Config::default());
+    let screen = Rc::new(RefCell::new(Screen::headless()));
+    gb.state.init(screen).unwrap();
+    gb.apply_config().unwrap();
+
+    // does nothing while running
+    gb.step_frame().unwrap();
+    assert_eq!(gb.state.cpu.borrow().pc, 0);
+
+    gb.state.flow.paused = true;
+    // the boot rom starts with the lcd off, get to a whole frame with it on
+    while !gb.state.ppu.borrow().lcdc.ppu_enabled {
+      gb.step_frame().unwrap();
+    }
+    gb.step_frame().unwrap();
+    for _ in 0..3 {
+      gb.state.ic.borrow_mut().write(IF_ADDR, 0x00).unwrap();
+      let div = gb.state.timer.borrow().div;
+      gb.step_frame().unwrap();
+      // went through vblank and stopped right after wrapping back to line 0
+      assert_eq!(gb.state.ppu.borrow().ly, 0);
+      // one frame is 70224 cycles, 274.3 ticks of the 256 cycle DIV
+      let div_ticks = gb.state.timer.borrow().div.wrapping_sub(div);
+      assert!((18..=19).contains(&div_ticks), "{} DIV ticks", div_ticks);
+      assert!(gb.state.ic.borrow().read(IF_ADDR).unwrap() & Interrupt::Vblank as u8 > 0);
+      assert!(gb.state.flow.paused);
+    }
+  }
+
+  #[test]
+  fn test_dropped_file_event() {
+    let rom = PathBuf::from("/roms/Tetris.GB");
+    match dropped_file_event(rom.clone()) {
+      Some(UserEvent::EmuReset(Some(path))) => assert_eq!(path, rom),
+      other => panic!("Unexpected event {:?}", other),
+    }
+    assert!(dropped_file_event(PathBuf::from("/roms/game.gbc")).is_some());
+    assert!(dropped_file_event(PathBuf::from("/roms/game.zip")).is_some());
+    assert!(dropped_file_event(PathBuf::from("/roms/notes.txt")).is_none());
+    assert!(dropped_file_event(PathBuf::from("/roms/gb")).is_none());
+  }
+
+  #[test]
+  fn test_dropped_bad_rom_keeps_running() {
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x134..0x13b].copy_from_slice(b"RUNNING");
+    let rom_path = std::env::temp_dir().join("gb_test_drop_running.gb");
+    std::fs::write(&rom_path, &rom).unwrap();
+    let mut gb = Gameboy::new(Config::default());
+    let screen = Rc::new(RefCell::new(Screen::headless()));
+    gb.state.init(screen).unwrap();
+    gb.state.load_cart(rom_path.clone()).unwrap();
+    std::fs::remove_file(rom_path).unwrap();
+
+    // a zip with nothing readable in it, a rom cut off in the header and a
+    // file that is gone by the time it loads
+    let bad_zip = std::env::temp_dir().join("gb_test_drop_bad.zip");
+    let mut zip = 0x04034b50u32.to_le_bytes().to_vec();
+    zip.resize(0x40, 0);
+    std::fs::write(&bad_zip, zip).unwrap();
+    let truncated = std::env::temp_dir().join("gb_test_drop_truncated.gb");
+    std::fs::write(&truncated, &rom[..0x140]).unwrap();
+    let missing = std::env::temp_dir().join("gb_test_drop_missing.gbc");
+
+    for dropped in [bad_zip, truncated, missing] {
+      let Some(UserEvent::EmuReset(Some(path))) = dropped_file_event(dropped.clone()) else {
+        panic!("{} was not loaded", dropped.display());
+      };
+      assert!(gb.state.open_cart(path).is_err());
+      let _ = std::fs::remove_file(dropped);
+
+      // the running game is still in and still runs
+      let div = gb.state.timer.borrow().div;
+      gb.state.step_frame().unwrap();
+      assert_ne!(gb.state.timer.borrow().div, div);
+      let cart = gb.state.cart.borrow();
+      assert_eq!(cart.header.title.trim_end_matches('\0'), "RUNNING");
+    }
+  }
+
+  #[test]
+  fn test_close_saves_battery() {
+    // 32KiB mbc1 rom with 8KiB of battery backed ram
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x147] = 0x03;
+    rom[0x149] = 0x02;
+    let rom_path = std::env::temp_dir().join("gb_test_close_battery.gb");
+    std::fs::write(&rom_path, &rom).unwrap();
+
+    let mut gb = Gameboy::new(Config::default());
+    gb.state.cart.borrow_mut().load(rom_path.clone()).unwrap();
+    let sav_path = gb.state.cart.borrow().battery_path().unwrap();
+    let _ = std::fs::remove_file(&sav_path);
+    {
+      let mut cart = gb.state.cart.borrow_mut();
+      cart.write(0x1000, 0x0a).unwrap();
+      cart.write(0xa000, 0x42).unwrap();
+    }
+
+    gb.on_close();
+    let sav = std::fs::read(&sav_path).unwrap();
+    assert_eq!(sav.len(), 0x2000);
+    assert_eq!(sav[0], 0x42);
+
+    // the save comes back with the rom
+    let mut cart = Cartridge::new();
+    cart.load(rom_path.clone()).unwrap();
+    cart.write(0x1000, 0x0a).unwrap();
+    assert_eq!(cart.read(0xa000).unwrap(), 0x42);
+
+    std::fs::remove_file(sav_path).unwrap();
+    std::fs::remove_file(rom_path).unwrap();
+  }
+
+  #[test]
+  fn test_state_slot_round_trip() {
+    // 32KiB rom with no mapper that just spins
+    let rom = vec![0u8; 0x8000];
+    let rom_path = std::env::temp_dir().join("gb_test_state_slot.gb");
+    std::fs::write(&rom_path, &rom).unwrap();
+
+    let mut gb = Gameboy::new(Config::default());
+    let screen = Rc::new(RefCell::new(Screen::headless()));
+    gb.state.init(screen).unwrap();
+    gb.state.cart.borrow_mut().load(rom_path.clone()).unwrap();
+    let slot_path = gb.state.state_slot_path(2).unwrap();
+
+    for _ in 0..3 {
+      gb.state.step_frame().unwrap();
+    }
+    assert_eq!(gb.save_state(2), "Saved state 2");
+    assert!(slot_path.exists());
+    let saved = gb.state.save_state();
+
+    for _ in 0..3 {
+      gb.state.step_frame().unwrap();
+    }
+    assert_ne!(gb.state.save_state(), saved);
+    assert_eq!(gb.load_state(2), "Loaded state 2");
+    assert_eq!(gb.state.save_state(), saved);
+    // hotkeys now use the last slot
+    assert_eq!(gb.state_slot, 2);
+
+    std::fs::remove_file(slot_path).unwrap();
+    std::fs::remove_file(rom_path).unwrap();
+    assert_eq!(gb.load_state(3), "Failed to load state 3");
+  }
+}
@@ -0,0 +1,169 @@
+//! Joypad inputs stamped with the frame they happen on. Recorded from a
+//! deterministic run and replayed to repeat it exactly.
+
+use crate::err::{GbError, GbErrorType, GbResult};
+use crate::gb_err;
+use crate::joypad::JoypadInput;
+use log::error;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Joypad change for a deterministic run
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ScriptedInput {
+  /// Frame the change happens at the start of
+  pub frame: u64,
+  pub input: JoypadInput,
+  pub pressed: bool,
+}
+
+/// Joypad changes in frame order. Saved as text, one change per line:
+/// `<frame> <input> <press|release>`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InputScript {
+  inputs: Vec<ScriptedInput>,
+}
+
+impl InputScript {
+  pub fn new() -> InputScript {
+    InputScript { inputs: Vec::new() }
+  }
+
+  pub fn record(&mut self, frame: u64, input: JoypadInput, pressed: bool) {
+    self.inputs.push(ScriptedInput {
+      frame,
+      input,
+      pressed,
+    });
+  }
+
+  /// Changes that happen at the start of `frame`
+  pub fn inputs_at(&self, frame: u64) -> impl Iterator<Item = &ScriptedInput> {
+    self
+      .inputs
+      .iter()
+      .filter(move |scripted| scripted.frame == frame)
+  }
+
+  pub fn inputs(&self) -> &[ScriptedInput] {
+    &self.inputs
+  }
+
+  pub fn to_text(&self) -> String {
+    self
+      .inputs
+      .iter()
+      .map(|scripted| {
+        let action = if scripted.pressed { "press" } else { "release" };
+        format!("{} {} {}\n", scripted.frame, scripted.input, action)
+      })
+      .collect()
+  }
+
+  /// Parse the text format. Blank lines and lines starting with `#` are
+  /// skipped.
+  pub fn from_text(text: &str) -> Result<InputScript, String> {
+    let mut script = InputScript::new();
+    for (line_num, line) in text.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      let bad_line = || format!("Bad input on line {}: {}", line_num + 1, line);
+      let fields: Vec<&str> = line.split_whitespace().collect();
+      let [frame, input, action] = fields[..] else {
+        return Err(bad_line());
+      };
+      let frame = frame.parse::<u64>().map_err(|_| bad_line())?;
+      let input = JoypadInput::from_str(input).map_err(|_| bad_line())?;
+      let pressed = match action {
+        "press" => true,
+        "release" => false,
+        _ => return Err(bad_line()),
+      };
+      script.record(frame, input, pressed);
+    }
+    // replay looks changes up by frame, keep the file order within a frame
+    script.inputs.sort_by_key(|scripted| scripted.frame);
+    Ok(script)
+  }
+
+  pub fn save(&self, path: &Path) -> GbResult<()> {
+    if let Err(why) = fs::write(path, self.to_text()) {
+      error!("Failed to save inputs to {}: {}", path.display(), why);
+      return gb_err!(GbErrorType::FileError);
+    }
+    Ok(())
+  }
+
+  pub fn load(path: &Path) -> GbResult<InputScript> {
+    let text = match fs::read_to_string(path) {
+      Ok(text) => text,
+      Err(why) => {
+        error!("Failed to read inputs from {}: {}", path.display(), why);
+        return gb_err!(GbErrorType::FileError);
+      }
+    };
+    match InputScript::from_text(&text) {
+      Ok(script) => Ok(script),
+      Err(why) => {
+        error!("{} in {}", why, path.display());
+        gb_err!(GbErrorType::BadValue)
+      }
+    }
+  }
+}
+
+impl From<Vec<ScriptedInput>> for InputScript {
+  fn from(mut inputs: Vec<ScriptedInput>) -> Self {
+    inputs.sort_by_key(|scripted| scripted.frame);
+    InputScript { inputs }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_text_round_trip() {
+    let mut script = InputScript::new();
+    script.record(3, JoypadInput::A, true);
+    script.record(3, JoypadInput::Up, true);
+    script.record(10, JoypadInput::A, false);
+    let text = script.to_text();
+    assert_eq!(text, "3 a press\n3 up press\n10 a release\n");
+    assert_eq!(InputScript::from_text(&text).unwrap(), script);
+
+    let at_3: Vec<_> = script.inputs_at(3).map(|s| s.input).collect();
+    assert_eq!(at_3, [JoypadInput::A, JoypadInput::Up]);
+    assert_eq!(script.inputs_at(4).count(), 0);
+  }
+
+  #[test]
+  fn test_from_text() {
+    let script =
+      InputScript::from_text("# comment\n\n 7 start press \n2 select release\n").unwrap();
+    assert_eq!(
+      script.inputs(),
+      [
+        ScriptedInput {
+          frame: 2,
+          input: JoypadInput::Select,
+          pressed: false,
+        },
+        ScriptedInput {
+          frame: 7,
+          input: JoypadInput::Start,
+          pressed: true,
+        },
+      ]
+    );
+
+    assert!(InputScript::from_text("1 a").is_err());
+    assert!(InputScript::from_text("x a press").is_err());
+    assert!(InputScript::from_text("1 turbo press").is_err());
+    assert!(InputScript::from_text("1 a hold").is_err());
+  }
+}
@@ -4,12 +4,13 @@ use crate::bus::{IE_ADDR, IF_ADDR};
 use crate::cpu::Cpu;
 use crate::err::{GbError, GbErrorType, GbResult};
 use crate::gb_err;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
 use crate::util::LazyDref;
 use log::error;
 use std::cell::RefCell;
 use std::rc::Rc;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Interrupt {
   Vblank = 1 << 0,
   Lcd = 1 << 1,
@@ -18,6 +19,27 @@ pub enum Interrupt {
   Joypad = 1 << 4,
 }
 
+/// Every interrupt, highest priority first
+pub const INTERRUPTS: [Interrupt; 5] = [
+  Interrupt::Vblank,
+  Interrupt::Lcd,
+  Interrupt::Timer,
+  Interrupt::Serial,
+  Interrupt::Joypad,
+];
+
+impl Interrupt {
+  pub fn name(self) -> &'static str {
+    match self {
+      Interrupt::Vblank => "VBlank",
+      Interrupt::Lcd => "LCD",
+      Interrupt::Timer => "Timer",
+      Interrupt::Serial => "Serial",
+      Interrupt::Joypad => "Joypad",
+    }
+  }
+}
+
 impl TryFrom<u8> for Interrupt {
   type Error = GbErrorType;
   fn try_from(value: u8) -> Result<Self, Self::Error> {
@@ -32,6 +54,16 @@ impl TryFrom<u8> for Interrupt {
   }
 }
 
+// bits of IE and IF that have an interrupt behind them
+const INTERRUPT_MASK: u8 = 0x1f;
+
+/// Everything the interrupt controller holds, as raw register values
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InterruptState {
+  pub ie: u8,
+  pub iflag: u8,
+}
+
 pub struct Interrupts {
   // regs
   /// Interrupt Enable
@@ -59,22 +91,55 @@ impl Interrupts {
     Ok(())
   }
 
+  pub fn snapshot(&self) -> InterruptState {
+    InterruptState {
+      ie: self.ie,
+      iflag: self.iflag,
+    }
+  }
+
+  /// Put back a state from `snapshot`. The cpu connection is left alone.
+  pub fn restore(&mut self, state: InterruptState) {
+    self.ie = state.ie;
+    self.iflag = state.iflag;
+  }
+
   pub fn raise(&mut self, interrupt: Interrupt) {
     self.iflag |= interrupt as u8;
   }
 
-  pub fn step(&mut self) {
-    // TODO: collect interrupts only when needed
-    for interrupt in self.collect_interrupts() {
-      if interrupt as u8 & self.ie > 0 {
-        if self.cpu.lazy_dref_mut().interrupt(interrupt) {
-          // successfully handled interrupt, so clear the flag
-          self.iflag &= !(interrupt as u8);
-        }
-        // only handle one interrupt
-        return;
+  /// Requested interrupts, as IF bits
+  pub fn pending(&self) -> u8 {
+    self.iflag & INTERRUPT_MASK
+  }
+
+  /// Interrupts the program has enabled, as IE bits
+  pub fn enabled(&self) -> u8 {
+    self.ie & INTERRUPT_MASK
+  }
+
+  /// The pending and enabled interrupt the cpu would take next. Lower bits
+  /// win, so VBlank > LCD > Timer > Serial > Joypad.
+  pub fn highest_priority(&self) -> Option<Interrupt> {
+    let ready = self.pending() & self.enabled();
+    if ready == 0 {
+      return None;
+    }
+    Interrupt::try_from(1 << ready.trailing_zeros()).ok()
+  }
+
+  /// Dispatch the highest priority interrupt if the cpu takes it. Returns
+  /// the interrupt whose handler the cpu just entered.
+  pub fn step(&mut self) -> GbResult<Option<Interrupt>> {
+    // only handle one interrupt
+    if let Some(interrupt) = self.highest_priority() {
+      if self.cpu.lazy_dref_mut().interrupt(interrupt)? {
+        // successfully handled interrupt, so clear the flag
+        self.iflag &= !(interrupt as u8);
+        return Ok(Some(interrupt));
       }
     }
+    Ok(None)
   }
 
   pub fn read(&self, addr: u16) -> GbResult<u8> {
@@ -99,14 +164,113 @@ impl Interrupts {
     }
     Ok(())
   }
+}
 
-  fn collect_interrupts(&self) -> Vec<Interrupt> {
-    let mut ints = Vec::new();
-    for bit in 0..7 {
-      if (1 << bit) & self.iflag > 0 {
-        ints.push(Interrupt::try_from(1 << bit).unwrap());
-      }
+impl Default for Interrupts {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Snapshot for Interrupts {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    let state = self.snapshot();
+    writer.write_u8(state.ie);
+    writer.write_u8(state.iflag);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    let ie = reader.read_u8()?;
+    let iflag = reader.read_u8()?;
+    self.restore(InterruptState { ie, iflag });
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_interrupt_bits() {
+    let bits = [
+      (Interrupt::Vblank, 0),
+      (Interrupt::Lcd, 1),
+      (Interrupt::Timer, 2),
+      (Interrupt::Serial, 3),
+      (Interrupt::Joypad, 4),
+    ];
+    for (interrupt, bit) in bits {
+      // raising sets only its IF bit
+      let mut ic = Interrupts::new();
+      ic.raise(interrupt);
+      assert_eq!(ic.read(IF_ADDR).unwrap(), 1 << bit);
+      assert_eq!(Interrupt::try_from(1 << bit), Ok(interrupt));
+
+      // enabling its IE bit alone lets it through with everything pending
+      let mut ic = Interrupts::new();
+      ic.write(IF_ADDR, 0x1f).unwrap();
+      ic.write(IE_ADDR, 1 << bit).unwrap();
+      assert_eq!(ic.enabled(), 1 << bit);
+      assert_eq!(ic.highest_priority(), Some(interrupt));
     }
-    ints
+  }
+
+  #[test]
+  fn test_snapshot_restore() {
+    let mut ic = Interrupts::new();
+    ic.write(IE_ADDR, 0x05).unwrap();
+    ic.raise(Interrupt::Timer);
+    ic.raise(Interrupt::Joypad);
+    let state = ic.snapshot();
+    let mut writer = SnapshotWriter::new();
+    ic.save(&mut writer);
+    let bytes = writer.into_bytes();
+
+    ic.write(IE_ADDR, 0).unwrap();
+    ic.write(IF_ADDR, 0).unwrap();
+    assert_eq!(ic.pending(), 0);
+    ic.restore(state);
+    assert_eq!(ic.pending(), 0x14);
+    assert_eq!(ic.enabled(), 0x05);
+    assert_eq!(ic.highest_priority(), Some(Interrupt::Timer));
+
+    let mut loaded = Interrupts::new();
+    loaded.load(&mut SnapshotReader::new(&bytes)).unwrap();
+    assert_eq!(loaded.snapshot(), state);
+  }
+
+  #[test]
+  fn test_highest_priority() {
+    let mut ic = Interrupts::new();
+    assert_eq!(ic.highest_priority(), None);
+
+    // pending but not enabled
+    ic.raise(Interrupt::Joypad);
+    ic.raise(Interrupt::Timer);
+    assert_eq!(ic.pending(), 0x14);
+    assert_eq!(ic.highest_priority(), None);
+
+    ic.write(IE_ADDR, 0xff).unwrap();
+    assert_eq!(ic.enabled(), 0x1f);
+    assert_eq!(ic.highest_priority(), Some(Interrupt::Timer));
+    ic.raise(Interrupt::Serial);
+    assert_eq!(ic.highest_priority(), Some(Interrupt::Timer));
+    ic.raise(Interrupt::Lcd);
+    assert_eq!(ic.highest_priority(), Some(Interrupt::Lcd));
+    ic.raise(Interrupt::Vblank);
+    assert_eq!(ic.highest_priority(), Some(Interrupt::Vblank));
+
+    // only enabled ones count
+    ic.write(IE_ADDR, 0x18).unwrap();
+    assert_eq!(ic.highest_priority(), Some(Interrupt::Serial));
+    ic.write(IE_ADDR, 0x10).unwrap();
+    assert_eq!(ic.highest_priority(), Some(Interrupt::Joypad));
+
+    // the unused top bits never count
+    ic.write(IF_ADDR, 0xe0).unwrap();
+    ic.write(IE_ADDR, 0xff).unwrap();
+    assert_eq!(ic.pending(), 0);
+    assert_eq!(ic.highest_priority(), None);
   }
 }
@@ -3,7 +3,10 @@
 use crate::err::GbResult;
 
 use log::info;
+use std::fmt;
+use std::str::FromStr;
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum JoypadInput {
   Up,
   Down,
@@ -24,6 +27,67 @@ const DPAD_LEFT_BIT: u8 = 1;
 const DPAD_UP_BIT: u8 = 2;
 const DPAD_DOWN_BIT: u8 = 3;
 
+// dpad bits along each axis
+const DPAD_HORIZONTAL_MASK: u8 = (1 << DPAD_LEFT_BIT) | (1 << DPAD_RIGHT_BIT);
+const DPAD_VERTICAL_MASK: u8 = (1 << DPAD_UP_BIT) | (1 << DPAD_DOWN_BIT);
+
+impl fmt::Display for JoypadInput {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      JoypadInput::Up => "up",
+      JoypadInput::Down => "down",
+      JoypadInput::Left => "left",
+      JoypadInput::Right => "right",
+      JoypadInput::A => "a",
+      JoypadInput::B => "b",
+      JoypadInput::Start => "start",
+      JoypadInput::Select => "select",
+    };
+    write!(f, "{}", name)
+  }
+}
+
+impl FromStr for JoypadInput {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "up" => Ok(JoypadInput::Up),
+      "down" => Ok(JoypadInput::Down),
+      "left" => Ok(JoypadInput::Left),
+      "right" => Ok(JoypadInput::Right),
+      "a" => Ok(JoypadInput::A),
+      "b" => Ok(JoypadInput::B),
+      "start" => Ok(JoypadInput::Start),
+      "select" => Ok(JoypadInput::Select),
+      _ => Err(format!("Unknown joypad input: {}", s)),
+    }
+  }
+}
+
+/// How to handle opposing directions (Left+Right or Up+Down) being held at the
+/// same time, which is impossible on real hardware
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum SocdPolicy {
+  /// Pass both directions through
+  Off,
+  /// Opposing directions cancel out
+  Neutral,
+  /// The most recently pressed direction wins
+  LastWins,
+}
+
+impl FromStr for SocdPolicy {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "off" => Ok(SocdPolicy::Off),
+      "neutral" => Ok(SocdPolicy::Neutral),
+      "last-wins" => Ok(SocdPolicy::LastWins),
+      _ => Err(format!("Unknown SOCD policy: {}", s)),
+    }
+  }
+}
+
 pub enum InputBit {
   Button(u8),
   Dpad(u8),
@@ -46,9 +110,16 @@ impl JoypadInput {
 
 pub struct Joypad {
   pub buttons_state: u8,
+  /// Dpad state seen by the game, after SOCD filtering
   pub dpad_state: u8,
   pub button_mode: bool,
   pub dpad_mode: bool,
+  pub socd: SocdPolicy,
+  // dpad directions physically held
+  held_dpad: u8,
+  // most recently pressed direction on each axis
+  last_horizontal: u8,
+  last_vertical: u8,
 }
 
 impl Joypad {
@@ -59,6 +130,10 @@ impl Joypad {
       dpad_state: 0xf,
       button_mode: false,
       dpad_mode: false,
+      socd: SocdPolicy::Off,
+      held_dpad: 0xf,
+      last_horizontal: 0,
+      last_vertical: 0,
     }
   }
 
@@ -66,7 +141,15 @@ impl Joypad {
     // setting means turning off the bit
     match input.as_mask() {
       InputBit::Button(mask) => self.buttons_state &= !mask,
-      InputBit::Dpad(mask) => self.dpad_state &= !mask,
+      InputBit::Dpad(mask) => {
+        self.held_dpad &= !mask;
+        if mask & DPAD_HORIZONTAL_MASK > 0 {
+          self.last_horizontal = mask;
+        } else {
+          self.last_vertical = mask;
+        }
+        self.update_dpad();
+      }
     }
   }
 
@@ -74,17 +157,46 @@ impl Joypad {
     // setting means turning on the bit
     match input.as_mask() {
       InputBit::Button(mask) => self.buttons_state |= mask,
-      InputBit::Dpad(mask) => self.dpad_state |= mask,
+      InputBit::Dpad(mask) => {
+        self.held_dpad |= mask;
+        self.update_dpad();
+      }
+    }
+  }
+
+  /// Change the SOCD policy and refilter the held directions
+  pub fn set_socd(&mut self, socd: SocdPolicy) {
+    self.socd = socd;
+    self.update_dpad();
+  }
+
+  fn update_dpad(&mut self) {
+    let mut dpad = self.held_dpad;
+    for (axis, last) in [
+      (DPAD_HORIZONTAL_MASK, self.last_horizontal),
+      (DPAD_VERTICAL_MASK, self.last_vertical),
+    ] {
+      // both directions on the axis are held
+      if dpad & axis == 0 {
+        match self.socd {
+          SocdPolicy::Off => {}
+          SocdPolicy::Neutral => dpad |= axis,
+          SocdPolicy::LastWins => dpad |= axis & !last,
+        }
+      }
     }
+    self.dpad_state = dpad;
   }
 
   pub fn read(&self, _addr: u16) -> GbResult<u8> {
+    // select bits read back as written, 0 selects
+    let select = (!self.button_mode as u8) << 5 | (!self.dpad_mode as u8) << 4;
     if self.button_mode {
-      Ok(self.buttons_state & 0xf)
+      Ok(select | self.buttons_state & 0xf)
     } else if self.dpad_mode {
-      Ok(self.dpad_state & 0xf)
+      Ok(select | self.dpad_state & 0xf)
     } else {
-      Ok(0xf)
+      Ok(select | 0xf)
     }
   }
 
@@ -94,3 +206,57 @@ impl Joypad {
     Ok(())
   }
 }
+
+impl Default for Joypad {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn left_right(socd: SocdPolicy) -> Joypad {
+    let mut joypad = Joypad::new();
+    joypad.set_socd(socd);
+    joypad.set_input(JoypadInput::Left);
+    joypad.set_input(JoypadInput::Right);
+    joypad
+  }
+
+  #[test]
+  fn test_socd_off() {
+    let joypad = left_right(SocdPolicy::Off);
+    assert_eq!(joypad.dpad_state, 0b1100);
+  }
+
+  #[test]
+  fn test_socd_neutral() {
+    let mut joypad = left_right(SocdPolicy::Neutral);
+    assert_eq!(joypad.dpad_state, 0b1111);
+
+    // vertical axis is unaffected
+    joypad.set_input(JoypadInput::Up);
+    assert_eq!(joypad.dpad_state, 0b1011);
+
+    // releasing one side lets the other through
+    joypad.clear_input(JoypadInput::Right);
+    assert_eq!(joypad.dpad_state, 0b1001);
+  }
+
+  #[test]
+  fn test_socd_last_wins() {
+    let mut joypad = left_right(SocdPolicy::LastWins);
+    assert_eq!(joypad.dpad_state, 0b1110);
+
+    // pressing left again makes it the latest
+    joypad.clear_input(JoypadInput::Left);
+    joypad.set_input(JoypadInput::Left);
+    assert_eq!(joypad.dpad_state, 0b1101);
+
+    // releasing the winner falls back to the still held direction
+    joypad.clear_input(JoypadInput::Left);
+    assert_eq!(joypad.dpad_state, 0b1110);
+  }
+}
@@ -0,0 +1,45 @@
+//! Gameboy emulator library. The binary in main.rs drives it, benchmarks and
+//! tests can use the components directly. `GameBoy` wraps them up for
+//! frontends that bring their own window, input and audio. The window, ui and
+//! audio output of the binary are behind the default `frontend` feature.
+
+extern crate core;
+
+pub mod apu;
+pub mod audio;
+pub mod breakpoint;
+pub mod bus;
+pub mod cart;
+pub mod cheat;
+pub mod config;
+pub mod cpu;
+pub mod crash;
+pub mod dasm;
+pub mod err;
+#[cfg(feature = "frontend")]
+pub mod event;
+pub mod gameboy;
+#[cfg(feature = "frontend")]
+pub mod gb;
+pub mod input_script;
+pub mod int;
+pub mod joypad;
+pub mod logger;
+pub mod ppu;
+pub mod ram;
+pub mod recent;
+pub mod screen;
+pub mod search;
+pub mod serial;
+pub mod snapshot;
+pub mod speed;
+pub mod state;
+pub mod tick_counter;
+pub mod timer;
+#[cfg(feature = "frontend")]
+pub mod ui;
+pub mod util;
+#[cfg(feature = "frontend")]
+pub mod video;
+
+pub use gameboy::GameBoy;
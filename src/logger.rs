@@ -2,6 +2,30 @@
 
 use colored::*;
 use log::{LevelFilter, Log, Metadata, Record};
+use std::env;
+use std::str::FromStr;
+
+/// Environment variable used to set the log level at runtime
+pub const LOG_ENV_VAR: &str = "RUST_LOG";
+
+/// Parse a log level such as "debug". Also accepts `RUST_LOG` style lists
+/// (e.g. "wgpu=warn,gb=trace"), where only bare levels and levels for this
+/// crate are considered.
+pub fn parse_level(s: &str) -> Option<LevelFilter> {
+  s.split(',').rev().find_map(|directive| {
+    let level = match directive.trim().split_once('=') {
+      Some((target, level)) if target.trim() == env!("CARGO_PKG_NAME") => level,
+      Some(_) => return None,
+      None => directive,
+    };
+    LevelFilter::from_str(level.trim()).ok()
+  })
+}
+
+/// Read the log level from the environment, if set
+pub fn env_level() -> Option<LevelFilter> {
+  env::var(LOG_ENV_VAR).ok().and_then(|val| parse_level(&val))
+}
 
 /// Logging implementation for the Log trait.
 pub struct Logger {
@@ -50,3 +74,18 @@ impl Log for Logger {
 
   fn flush(&self) {}
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_level() {
+    assert_eq!(parse_level("debug"), Some(LevelFilter::Debug));
+    assert_eq!(parse_level("TRACE"), Some(LevelFilter::Trace));
+    assert_eq!(parse_level("off"), Some(LevelFilter::Off));
+    assert_eq!(parse_level("wgpu=warn,gb=info"), Some(LevelFilter::Info));
+    assert_eq!(parse_level("wgpu=warn"), None);
+    assert_eq!(parse_level("loud"), None);
+  }
+}
@@ -1,38 +1,52 @@
 //! Gameboy Emulator entry point
 
-extern crate core;
-
-mod bus;
-mod cart;
-mod cpu;
-mod dasm;
-mod err;
-mod event;
-mod gb;
-mod int;
-mod joypad;
-mod logger;
-mod ppu;
-mod ram;
-mod screen;
-mod state;
-mod tick_counter;
-mod timer;
-mod ui;
-mod util;
-mod video;
-
-use log::LevelFilter;
+use gb::config::{Config, USAGE};
+use gb::{crash, gb as emu};
 
 fn main() {
-  println!("~~~ Enter the Gameboy Emulation ~~~");
+  let config = match Config::from_args(std::env::args().skip(1)) {
+    Ok(config) => config,
+    Err(msg) => {
+      eprintln!("{}\n\n{}", msg, USAGE);
+      std::process::exit(1);
+    }
+  };
+  if config.help {
+    println!("{}", USAGE);
+    return;
+  }
+
+  if !config.no_panic_dump {
+    crash::install_panic_hook();
+  }
+
+  // command line level takes priority over RUST_LOG
+  emu::init_logging(config.resolve_log_level());
 
-  // set the max through compile time config in Cargo.toml
-  let log_level_filter = LevelFilter::Info;
+  // headless runs for test automation
+  if let Some(frames) = config.frames {
+    let dump_hash = config.dump_hash;
+    let hash = match emu::Gameboy::new(config).run_headless(frames) {
+      Ok(hash) => hash,
+      Err(e) => {
+        eprintln!("Emulation failed: {}", e);
+        std::process::exit(1);
+      }
+    };
+    if dump_hash {
+      println!("{:016x}", hash);
+    }
+    return;
+  }
+
+  println!("~~~ Enter the Gameboy Emulation ~~~");
 
   // initialize hardware
-  let mut gameboy = gb::Gameboy::new(log_level_filter);
+  let mut gameboy = emu::Gameboy::new(config);
 
   // start the emulation
-  gameboy.run().unwrap();
+  if let Err(e) = gameboy.run() {
+    eprintln!("Emulation failed: {}", e);
+    std::process::exit(1);
+  }
 }
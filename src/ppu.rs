@@ -3,31 +3,42 @@
 use crate::err::{GbError, GbErrorType, GbResult};
 use crate::int::{Interrupt, Interrupts};
 use crate::screen::{Pos, Screen};
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
 use crate::util::LazyDref;
 use crate::{
   bus::{self, OAM_END, OAM_START, PPU_END, PPU_START},
   gb_err, screen,
 };
 use bit_field::BitField;
-use log::{trace, warn};
+use log::{error, trace, warn};
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::str::FromStr;
 
-const LCDC_ADDR: u16 = 0xff40;
-const STAT_ADDR: u16 = 0xff41;
-const SCY_ADDR: u16 = 0xff42;
-const SCX_ADDR: u16 = 0xff43;
+pub const LCDC_ADDR: u16 = 0xff40;
+pub const STAT_ADDR: u16 = 0xff41;
+pub const SCY_ADDR: u16 = 0xff42;
+pub const SCX_ADDR: u16 = 0xff43;
 const LY_ADDR: u16 = 0xff44;
-const LYC_ADDR: u16 = 0xff45;
+pub const LYC_ADDR: u16 = 0xff45;
 const BGP_ADDR: u16 = 0xff47;
 const OBP0_ADDR: u16 = 0xff48;
 const OBP1_ADDR: u16 = 0xff49;
 const WY_ADDR: u16 = 0xff4a;
 const WX_ADDR: u16 = 0xff4b;
+pub const VBK_ADDR: u16 = 0xff4f;
+pub const BCPS_ADDR: u16 = 0xff68;
+const BCPD_ADDR: u16 = 0xff69;
+pub const OCPS_ADDR: u16 = 0xff6a;
+pub const OCPD_ADDR: u16 = 0xff6b;
 
 // addresses for vram
-const VRAM_SIZE: usize = 8 * 1024;
+pub const VRAM_SIZE: usize = 8 * 1024;
+// CGB has a second vram bank for tile attributes and extra tile data
+pub const VRAM_BANKS: usize = 2;
 pub const OAM_SIZE: usize = 160;
+/// End of the unusable area after OAM, still hits the OAM corruption bug
+pub const OAM_BUG_END: u16 = 0xfeff;
 const TILE_MAP_START_LO: u16 = 0x9800 - bus::PPU_START;
 const TILE_MAP_START_HI: u16 = 0x9C00 - bus::PPU_START;
 const TILE_DATA_START_LO: u16 = 0x8000 - bus::PPU_START;
@@ -35,10 +46,27 @@ const TILE_DATA_START_HI: u16 = 0x9000 - bus::PPU_START;
 const TILE_DATA_SIZE: u8 = 16;
 
 // Important Pixel Positions
-const HBLANK_START: u32 = 160;
-const HBLANK_END: u32 = 360; // TODO: what is the correct value here?
-const VBLANK_START: u32 = 144;
-const VBLANK_END: u32 = 154 + 1;
+pub const SCREEN_WIDTH: u32 = 160;
+pub const SCREEN_HEIGHT: u32 = 144;
+const VBLANK_START: u32 = SCREEN_HEIGHT;
+const LINES_PER_FRAME: u32 = 154;
+// WX is the window's left edge plus 7, so this puts it past the right edge
+const WX_HIDDEN: u8 = 166;
+
+// Scanline timing in dots
+const DOTS_PER_LINE: u32 = 456;
+const OAM_SCAN_DOTS: u32 = 80;
+const MODE3_MIN_DOTS: u32 = 172;
+// Each object on the line stalls the fetcher for at least this many dots
+const OBJ_PENALTY_DOTS: u32 = 6;
+// LY only reads 153 for the first m-cycle of the last line, then reads 0 for
+// the rest of it. The LYC compare for line 0 happens here, not at line 0.
+const LY_WRAP_DOT: u32 = 4;
+// The first line after the lcd is turned on has no OAM scan, the mode reads
+// 0 until mode 3 starts, and it is this many dots short
+const LCD_ON_SKIPPED_DOTS: u32 = 4;
+/// PPU dots in one frame, the fixed schedule of deterministic runs
+pub const DOTS_PER_FRAME: u32 = LINES_PER_FRAME * DOTS_PER_LINE;
 
 // Color Palettes
 pub const PALETTE_GRAY: [screen::Color; 4] = [
@@ -63,6 +91,149 @@ pub const PALETTE_BLUE: [screen::Color; 4] = [
   screen::Color::new(15.0 / 255.0, 15.0 / 255.0, 55.0 / 255.0),   // black
 ];
 
+/// Names for the built in color palettes
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum PaletteKind {
+  Gray,
+  Green,
+  Blue,
+  /// User chosen colors, gray until some are saved
+  Custom,
+}
+
+impl PaletteKind {
+  pub fn colors(self) -> [screen::Color; 4] {
+    match self {
+      PaletteKind::Gray => PALETTE_GRAY,
+      PaletteKind::Green => PALETTE_GREEN,
+      PaletteKind::Blue => PALETTE_BLUE,
+      // the saved colors live with the frontend
+      PaletteKind::Custom => PALETTE_GRAY,
+    }
+  }
+}
+
+/// Palette as text, one rrggbb hex color per line
+pub fn format_palette(palette: &[screen::Color; 4]) -> String {
+  palette
+    .iter()
+    .map(|color| {
+      let [r, g, b] = color.to_rgb8();
+      format!("{:02x}{:02x}{:02x}\n", r, g, b)
+    })
+    .collect()
+}
+
+/// Read a palette written by `format_palette`
+pub fn parse_palette(text: &str) -> Option<[screen::Color; 4]> {
+  let mut palette = PALETTE_GRAY;
+  let mut lines = text.lines();
+  for color in palette.iter_mut() {
+    let rgb = u32::from_str_radix(lines.next()?.trim(), 16).ok()?;
+    let [_, r, g, b] = rgb.to_be_bytes();
+    *color = screen::Color::from_rgb8([r, g, b]);
+  }
+  Some(palette)
+}
+
+impl FromStr for PaletteKind {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "gray" | "grey" => Ok(PaletteKind::Gray),
+      "green" => Ok(PaletteKind::Green),
+      "blue" => Ok(PaletteKind::Blue),
+      "custom" => Ok(PaletteKind::Custom),
+      _ => Err(format!("Unknown palette: {}", s)),
+    }
+  }
+}
+
+/// Shade each color index maps to for a BGP/OBP register value
+pub fn palette_shades(reg: u8) -> [u8; 4] {
+  std::array::from_fn(|col_index| (reg >> (col_index * 2)) & 0x3)
+}
+
+// CGB palette ram holds 8 palettes of 4 colors, 2 bytes per color
+const CGB_PALETTE_RAM_SIZE: usize = 8 * 4 * 2;
+
+/// CGB color palette ram along with its specification (index) register
+#[derive(Copy, Clone)]
+pub struct CgbPalettes {
+  data: [u8; CGB_PALETTE_RAM_SIZE],
+  index: u8,
+  auto_increment: bool,
+}
+
+impl CgbPalettes {
+  pub fn new() -> Self {
+    Self {
+      data: [0; CGB_PALETTE_RAM_SIZE],
+      index: 0,
+      auto_increment: false,
+    }
+  }
+
+  /// Read the specification register (BCPS/OCPS)
+  pub fn read_spec(&self) -> u8 {
+    (self.auto_increment as u8) << 7 | self.index
+  }
+
+  /// Write the specification register (BCPS/OCPS)
+  pub fn write_spec(&mut self, data: u8) {
+    self.index = data & 0x3f;
+    self.auto_increment = data.get_bit(7);
+  }
+
+  /// Read the data register (BCPD/OCPD)
+  pub fn read_data(&self) -> u8 {
+    self.data[self.index as usize]
+  }
+
+  /// Write the data register (BCPD/OCPD), advancing the index if auto
+  /// increment is set
+  pub fn write_data(&mut self, data: u8) {
+    self.data[self.index as usize] = data;
+    if self.auto_increment {
+      self.index = (self.index + 1) & 0x3f;
+    }
+  }
+
+  /// Raw RGB555 value of a color in a palette
+  pub fn rgb555(&self, palette: u8, color: u8) -> u16 {
+    let offset = (palette as usize & 0x7) * 8 + (color as usize & 0x3) * 2;
+    u16::from_le_bytes([self.data[offset], self.data[offset + 1]])
+  }
+
+  /// Color in a palette converted for the screen
+  pub fn color(&self, palette: u8, color: u8) -> screen::Color {
+    let rgb = self.rgb555(palette, color);
+    let channel = |shift: u16| ((rgb >> shift) & 0x1f) as f32 / 31.0;
+    screen::Color::new(channel(0), channel(5), channel(10))
+  }
+}
+
+impl Default for CgbPalettes {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Snapshot for CgbPalettes {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    writer.write_bytes(&self.data);
+    writer.write_u8(self.index);
+    writer.write_bool(self.auto_increment);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    reader.read_bytes_into(&mut self.data)?;
+    self.index = reader.read_u8()? & 0x3f;
+    self.auto_increment = reader.read_bool()?;
+    Ok(())
+  }
+}
+
 #[derive(PartialEq, Copy, Clone)]
 pub enum PpuMode {
   HBlank = 0,
@@ -195,7 +366,9 @@ pub struct ObjAttrFlags {
   pub flip_y: bool,
   pub flip_x: bool,
   pub palette_idx: u8,
-  // CGB attributes not included
+  /// CGB object palette (OBP0-7)
+  pub cgb_palette: u8,
+  // CGB vram bank attribute not included
 }
 
 impl From<u8> for ObjAttrFlags {
@@ -205,10 +378,41 @@ impl From<u8> for ObjAttrFlags {
       flip_y: value.get_bit(6),
       flip_x: value.get_bit(5),
       palette_idx: value.get_bit(4) as u8,
+      cgb_palette: value.get_bits(0..3),
     }
   }
 }
 
+impl From<ObjAttrFlags> for u8 {
+  fn from(value: ObjAttrFlags) -> Self {
+    let mut val_u8 = 0;
+    val_u8.set_bit(7, value.low_priority);
+    val_u8.set_bit(6, value.flip_y);
+    val_u8.set_bit(5, value.flip_x);
+    val_u8.set_bit(4, value.palette_idx > 0);
+    val_u8.set_bits(0..3, value.cgb_palette);
+    val_u8
+  }
+}
+
+/// Called with LY at the end of every scanline
+pub type LineCallback = Box<dyn FnMut(u8)>;
+/// Called with the finished framebuffer at the end of every frame
+pub type FrameCallback = Box<dyn FnMut(&[screen::Color])>;
+
+/// Where an object sits on the screen, for debug views. Positions can be
+/// partly off screen.
+#[derive(Debug, PartialEq)]
+pub struct ObjectBounds {
+  /// Index of the object in OAM
+  pub index: usize,
+  pub x: i32,
+  pub y: i32,
+  pub height: u32,
+  /// Left out of at least one of its lines by the 10 objects per line limit
+  pub over_limit: bool,
+}
+
 #[derive(Copy, Clone)]
 pub struct ObjectAttribute {
   pub y_pos: u8,
@@ -228,8 +432,17 @@ impl From<[u8; 4]> for ObjectAttribute {
   }
 }
 
+impl From<ObjectAttribute> for [u8; 4] {
+  fn from(value: ObjectAttribute) -> Self {
+    [value.y_pos, value.x_pos, value.tile_idx, value.flags.into()]
+  }
+}
+
 pub struct Ppu {
+  /// Both vram banks back to back. Bank 0 is at the front.
   pub vram: Vec<u8>,
+  /// CGB vram bank select
+  pub vbk: u8,
   pub oam: Vec<u8>,
   /// lcd control register
   pub lcdc: LcdControl,
@@ -240,7 +453,7 @@ pub struct Ppu {
   /// LCD Status register
   pub stat: Status,
   /// Background palette index mapping
-  pub bgp: u8,
+  bgp: u8,
   /// Scroll X
   pub scx: u8,
   /// Scroll Y
@@ -248,33 +461,76 @@ pub struct Ppu {
   /// OAM Cache (max 10 items)
   pub oam_cache: Vec<ObjectAttribute>,
   /// object palette mapping
-  pub obp: [u8; 2],
+  obp: [u8; 2],
 
   // window position
   pub wy: u8,
+  /// Left edge of the window plus 7. 0-6 clip off the left of the window and
+  /// 166 or more hide it. At 0 the SCX fine scroll is also taken off the
+  /// window, like the DMG does.
   pub wx: u8,
   pub wstart: bool,
+  /// Row of the window to draw next. Only counts lines the window was
+  /// actually drawn on.
+  pub window_line: u8,
+  // the window was drawn on the current line
+  window_drawn: bool,
 
   // palette
-  pub palette: [screen::Color; 4],
+  palette: [screen::Color; 4],
+  /// BGP resolved to colors, refreshed whenever BGP or the palette changes
+  bg_colors: [screen::Color; 4],
+  /// OBP0/OBP1 resolved to colors
+  obj_colors: [[screen::Color; 4]; 2],
+  /// Use the CGB color palettes instead of the monochrome palette
+  pub cgb_mode: bool,
+  /// CGB background palettes (BCPS/BCPD)
+  pub bg_cpal: CgbPalettes,
+  /// CGB object palettes (OCPS/OCPD)
+  pub obj_cpal: CgbPalettes,
+  /// Draw each visible line in one go when mode 3 starts and skip ahead to
+  /// the next mode change. Faster, but register writes in the middle of mode
+  /// 3 are not seen until the next line, so it is off by default.
+  pub scanline_render: bool,
+  /// Emulate the DMG OAM corruption bug, see `oam_bug`. Off by default since
+  /// getting it slightly wrong breaks games that never relied on it.
+  pub oam_bug_enabled: bool,
 
   // Screen to draw to
   screen: Option<Rc<RefCell<Screen>>>,
   // interrupt controller handle
   ic: Option<Rc<RefCell<Interrupts>>>,
+  // hooks for hosts embedding the core
+  line_callback: Option<LineCallback>,
+  frame_callback: Option<FrameCallback>,
 
   // current screen position we are drawing
   pos: Pos,
+  // dot within the current scanline
+  dot: u32,
+  // length of mode 3 for the current scanline
+  mode3_dots: u32,
+  // SCX fine scroll latched at the start of mode 3
+  fine_scroll: u8,
+  // leading pixels left to throw away for the fine scroll
+  discard: u8,
+  // the current line was already drawn by the scanline renderer
+  line_rendered: bool,
+  // dots into the current blank frame while the lcd is off
+  off_dots: u32,
+  // the first frame after the lcd turns on is not shown
+  warming_up: bool,
 }
 
 impl Ppu {
   pub fn new() -> Ppu {
-    // start in rendering mode
+    // start scanning oam for the first line
     let mut stat: Status = 0.into();
-    stat.ppu_mode = PpuMode::Rendering;
+    stat.ppu_mode = PpuMode::OamScan;
 
     Ppu {
-      vram: vec![0; VRAM_SIZE],
+      vram: vec![0; VRAM_SIZE * VRAM_BANKS],
+      vbk: 0,
       oam: vec![0; OAM_SIZE],
       oam_cache: Vec::new(),
       lcdc: 0.into(),
@@ -288,11 +544,142 @@ impl Ppu {
       wy: 0,
       wx: 0,
       wstart: false,
+      window_line: 0,
+      window_drawn: false,
       palette: PALETTE_GRAY,
+      bg_colors: [PALETTE_GRAY[0]; 4],
+      obj_colors: [[PALETTE_GRAY[0]; 4]; 2],
+      cgb_mode: false,
+      bg_cpal: CgbPalettes::new(),
+      obj_cpal: CgbPalettes::new(),
+      scanline_render: false,
+      oam_bug_enabled: false,
       screen: None,
       ic: None,
+      off_dots: 0,
+      warming_up: false,
+      line_callback: None,
+      frame_callback: None,
       pos: Pos { x: 0, y: 0 },
+      dot: 0,
+      mode3_dots: MODE3_MIN_DOTS,
+      fine_scroll: 0,
+      discard: 0,
+      line_rendered: false,
+    }
+  }
+
+  /// Return to the power-up state while keeping the connected components,
+  /// the color palette and the CGB mode. VRAM and OAM are only zeroed if
+  /// `clear_mem` is set.
+  pub fn reset(&mut self, clear_mem: bool) {
+    if clear_mem {
+      self.vram.fill(0);
+      self.oam.fill(0);
     }
+    self.vbk = 0;
+    self.oam_cache.clear();
+    self.lcdc = 0.into();
+    self.stat = 0.into();
+    self.stat.ppu_mode = PpuMode::OamScan;
+    self.ly = 0;
+    self.lyc = 0;
+    self.bgp = 0;
+    self.obp = [0; 2];
+    self.refresh_palettes();
+    self.scx = 0;
+    self.scy = 0;
+    self.wy = 0;
+    self.wx = 0;
+    self.wstart = false;
+    self.window_line = 0;
+    self.window_drawn = false;
+    self.bg_cpal = CgbPalettes::new();
+    self.obj_cpal = CgbPalettes::new();
+    self.pos = Pos { x: 0, y: 0 };
+    self.dot = 0;
+    self.mode3_dots = MODE3_MIN_DOTS;
+    self.fine_scroll = 0;
+    self.discard = 0;
+    self.line_rendered = false;
+    self.off_dots = 0;
+    self.warming_up = false;
+  }
+
+  /// DMG OAM corruption bug. During mode 2 the cpu putting an address in
+  /// $FE00-$FEFF on the bus garbles the 8 byte row of OAM the ppu is scanning.
+  /// Writes there and INC/DEC of a 16-bit register holding such an address
+  /// both do it. The first word of the row becomes a mix of itself and the
+  /// row before, the other three words are copied from the row before. Row 0
+  /// is never hit. Only the write pattern is done, reads are not affected.
+  pub fn oam_bug(&mut self, addr: u16) {
+    if !self.oam_bug_enabled
+      || !self.lcdc.ppu_enabled
+      || self.stat.ppu_mode != PpuMode::OamScan
+      || !(OAM_START..=OAM_BUG_END).contains(&addr)
+    {
+      return;
+    }
+    // the scan reads one row every 4 dots
+    let row = (self.dot / 4) as usize * 8;
+    if row == 0 || row >= OAM_SIZE {
+      return;
+    }
+    let word = |i: usize| u16::from_le_bytes([self.oam[i], self.oam[i + 1]]);
+    let (a, b, c) = (word(row), word(row - 8), word(row - 4));
+    let first = ((a ^ c) & (b ^ c)) ^ c;
+    self.oam[row..row + 2].copy_from_slice(&first.to_le_bytes());
+    self.oam.copy_within(row - 6..row, row + 2);
+  }
+
+  /// One whole 8K bank of vram, as the debug viewers show it
+  pub fn vram_bank(&self, bank: usize) -> &[u8] {
+    &self.vram[bank * VRAM_SIZE..(bank + 1) * VRAM_SIZE]
+  }
+
+  /// The four dmg shades, lightest first
+  pub fn palette(&self) -> &[screen::Color; 4] {
+    &self.palette
+  }
+
+  /// Replace all of the dmg shades. Takes effect on the next pixel drawn.
+  pub fn set_palette(&mut self, palette: [screen::Color; 4]) {
+    self.palette = palette;
+    self.refresh_palettes();
+  }
+
+  /// Change one of the dmg shades. Takes effect on the next pixel drawn.
+  pub fn set_palette_color(&mut self, index: usize, rgb: [u8; 3]) {
+    self.palette[index] = screen::Color::from_rgb8(rgb);
+    self.refresh_palettes();
+  }
+
+  pub fn bgp(&self) -> u8 {
+    self.bgp
+  }
+
+  pub fn set_bgp(&mut self, bgp: u8) {
+    self.bgp = bgp;
+    self.bg_colors = self.resolve_palette(bgp);
+  }
+
+  pub fn obp(&self, index: usize) -> u8 {
+    self.obp[index]
+  }
+
+  pub fn set_obp(&mut self, index: usize, obp: u8) {
+    self.obp[index] = obp;
+    self.obj_colors[index] = self.resolve_palette(obp);
+  }
+
+  /// Colors each color index maps to through a BGP/OBP register value
+  fn resolve_palette(&self, reg: u8) -> [screen::Color; 4] {
+    palette_shades(reg).map(|shade| self.palette[shade as usize])
+  }
+
+  fn refresh_palettes(&mut self) {
+    self.bg_colors = self.resolve_palette(self.bgp);
+    self.obj_colors = self.obp.map(|obp| self.resolve_palette(obp));
   }
 
   pub fn connect_screen(&mut self, screen: Rc<RefCell<Screen>>) -> GbResult<()> {
@@ -312,7 +699,30 @@ impl Ppu {
     Ok(())
   }
 
+  /// Call `callback` with LY at the end of every scanline, vblank lines
+  /// included. The ppu is borrowed while it runs.
+  pub fn set_line_callback(&mut self, callback: impl FnMut(u8) + 'static) {
+    self.line_callback = Some(Box::new(callback));
+  }
+
+  /// Call `callback` with the framebuffer each time a frame finishes. The ppu
+  /// and screen are borrowed while it runs.
+  pub fn set_frame_callback(&mut self, callback: impl FnMut(&[screen::Color]) + 'static) {
+    self.frame_callback = Some(Box::new(callback));
+  }
+
+  pub fn clear_callbacks(&mut self) {
+    self.line_callback = None;
+    self.frame_callback = None;
+  }
+
   pub fn step(&mut self, cycle_budget: u32) -> GbResult<bool> {
+    if !self.lcdc.ppu_enabled {
+      return Ok(self.step_off(cycle_budget));
+    }
+    if self.scanline_render {
+      return self.step_scanline(cycle_budget);
+    }
     let mut should_render = false;
     for _ in 0..cycle_budget {
       should_render = should_render | self.step_one()?;
@@ -320,64 +730,215 @@ impl Ppu {
     Ok(should_render)
   }
 
-  fn step_one(&mut self) -> GbResult<bool> {
-    // only draw when we need to
-    if self.stat.ppu_mode == PpuMode::Rendering {
-      assert!(self.pos.y < VBLANK_START);
-      assert!(self.pos.x < HBLANK_START);
-      // our pixel coordinate needs to be adjusted for scrolling
-      let scrolled_pos = self.pos_with_scroll();
-      trace!("Adjusted Pos: {:?}", scrolled_pos);
-
-      // position used in bg depends on if we are drawing the window or not
-      let draw_win = self.lcdc.win_enabled && self.wstart && self.pos.x as u8 + 7 >= self.wx;
-      let pos = if draw_win {
-        let y = self.pos.y - self.wy as u32;
-        let x = (self.pos.x + 7) - self.wx as u32;
-        Pos { x, y }
-      } else {
-        scrolled_pos
+  /// Fast path for `step`. Draws the whole line at the start of mode 3 and
+  /// only steps dot by dot where a mode change or interrupt can happen.
+  fn step_scanline(&mut self, cycle_budget: u32) -> GbResult<bool> {
+    let mut is_new_frame = false;
+    let mut left = cycle_budget;
+    while left > 0 {
+      if self.stat.ppu_mode == PpuMode::Rendering && !self.line_rendered && !self.warming_up {
+        self.render_line();
+      }
+      // the dot that ends the current mode goes through update_pos
+      let mode_end = match self.stat.ppu_mode {
+        PpuMode::OamScan => OAM_SCAN_DOTS,
+        PpuMode::HBlank if self.dot < OAM_SCAN_DOTS => OAM_SCAN_DOTS,
+        PpuMode::Rendering => OAM_SCAN_DOTS + self.mode3_dots,
+        _ if self.pos.y == LINES_PER_FRAME - 1 && self.dot < LY_WRAP_DOT => LY_WRAP_DOT,
+        _ => DOTS_PER_LINE,
       };
+      let skip = mode_end.saturating_sub(self.dot + 1).min(left - 1);
+      self.skip_dots(skip);
+      is_new_frame |= self.update_pos()?;
+      left -= skip + 1;
+    }
+    Ok(is_new_frame)
+  }
+
+  /// Nothing is drawn with the lcd off, but blank frames still come out on
+  /// the usual schedule so the frontend keeps going
+  fn step_off(&mut self, dots: u32) -> bool {
+    self.off_dots += dots;
+    if self.off_dots < DOTS_PER_FRAME {
+      return false;
+    }
+    self.off_dots %= DOTS_PER_FRAME;
+    self.blank_screen();
+    self.finish_frame();
+    true
+  }
+
+  // only the interrupt selects are writable, mode and lyc flag are read-only
+  fn write_stat(&mut self, data: u8) {
+    let written = Status::from(data);
+    self.stat.mode0_int_select = written.mode0_int_select;
+    self.stat.mode1_int_select = written.mode1_int_select;
+    self.stat.mode2_int_select = written.mode2_int_select;
+    self.stat.lyc_int_select = written.lyc_int_select;
+  }
+
+  fn write_lcdc(&mut self, data: u8) -> GbResult<()> {
+    let was_on = self.lcdc.ppu_enabled;
+    self.lcdc = data.into();
+    match (was_on, self.lcdc.ppu_enabled) {
+      (true, false) => {
+        // LY and the mode read 0 until the lcd is back on
+        self.ly = 0;
+        self.pos = Pos { x: 0, y: 0 };
+        self.dot = 0;
+        self.stat.ppu_mode = PpuMode::HBlank;
+        self.wstart = false;
+        self.window_line = 0;
+        self.window_drawn = false;
+        self.off_dots = 0;
+        self.blank_screen();
+      }
+      (false, true) => {
+        // starts over from the top of a frame the ppu only warms up on
+        self.ly = 0;
+        self.pos = Pos { x: 0, y: 0 };
+        self.dot = LCD_ON_SKIPPED_DOTS;
+        self.stat.ppu_mode = PpuMode::HBlank;
+        self.oam_cache.clear();
+        self.warming_up = true;
+        self.compare_lyc()?;
+      }
+      _ => {}
+    }
+    Ok(())
+  }
+
+  /// What the lcd shows while it is off or warming up, the lightest shade
+  fn blank_screen(&mut self) {
+    let color = if self.cgb_mode {
+      PALETTE_GRAY[0]
+    } else {
+      self.palette[0]
+    };
+    if let Some(screen) = &self.screen {
+      screen.borrow_mut().clear(color);
+    }
+  }
+
+  fn finish_frame(&mut self) {
+    if let Some(callback) = &mut self.frame_callback {
+      callback(self.screen.lazy_dref().get_framebuffer());
+    }
+  }
+
+  /// Draw every visible pixel of the current line
+  fn render_line(&mut self) {
+    let x = self.pos.x;
+    for pixel_x in 0..SCREEN_WIDTH {
+      self.pos.x = pixel_x;
+      self.draw_pixel();
+    }
+    self.pos.x = x;
+    self.line_rendered = true;
+  }
+
+  /// Advance through dots that can not change the mode or raise interrupts
+  fn skip_dots(&mut self, dots: u32) {
+    if dots == 0 {
+      return;
+    }
+    self.dot += dots;
+    if self.stat.ppu_mode == PpuMode::Rendering {
+      let discarded = dots.min(self.discard as u32);
+      self.discard -= discarded as u8;
+      self.pos.x += dots - discarded;
+    }
+    if self.wy == self.ly {
+      self.wstart = true;
+    }
+  }
+
+  fn step_one(&mut self) -> GbResult<bool> {
+    // only draw when we need to. Mode 3 may run longer than the visible line
+    // while the fetcher is stalled.
+    let discarding = self.discard > 0;
+    if self.stat.ppu_mode == PpuMode::Rendering
+      && !discarding
+      && self.pos.x < SCREEN_WIDTH
+      && !self.warming_up
+    {
+      self.draw_pixel();
+    }
+
+    // update position
+    self.update_pos()
+  }
+
+  /// Draw the pixel at the current position
+  fn draw_pixel(&mut self) {
+    // a malformed snapshot could leave us outside the visible screen
+    if self.pos.y >= VBLANK_START || self.pos.x >= SCREEN_WIDTH {
+      return;
+    }
+    // our pixel coordinate needs to be adjusted for scrolling
+    let scrolled_pos = self.pos_with_scroll();
+    trace!("Adjusted Pos: {:?}", scrolled_pos);
 
-      // Render background
-      // figure out the tile map entry we are on in the tile map table
-      // use the tile map entry to read the tile data in the tile data table
-      // use the tile data entry to figure out the color of the pixel
-      let tile_data_index = if draw_win {
-        self.get_win_tile_map_entry(pos)
+    // position used in bg depends on if we are drawing the window or not
+    let draw_win = self.lcdc.win_enabled
+      && self.wstart
+      && self.wx < WX_HIDDEN
+      && self.pos.x + 7 >= self.wx as u32;
+    let pos = if draw_win {
+      self.window_drawn = true;
+      // the window keeps its own line count, so lines where it was hidden
+      // don't skip rows of the window
+      let y = self.window_line as u32;
+      // at WX=0 the window starts before the fine scroll pixels are thrown
+      // away, so they come out of the window instead of the bg
+      let clip = if self.wx == 0 {
+        self.fine_scroll as u32
       } else {
-        self.get_bg_tile_map_entry(pos)
+        0
       };
-      // next we get the tile data info
-      let tile_data = self.get_tile_data_location(tile_data_index, pos);
-      // now transform that tile data into a color
-      let mut pixel_color = self.get_color_from_tile_data(tile_data, pos);
-
-      // find obj attributes from cache
-      let objs = self.get_available_cached_objs();
-      for attr in objs {
-        // get object color
-        let obj_color = self.get_color_from_attribute(&attr);
-
-        // check if object should be drawn over background
-        assert!(!attr.flags.low_priority);
-        if obj_color.is_some() && !attr.flags.low_priority {
-          pixel_color = obj_color.unwrap();
-        }
+      let x = (self.pos.x + 7 + clip - self.wx as u32) & 0xff;
+      Pos { x, y }
+    } else {
+      scrolled_pos
+    };
+
+    // Render background
+    // figure out the tile map entry we are on in the tile map table
+    // use the tile map entry to read the tile data in the tile data table
+    // use the tile data entry to figure out the color of the pixel
+    let tile_data_index = if draw_win {
+      self.get_win_tile_map_entry(pos)
+    } else {
+      self.get_bg_tile_map_entry(pos)
+    };
+    // next we get the tile data info
+    let tile_data = self.get_tile_data_location(tile_data_index, pos);
+    // now transform that tile data into a color
+    let bg_index = self.get_bg_color_index(tile_data, pos);
+    let mut pixel_color = self.get_bg_color(bg_index);
+
+    // find obj attributes from cache. The last opaque object wins.
+    let mut obj_pixel = None;
+    for attr in self.get_available_cached_objs() {
+      if let Some(obj_color) = self.get_color_from_attribute(&attr) {
+        obj_pixel = Some((obj_color, attr.flags.low_priority));
       }
+    }
 
-      // draw pixel
-      self.screen.lazy_dref_mut().set_pixel(self.pos, pixel_color);
+    // low priority objects only show over bg color 0
+    if let Some((obj_color, low_priority)) = obj_pixel {
+      if !low_priority || bg_index == 0 {
+        pixel_color = obj_color;
+      }
     }
 
-    // update position
-    let is_new_frame = self.update_pos();
-    Ok(is_new_frame)
+    // draw pixel
+    self.screen.lazy_dref_mut().set_pixel(self.pos, pixel_color);
   }
 
   pub fn read(&self, addr: u16) -> GbResult<u8> {
     if (PPU_START..=PPU_END).contains(&addr) {
-      Ok(self.vram[(addr - PPU_START) as usize])
+      Ok(self.vram[self.vram_index(addr)])
     } else if (OAM_START..=OAM_END).contains(&addr) {
       Ok(self.oam[(addr - OAM_START) as usize])
     } else {
@@ -389,7 +950,8 @@ impl Ppu {
     // TODO: ignore writes in certain modes
 
     if (PPU_START..=PPU_END).contains(&addr) {
-      self.vram[(addr - PPU_START) as usize] = data;
+      let index = self.vram_index(addr);
+      self.vram[index] = data;
     } else if (OAM_START..=OAM_END).contains(&addr) {
       self.oam[(addr - OAM_START) as usize] = data;
     } else {
@@ -411,6 +973,13 @@ impl Ppu {
       OBP1_ADDR => Ok(self.obp[1]),
       WY_ADDR => Ok(self.wy),
       WX_ADDR => Ok(self.wx),
+      // only CGB mode has a second vram bank
+      VBK_ADDR if !self.cgb_mode => Ok(0xff),
+      VBK_ADDR => Ok(self.vbk),
+      BCPS_ADDR => Ok(self.bg_cpal.read_spec()),
+      BCPD_ADDR => Ok(self.bg_cpal.read_data()),
+      OCPS_ADDR => Ok(self.obj_cpal.read_spec()),
+      OCPD_ADDR => Ok(self.obj_cpal.read_data()),
       _ => {
         warn!("Read from unsupported IO Reg: ${:04X}. Returning 0", addr);
         Ok(0)
@@ -420,16 +989,22 @@ impl Ppu {
 
   pub fn io_write(&mut self, addr: u16, data: u8) -> GbResult<()> {
     match addr {
-      LCDC_ADDR => self.lcdc = data.into(),
-      STAT_ADDR => self.stat = data.into(),
+      LCDC_ADDR => self.write_lcdc(data)?,
+      STAT_ADDR => self.write_stat(data),
       LYC_ADDR => self.lyc = data,
-      BGP_ADDR => self.bgp = data,
+      BGP_ADDR => self.set_bgp(data),
       SCY_ADDR => self.scy = data,
       SCX_ADDR => self.scx = data,
-      OBP0_ADDR => self.obp[0] = data,
-      OBP1_ADDR => self.obp[1] = data,
+      OBP0_ADDR => self.set_obp(0, data),
+      OBP1_ADDR => self.set_obp(1, data),
       WY_ADDR => self.wy = data,
       WX_ADDR => self.wx = data,
+      VBK_ADDR if !self.cgb_mode => {}
+      VBK_ADDR => self.vbk = data & 0x1,
+      BCPS_ADDR => self.bg_cpal.write_spec(data),
+      BCPD_ADDR => self.bg_cpal.write_data(data),
+      OCPS_ADDR => self.obj_cpal.write_spec(data),
+      OCPD_ADDR => self.obj_cpal.write_data(data),
       _ => warn!(
         "Write to unsupported IO Reg: [{:02X}] -> ${:04X}",
         data, addr
@@ -438,6 +1013,18 @@ impl Ppu {
     Ok(())
   }
 
+  /// Index into vram for a cpu access in the currently selected bank
+  fn vram_index(&self, addr: u16) -> usize {
+    self.vbk as usize * VRAM_SIZE + (addr - PPU_START) as usize
+  }
+
+  /// Read a byte from vram bank 0 for rendering. The index is masked into
+  /// the bank so bad tile indices or object attributes read garbage instead of
+  /// panicking.
+  fn vram_byte(&self, index: usize) -> u8 {
+    self.vram[index & (VRAM_SIZE - 1)]
+  }
+
   /// Gets the tile map entry using the current pixel positioning we are
   /// rendering
   fn get_bg_tile_map_entry(&self, pos: screen::Pos) -> u8 {
@@ -451,7 +1038,7 @@ impl Ppu {
     } else {
       TILE_MAP_START_LO
     };
-    self.vram[(map_start + map_index) as usize]
+    self.vram_byte((map_start + map_index) as usize)
   }
 
   /// Gets the tile map entry using the current pixel positioning we are
@@ -467,7 +1054,7 @@ impl Ppu {
     } else {
       TILE_MAP_START_LO
     };
-    self.vram[(map_start + map_index) as usize]
+    self.vram_byte((map_start + map_index) as usize)
   }
 
   /// Get the vram offset for the tile that matches the given `index`
@@ -478,9 +1065,7 @@ impl Ppu {
       // indexing using this mode requires using a signed index since we can index
       // backwards
       let signed_index = index as i8;
-      let signed_start = TILE_DATA_START_HI as i32 + (signed_index as i32 * TILE_DATA_SIZE as i32);
-      assert!(signed_start >= 0);
-      signed_start as u16
+      TILE_DATA_START_HI.wrapping_add_signed(signed_index as i16 * TILE_DATA_SIZE as i16)
     };
     // use the y position to figure out which row of the tile we are on
     let fine_y = scrolled_pos.y as u16 % 8;
@@ -488,27 +1073,37 @@ impl Ppu {
     location_start + (2 * fine_y)
   }
 
-  /// Given a tile, construct the tile
-  fn get_color_from_tile_data(&self, tile_data_location: u16, scrolled_pos: Pos) -> screen::Color {
-    // let bit_x = 7 - self.pos.x % 8;
+  /// Given a tile, get the bg color index (0-3) of the pixel before palette
+  /// mapping
+  fn get_bg_color_index(&self, tile_data_location: u16, scrolled_pos: Pos) -> u8 {
     let bit_x = 7 - scrolled_pos.x % 8;
-    let lo_byte = self.vram[tile_data_location as usize];
-    let hi_byte = self.vram[tile_data_location as usize + 1];
-    let col_index = ((lo_byte >> bit_x) & 0x1) | (((hi_byte >> bit_x) & 0x1) << 1);
-    let palette_index = (self.bgp >> (col_index * 2)) & 0x3;
-    self.palette[palette_index as usize]
+    let lo_byte = self.vram_byte(tile_data_location as usize);
+    let hi_byte = self.vram_byte(tile_data_location as usize + 1);
+    ((lo_byte >> bit_x) & 0x1) | (((hi_byte >> bit_x) & 0x1) << 1)
+  }
+
+  /// Map a bg color index through the bg palette
+  fn get_bg_color(&self, col_index: u8) -> screen::Color {
+    if self.cgb_mode {
+      // TODO: use the palette from the bg map attributes in vram bank 1
+      return self.bg_cpal.color(0, col_index);
+    }
+    self.bg_colors[col_index as usize]
   }
 
   /// Given some object attribute data, get the pixel's color.
   fn get_color_from_attribute(&self, attribute: &ObjectAttribute) -> Option<screen::Color> {
-    let x_rel = (self.pos.x + 8) - attribute.x_pos as u32;
+    // objects are offset by 8 so they can be partially off the left edge
+    // the cache should only hold objects covering this pixel, but mask the
+    // offsets anyway so a stale entry can't index outside the tile
+    let x_rel = (self.pos.x + 8).wrapping_sub(attribute.x_pos as u32) & 0x7;
     let bit_x = if attribute.flags.flip_x {
       x_rel % 8
     } else {
       7 - (x_rel % 8)
     };
     let mut tile_data_location = attribute.tile_idx as usize * TILE_DATA_SIZE as usize;
-    let mut fine_y = ((self.pos.y + 16) as u8 - attribute.y_pos) as usize;
+    let mut fine_y = ((self.pos.y + 16) as u8).wrapping_sub(attribute.y_pos) as usize & 0xf;
     if attribute.flags.flip_y {
       // TODO: this doesn't seem totally right
       fine_y = 16 - fine_y;
@@ -516,86 +1111,180 @@ impl Ppu {
     tile_data_location += 2 * fine_y;
     let col_index = if fine_y < 8 {
       // first block
-      let lo_byte = self.vram[tile_data_location];
-      let hi_byte = self.vram[tile_data_location + 1];
+      let lo_byte = self.vram_byte(tile_data_location);
+      let hi_byte = self.vram_byte(tile_data_location + 1);
       ((lo_byte >> bit_x) & 0x1) | (((hi_byte >> bit_x) & 0x1) << 1)
     } else {
       // second block
-      let lo_byte = self.vram[tile_data_location + 2];
-      let hi_byte = self.vram[tile_data_location + 3];
+      let lo_byte = self.vram_byte(tile_data_location + 2);
+      let hi_byte = self.vram_byte(tile_data_location + 3);
       ((lo_byte >> bit_x) & 0x1) | (((hi_byte >> bit_x) & 0x1) << 1)
     };
-    let palette_index = (self.obp[attribute.flags.palette_idx as usize] >> (col_index * 2)) & 0x3;
     // color index of 0 is transparent
     if col_index == 0 {
       None
+    } else if self.cgb_mode {
+      Some(self.obj_cpal.color(attribute.flags.cgb_palette, col_index))
     } else {
-      Some(self.palette[palette_index as usize])
+      Some(self.obj_colors[attribute.flags.palette_idx as usize][col_index as usize])
     }
   }
 
   fn pos_with_scroll(&self) -> screen::Pos {
-    // self.pos
+    // the coarse scroll is read as tiles are fetched but the fine scroll is
+    // fixed for the line
+    let scx = (self.scx & !0x7) as u32 + self.fine_scroll as u32;
     Pos {
-      x: (self.pos.x + self.scx as u32) % 256,
+      x: (self.pos.x + scx) % 256,
       y: (self.pos.y + self.scy as u32) % 256,
     }
   }
 
-  fn update_pos(&mut self) -> bool {
+  /// Number of dots mode 3 takes for the current line. Grows with the objects
+  /// on the line and the SCX fine scroll that gets discarded.
+  fn mode3_duration(&self) -> u32 {
+    MODE3_MIN_DOTS + (self.scx % 8) as u32 + OBJ_PENALTY_DOTS * self.oam_cache.len() as u32
+  }
+
+  fn update_pos(&mut self) -> GbResult<bool> {
     // track if we finished a frame
     let mut is_new_frame = false;
-    // always advance x
-    self.pos.x += 1;
+    // always advance the dot
+    self.dot += 1;
 
-    if self.pos.x == HBLANK_START {
-      if self.stat.ppu_mode != PpuMode::VBlank {
-        self.stat.ppu_mode = PpuMode::HBlank;
+    match self.stat.ppu_mode {
+      PpuMode::OamScan if self.dot == OAM_SCAN_DOTS => {
+        // TODO: objects should be picked up over the course of the scan
+        self.fill_oam_cache();
+        self.start_rendering();
       }
-    }
-    if self.pos.x == HBLANK_END {
-      // reset x position and start rendering again if not in vblank
-      self.pos.x = 0;
-      if self.stat.ppu_mode != PpuMode::VBlank {
-        self.stat.ppu_mode = PpuMode::Rendering;
+      // only the line the lcd came on on is in mode 0 this early, it goes
+      // straight to mode 3 without any objects
+      PpuMode::HBlank if self.dot == OAM_SCAN_DOTS => self.start_rendering(),
+      PpuMode::Rendering => {
+        if self.discard > 0 {
+          self.discard -= 1;
+        } else {
+          self.pos.x += 1;
+        }
+        if self.dot == OAM_SCAN_DOTS + self.mode3_dots {
+          // HBlank takes up the rest of the line
+          self.stat.ppu_mode = PpuMode::HBlank;
+        }
       }
+      _ => {}
+    }
+
+    if self.pos.y == LINES_PER_FRAME - 1 && self.dot == LY_WRAP_DOT {
+      self.ly = 0;
+      self.compare_lyc()?;
     }
-    if self.pos.x == 0 {
+
+    if self.dot == DOTS_PER_LINE {
+      if let Some(callback) = &mut self.line_callback {
+        callback(self.pos.y as u8);
+      }
       // new row
+      self.dot = 0;
+      self.pos.x = 0;
       self.pos.y += 1;
+      if self.window_drawn {
+        self.window_line = self.window_line.wrapping_add(1);
+        self.window_drawn = false;
+      }
 
       if self.pos.y == VBLANK_START {
+        // this is the only place the interrupt is raised, so it fires once
+        // per frame on the first dot of line 144
         self.stat.ppu_mode = PpuMode::VBlank;
-        self.ic.lazy_dref_mut().raise(Interrupt::Vblank);
-      } else if self.pos.y == VBLANK_END {
+        self.ic.try_dref_mut()?.raise(Interrupt::Vblank);
+      } else if self.pos.y == LINES_PER_FRAME {
         // new frame
         is_new_frame = true;
+        if self.warming_up {
+          self.warming_up = false;
+          self.blank_screen();
+        }
+        self.finish_frame();
         self.wstart = false;
+        self.window_line = 0;
         self.pos.y = 0;
-        self.stat.ppu_mode = PpuMode::Rendering;
       }
       self.ly = self.pos.y as u8;
 
-      // TODO: maybe this needs to happen during an OAM scan period
-      if self.stat.ppu_mode != PpuMode::VBlank {
-        self.fill_oam_cache();
+      if self.stat.ppu_mode != PpuMode::VBlank || self.pos.y == 0 {
+        self.stat.ppu_mode = PpuMode::OamScan;
       }
 
-      // Update stat reg and trigger interrupt on lyc compare
-      self.stat.lyc_eq_ly = if self.ly == self.lyc {
-        if self.stat.lyc_int_select {
-          self.ic.lazy_dref_mut().raise(Interrupt::Lcd);
-        }
-        true
-      } else {
-        false
-      };
+      // LY has been 0 since early in the last line, line 0 was compared then
+      if self.pos.y != 0 {
+        self.compare_lyc()?;
+      }
     }
 
     if self.wy == self.ly {
       self.wstart = true;
     }
-    return is_new_frame;
+    return Ok(is_new_frame);
+  }
+
+  /// Enter mode 3 for the current line
+  fn start_rendering(&mut self) {
+    self.stat.ppu_mode = PpuMode::Rendering;
+    self.mode3_dots = self.mode3_duration();
+    self.pos.x = 0;
+    // the first SCX % 8 pixels fetched are thrown away
+    self.fine_scroll = self.scx % 8;
+    self.discard = self.fine_scroll;
+    self.line_rendered = false;
+  }
+
+  /// Update the stat reg and trigger the interrupt on an lyc match
+  fn compare_lyc(&mut self) -> GbResult<()> {
+    self.stat.lyc_eq_ly = self.ly == self.lyc;
+    if self.stat.lyc_eq_ly && self.stat.lyc_int_select {
+      self.ic.try_dref_mut()?.raise(Interrupt::Lcd);
+    }
+    Ok(())
+  }
+
+  /// Every object at least partly on screen, in OAM order
+  pub fn object_bounds(&self) -> Vec<ObjectBounds> {
+    let height = if self.lcdc.obj_size_large { 16 } else { 8 };
+    let mut bounds: Vec<ObjectBounds> = (0..OAM_SIZE / 4)
+      .filter_map(|index| {
+        let x = self.oam[index * 4 + 1] as i32 - 8;
+        let y = self.oam[index * 4] as i32 - 16;
+        let on_screen =
+          x > -8 && x < SCREEN_WIDTH as i32 && y > -(height as i32) && y < VBLANK_START as i32;
+        on_screen.then_some(ObjectBounds {
+          index,
+          x,
+          y,
+          height,
+          over_limit: false,
+        })
+      })
+      .collect();
+
+    // same selection as fill_oam_cache. Objects off the sides still count
+    // towards the limit.
+    for line in 0..VBLANK_START as i32 {
+      let mut selected = 0;
+      for index in 0..OAM_SIZE / 4 {
+        let y = self.oam[index * 4] as i32 - 16;
+        if !(y..y + height as i32).contains(&line) {
+          continue;
+        }
+        selected += 1;
+        if selected > 10 {
+          if let Some(b) = bounds.iter_mut().find(|b| b.index == index) {
+            b.over_limit = true;
+          }
+        }
+      }
+    }
+    bounds
   }
 
   fn fill_oam_cache(&mut self) {
@@ -604,21 +1293,23 @@ impl Ppu {
 
     let mut obj_idx = 0;
     let obj_height = if self.lcdc.obj_size_large { 16 } else { 8 };
+    // obj y is offset by 16 from top of screen. Objects at y = 0 or y >= 160
+    // can never overlap a visible line so they are hidden by this check too.
+    let line = self.ly as u32 + 16;
+    // the first 10 objects on this line in OAM order are selected, even if
+    // they end up off screen horizontally
     while obj_idx < OAM_SIZE && self.oam_cache.len() < 10 {
       // y position is index 0 so no need to add offsets
-      let obj_y = self.oam[obj_idx];
-      // object is hidden so no point to add to cache
-      if obj_y < 160 {
-        // obj y is offset by 16 from top of screen
-        if (obj_y..(obj_y + obj_height)).contains(&(self.ly + 16)) {
-          let obj_bytes = [
-            self.oam[obj_idx + 0],
-            self.oam[obj_idx + 1],
-            self.oam[obj_idx + 2],
-            self.oam[obj_idx + 3],
-          ];
-          self.oam_cache.push(ObjectAttribute::from(obj_bytes));
-        }
+      let obj_y = self.oam[obj_idx] as u32;
+      // use wider math so objects near the bottom don't wrap
+      if (obj_y..(obj_y + obj_height)).contains(&line) {
+        let obj_bytes = [
+          self.oam[obj_idx + 0],
+          self.oam[obj_idx + 1],
+          self.oam[obj_idx + 2],
+          self.oam[obj_idx + 3],
+        ];
+        self.oam_cache.push(ObjectAttribute::from(obj_bytes));
       }
       // obj attribute is 4 bytes
       obj_idx += 4;
@@ -628,26 +1319,973 @@ impl Ppu {
 
   // Gets all available cached objs which could be drawn at this x coord
   fn get_available_cached_objs(&self) -> Vec<ObjectAttribute> {
-    let mut objs: Vec<ObjectAttribute> = Vec::new();
-    for attribute in &self.oam_cache {
-      if (attribute.x_pos..(attribute.x_pos + 8)).contains(&(self.pos.x as u8 + 8)) {
-        objs.push(attribute.clone());
+    // the cache is in OAM order, so keep each object's position for tie breaks
+    let mut objs: Vec<(usize, ObjectAttribute)> = Vec::new();
+    for (oam_order, attribute) in self.oam_cache.iter().enumerate() {
+      // use wider math so objects near the right edge don't wrap
+      let obj_x = attribute.x_pos as u32;
+      if (obj_x..(obj_x + 8)).contains(&(self.pos.x + 8)) {
+        objs.push((oam_order, *attribute));
+      }
+    }
+    Self::sort_obj_attributes_by_rev_render_order(&mut objs, self.cgb_mode);
+    objs.into_iter().map(|(_, attribute)| attribute).collect()
+  }
+
+  // Sort the object attrs from lowest to highest priority so iterating over
+  // them in order will allow the highest priority color to overwrite the rest.
+  // On DMG a smaller x coord wins and ties go to the lower OAM index. On CGB
+  // only the OAM index matters.
+  fn sort_obj_attributes_by_rev_render_order(
+    objs: &mut Vec<(usize, ObjectAttribute)>,
+    cgb_mode: bool,
+  ) {
+    objs.sort_by_key(|(oam_order, attribute)| {
+      let x = if cgb_mode { 0 } else { attribute.x_pos };
+      std::cmp::Reverse((x, *oam_order))
+    });
+  }
+}
+
+impl Default for Ppu {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// The screen and interrupt controller handles, color palette and CGB mode
+/// are not part of the snapshot
+impl Snapshot for Ppu {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    writer.write_bytes(&self.vram);
+    writer.write_u8(self.vbk);
+    writer.write_bytes(&self.oam);
+    writer.write_u8(self.lcdc.into());
+    writer.write_u8(self.ly);
+    writer.write_u8(self.lyc);
+    writer.write_u8(self.stat.into());
+    writer.write_u8(self.bgp);
+    writer.write_bytes(&self.obp);
+    writer.write_u8(self.scx);
+    writer.write_u8(self.scy);
+    writer.write_u8(self.wy);
+    writer.write_u8(self.wx);
+    writer.write_bool(self.wstart);
+    writer.write_u8(self.window_line);
+    writer.write_bool(self.window_drawn);
+    self.bg_cpal.save(writer);
+    self.obj_cpal.save(writer);
+    writer.write_u32(self.pos.x);
+    writer.write_u32(self.pos.y);
+    writer.write_u32(self.dot);
+    writer.write_u32(self.mode3_dots);
+    writer.write_u8(self.fine_scroll);
+    writer.write_u8(self.discard);
+    let cache: Vec<u8> = self
+      .oam_cache
+      .iter()
+      .flat_map(|attr| <[u8; 4]>::from(*attr))
+      .collect();
+    writer.write_bytes(&cache);
+    writer.write_u32(self.off_dots);
+    writer.write_bool(self.warming_up);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    reader.read_bytes_into(&mut self.vram)?;
+    self.vbk = reader.read_u8()? & 0x1;
+    reader.read_bytes_into(&mut self.oam)?;
+    self.lcdc = reader.read_u8()?.into();
+    self.ly = reader.read_u8()?;
+    self.lyc = reader.read_u8()?;
+    self.stat = reader.read_u8()?.into();
+    self.bgp = reader.read_u8()?;
+    reader.read_bytes_into(&mut self.obp)?;
+    self.refresh_palettes();
+    self.scx = reader.read_u8()?;
+    self.scy = reader.read_u8()?;
+    self.wy = reader.read_u8()?;
+    self.wx = reader.read_u8()?;
+    self.wstart = reader.read_bool()?;
+    self.window_line = reader.read_u8()?;
+    self.window_drawn = reader.read_bool()?;
+    self.bg_cpal.load(reader)?;
+    self.obj_cpal.load(reader)?;
+    self.pos.x = reader.read_u32()?;
+    self.pos.y = reader.read_u32()?;
+    self.dot = reader.read_u32()?;
+    self.mode3_dots = reader.read_u32()?;
+    self.fine_scroll = reader.read_u8()?;
+    self.discard = reader.read_u8()?;
+    self.line_rendered = false;
+
+    let cache = reader.read_bytes()?;
+    if cache.len() % 4 != 0 || cache.len() > 4 * 10 {
+      error!("Bad OAM cache size in snapshot: {} bytes", cache.len());
+      return gb_err!(GbErrorType::BadValue);
+    }
+    self.oam_cache = cache
+      .chunks_exact(4)
+      .map(|bytes| ObjectAttribute::from([bytes[0], bytes[1], bytes[2], bytes[3]]))
+      .collect();
+    self.off_dots = reader.read_u32()? % DOTS_PER_FRAME;
+    self.warming_up = reader.read_bool()?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::bus::IF_ADDR;
+
+  /// Ppu with the lcd already on and warmed up, like a game that is running
+  fn connected_ppu() -> Ppu {
+    let mut ppu = Ppu::new();
+    ppu.lcdc.ppu_enabled = true;
+    ppu
+      .connect_screen(Rc::new(RefCell::new(Screen::headless())))
+      .unwrap();
+    ppu
+      .connect_ic(Rc::new(RefCell::new(Interrupts::new())))
+      .unwrap();
+    ppu
+  }
+
+  /// Step through the next full scanline and count the dots in each mode
+  fn line_mode_dots(ppu: &mut Ppu) -> [u32; 4] {
+    // finish the current line so the next one starts with a fresh oam scan
+    ppu.step(1).unwrap();
+    while ppu.dot != 0 {
+      ppu.step(1).unwrap();
+    }
+    let mut dots = [0; 4];
+    loop {
+      dots[ppu.stat.ppu_mode as usize] += 1;
+      ppu.step(1).unwrap();
+      if ppu.dot == 0 {
+        return dots;
       }
     }
-    Self::sort_obj_attributes_by_rev_render_order(&mut objs);
-    objs
   }
 
-  // Sort the object attrs by largest x coord. Larger X coord are lower priority
-  // so iterating over in order will allow to overwrite the color.
-  fn sort_obj_attributes_by_rev_render_order(objs: &mut Vec<ObjectAttribute>) {
-    // simple insertion sort since objs will be 10 or less in size
-    for min_start in 0..objs.len() {
-      for i in min_start..objs.len() {
-        if objs[i].x_pos < objs[min_start].x_pos {
-          objs.swap(i, min_start);
+  /// Render the first line and return its colors as palette indices
+  fn render_first_line(ppu: &mut Ppu) -> Vec<usize> {
+    for _ in 0..DOTS_PER_LINE {
+      ppu.step(1).unwrap();
+    }
+    let screen = ppu.screen.as_ref().unwrap().borrow();
+    screen.get_framebuffer()[..SCREEN_WIDTH as usize]
+      .iter()
+      .map(|color| {
+        PALETTE_GRAY
+          .iter()
+          .position(|c| c.to_rgb8() == color.to_rgb8())
+          .unwrap()
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_scanline_matches_per_dot() {
+    // random tiles and maps with scrolling, the window and objects on screen
+    let scene = |scanline_render| {
+      let mut ppu = connected_ppu();
+      ppu.scanline_render = scanline_render;
+      let mut seed: u32 = 0x1234_5678;
+      let mut rand = || {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (seed >> 16) as u8
+      };
+      for addr in 0x8000..0x9c00 {
+        ppu.write(addr, rand()).unwrap();
+      }
+      for obj in 0..20u16 {
+        let y = 16 + (obj * 7) as u8;
+        let x = 8 + (obj * 13) as u8;
+        for (offset, byte) in [y, x, rand(), rand()].into_iter().enumerate() {
+          ppu
+            .write(OAM_START + obj * 4 + offset as u16, byte)
+            .unwrap();
         }
       }
+      ppu.io_write(LCDC_ADDR, 0xb3).unwrap();
+      ppu.io_write(BGP_ADDR, 0xe4).unwrap();
+      ppu.io_write(OBP0_ADDR, 0xd2).unwrap();
+      ppu.io_write(OBP1_ADDR, 0x1b).unwrap();
+      ppu.io_write(SCX_ADDR, 5).unwrap();
+      ppu.io_write(SCY_ADDR, 3).unwrap();
+      ppu.io_write(WY_ADDR, 40).unwrap();
+      ppu.io_write(WX_ADDR, 50).unwrap();
+      ppu
+    };
+    let mut per_dot = scene(false);
+    let mut scanline = scene(true);
+
+    // uneven budgets like the cpu hands out, over two frames
+    let budgets = [4, 8, 12, 20, 24, 3, 456, 80];
+    let mut frames = 0;
+    for budget in budgets.iter().cycle() {
+      let new_frame = per_dot.step(*budget).unwrap();
+      assert_eq!(scanline.step(*budget).unwrap(), new_frame);
+      assert_eq!(scanline.dot, per_dot.dot);
+      assert_eq!(scanline.pos.x, per_dot.pos.x);
+      assert_eq!(scanline.ly, per_dot.ly);
+      assert!(scanline.stat.ppu_mode == per_dot.stat.ppu_mode);
+      if new_frame {
+        frames += 1;
+        if frames == 2 {
+          break;
+        }
+      }
+    }
+
+    let hash = |ppu: &Ppu| ppu.screen.as_ref().unwrap().borrow().framebuffer_hash();
+    assert_eq!(hash(&scanline), hash(&per_dot));
+    assert_eq!(
+      scanline.ic.lazy_dref().read(IF_ADDR).unwrap(),
+      per_dot.ic.lazy_dref().read(IF_ADDR).unwrap()
+    );
+  }
+
+  #[test]
+  fn test_palette_colors() {
+    let mut ppu = connected_ppu();
+    for reg in 0..=255u8 {
+      ppu.io_write(BGP_ADDR, reg).unwrap();
+      for col_index in 0..4u8 {
+        let shade = (reg >> (col_index * 2)) & 0x3;
+        assert_eq!(palette_shades(reg)[col_index as usize], shade);
+        assert_eq!(
+          ppu.get_bg_color(col_index).to_rgb8(),
+          PALETTE_GRAY[shade as usize].to_rgb8()
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn test_custom_palette_color() {
+    let mut ppu = connected_ppu();
+    ppu.set_bgp(0xe4);
+    ppu.set_palette_color(0, [0x12, 0x34, 0x56]);
+    assert_eq!(ppu.palette[0].to_rgb8(), [0x12, 0x34, 0x56]);
+    assert_eq!(ppu.palette[1].to_rgb8(), PALETTE_GRAY[1].to_rgb8());
+
+    // the blank bg is drawn with the new shade
+    for _ in 0..DOTS_PER_LINE {
+      ppu.step(1).unwrap();
     }
+    let screen = ppu.screen.as_ref().unwrap().borrow();
+    assert!(screen.get_framebuffer()[..SCREEN_WIDTH as usize]
+      .iter()
+      .all(|color| color.to_rgb8() == [0x12, 0x34, 0x56]));
+
+    // colors survive the trip through the palette file
+    let parsed = parse_palette(&format_palette(&ppu.palette)).unwrap();
+    for (a, b) in parsed.iter().zip(ppu.palette.iter()) {
+      assert_eq!(a.to_rgb8(), b.to_rgb8());
+    }
+    assert!(parse_palette("123456\nzz").is_none());
+  }
+
+  #[test]
+  fn test_scx_fine_scroll() {
+    // bg x = 8 and bg x = 255 are black, the rest white
+    let setup = |scx| {
+      let mut ppu = connected_ppu();
+      ppu.lcdc.win_and_bg_data_map_lo = true;
+      ppu.set_bgp(0xe4);
+      ppu.write(0x9801, 1).unwrap();
+      ppu.write(0x981f, 2).unwrap();
+      ppu.write(0x8010, 0x80).unwrap();
+      ppu.write(0x8011, 0x80).unwrap();
+      ppu.write(0x8020, 0x01).unwrap();
+      ppu.write(0x8021, 0x01).unwrap();
+      ppu.scx = scx;
+      render_first_line(&mut ppu)
+    };
+
+    let line = setup(3);
+    let black: Vec<usize> = (0..line.len()).filter(|x| line[*x] == 3).collect();
+    assert_eq!(black, vec![5]);
+
+    // wrapping around the end of the bg map
+    let line = setup(253);
+    let black: Vec<usize> = (0..line.len()).filter(|x| line[*x] == 3).collect();
+    assert_eq!(black, vec![2, 11]);
+  }
+
+  #[test]
+  fn test_low_priority_obj() {
+    let render = |obj_flags| {
+      let mut ppu = connected_ppu();
+      ppu.lcdc.win_and_bg_data_map_lo = true;
+      ppu.set_bgp(0xe4);
+      ppu.set_obp(0, 0xe4);
+      // bg tile 1 is color 2 on its left half and color 0 on its right half
+      ppu.write(0x8010, 0x00).unwrap();
+      ppu.write(0x8011, 0xf0).unwrap();
+      ppu.write(0x9800, 1).unwrap();
+      // object tile 3 is solid color 1
+      ppu.write(0x8030, 0xff).unwrap();
+      ppu.write(0x8031, 0x00).unwrap();
+      // object covering screen x 0-7 on line 0
+      ppu.oam[..4].copy_from_slice(&[16, 8, 3, obj_flags]);
+      render_first_line(&mut ppu)
+    };
+
+    // low priority is hidden behind bg colors 1-3
+    let line = render(0x80);
+    assert_eq!(line[..8], [2, 2, 2, 2, 1, 1, 1, 1]);
+
+    // normal priority is drawn over everything
+    let line = render(0x00);
+    assert_eq!(line[..8], [1; 8]);
+  }
+
+  #[test]
+  fn test_bad_obj_attributes_dont_panic() {
+    let mut ppu = connected_ppu();
+    ppu.set_obp(0, 0xe4);
+    // every tile row in bank 0 is solid color 3
+    ppu.vram[..VRAM_SIZE].fill(0xff);
+    let black = PALETTE_GRAY[3].to_rgb8();
+    for (x, y) in [(0, 0), (159, 143)] {
+      ppu.pos = Pos { x, y };
+      for (y_pos, x_pos, flags) in [(0, 0, 0x00), (255, 255, 0x40), (16, 200, 0x60)] {
+        // attributes that don't cover the current pixel, as a stale cache
+        // entry would
+        let attr = ObjectAttribute::from([y_pos, x_pos, 0xff, flags]);
+        let color = ppu.get_color_from_attribute(&attr).unwrap();
+        assert_eq!(color.to_rgb8(), black);
+      }
+    }
+
+    // window moved below the current line after it started drawing
+    ppu.pos = Pos { x: 0, y: 0 };
+    ppu.lcdc.win_enabled = true;
+    ppu.wstart = true;
+    ppu.wy = 100;
+    ppu.draw_pixel();
+    // off screen positions are ignored
+    ppu.pos = Pos {
+      x: 0,
+      y: VBLANK_START,
+    };
+    ppu.draw_pixel();
+  }
+
+  #[test]
+  fn test_window_line_counter() {
+    let mut ppu = connected_ppu();
+    // window tile 1 has rows of color 3, 1, 2 and then 1
+    for (row, (lo, hi)) in [(0xff, 0xff), (0xff, 0x00), (0x00, 0xff)]
+      .into_iter()
+      .chain([(0xff, 0x00); 5])
+      .enumerate()
+    {
+      ppu.write(0x8010 + row as u16 * 2, lo).unwrap();
+      ppu.write(0x8011 + row as u16 * 2, hi).unwrap();
+    }
+    for addr in 0x9c00..0xa000 {
+      ppu.write(addr, 1).unwrap();
+    }
+    // window on with its map at $9C00, bg uses the empty map at $9800
+    ppu.io_write(LCDC_ADDR, 0xf1).unwrap();
+    ppu.io_write(BGP_ADDR, 0xe4).unwrap();
+    ppu.io_write(WY_ADDR, 64).unwrap();
+    ppu.io_write(WX_ADDR, 7).unwrap();
+
+    let run_to_line = |ppu: &mut Ppu, line: u8| {
+      while ppu.ly != line || ppu.dot != 0 {
+        ppu.step(1).unwrap();
+      }
+    };
+    let line_colors = |ppu: &Ppu, line: usize| -> Vec<usize> {
+      let screen = ppu.screen.as_ref().unwrap().borrow();
+      let start = line * SCREEN_WIDTH as usize;
+      screen.get_framebuffer()[start..start + SCREEN_WIDTH as usize]
+        .iter()
+        .map(|color| {
+          PALETTE_GRAY
+            .iter()
+            .position(|c| c.to_rgb8() == color.to_rgb8())
+            .unwrap()
+        })
+        .collect()
+    };
+
+    // hide the window for a few lines after its first two rows
+    run_to_line(&mut ppu, 66);
+    ppu.io_write(LCDC_ADDR, 0xd1).unwrap();
+    run_to_line(&mut ppu, 70);
+    ppu.io_write(LCDC_ADDR, 0xf1).unwrap();
+    run_to_line(&mut ppu, 71);
+
+    assert_eq!(line_colors(&ppu, 63), [0; 160]);
+    // first visible row of the window is window line 0
+    assert_eq!(line_colors(&ppu, 64), [3; 160]);
+    assert_eq!(line_colors(&ppu, 65), [1; 160]);
+    assert_eq!(line_colors(&ppu, 66), [0; 160]);
+    // picks up at window line 2, not LY - WY
+    assert_eq!(line_colors(&ppu, 70), [2; 160]);
+    assert_eq!(ppu.window_line, 3);
+
+    // the count starts over with the next frame
+    run_to_line(&mut ppu, 0);
+    assert_eq!(ppu.window_line, 0);
+  }
+
+  #[test]
+  fn test_window_wx_edges() {
+    // first line with the window at WY=0 for a WX and SCX
+    let window_line = |wx: u8, scx: u8| -> Vec<usize> {
+      let mut ppu = connected_ppu();
+      // bg tile 0 is all color 2, window tile 1 has four pixels of color 1 and
+      // then four of color 0
+      for row in 0..8 {
+        ppu.write(0x8001 + row * 2, 0xff).unwrap();
+        ppu.write(0x8010 + row * 2, 0xf0).unwrap();
+      }
+      for addr in 0x9c00..0xa000 {
+        ppu.write(addr, 1).unwrap();
+      }
+      ppu.io_write(LCDC_ADDR, 0xf1).unwrap();
+      ppu.io_write(BGP_ADDR, 0xe4).unwrap();
+      ppu.io_write(WX_ADDR, wx).unwrap();
+      ppu.io_write(SCX_ADDR, scx).unwrap();
+      while ppu.ly != 1 {
+        ppu.step(1).unwrap();
+      }
+      let screen = ppu.screen.as_ref().unwrap().borrow();
+      screen.get_framebuffer()[..SCREEN_WIDTH as usize]
+        .iter()
+        .map(|color| {
+          PALETTE_GRAY
+            .iter()
+            .position(|c| c.to_rgb8() == color.to_rgb8())
+            .unwrap()
+        })
+        .collect()
+    };
+
+    // lined up with the left edge
+    assert_eq!(window_line(7, 0)[..8], [1, 1, 1, 1, 0, 0, 0, 0]);
+    // WX under 7 clips the left of the window
+    assert_eq!(window_line(3, 0)[..8], [0, 0, 0, 0, 1, 1, 1, 1]);
+    assert_eq!(window_line(0, 0)[..8], [0, 1, 1, 1, 1, 0, 0, 0]);
+    // and at 0 the fine scroll comes off the window too
+    assert_eq!(window_line(0, 3)[..8], [1, 1, 0, 0, 0, 0, 1, 1]);
+    // SCX does not move the window otherwise
+    assert_eq!(window_line(7, 3)[..8], [1, 1, 1, 1, 0, 0, 0, 0]);
+    // starts partway across the screen
+    assert_eq!(window_line(163, 0)[155..], [2, 1, 1, 1, 1]);
+    // hidden
+    assert_eq!(window_line(166, 0), [2; 160]);
+  }
+
+  #[test]
+  fn test_object_bounds() {
+    let mut ppu = connected_ppu();
+    // 12 objects on the same lines, the last two go over the limit
+    for index in 0..12 {
+      ppu.oam[index * 4..index * 4 + 2].copy_from_slice(&[32, 8 + index as u8 * 8]);
+    }
+    // off screen objects are left out
+    ppu.oam[12 * 4..12 * 4 + 2].copy_from_slice(&[0, 50]);
+    ppu.oam[13 * 4..13 * 4 + 2].copy_from_slice(&[50, 168]);
+    // partly off the top left
+    ppu.oam[14 * 4..14 * 4 + 2].copy_from_slice(&[9, 1]);
+
+    let bounds = ppu.object_bounds();
+    let indices: Vec<usize> = bounds.iter().map(|b| b.index).collect();
+    assert_eq!(indices, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 14]);
+    assert_eq!(
+      bounds[0],
+      ObjectBounds {
+        index: 0,
+        x: 0,
+        y: 16,
+        height: 8,
+        over_limit: false,
+      }
+    );
+    assert!(bounds[..10].iter().all(|b| !b.over_limit));
+    assert!(bounds[10].over_limit && bounds[11].over_limit);
+    assert_eq!((bounds[12].x, bounds[12].y), (-7, -7));
+
+    // tall objects reach 8 lines further down
+    ppu.lcdc.obj_size_large = true;
+    assert!(ppu.object_bounds().iter().all(|b| b.height == 16));
+    ppu.oam[12 * 4] = 1;
+    assert_eq!(ppu.object_bounds()[12].index, 12);
+  }
+
+  #[test]
+  fn test_obj_x_clipping() {
+    let render = |x_pos| {
+      let mut ppu = connected_ppu();
+      ppu.set_obp(0, 0xe4);
+      // object tile 1 is color 1 on its left half and color 2 on its right half
+      ppu.write(0x8010, 0xf0).unwrap();
+      ppu.write(0x8011, 0x0f).unwrap();
+      ppu.oam[..4].copy_from_slice(&[16, x_pos, 1, 0x00]);
+      render_first_line(&mut ppu)
+    };
+
+    // fully off the left edge
+    assert_eq!(render(0), [0; 160]);
+
+    // right half visible at the left edge
+    let line = render(4);
+    assert_eq!(line[..5], [2, 2, 2, 2, 0]);
+    assert_eq!(line[5..], [0; 155]);
+
+    // left half visible at the right edge
+    let line = render(164);
+    assert_eq!(line[..155], [0; 155]);
+    assert_eq!(line[155..], [0, 1, 1, 1, 1]);
+
+    // fully off the right edge, including positions that would wrap a u8
+    assert_eq!(render(168), [0; 160]);
+    assert_eq!(render(252), [0; 160]);
+  }
+
+  #[test]
+  fn test_obj_line_limit_and_priority() {
+    let mut ppu = connected_ppu();
+    ppu.set_obp(0, 0xe4);
+    // tiles 1, 2 and 3 are solid colors 1, 2 and 3
+    ppu.write(0x8010, 0xff).unwrap();
+    ppu.write(0x8021, 0xff).unwrap();
+    ppu.write(0x8030, 0xff).unwrap();
+    ppu.write(0x8031, 0xff).unwrap();
+
+    let mut objs = vec![
+      // hidden objects don't count towards the limit
+      [0, 8, 3, 0x00],
+      [160, 8, 3, 0x00],
+      // same x: the lower OAM index wins
+      [16, 20, 2, 0x00],
+      [16, 20, 3, 0x00],
+      // overlaps the first object on the right: the smaller x wins
+      [16, 24, 3, 0x00],
+    ];
+    for obj in 0..7 {
+      objs.push([16, 40 + obj * 10, 1, 0x00]);
+    }
+    // 11th object on the line is dropped
+    objs.push([16, 150, 3, 0x00]);
+    for (i, obj) in objs.iter().enumerate() {
+      ppu.oam[i * 4..i * 4 + 4].copy_from_slice(obj);
+    }
+
+    let line = render_first_line(&mut ppu);
+    assert_eq!(ppu.oam_cache.len(), 10);
+    assert_eq!(line[12..20], [2; 8]);
+    assert_eq!(line[20..24], [3; 4]);
+    assert_eq!(line[32..40], [1; 8]);
+    assert_eq!(line[142..150], [0; 8]);
+  }
+
+  #[test]
+  fn test_vblank_once_per_frame() {
+    let mut ppu = connected_ppu();
+    let ic = ppu.ic.clone().unwrap();
+    let mut vblanks = Vec::new();
+    let mut vblank_lines = Vec::new();
+    for dot in 0..(LINES_PER_FRAME * DOTS_PER_LINE) {
+      ppu.step(1).unwrap();
+      if ic.borrow().read(IF_ADDR).unwrap() & Interrupt::Vblank as u8 > 0 {
+        vblanks.push(dot + 1);
+        ic.borrow_mut().write(IF_ADDR, 0).unwrap();
+      }
+      if ppu.stat.ppu_mode == PpuMode::VBlank {
+        let ly = ppu.io_read(LY_ADDR).unwrap();
+        if vblank_lines.last() != Some(&ly) {
+          vblank_lines.push(ly);
+        }
+      }
+    }
+    assert_eq!(vblanks, vec![VBLANK_START * DOTS_PER_LINE]);
+    // LY already reads 0 for most of the last line
+    let mut expected: Vec<u8> = (144..=153).collect();
+    expected.push(0);
+    assert_eq!(vblank_lines, expected);
+    // back at the top of the next frame
+    assert_eq!(ppu.ly, 0);
+    assert!(ppu.stat.ppu_mode == PpuMode::OamScan);
+  }
+
+  #[test]
+  fn test_lcd_reenable_skips_frame() {
+    for scanline_render in [false, true] {
+      let mut ppu = connected_ppu();
+      ppu.scanline_render = scanline_render;
+      let ic = ppu.ic.clone().unwrap();
+      let frames = Rc::new(RefCell::new(0));
+      let frame_count = frames.clone();
+      ppu.set_frame_callback(move |_| *frame_count.borrow_mut() += 1);
+      // a black background everywhere
+      for addr in 0x8000..0x8010 {
+        ppu.write(addr, 0xff).unwrap();
+      }
+      ppu.io_write(BGP_ADDR, 0xe4).unwrap();
+      ppu.io_write(LCDC_ADDR, 0x91).unwrap();
+      ppu.step(DOTS_PER_LINE * 10).unwrap();
+      let screen = ppu.screen.clone().unwrap();
+      let black = PALETTE_GRAY[3].to_rgb8();
+      let all = |rgb: [u8; 3]| {
+        screen
+          .borrow()
+          .get_framebuffer()
+          .iter()
+          .all(|color| color.to_rgb8() == rgb)
+      };
+      let white = PALETTE_GRAY[0].to_rgb8();
+
+      // off blanks the screen straight away, LY and the mode read 0 and
+      // blank frames keep coming without a vblank
+      ppu.io_write(LCDC_ADDR, 0x11).unwrap();
+      assert!(all(white));
+      assert_eq!(ppu.io_read(LY_ADDR).unwrap(), 0);
+      assert_eq!(u8::from(ppu.stat) & 0x03, 0);
+      ic.borrow_mut().write(IF_ADDR, 0).unwrap();
+      *frames.borrow_mut() = 0;
+      assert!(ppu.step(DOTS_PER_FRAME).unwrap());
+      assert_eq!(*frames.borrow(), 1);
+      assert_eq!(ppu.io_read(LY_ADDR).unwrap(), 0);
+      assert_eq!(
+        ic.borrow().read(IF_ADDR).unwrap() & Interrupt::Vblank as u8,
+        0
+      );
+
+      // back on from line 0, the first frame runs but isn't shown. The
+      // first line skips the oam scan and is a little short.
+      ppu.io_write(LCDC_ADDR, 0x91).unwrap();
+      assert_eq!(u8::from(ppu.stat) & 0x03, 0);
+      ppu.step(OAM_SCAN_DOTS - LCD_ON_SKIPPED_DOTS - 1).unwrap();
+      assert!(ppu.stat.ppu_mode == PpuMode::HBlank);
+      ppu.step(1).unwrap();
+      assert!(ppu.stat.ppu_mode == PpuMode::Rendering);
+      ppu.step(DOTS_PER_LINE - OAM_SCAN_DOTS).unwrap();
+      assert_eq!(ppu.io_read(LY_ADDR).unwrap(), 1);
+      assert!(ppu.stat.ppu_mode == PpuMode::OamScan);
+      ppu.step(DOTS_PER_LINE * 9).unwrap();
+      assert_eq!(ppu.io_read(LY_ADDR).unwrap(), 10);
+      assert!(all(white));
+      assert!(ppu.step(DOTS_PER_FRAME - DOTS_PER_LINE * 10).unwrap());
+      assert!(ic.borrow().read(IF_ADDR).unwrap() & Interrupt::Vblank as u8 > 0);
+      assert!(all(white));
+
+      // the next one is drawn as usual
+      assert!(ppu.step(DOTS_PER_FRAME).unwrap());
+      assert!(all(black));
+    }
+  }
+
+  #[test]
+  fn test_lyc_zero_on_last_line() {
+    for scanline_render in [false, true] {
+      let mut ppu = connected_ppu();
+      ppu.scanline_render = scanline_render;
+      let ic = ppu.ic.clone().unwrap();
+      ppu.io_write(LCDC_ADDR, 0x91).unwrap();
+      ppu.io_write(LYC_ADDR, 0).unwrap();
+      ppu.stat.lyc_int_select = true;
+      // to the start of line 153, the first frame's line 0 match is old news
+      ppu.step((LINES_PER_FRAME - 1) * DOTS_PER_LINE).unwrap();
+      ic.borrow_mut().write(IF_ADDR, 0).unwrap();
+      assert_eq!(ppu.io_read(LY_ADDR).unwrap(), 153);
+      assert!(!ppu.stat.lyc_eq_ly);
+
+      ppu.step(LY_WRAP_DOT - 1).unwrap();
+      assert_eq!(ppu.io_read(LY_ADDR).unwrap(), 153);
+      assert_eq!(ic.borrow().read(IF_ADDR).unwrap() & Interrupt::Lcd as u8, 0);
+      // one m-cycle in LY wraps and LYC=0 fires while still in vblank
+      ppu.step(1).unwrap();
+      assert_eq!(ppu.io_read(LY_ADDR).unwrap(), 0);
+      assert!(ppu.stat.lyc_eq_ly);
+      assert!(ppu.stat.ppu_mode == PpuMode::VBlank);
+      assert!(ic.borrow().read(IF_ADDR).unwrap() & Interrupt::Lcd as u8 > 0);
+
+      // the real line 0 doesn't fire it again
+      ic.borrow_mut().write(IF_ADDR, 0).unwrap();
+      ppu.step(DOTS_PER_LINE - LY_WRAP_DOT).unwrap();
+      assert_eq!(ppu.pos.y, 0);
+      assert!(ppu.stat.ppu_mode == PpuMode::OamScan);
+      assert!(ppu.stat.lyc_eq_ly);
+      assert_eq!(ic.borrow().read(IF_ADDR).unwrap() & Interrupt::Lcd as u8, 0);
+
+      // and LYC=153 still matches for that first m-cycle
+      ppu.io_write(LYC_ADDR, 153).unwrap();
+      ppu.step((LINES_PER_FRAME - 1) * DOTS_PER_LINE).unwrap();
+      assert!(ppu.stat.lyc_eq_ly);
+      assert!(ic.borrow().read(IF_ADDR).unwrap() & Interrupt::Lcd as u8 > 0);
+    }
+  }
+
+  #[test]
+  fn test_oam_bug_pattern() {
+    let mut ppu = connected_ppu();
+    ppu.io_write(LCDC_ADDR, 0x91).unwrap();
+    for (i, byte) in ppu.oam.iter_mut().enumerate() {
+      *byte = (i * 37) as u8;
+    }
+    let before = ppu.oam.clone();
+    // scanning row 2
+    ppu.step(8).unwrap();
+
+    ppu.oam_bug(OAM_START);
+    assert_eq!(ppu.oam, before);
+
+    ppu.oam_bug_enabled = true;
+    ppu.oam_bug(0xff00);
+    assert_eq!(ppu.oam, before);
+    ppu.oam_bug(OAM_BUG_END);
+    let word = |i: usize| u16::from_le_bytes([before[i], before[i + 1]]);
+    let (a, b, c) = (word(16), word(8), word(12));
+    assert_eq!(ppu.oam[16..18], (((a ^ c) & (b ^ c)) ^ c).to_le_bytes());
+    assert_eq!(ppu.oam[18..24], before[10..16]);
+    assert_eq!(ppu.oam[..16], before[..16]);
+    assert_eq!(ppu.oam[24..], before[24..]);
+
+    // only while scanning oam
+    let corrupted = ppu.oam.clone();
+    ppu.step(OAM_SCAN_DOTS).unwrap();
+    ppu.oam_bug(OAM_START);
+    assert_eq!(ppu.oam, corrupted);
+  }
+
+  #[test]
+  fn test_line_and_frame_callbacks() {
+    let lines = Rc::new(RefCell::new(Vec::new()));
+    let frames = Rc::new(RefCell::new(0));
+    for scanline_render in [false, true] {
+      let mut ppu = connected_ppu();
+      ppu.scanline_render = scanline_render;
+      ppu.io_write(LCDC_ADDR, 0x91).unwrap();
+      lines.borrow_mut().clear();
+      *frames.borrow_mut() = 0;
+      let line_log = lines.clone();
+      ppu.set_line_callback(move |ly| line_log.borrow_mut().push(ly));
+      let frame_count = frames.clone();
+      ppu.set_frame_callback(move |framebuffer| {
+        assert_eq!(framebuffer.len(), (SCREEN_WIDTH * SCREEN_HEIGHT) as usize);
+        *frame_count.borrow_mut() += 1;
+      });
+
+      for _ in 0..LINES_PER_FRAME {
+        ppu.step(DOTS_PER_LINE).unwrap();
+      }
+      assert_eq!(*lines.borrow(), (0..=153).collect::<Vec<u8>>());
+      assert_eq!(*frames.borrow(), 1);
+
+      ppu.clear_callbacks();
+      ppu.step(DOTS_PER_LINE).unwrap();
+      assert_eq!(lines.borrow().len(), 154);
+    }
+  }
+
+  #[test]
+  fn test_reset() {
+    let mut ppu = connected_ppu();
+    ppu.write(0x8000, 0xaa).unwrap();
+    ppu.oam[0] = 0x55;
+    ppu.io_write(LCDC_ADDR, 0x91).unwrap();
+    ppu.io_write(STAT_ADDR, 0x40).unwrap();
+    ppu.io_write(SCY_ADDR, 0x12).unwrap();
+    ppu.io_write(SCX_ADDR, 0x34).unwrap();
+    ppu.io_write(LYC_ADDR, 0x56).unwrap();
+    ppu.io_write(BGP_ADDR, 0xfc).unwrap();
+    ppu.io_write(WY_ADDR, 0x10).unwrap();
+    ppu.io_write(WX_ADDR, 0x07).unwrap();
+    ppu.cgb_mode = true;
+    ppu.io_write(VBK_ADDR, 1).unwrap();
+    for _ in 0..(DOTS_PER_LINE * 3 + 100) {
+      ppu.step(1).unwrap();
+    }
+
+    // memory is kept unless asked for
+    ppu.reset(false);
+    assert_eq!(ppu.vram[0], 0xaa);
+    assert_eq!(ppu.oam[0], 0x55);
+    for addr in [
+      LCDC_ADDR, SCY_ADDR, SCX_ADDR, LY_ADDR, LYC_ADDR, BGP_ADDR, WY_ADDR, WX_ADDR,
+    ] {
+      assert_eq!(ppu.io_read(addr).unwrap(), 0, "${:04X}", addr);
+    }
+    assert_eq!(u8::from(ppu.stat) & 0x7f, PpuMode::OamScan as u8);
+    assert_eq!(ppu.vbk, 0);
+    assert_eq!((ppu.pos.x, ppu.pos.y, ppu.dot), (0, 0, 0));
+
+    ppu.reset(true);
+    assert!(ppu.vram.iter().all(|b| *b == 0));
+    assert!(ppu.oam.iter().all(|b| *b == 0));
+
+    // still connected and stepping from the top of the frame once the lcd
+    // is back on
+    ppu.io_write(LCDC_ADDR, 0x80).unwrap();
+    ppu.step(DOTS_PER_LINE).unwrap();
+    assert_eq!(ppu.ly, 1);
+  }
+
+  #[test]
+  fn test_snapshot_round_trip() {
+    let scene = || {
+      let mut ppu = connected_ppu();
+      ppu.lcdc.win_and_bg_data_map_lo = true;
+      ppu.set_bgp(0xe4);
+      ppu.set_obp(0, 0xe4);
+      for i in 0..0x400 {
+        ppu.write(0x9800 + i, (i % 3) as u8).unwrap();
+      }
+      for i in 0..0x30 {
+        ppu.write(0x8000 + i, (i * 37) as u8).unwrap();
+      }
+      ppu.oam[..8].copy_from_slice(&[40, 30, 1, 0x00, 90, 100, 2, 0x20]);
+      ppu.scx = 3;
+      ppu
+    };
+    let frame_hash = |ppu: &Ppu| ppu.screen.as_ref().unwrap().borrow().framebuffer_hash();
+    let frame_dots = LINES_PER_FRAME * DOTS_PER_LINE;
+
+    let mut reference = scene();
+    reference.step(frame_dots).unwrap();
+
+    // snapshot in the middle of mode 3 part way down the screen
+    let mut ppu = scene();
+    let split = 60 * DOTS_PER_LINE + 123;
+    ppu.step(split).unwrap();
+    let mut writer = SnapshotWriter::new();
+    ppu.save(&mut writer);
+    let bytes = writer.into_bytes();
+
+    ppu.vram.fill(0xff);
+    ppu.oam.fill(0x10);
+    ppu.scx = 0x55;
+    ppu.step(DOTS_PER_LINE + 7).unwrap();
+
+    ppu.load(&mut SnapshotReader::new(&bytes)).unwrap();
+    ppu.step(frame_dots - split).unwrap();
+    assert_eq!(frame_hash(&ppu), frame_hash(&reference));
+
+    // truncated snapshots are rejected
+    let err = ppu
+      .load(&mut SnapshotReader::new(&bytes[..bytes.len() - 1]))
+      .unwrap_err();
+    assert_eq!(*err.error_type(), GbErrorType::OutOfBounds);
+  }
+
+  #[test]
+  fn test_mode3_duration() {
+    let mut ppu = connected_ppu();
+    let dots = line_mode_dots(&mut ppu);
+    let (hblank, oam_scan, rendering) = (
+      dots[PpuMode::HBlank as usize],
+      dots[PpuMode::OamScan as usize],
+      dots[PpuMode::Rendering as usize],
+    );
+    assert_eq!(oam_scan, OAM_SCAN_DOTS);
+    assert_eq!(rendering, MODE3_MIN_DOTS);
+    assert_eq!(hblank + oam_scan + rendering, DOTS_PER_LINE);
+
+    // 4 objects on the next line and some fine scroll
+    for obj in 0..4 {
+      ppu.oam[obj * 4] = 16;
+      ppu.oam[obj * 4 + 1] = 8 + obj as u8 * 20;
+    }
+    ppu.scx = 3;
+    let dots = line_mode_dots(&mut ppu);
+    assert_eq!(dots[PpuMode::OamScan as usize], OAM_SCAN_DOTS);
+    assert_eq!(
+      dots[PpuMode::Rendering as usize],
+      rendering + 3 + 4 * OBJ_PENALTY_DOTS
+    );
+    assert_eq!(
+      dots[PpuMode::HBlank as usize],
+      hblank - 3 - 4 * OBJ_PENALTY_DOTS
+    );
+  }
+
+  #[test]
+  fn test_stat_write_keeps_read_only_bits() {
+    let mut ppu = connected_ppu();
+    let expected = line_mode_dots(&mut ppu);
+    while ppu.stat.ppu_mode != PpuMode::Rendering {
+      ppu.step(1).unwrap();
+    }
+    ppu.lyc = ppu.ly;
+    ppu.stat.lyc_eq_ly = true;
+
+    ppu.io_write(STAT_ADDR, 0x40).unwrap();
+    assert!(ppu.stat.ppu_mode == PpuMode::Rendering);
+    assert!(ppu.stat.lyc_eq_ly);
+    assert!(ppu.stat.lyc_int_select);
+    assert_eq!(ppu.io_read(STAT_ADDR).unwrap() & 0x7f, 0x40 | 0x04 | 0x03);
+
+    // rendering isn't cut short
+    assert_eq!(line_mode_dots(&mut ppu), expected);
+  }
+
+  #[test]
+  fn test_vram_bank_switch() {
+    let mut ppu = Ppu::new();
+    // a DMG ignores VBK
+    ppu.io_write(VBK_ADDR, 0x01).unwrap();
+    assert_eq!(ppu.io_read(VBK_ADDR).unwrap(), 0xff);
+    assert_eq!(ppu.vbk, 0);
+
+    ppu.cgb_mode = true;
+    assert_eq!(ppu.io_read(VBK_ADDR).unwrap(), 0x00);
+    ppu.write(0x8010, 0x11).unwrap();
+    ppu.io_write(VBK_ADDR, 0x01).unwrap();
+    assert_eq!(ppu.io_read(VBK_ADDR).unwrap(), 0x01);
+    assert_eq!(ppu.read(0x8010).unwrap(), 0x00);
+    ppu.write(0x8010, 0x22).unwrap();
+    ppu.write(0x9fff, 0x33).unwrap();
+
+    // writes landed in their own banks
+    assert_eq!(ppu.vram[0x0010], 0x11);
+    assert_eq!(ppu.vram[VRAM_SIZE + 0x0010], 0x22);
+    assert_eq!(ppu.vram[2 * VRAM_SIZE - 1], 0x33);
+
+    // only bit 0 selects the bank
+    ppu.io_write(VBK_ADDR, 0xfe).unwrap();
+    assert_eq!(ppu.read(0x8010).unwrap(), 0x11);
+    assert_eq!(ppu.read(0x9fff).unwrap(), 0x00);
+  }
+
+  #[test]
+  fn test_cgb_palette_auto_increment() {
+    let mut ppu = Ppu::new();
+
+    // palette 1, color 2 with auto increment
+    ppu.io_write(BCPS_ADDR, 0x80 | 0x0c).unwrap();
+    assert_eq!(ppu.io_read(BCPS_ADDR).unwrap(), 0x8c);
+    // pure red then pure blue in RGB555
+    for byte in [0x1f, 0x00, 0x00, 0x7c] {
+      ppu.io_write(BCPD_ADDR, byte).unwrap();
+    }
+    assert_eq!(ppu.io_read(BCPS_ADDR).unwrap(), 0x90);
+    assert_eq!(ppu.bg_cpal.rgb555(1, 2), 0x001f);
+    assert_eq!(ppu.bg_cpal.rgb555(1, 3), 0x7c00);
+    let red = ppu.bg_cpal.color(1, 2);
+    assert_eq!((red.r, red.g, red.b), (1.0, 0.0, 0.0));
+
+    // read back without auto increment
+    ppu.io_write(BCPS_ADDR, 0x0d).unwrap();
+    assert_eq!(ppu.io_read(BCPD_ADDR).unwrap(), 0x00);
+    assert_eq!(ppu.io_read(BCPD_ADDR).unwrap(), 0x00);
+    ppu.io_write(BCPS_ADDR, 0x0f).unwrap();
+    assert_eq!(ppu.io_read(BCPD_ADDR).unwrap(), 0x7c);
+
+    // index wraps at the end of palette ram
+    ppu.io_write(OCPS_ADDR, 0x80 | 0x3f).unwrap();
+    ppu.io_write(OCPD_ADDR, 0x12).unwrap();
+    assert_eq!(ppu.io_read(OCPS_ADDR).unwrap(), 0x80);
+    assert_eq!(ppu.obj_cpal.rgb555(7, 3), 0x1200);
+    assert_eq!(ppu.bg_cpal.rgb555(7, 3), 0x0000);
   }
 }
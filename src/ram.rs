@@ -6,12 +6,67 @@
 //! power cycle.
 
 use log::{debug, info};
+use std::str::FromStr;
 
 use crate::{
   err::{GbError, GbErrorType, GbResult},
   gb_err,
+  snapshot::{Snapshot, SnapshotReader, SnapshotWriter},
 };
 
+/// What ram holds at power up. Real hardware comes up with junk that some
+/// games end up depending on.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum RamInit {
+  Zeros,
+  Ones,
+  /// Pseudo-random bytes, the same for the same seed
+  Random(u64),
+}
+
+impl RamInit {
+  /// Fill `buf` with the pattern. Random fills with a different `stream` get
+  /// different bytes from the same seed.
+  pub fn fill(self, buf: &mut [u8], stream: u64) {
+    match self {
+      RamInit::Zeros => buf.fill(0x00),
+      RamInit::Ones => buf.fill(0xff),
+      RamInit::Random(seed) => {
+        // splitmix64
+        let mut state = seed ^ stream.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        for chunk in buf.chunks_mut(8) {
+          state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+          let mut z = state;
+          z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+          z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+          z ^= z >> 31;
+          chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+        }
+      }
+    }
+  }
+}
+
+impl FromStr for RamInit {
+  type Err = String;
+  /// `zeros`, `ff` or `random` with an optional seed like `random:42`
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "zeros" => Ok(RamInit::Zeros),
+      "ff" => Ok(RamInit::Ones),
+      "random" => Ok(RamInit::Random(0)),
+      other => match other.strip_prefix("random:").map(str::parse::<u64>) {
+        Some(Ok(seed)) => Ok(RamInit::Random(seed)),
+        _ => Err(format!("Unknown ram init pattern: {}", s)),
+      },
+    }
+  }
+}
+
+// random fill streams, so hram and each wram bank get different bytes
+const HRAM_STREAM: u64 = 0;
+const WRAM_STREAM: u64 = 1;
+
 pub struct Ram {
   pub data: Vec<u8>,
 }
@@ -24,6 +79,13 @@ impl Ram {
     }
   }
 
+  /// High ram filled with a power up pattern
+  pub fn hram_with_init(size: u16, init: RamInit) -> Ram {
+    let mut ram = Ram::new(size);
+    init.fill(&mut ram.data, HRAM_STREAM);
+    ram
+  }
+
   pub fn read(&self, addr: u16) -> GbResult<u8> {
     Ok(self.data[addr as usize])
   }
@@ -42,6 +104,124 @@ impl Ram {
   }
 }
 
+// 4 KB wram banks
+const WRAM_BANK_SIZE: usize = 4 * 1024;
+const WRAM_NUM_BANKS: usize = 8;
+
+/// CGB working ram bank select register
+pub const SVBK_ADDR: u16 = 0xff70;
+
+/// Working ram. The upper 4 KB (0xD000-0xDFFF) is switchable between banks
+/// 1-7 through SVBK on the CGB.
+pub struct Wram {
+  banks: Vec<[u8; WRAM_BANK_SIZE]>,
+  svbk: u8,
+  /// Only CGB mode has SVBK, a DMG reads $FF, ignores writes and always
+  /// maps bank 1
+  pub cgb_mode: bool,
+}
+
+impl Wram {
+  pub fn new() -> Wram {
+    debug!(
+      "Creating wram with {} banks of {} bytes",
+      WRAM_NUM_BANKS, WRAM_BANK_SIZE
+    );
+    Wram {
+      banks: vec![[0u8; WRAM_BANK_SIZE]; WRAM_NUM_BANKS],
+      svbk: 1,
+      cgb_mode: false,
+    }
+  }
+
+  /// Working ram filled with a power up pattern
+  pub fn with_init(init: RamInit) -> Wram {
+    let mut wram = Wram::new();
+    for (bank, data) in wram.banks.iter_mut().enumerate() {
+      init.fill(data, WRAM_STREAM + bank as u64);
+    }
+    wram
+  }
+
+  /// Bank mapped into 0xD000-0xDFFF
+  pub fn bank(&self) -> usize {
+    if self.cgb_mode {
+      self.svbk as usize
+    } else {
+      1
+    }
+  }
+
+  /// Read relative to the start of wram
+  pub fn read(&self, addr: u16) -> GbResult<u8> {
+    let (bank, offset) = self.map(addr)?;
+    Ok(self.banks[bank][offset])
+  }
+
+  /// Write relative to the start of wram
+  pub fn write(&mut self, addr: u16, val: u8) -> GbResult<()> {
+    let (bank, offset) = self.map(addr)?;
+    self.banks[bank][offset] = val;
+    Ok(())
+  }
+
+  pub fn io_read(&self, addr: u16) -> GbResult<u8> {
+    match addr {
+      SVBK_ADDR if !self.cgb_mode => Ok(0xff),
+      SVBK_ADDR => Ok(self.svbk),
+      _ => gb_err!(GbErrorType::OutOfBounds),
+    }
+  }
+
+  pub fn io_write(&mut self, addr: u16, data: u8) -> GbResult<()> {
+    match addr {
+      SVBK_ADDR if !self.cgb_mode => {}
+      // bank 0 selects bank 1
+      SVBK_ADDR => self.svbk = (data & 0x7).max(1),
+      _ => return gb_err!(GbErrorType::OutOfBounds),
+    }
+    Ok(())
+  }
+
+  fn map(&self, addr: u16) -> GbResult<(usize, usize)> {
+    let addr = addr as usize;
+    match addr / WRAM_BANK_SIZE {
+      0 => Ok((0, addr)),
+      1 => Ok((self.bank(), addr - WRAM_BANK_SIZE)),
+      _ => gb_err!(GbErrorType::OutOfBounds),
+    }
+  }
+}
+
+impl Default for Wram {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Snapshot for Ram {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    writer.write_bytes(&self.data);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    reader.read_bytes_into(&mut self.data)
+  }
+}
+
+impl Snapshot for Wram {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    writer.write_banks(&self.banks);
+    writer.write_u8(self.svbk);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    reader.read_banks_into(&mut self.banks)?;
+    self.svbk = (reader.read_u8()? & 0x7).max(1);
+    Ok(())
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -55,4 +235,64 @@ mod tests {
       assert_eq!(val, i as u8);
     }
   }
+
+  #[test]
+  fn test_ram_init_patterns() {
+    let wram = Wram::with_init(RamInit::Ones);
+    assert!(wram.banks.iter().flatten().all(|byte| *byte == 0xff));
+    let hram = Ram::hram_with_init(127, RamInit::Ones);
+    assert!(hram.data.iter().all(|byte| *byte == 0xff));
+    let wram = Wram::with_init(RamInit::Zeros);
+    assert!(wram.banks.iter().flatten().all(|byte| *byte == 0x00));
+
+    // random is repeatable for a seed and not a flat fill
+    let wram = Wram::with_init(RamInit::Random(7));
+    assert!(wram.banks == Wram::with_init(RamInit::Random(7)).banks);
+    assert!(wram.banks != Wram::with_init(RamInit::Random(8)).banks);
+    assert!(wram.banks[0] != wram.banks[1]);
+    assert!(wram.banks[0].iter().any(|byte| *byte != wram.banks[0][0]));
+    let hram = Ram::hram_with_init(127, RamInit::Random(7));
+    assert_ne!(hram.data[..], wram.banks[0][..127]);
+
+    assert_eq!("FF".parse::<RamInit>(), Ok(RamInit::Ones));
+    assert_eq!("random".parse::<RamInit>(), Ok(RamInit::Random(0)));
+    assert_eq!("random:42".parse::<RamInit>(), Ok(RamInit::Random(42)));
+    assert!("random:x".parse::<RamInit>().is_err());
+    assert!("ones".parse::<RamInit>().is_err());
+  }
+
+  #[test]
+  fn test_wram_bank_switch() {
+    let mut wram = Wram::new();
+    wram.cgb_mode = true;
+    assert_eq!(wram.bank(), 1);
+    assert_eq!(wram.io_read(SVBK_ADDR).unwrap(), 0x01);
+
+    // write a marker to each bank
+    for bank in 1..8u8 {
+      wram.io_write(SVBK_ADDR, bank).unwrap();
+      wram.write(0x1000, 0xa0 | bank).unwrap();
+    }
+    for bank in 1..8u8 {
+      wram.io_write(SVBK_ADDR, bank).unwrap();
+      assert_eq!(wram.read(0x1000).unwrap(), 0xa0 | bank);
+    }
+
+    // bank 0 selects bank 1
+    wram.io_write(SVBK_ADDR, 0).unwrap();
+    assert_eq!(wram.bank(), 1);
+    assert_eq!(wram.read(0x1000).unwrap(), 0xa1);
+
+    // fixed bank is untouched by switching
+    wram.write(0x0000, 0x55).unwrap();
+    wram.io_write(SVBK_ADDR, 3).unwrap();
+    assert_eq!(wram.read(0x0000).unwrap(), 0x55);
+
+    // a DMG has no SVBK and only sees bank 1
+    wram.cgb_mode = false;
+    assert_eq!(wram.io_read(SVBK_ADDR).unwrap(), 0xff);
+    wram.io_write(SVBK_ADDR, 5).unwrap();
+    assert_eq!(wram.bank(), 1);
+    assert_eq!(wram.read(0x1000).unwrap(), 0xa1);
+  }
 }
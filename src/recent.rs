@@ -0,0 +1,95 @@
+//! List of recently loaded roms, kept in a file in the home directory.
+
+use crate::err::{GbError, GbErrorType, GbResult};
+use crate::gb_err;
+use crate::util::home_file;
+use log::error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// file the list is kept in, relative to the home directory
+const RECENT_ROMS_FILE: &str = ".gb_recent";
+
+/// Max number of roms remembered
+pub const MAX_RECENT_ROMS: usize = 8;
+
+pub struct RecentRoms {
+  // most recent first
+  paths: Vec<PathBuf>,
+  cap: usize,
+}
+
+impl RecentRoms {
+  pub fn new(cap: usize) -> RecentRoms {
+    RecentRoms {
+      paths: Vec::new(),
+      cap,
+    }
+  }
+
+  /// Read the saved list, or start empty if there is none
+  pub fn load() -> RecentRoms {
+    let mut recent = RecentRoms::new(MAX_RECENT_ROMS);
+    if let Ok(text) = fs::read_to_string(home_file(RECENT_ROMS_FILE)) {
+      // oldest first so the saved order is kept
+      for line in text.lines().rev().filter(|line| !line.is_empty()) {
+        recent.push(PathBuf::from(line));
+      }
+    }
+    recent
+  }
+
+  pub fn save(&self) -> GbResult<()> {
+    let path = home_file(RECENT_ROMS_FILE);
+    let text: String = self
+      .paths
+      .iter()
+      .map(|rom| format!("{}\n", rom.display()))
+      .collect();
+    if let Err(why) = fs::write(&path, text) {
+      error!("Failed to save recent roms to {}: {}", path.display(), why);
+      return gb_err!(GbErrorType::FileError);
+    }
+    Ok(())
+  }
+
+  /// Move a rom to the front of the list, dropping the oldest past the cap
+  pub fn push(&mut self, rom: PathBuf) {
+    self.paths.retain(|path| *path != rom);
+    self.paths.insert(0, rom);
+    self.paths.truncate(self.cap);
+  }
+
+  /// Forget a rom, like one that was moved or deleted
+  pub fn remove(&mut self, rom: &Path) {
+    self.paths.retain(|path| path != rom);
+  }
+
+  /// Roms with the most recent first
+  pub fn paths(&self) -> &[PathBuf] {
+    &self.paths
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_push_dedup_and_cap() {
+    let mut recent = RecentRoms::new(3);
+    for rom in ["a.gb", "b.gb", "c.gb", "b.gb"] {
+      recent.push(PathBuf::from(rom));
+    }
+    let names: Vec<_> = recent.paths().iter().map(|p| p.to_str().unwrap()).collect();
+    assert_eq!(names, ["b.gb", "c.gb", "a.gb"]);
+
+    recent.push(PathBuf::from("d.gb"));
+    let names: Vec<_> = recent.paths().iter().map(|p| p.to_str().unwrap()).collect();
+    assert_eq!(names, ["d.gb", "b.gb", "c.gb"]);
+
+    recent.remove(Path::new("b.gb"));
+    let names: Vec<_> = recent.paths().iter().map(|p| p.to_str().unwrap()).collect();
+    assert_eq!(names, ["d.gb", "c.gb"]);
+  }
+}
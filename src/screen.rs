@@ -1,6 +1,8 @@
 //! Screen for the gameboy emulator
 
+#[cfg(feature = "frontend")]
 use egui_wgpu::wgpu;
+#[cfg(feature = "frontend")]
 use egui_wgpu::wgpu::util::DeviceExt;
 
 pub const GB_RESOLUTION: Resolution = Resolution {
@@ -8,8 +10,6 @@ pub const GB_RESOLUTION: Resolution = Resolution {
   height: 144,
 };
 
-const NUM_PIXELS: usize = (GB_RESOLUTION.width * GB_RESOLUTION.height) as usize;
-
 const PIXEL_CLEAR: Color = Color {
   r: 0.1,
   g: 0.1,
@@ -18,12 +18,18 @@ const PIXEL_CLEAR: Color = Color {
 };
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Resolution {
   pub width: u32,
   pub height: u32,
 }
 
+impl Resolution {
+  fn num_pixels(&self) -> usize {
+    (self.width * self.height) as usize
+  }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Pos {
@@ -47,36 +53,59 @@ impl Color {
   }
 }
 
-pub struct Screen {
-  pixels: Vec<Color>,
+impl Color {
+  /// Convert from 8 bit rgb values
+  pub fn from_rgb8(rgb: [u8; 3]) -> Self {
+    Self::new(
+      rgb[0] as f32 / 255.0,
+      rgb[1] as f32 / 255.0,
+      rgb[2] as f32 / 255.0,
+    )
+  }
+
+  /// Convert to 8 bit rgb values
+  pub fn to_rgb8(self) -> [u8; 3] {
+    [
+      (self.r.clamp(0.0, 1.0) * 255.0).round() as u8,
+      (self.g.clamp(0.0, 1.0) * 255.0).round() as u8,
+      (self.b.clamp(0.0, 1.0) * 255.0).round() as u8,
+    ]
+  }
+}
+
+/// GPU resources used to draw the screen
+#[cfg(feature = "frontend")]
+struct ScreenGpu {
   pixels_bind_group: wgpu::BindGroup,
   pixels_bind_group_layout: wgpu::BindGroupLayout,
   pixels_buffer: wgpu::Buffer,
+  // resolution the buffers were made for
+  res: Resolution,
+}
+
+pub struct Screen {
+  pixels: Vec<Color>,
+  res: Resolution,
+  // None when running headless
+  #[cfg(feature = "frontend")]
+  gpu: Option<ScreenGpu>,
 }
 
 impl Screen {
-  pub fn new(device: &wgpu::Device) -> Self {
-    // set up initial pixels
-    let mut pixels = Vec::new();
-    for _ in 0..GB_RESOLUTION.height {
-      for _ in 0..GB_RESOLUTION.width {
-        pixels.push(PIXEL_CLEAR);
-      }
+  /// Create a screen without any GPU backing. Only the framebuffer is kept.
+  pub fn headless() -> Self {
+    Self {
+      pixels: vec![PIXEL_CLEAR; GB_RESOLUTION.num_pixels()],
+      res: GB_RESOLUTION,
+      #[cfg(feature = "frontend")]
+      gpu: None,
     }
+  }
 
-    // set up storage buffer to pass screen colors to gpu
-    let pixels_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-      label: Some("Pixels Storage Buffer"),
-      contents: bytemuck::cast_slice(&pixels.as_slice()),
-      usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-    });
-
-    // set up uniform buffer to pass gameboy screen resolution to gpu
-    let screen_res_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-      label: Some("Screen Resolution Uniform Buffer"),
-      contents: bytemuck::cast_slice(&[GB_RESOLUTION]),
-      usage: wgpu::BufferUsages::UNIFORM,
-    });
+  #[cfg(feature = "frontend")]
+  pub fn new(device: &wgpu::Device) -> Self {
+    // set up initial pixels
+    let pixels = vec![PIXEL_CLEAR; GB_RESOLUTION.num_pixels()];
 
     let pixels_bind_group_layout =
       device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -105,9 +134,46 @@ impl Screen {
         label: Some("pixels_bind_group_layout"),
       });
 
+    let (pixels_buffer, pixels_bind_group) =
+      Self::create_buffers(device, &pixels_bind_group_layout, &pixels, GB_RESOLUTION);
+
+    Self {
+      pixels,
+      res: GB_RESOLUTION,
+      gpu: Some(ScreenGpu {
+        pixels_bind_group,
+        pixels_bind_group_layout,
+        pixels_buffer,
+        res: GB_RESOLUTION,
+      }),
+    }
+  }
+
+  #[cfg(feature = "frontend")]
+  /// Create the pixel buffers for a resolution and bind them with `layout`
+  fn create_buffers(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    pixels: &[Color],
+    res: Resolution,
+  ) -> (wgpu::Buffer, wgpu::BindGroup) {
+    // set up storage buffer to pass screen colors to gpu
+    let pixels_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+      label: Some("Pixels Storage Buffer"),
+      contents: bytemuck::cast_slice(pixels),
+      usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+    });
+
+    // set up uniform buffer to pass gameboy screen resolution to gpu
+    let screen_res_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+      label: Some("Screen Resolution Uniform Buffer"),
+      contents: bytemuck::cast_slice(&[res]),
+      usage: wgpu::BufferUsages::UNIFORM,
+    });
+
     let pixels_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
       label: Some("pixels_bind_group"),
-      layout: &pixels_bind_group_layout,
+      layout,
       entries: &[
         wgpu::BindGroupEntry {
           binding: 0,
@@ -119,34 +185,149 @@ impl Screen {
         },
       ],
     });
+    (pixels_buffer, pixels_bind_group)
+  }
 
-    Self {
-      pixels,
-      pixels_bind_group,
-      pixels_bind_group_layout,
-      pixels_buffer,
-    }
+  #[cfg(feature = "frontend")]
+  fn gpu(&self) -> &ScreenGpu {
+    self.gpu.as_ref().expect("Screen has no GPU resources")
   }
 
+  #[cfg(feature = "frontend")]
   pub fn group_layout(&self) -> &wgpu::BindGroupLayout {
-    &self.pixels_bind_group_layout
+    &self.gpu().pixels_bind_group_layout
   }
 
+  #[cfg(feature = "frontend")]
   pub fn bind_group(&mut self) -> &wgpu::BindGroup {
-    &self.pixels_bind_group
+    &self.gpu().pixels_bind_group
+  }
+
+  #[cfg(feature = "frontend")]
+  /// Send the framebuffer to the gpu. The buffers are remade first if the
+  /// screen was resized.
+  pub fn write_buffer(&mut self, device: &wgpu::Device, queue: &mut wgpu::Queue) {
+    if let Some(gpu) = &mut self.gpu {
+      if gpu.res != self.res {
+        let (pixels_buffer, pixels_bind_group) = Self::create_buffers(
+          device,
+          &gpu.pixels_bind_group_layout,
+          &self.pixels,
+          self.res,
+        );
+        gpu.pixels_buffer = pixels_buffer;
+        gpu.pixels_bind_group = pixels_bind_group;
+        gpu.res = self.res;
+      }
+      queue.write_buffer(
+        &gpu.pixels_buffer,
+        0,
+        bytemuck::cast_slice(self.pixels.as_slice()),
+      );
+    }
+  }
+
+  /// Current contents of the framebuffer in row major order
+  pub fn get_framebuffer(&self) -> &[Color] {
+    &self.pixels
   }
 
-  pub fn write_buffer(&mut self, queue: &mut wgpu::Queue) {
-    queue.write_buffer(
-      &self.pixels_buffer,
-      0,
-      bytemuck::cast_slice(self.pixels.as_slice()),
-    );
+  /// Stable 64 bit FNV-1a hash of the framebuffer's 8 bit rgb values
+  pub fn framebuffer_hash(&self) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+    let mut hash = FNV_OFFSET;
+    for byte in self.pixels.iter().flat_map(|pixel| pixel.to_rgb8()) {
+      hash ^= byte as u64;
+      hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+  }
+
+  pub fn resolution(&self) -> Resolution {
+    self.res
+  }
+
+  /// Fill every pixel with one color
+  pub fn clear(&mut self, col: Color) {
+    self.pixels.fill(col);
+  }
+
+  /// Change the size of the framebuffer. Pixels that are on both the old and
+  /// new screen keep their color, new pixels are cleared.
+  pub fn resize(&mut self, res: Resolution) {
+    if res == self.res {
+      return;
+    }
+    let mut pixels = vec![PIXEL_CLEAR; res.num_pixels()];
+    let width = res.width.min(self.res.width) as usize;
+    for y in 0..res.height.min(self.res.height) as usize {
+      let old_row = y * self.res.width as usize;
+      let new_row = y * res.width as usize;
+      pixels[new_row..new_row + width].copy_from_slice(&self.pixels[old_row..old_row + width]);
+    }
+    self.pixels = pixels;
+    self.res = res;
   }
 
   pub fn set_pixel(&mut self, pos: Pos, col: Color) {
-    assert!(pos.x < GB_RESOLUTION.width);
-    assert!(pos.y < GB_RESOLUTION.height);
-    self.pixels[(pos.y * GB_RESOLUTION.width + pos.x) as usize] = col;
+    assert!(pos.x < self.res.width);
+    assert!(pos.y < self.res.height);
+    self.pixels[(pos.y * self.res.width + pos.x) as usize] = col;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_clear() {
+    let mut screen = Screen::headless();
+    screen.set_pixel(Pos { x: 3, y: 4 }, Color::new(1.0, 0.0, 0.0));
+    let white = Color::new(1.0, 1.0, 1.0);
+    screen.clear(white);
+    assert_eq!(screen.get_framebuffer().len(), GB_RESOLUTION.num_pixels());
+    assert!(screen
+      .get_framebuffer()
+      .iter()
+      .all(|pixel| pixel.to_rgb8() == white.to_rgb8()));
+  }
+
+  #[test]
+  fn test_resize_keeps_overlap() {
+    let mut screen = Screen::headless();
+    // each pixel's red and green are its position
+    for y in 0..GB_RESOLUTION.height {
+      for x in 0..GB_RESOLUTION.width {
+        screen.set_pixel(Pos { x, y }, Color::from_rgb8([x as u8, y as u8, 0]));
+      }
+    }
+
+    // wider and shorter
+    let res = Resolution {
+      width: 200,
+      height: 100,
+    };
+    screen.resize(res);
+    assert_eq!(screen.resolution(), res);
+    let pixels = screen.get_framebuffer();
+    assert_eq!(pixels.len(), 200 * 100);
+    for y in 0..100 {
+      for x in 0..200 {
+        let expected = if x < GB_RESOLUTION.width as usize {
+          [x as u8, y as u8, 0]
+        } else {
+          PIXEL_CLEAR.to_rgb8()
+        };
+        assert_eq!(pixels[y * 200 + x].to_rgb8(), expected, "({}, {})", x, y);
+      }
+    }
+
+    // and back, the cut off rows are gone
+    screen.resize(GB_RESOLUTION);
+    let pixels = screen.get_framebuffer();
+    assert_eq!(pixels[99 * 160 + 159].to_rgb8(), [159, 99, 0]);
+    assert_eq!(pixels[100 * 160].to_rgb8(), PIXEL_CLEAR.to_rgb8());
   }
 }
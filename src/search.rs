@@ -0,0 +1,306 @@
+//! Memory search for the debug ui. Finds a byte pattern or a value anywhere
+//! in the 64K address space, and narrows down addresses by how they change
+//! between scans to find things like a lives counter.
+
+/// Bytes to look for, from the search box. Several hex bytes like `3e 05`
+/// are a pattern. A single number is a value, `$`/`0x` for hex or decimal
+/// otherwise, stored little endian in as few bytes as it fits.
+pub fn parse_pattern(text: &str) -> Result<Vec<u8>, String> {
+  let tokens: Vec<&str> = text.split_whitespace().collect();
+  match tokens.as_slice() {
+    [] => Err(String::from("Nothing to search for")),
+    [value] => parse_value(value),
+    bytes => bytes
+      .iter()
+      .map(|byte| {
+        u8::from_str_radix(byte, 16).map_err(|_| format!("Bad byte in pattern: {}", byte))
+      })
+      .collect(),
+  }
+}
+
+fn parse_value(text: &str) -> Result<Vec<u8>, String> {
+  let hex = text.strip_prefix('$').or_else(|| text.strip_prefix("0x"));
+  let value = match hex {
+    Some(digits) => u32::from_str_radix(digits, 16),
+    None => text.parse(),
+  }
+  .map_err(|_| format!("Bad search value: {}", text))?;
+  let len = (4 - value.leading_zeros() as usize / 8).max(1);
+  Ok(value.to_le_bytes()[..len].to_vec())
+}
+
+/// Addresses where `pattern` starts. A match can't run past $FFFF.
+pub fn find<F>(pattern: &[u8], read: F) -> Vec<u16>
+where
+  F: Fn(u16) -> u8,
+{
+  if pattern.is_empty() {
+    return Vec::new();
+  }
+  snapshot(read)
+    .windows(pattern.len())
+    .enumerate()
+    .filter(|(_, window)| *window == pattern)
+    .map(|(addr, _)| addr as u16)
+    .collect()
+}
+
+fn snapshot<F>(read: F) -> Vec<u8>
+where
+  F: Fn(u16) -> u8,
+{
+  (0..=0xffff).map(read).collect()
+}
+
+/// How a byte has to compare to the last scan to stay a candidate
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ScanFilter {
+  Increased,
+  Decreased,
+  Unchanged,
+  Changed,
+  Equals(u8),
+}
+
+impl ScanFilter {
+  fn keeps(self, old: u8, new: u8) -> bool {
+    match self {
+      ScanFilter::Increased => new > old,
+      ScanFilter::Decreased => new < old,
+      ScanFilter::Unchanged => new == old,
+      ScanFilter::Changed => new != old,
+      ScanFilter::Equals(val) => new == val,
+    }
+  }
+}
+
+/// Cheat finder. Start with every address, then each scan keeps the ones
+/// whose byte changed the way the filter asks since the scan before.
+pub struct CheatScanner {
+  candidates: Vec<u16>,
+  // memory at the last scan, None before the first one
+  last: Option<Vec<u8>>,
+}
+
+impl CheatScanner {
+  pub fn new() -> Self {
+    Self {
+      candidates: Vec::new(),
+      last: None,
+    }
+  }
+
+  /// Take the first snapshot, every address is a candidate
+  pub fn start<F>(&mut self, read: F)
+  where
+    F: Fn(u16) -> u8,
+  {
+    self.candidates = (0..=0xffff).collect();
+    self.last = Some(snapshot(read));
+  }
+
+  pub fn started(&self) -> bool {
+    self.last.is_some()
+  }
+
+  /// Compare memory to the last scan and drop the addresses that don't pass
+  /// `filter`. Does nothing before `start`.
+  pub fn scan<F>(&mut self, filter: ScanFilter, read: F)
+  where
+    F: Fn(u16) -> u8,
+  {
+    let Some(last) = &self.last else {
+      return;
+    };
+    let mem = snapshot(read);
+    self
+      .candidates
+      .retain(|&addr| filter.keeps(last[addr as usize], mem[addr as usize]));
+    self.last = Some(mem);
+  }
+
+  pub fn candidates(&self) -> &[u16] {
+    &self.candidates
+  }
+
+  /// Value of a candidate at the last scan
+  pub fn value(&self, addr: u16) -> Option<u8> {
+    self.last.as_ref().map(|mem| mem[addr as usize])
+  }
+}
+
+impl Default for CheatScanner {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Search box state for the memory viewer
+pub struct MemSearch {
+  pub text: String,
+  /// Matches from the last search
+  pub results: Vec<u16>,
+  /// Address the viewer should scroll to next frame
+  pub jump_to: Option<u16>,
+  pub scanner: CheatScanner,
+  /// Contents of the box for the equals filter
+  pub scan_value: String,
+}
+
+impl MemSearch {
+  pub fn new() -> Self {
+    Self {
+      text: String::new(),
+      results: Vec::new(),
+      jump_to: None,
+      scanner: CheatScanner::new(),
+      scan_value: String::new(),
+    }
+  }
+
+  /// Search for what is in the box
+  pub fn run<F>(&mut self, read: F) -> Result<(), String>
+  where
+    F: Fn(u16) -> u8,
+  {
+    let pattern = parse_pattern(&self.text)?;
+    self.results = find(&pattern, read);
+    Ok(())
+  }
+}
+
+impl Default for MemSearch {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::screen::Screen;
+  use crate::state::{EmuFlow, GbState};
+  use std::cell::RefCell;
+  use std::rc::Rc;
+
+  /// Emulator with a 32KiB rom and no cartridge ram, like Tetris
+  fn rom_only_state(mapper: u8) -> GbState {
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x147] = mapper;
+    rom[0x150..0x153].copy_from_slice(&[0x3e, 0x05, 0xea]);
+    let mut state = GbState::new(EmuFlow::new(false, false, 1.0));
+    state
+      .init(Rc::new(RefCell::new(Screen::headless())))
+      .unwrap();
+    state.load_rom(&rom).unwrap();
+    state.cart.borrow_mut().io_write(0xff50, 0x01).unwrap();
+    state
+  }
+
+  #[test]
+  fn test_parse_pattern() {
+    assert_eq!(parse_pattern("3e 05 ea"), Ok(vec![0x3e, 0x05, 0xea]));
+    assert_eq!(parse_pattern("$c100"), Ok(vec![0x00, 0xc1]));
+    assert_eq!(parse_pattern("0x5"), Ok(vec![0x05]));
+    assert_eq!(parse_pattern("300"), Ok(vec![0x2c, 0x01]));
+    assert_eq!(parse_pattern("0"), Ok(vec![0x00]));
+    assert!(parse_pattern("").is_err());
+    assert!(parse_pattern("3e zz").is_err());
+    assert!(parse_pattern("ten").is_err());
+  }
+
+  #[test]
+  fn test_find() {
+    let mut mem = vec![0u8; 0x1_0000];
+    mem[0x0150..0x0153].copy_from_slice(&[0x3e, 0x05, 0xea]);
+    mem[0xc000..0xc003].copy_from_slice(&[0x3e, 0x05, 0xea]);
+    mem[0xc010..0xc012].copy_from_slice(&[0x3e, 0x05]);
+    // only the first half fits before the end of memory
+    mem[0xfffe..].copy_from_slice(&[0x3e, 0x05]);
+    let read = |addr: u16| mem[addr as usize];
+
+    assert_eq!(find(&[0x3e, 0x05, 0xea], read), [0x0150, 0xc000]);
+    assert_eq!(find(&[0x3e, 0x05], read), [0x0150, 0xc000, 0xc010, 0xfffe]);
+    assert_eq!(find(&[0x05, 0xea], read).len(), 2);
+    assert!(find(&[], read).is_empty());
+
+    let mut search = MemSearch::new();
+    search.text = String::from("$ea05");
+    search.run(read).unwrap();
+    assert_eq!(search.results, [0x0151, 0xc001]);
+  }
+
+  #[test]
+  fn test_find_without_cart_ram() {
+    // no mapper and mbc3, both without ram
+    for mapper in [0x00, 0x11] {
+      let state = rom_only_state(mapper);
+      let bus = state.bus.borrow();
+      let read = |addr: u16| bus.read8(addr).unwrap_or(0xff);
+      assert_eq!(read(0xa000), 0xff);
+
+      let mut search = MemSearch::new();
+      search.text = String::from("3e 05 ea");
+      search.run(read).unwrap();
+      assert_eq!(search.results, [0x0150]);
+    }
+  }
+
+  #[test]
+  fn test_cheat_scan() {
+    // lives at $c0a0 go 3 -> 2 -> 2 -> 5, a timer at $ff05 counts up and
+    // everything else stays put
+    let mut mem = vec![0u8; 0x1_0000];
+    mem[0xc0a0] = 3;
+    mem[0xc0b0] = 3;
+    let mut scanner = CheatScanner::new();
+    scanner.scan(ScanFilter::Changed, |_| 0);
+    assert!(!scanner.started());
+    scanner.start(|addr| mem[addr as usize]);
+    assert_eq!(scanner.candidates().len(), 0x1_0000);
+
+    mem[0xc0a0] = 2;
+    mem[0xff05] = 1;
+    scanner.scan(ScanFilter::Decreased, |addr| mem[addr as usize]);
+    assert_eq!(scanner.candidates(), [0xc0a0]);
+
+    // unchanged from the last scan, not the first
+    mem[0xff05] = 2;
+    scanner.scan(ScanFilter::Unchanged, |addr| mem[addr as usize]);
+    assert_eq!(scanner.candidates(), [0xc0a0]);
+    assert_eq!(scanner.value(0xc0a0), Some(2));
+
+    mem[0xc0a0] = 5;
+    scanner.scan(ScanFilter::Equals(4), |addr| mem[addr as usize]);
+    assert!(scanner.candidates().is_empty());
+
+    // a fresh start brings everything back, increased narrows it again
+    scanner.start(|addr| mem[addr as usize]);
+    mem[0xc0a0] = 6;
+    mem[0xff05] = 3;
+    mem[0xc0b0] = 1;
+    scanner.scan(ScanFilter::Increased, |addr| mem[addr as usize]);
+    assert_eq!(scanner.candidates(), [0xc0a0, 0xff05]);
+    scanner.scan(ScanFilter::Equals(6), |addr| mem[addr as usize]);
+    assert_eq!(scanner.candidates(), [0xc0a0]);
+  }
+
+  #[test]
+  fn test_cheat_scan_without_cart_ram() {
+    for mapper in [0x00, 0x11] {
+      let state = rom_only_state(mapper);
+      let read = |addr: u16| state.bus.borrow().read8(addr).unwrap_or(0xff);
+      let mut scanner = CheatScanner::new();
+      scanner.start(read);
+      assert_eq!(scanner.value(0xa000), Some(0xff));
+
+      // writes to the missing ram go nowhere
+      state.bus.borrow_mut().write8(0xc0a0, 0x03).unwrap();
+      state.bus.borrow_mut().write8(0xa000, 0x03).unwrap();
+      scanner.scan(ScanFilter::Equals(3), read);
+      assert!(scanner.candidates().contains(&0xc0a0));
+      assert!(!scanner.candidates().contains(&0xa000));
+    }
+  }
+}
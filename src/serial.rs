@@ -0,0 +1,299 @@
+//! Serial port for the Gameboy. Bytes can be exchanged with another instance
+//! over a TCP link cable.
+
+pub mod link;
+
+use crate::err::{GbError, GbErrorType, GbResult};
+use crate::gb_err;
+use crate::int::{Interrupt, Interrupts};
+use crate::serial::link::{TcpLink, DISCONNECTED_BYTE};
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+use crate::util::LazyDref;
+use log::error;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const SB_ADDR: u16 = 0xff01;
+pub const SC_ADDR: u16 = 0xff02;
+
+// cpu cycles to shift out a byte with the internal 8192 Hz clock
+const TRANSFER_CYCLES: u32 = 4096;
+
+pub struct Serial {
+  // Registers
+  /// Serial transfer data
+  pub sb: u8,
+  /// SC bit 7: transfer requested or in progress
+  pub transfer: bool,
+  /// SC bit 0: we drive the clock
+  pub internal_clock: bool,
+
+  /// Every byte shifted out of SB
+  pub output: Vec<u8>,
+
+  /// interrupt controller handle
+  ic: Option<Rc<RefCell<Interrupts>>>,
+  /// optional link cable to another instance
+  link: Option<TcpLink>,
+
+  // cycles into the current internally clocked transfer
+  cycles: u32,
+}
+
+impl Serial {
+  pub fn new() -> Self {
+    Self {
+      sb: 0,
+      transfer: false,
+      internal_clock: false,
+      output: Vec::new(),
+      ic: None,
+      link: None,
+      cycles: 0,
+    }
+  }
+
+  /// Adds a reference to the interrupt controller to the serial port
+  pub fn connect_ic(&mut self, ic: Rc<RefCell<Interrupts>>) -> GbResult<()> {
+    match self.ic {
+      None => self.ic = Some(ic),
+      Some(_) => return gb_err!(GbErrorType::AlreadyInitialized),
+    }
+    Ok(())
+  }
+
+  /// Plug in a link cable to another instance
+  pub fn connect_link(&mut self, link: TcpLink) -> GbResult<()> {
+    match self.link {
+      None => self.link = Some(link),
+      Some(_) => return gb_err!(GbErrorType::AlreadyInitialized),
+    }
+    Ok(())
+  }
+
+  /// Return the registers to their power-up values. The interrupt controller
+  /// and link cable stay connected.
+  pub fn reset(&mut self) {
+    self.sb = 0;
+    self.transfer = false;
+    self.internal_clock = false;
+    self.output.clear();
+    self.cycles = 0;
+  }
+
+  pub fn step(&mut self, cycle_budget: u32) {
+    // the partner can clock a byte through at any time, so the link gets
+    // polled even while we are idle
+    let received = match &mut self.link {
+      Some(link) => link.poll(self.sb),
+      None => None,
+    };
+    if !self.transfer {
+      return;
+    }
+
+    if self.internal_clock {
+      self.cycles = (self.cycles + cycle_budget).min(TRANSFER_CYCLES);
+      if self.cycles < TRANSFER_CYCLES {
+        return;
+      }
+      // the byte is shifted out, finish once the partner's byte is back
+      match &mut self.link {
+        Some(link) if link.is_connected() => match received {
+          Some(byte) => self.complete(byte),
+          None if !link.is_waiting() => link.start_transfer(self.sb),
+          None => {}
+        },
+        // nothing plugged in reads as all 1s
+        _ => self.complete(DISCONNECTED_BYTE),
+      }
+    } else if let Some(byte) = received {
+      // the partner drives the clock, so only finish once it shifts a byte in
+      self.complete(byte);
+    }
+  }
+
+  fn complete(&mut self, received: u8) {
+    self.output.push(self.sb);
+    self.sb = received;
+    self.transfer = false;
+    self.cycles = 0;
+    self.ic.lazy_dref_mut().raise(Interrupt::Serial);
+  }
+
+  pub fn read(&self, addr: u16) -> GbResult<u8> {
+    match addr {
+      SB_ADDR => Ok(self.sb),
+      SC_ADDR => Ok((self.transfer as u8) << 7 | self.internal_clock as u8),
+      _ => {
+        error!("Unknown read from addr ${:04X}", addr);
+        gb_err!(GbErrorType::OutOfBounds)
+      }
+    }
+  }
+
+  pub fn write(&mut self, addr: u16, data: u8) -> GbResult<()> {
+    match addr {
+      SB_ADDR => self.sb = data,
+      SC_ADDR => {
+        self.transfer = data & 0x80 > 0;
+        self.internal_clock = data & 0x1 > 0;
+        self.cycles = 0;
+      }
+      _ => {
+        error!("Unknown write: 0x{:02X} -> ${:04X}", data, addr);
+        return gb_err!(GbErrorType::OutOfBounds);
+      }
+    }
+    Ok(())
+  }
+}
+
+impl Default for Serial {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// The output log and link cable are not part of the snapshot
+impl Snapshot for Serial {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    writer.write_u8(self.sb);
+    writer.write_bool(self.transfer);
+    writer.write_bool(self.internal_clock);
+    writer.write_u32(self.cycles);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    self.sb = reader.read_u8()?;
+    self.transfer = reader.read_bool()?;
+    self.internal_clock = reader.read_bool()?;
+    self.cycles = reader.read_u32()?;
+    Ok(())
+  }
+}
+
+/// Format serial output as text. Newlines and tabs are kept, other
+/// non-printable bytes are shown as hex escapes.
+pub fn escape_output(bytes: &[u8]) -> String {
+  let mut text = String::with_capacity(bytes.len());
+  for byte in bytes {
+    match byte {
+      b'\n' | b'\t' | 0x20..=0x7e => text.push(*byte as char),
+      _ => text.push_str(&format!("\\x{:02X}", byte)),
+    }
+  }
+  text
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::bus::IF_ADDR;
+  use std::net::TcpListener;
+  use std::thread;
+
+  fn connected_serial() -> (Serial, Rc<RefCell<Interrupts>>) {
+    let ic = Rc::new(RefCell::new(Interrupts::new()));
+    let mut serial = Serial::new();
+    serial.connect_ic(ic.clone()).unwrap();
+    (serial, ic)
+  }
+
+  fn serial_raised(ic: &Rc<RefCell<Interrupts>>) -> bool {
+    ic.borrow().read(IF_ADDR).unwrap() & Interrupt::Serial as u8 > 0
+  }
+
+  #[test]
+  fn test_transfer_without_link() {
+    let (mut serial, ic) = connected_serial();
+    serial.write(SB_ADDR, b'P').unwrap();
+    serial.write(SC_ADDR, 0x81).unwrap();
+    assert_eq!(serial.read(SC_ADDR).unwrap(), 0x81);
+
+    serial.step(TRANSFER_CYCLES - 1);
+    assert!(!serial_raised(&ic));
+    serial.step(1);
+    assert!(serial_raised(&ic));
+    assert_eq!(serial.read(SB_ADDR).unwrap(), DISCONNECTED_BYTE);
+    assert_eq!(serial.read(SC_ADDR).unwrap(), 0x01);
+    assert_eq!(serial.output, b"P");
+
+    // the external clock never ticks without a partner
+    serial.write(SC_ADDR, 0x80).unwrap();
+    serial.step(TRANSFER_CYCLES * 4);
+    assert!(serial.transfer);
+    assert_eq!(serial.output, b"P");
+  }
+
+  #[test]
+  fn test_link_loopback() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // partner waits on the external clock
+    let partner = thread::spawn(move || {
+      let (mut serial, ic) = connected_serial();
+      serial
+        .connect_link(TcpLink::accept(&listener).unwrap())
+        .unwrap();
+      serial.write(SB_ADDR, 0x99).unwrap();
+      serial.write(SC_ADDR, 0x80).unwrap();
+      while !serial_raised(&ic) {
+        serial.step(4);
+      }
+      serial.read(SB_ADDR).unwrap()
+    });
+
+    let (mut serial, ic) = connected_serial();
+    serial
+      .connect_link(TcpLink::connect(addr).unwrap())
+      .unwrap();
+    serial.write(SB_ADDR, 0x42).unwrap();
+    serial.write(SC_ADDR, 0x81).unwrap();
+    serial.step(TRANSFER_CYCLES);
+    while !serial_raised(&ic) {
+      serial.step(4);
+    }
+
+    assert_eq!(serial.read(SB_ADDR).unwrap(), 0x99);
+    assert_eq!(partner.join().unwrap(), 0x42);
+  }
+
+  #[test]
+  fn test_link_idle_partner() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (mut master, master_ic) = connected_serial();
+    master
+      .connect_link(TcpLink::connect(addr).unwrap())
+      .unwrap();
+    let (mut partner, partner_ic) = connected_serial();
+    partner
+      .connect_link(TcpLink::accept(&listener).unwrap())
+      .unwrap();
+
+    // the partner never starts a transfer, it still answers with its SB and
+    // neither side blocks while the reply is on its way
+    partner.write(SB_ADDR, 0x77).unwrap();
+    master.write(SB_ADDR, 0x42).unwrap();
+    master.write(SC_ADDR, 0x81).unwrap();
+    master.step(TRANSFER_CYCLES);
+    while !serial_raised(&master_ic) {
+      partner.step(4);
+      master.step(4);
+    }
+    assert_eq!(master.read(SB_ADDR).unwrap(), 0x77);
+    assert_eq!(partner.read(SB_ADDR).unwrap(), 0x77);
+    assert!(!serial_raised(&partner_ic));
+  }
+
+  #[test]
+  fn test_escape_output() {
+    assert_eq!(escape_output(b"cpu_instrs\n\n01:ok"), "cpu_instrs\n\n01:ok");
+    assert_eq!(
+      escape_output(&[b'A', 0x00, b'\t', 0xff, b'\r']),
+      "A\\x00\t\\xFF\\x0D"
+    );
+  }
+}
@@ -0,0 +1,348 @@
+//! Link cable over TCP. The instance driving the clock sends a transfer and
+//! finishes it once the reply arrives, the other side answers transfers with
+//! its current byte as it polls. Neither side ever blocks the emulator.
+
+use crate::err::{GbError, GbErrorType, GbResult};
+use crate::gb_err;
+use log::{error, info, warn};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// every message is a tag byte followed by the data byte
+const MSG_TRANSFER: u8 = 0x01;
+const MSG_REPLY: u8 = 0x02;
+const MSG_SIZE: usize = 2;
+
+// how long a transfer waits for the partner's reply before reading as
+// nothing plugged in
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+const WRITE_RETRY_INTERVAL: Duration = Duration::from_micros(100);
+// time between attempts to reach a lost partner
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Byte seen on the data line when no partner is connected
+pub const DISCONNECTED_BYTE: u8 = 0xff;
+
+/// Where to find the partner again after losing it
+enum Partner {
+  Listener(TcpListener),
+  Address(SocketAddr),
+}
+
+pub struct TcpLink {
+  stream: Option<TcpStream>,
+  // partially received message
+  pending: Vec<u8>,
+  partner: Partner,
+  // streams from the background connect after a disconnect
+  reconnect: Option<Receiver<TcpStream>>,
+  // when our transfer went out, while waiting on the reply
+  sent_at: Option<Instant>,
+}
+
+impl TcpLink {
+  /// Listen on the given port and block until a partner connects
+  pub fn listen(port: u16) -> GbResult<TcpLink> {
+    let listener = match TcpListener::bind(("0.0.0.0", port)) {
+      Ok(listener) => listener,
+      Err(e) => {
+        error!("Failed to listen on port {}: {}", port, e);
+        return gb_err!(GbErrorType::LinkError);
+      }
+    };
+    info!("Waiting for link partner on port {}", port);
+    TcpLink::accept(&listener)
+  }
+
+  /// Block until a partner connects to the listener. The listener is kept to
+  /// take the partner back if it drops.
+  pub fn accept(listener: &TcpListener) -> GbResult<TcpLink> {
+    let stream = match listener.accept() {
+      Ok((stream, peer)) => {
+        info!("Link partner connected from {}", peer);
+        stream
+      }
+      Err(e) => {
+        error!("Failed to accept link partner: {}", e);
+        return gb_err!(GbErrorType::LinkError);
+      }
+    };
+    let listener = match listener
+      .try_clone()
+      .and_then(|l| l.set_nonblocking(true).map(|_| l))
+    {
+      Ok(listener) => listener,
+      Err(e) => {
+        error!("Failed to configure link listener: {}", e);
+        return gb_err!(GbErrorType::LinkError);
+      }
+    };
+    TcpLink::from_stream(stream, Partner::Listener(listener))
+  }
+
+  /// Connect to a listening partner
+  pub fn connect<A: ToSocketAddrs>(addr: A) -> GbResult<TcpLink> {
+    let stream = match TcpStream::connect(addr) {
+      Ok(stream) => stream,
+      Err(e) => {
+        error!("Failed to connect to link partner: {}", e);
+        return gb_err!(GbErrorType::LinkError);
+      }
+    };
+    let addr = match stream.peer_addr() {
+      Ok(addr) => addr,
+      Err(e) => {
+        error!("Failed to connect to link partner: {}", e);
+        return gb_err!(GbErrorType::LinkError);
+      }
+    };
+    info!("Connected to link partner at {}", addr);
+    TcpLink::from_stream(stream, Partner::Address(addr))
+  }
+
+  fn from_stream(stream: TcpStream, partner: Partner) -> GbResult<TcpLink> {
+    let mut link = TcpLink {
+      stream: None,
+      pending: Vec::new(),
+      partner,
+      reconnect: None,
+      sent_at: None,
+    };
+    if !link.attach(stream) {
+      return gb_err!(GbErrorType::LinkError);
+    }
+    Ok(link)
+  }
+
+  /// Use a freshly connected stream. Single bytes should go out right away
+  /// and reads should never stall the emulator.
+  fn attach(&mut self, stream: TcpStream) -> bool {
+    if let Err(e) = stream
+      .set_nodelay(true)
+      .and_then(|_| stream.set_nonblocking(true))
+    {
+      error!("Failed to configure link socket: {}", e);
+      return false;
+    }
+    self.stream = Some(stream);
+    true
+  }
+
+  pub fn is_connected(&self) -> bool {
+    self.stream.is_some()
+  }
+
+  /// True while our transfer is out and the partner hasn't replied
+  pub fn is_waiting(&self) -> bool {
+    self.sent_at.is_some()
+  }
+
+  /// Shift a byte out using our clock. The partner's byte comes back later
+  /// through `poll`.
+  pub fn start_transfer(&mut self, byte: u8) {
+    if self.send(MSG_TRANSFER, byte) {
+      self.sent_at = Some(Instant::now());
+    }
+  }
+
+  /// Handle whatever the partner sent, without blocking. Transfers clocked by
+  /// the partner are answered with `sb` whether or not we are waiting on one,
+  /// like the shift register on hardware. Returns the partner's byte when a
+  /// transfer finished, either the reply to ours or one the partner clocked.
+  /// Reconnects when the partner was lost.
+  pub fn poll(&mut self, sb: u8) -> Option<u8> {
+    if !self.is_connected() {
+      self.try_reconnect();
+      return None;
+    }
+    while let Some((tag, data)) = self.recv() {
+      match tag {
+        MSG_REPLY if self.is_waiting() => {
+          self.sent_at = None;
+          return Some(data);
+        }
+        // reply to a transfer that already timed out
+        MSG_REPLY => {}
+        MSG_TRANSFER => {
+          // also covers both sides starting a transfer at once, the two
+          // transfers are treated as one exchange
+          self.sent_at = None;
+          self.send(MSG_REPLY, sb);
+          return Some(data);
+        }
+        _ => warn!("Unknown link message 0x{:02X}", tag),
+      }
+    }
+    match self.sent_at {
+      Some(sent_at) if sent_at.elapsed() > REPLY_TIMEOUT => {
+        warn!("Link partner did not respond");
+        self.sent_at = None;
+        Some(DISCONNECTED_BYTE)
+      }
+      _ => None,
+    }
+  }
+
+  /// Look for the partner again without blocking. The listening side takes
+  /// the next connection, the connecting side dials in the background.
+  fn try_reconnect(&mut self) {
+    let stream = match &self.partner {
+      Partner::Listener(listener) => match listener.accept() {
+        Ok((stream, peer)) => {
+          info!("Link partner reconnected from {}", peer);
+          stream
+        }
+        Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+        Err(e) => {
+          warn!("Failed to accept link partner: {}", e);
+          return;
+        }
+      },
+      Partner::Address(addr) => {
+        let addr = *addr;
+        let reconnect = self.reconnect.get_or_insert_with(|| {
+          let (tx, rx) = mpsc::channel();
+          thread::spawn(move || loop {
+            if let Ok(stream) = TcpStream::connect(addr) {
+              // the link may be gone by now, nothing to do then
+              let _ = tx.send(stream);
+              return;
+            }
+            thread::sleep(RECONNECT_INTERVAL);
+          });
+          rx
+        });
+        match reconnect.try_recv() {
+          Ok(stream) => {
+            info!("Reconnected to link partner at {}", addr);
+            self.reconnect = None;
+            stream
+          }
+          Err(mpsc::TryRecvError::Empty) => return,
+          Err(mpsc::TryRecvError::Disconnected) => {
+            self.reconnect = None;
+            return;
+          }
+        }
+      }
+    };
+    self.attach(stream);
+  }
+
+  fn send(&mut self, tag: u8, data: u8) -> bool {
+    let stream = match &mut self.stream {
+      Some(stream) => stream,
+      None => return false,
+    };
+    // nonblocking sockets may still refuse a tiny write if the partner has
+    // stopped reading
+    let mut msg: &[u8] = &[tag, data];
+    while !msg.is_empty() {
+      match stream.write(msg) {
+        Ok(0) => break,
+        Ok(n) => msg = &msg[n..],
+        Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(WRITE_RETRY_INTERVAL),
+        Err(e) if e.kind() == ErrorKind::Interrupted => {}
+        Err(e) => {
+          warn!("Link write failed: {}", e);
+          break;
+        }
+      }
+    }
+    if !msg.is_empty() {
+      self.disconnect();
+      return false;
+    }
+    true
+  }
+
+  /// Read a full message if one has arrived
+  fn recv(&mut self) -> Option<(u8, u8)> {
+    let stream = self.stream.as_mut()?;
+    let mut buf = [0u8; MSG_SIZE];
+    while self.pending.len() < MSG_SIZE {
+      match stream.read(&mut buf[..MSG_SIZE - self.pending.len()]) {
+        Ok(0) => {
+          info!("Link partner disconnected");
+          self.disconnect();
+          return None;
+        }
+        Ok(n) => self.pending.extend_from_slice(&buf[..n]),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => return None,
+        Err(e) if e.kind() == ErrorKind::Interrupted => {}
+        Err(e) => {
+          warn!("Link read failed: {}", e);
+          self.disconnect();
+          return None;
+        }
+      }
+    }
+    let msg = (self.pending[0], self.pending[1]);
+    self.pending.clear();
+    Some(msg)
+  }
+
+  fn disconnect(&mut self) {
+    self.stream = None;
+    self.pending.clear();
+    self.sent_at = None;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // poll until something comes back, the partner's reply or a timeout
+  fn wait_poll(link: &mut TcpLink, sb: u8) -> u8 {
+    loop {
+      if let Some(byte) = link.poll(sb) {
+        return byte;
+      }
+      thread::sleep(Duration::from_millis(1));
+    }
+  }
+
+  #[test]
+  fn test_link_disconnect() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let partner = thread::spawn(move || {
+      let link = TcpLink::connect(addr).unwrap();
+      drop(link);
+    });
+    let mut link = TcpLink::accept(&listener).unwrap();
+    partner.join().unwrap();
+
+    link.start_transfer(0x12);
+    while link.is_connected() {
+      assert_eq!(link.poll(0x12), None);
+    }
+    assert!(!link.is_waiting());
+    assert_eq!(link.poll(0x12), None);
+  }
+
+  #[test]
+  fn test_link_reconnect() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let first = TcpLink::connect(addr).unwrap();
+    let mut host = TcpLink::accept(&listener).unwrap();
+    drop(first);
+    while host.is_connected() {
+      host.poll(0);
+    }
+
+    // the host takes the partner back on the same listener
+    let mut partner = TcpLink::connect(addr).unwrap();
+    while !host.is_connected() {
+      host.poll(0);
+    }
+    partner.start_transfer(0x34);
+    assert_eq!(wait_poll(&mut host, 0x56), 0x34);
+    assert_eq!(wait_poll(&mut partner, 0x34), 0x56);
+  }
+}
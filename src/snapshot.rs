@@ -0,0 +1,172 @@
+//! Save state snapshots. Components write their state to a flat byte stream
+//! and read it back in the same order. Handles to other components are not
+//! part of a snapshot, state is loaded into already connected components.
+
+use crate::err::{GbError, GbErrorType, GbResult};
+use crate::gb_err;
+use log::error;
+
+/// A component whose state can be saved and restored
+pub trait Snapshot {
+  fn save(&self, writer: &mut SnapshotWriter);
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()>;
+}
+
+pub struct SnapshotWriter {
+  data: Vec<u8>,
+}
+
+impl SnapshotWriter {
+  pub fn new() -> SnapshotWriter {
+    SnapshotWriter { data: Vec::new() }
+  }
+
+  pub fn write_u8(&mut self, val: u8) {
+    self.data.push(val);
+  }
+
+  pub fn write_bool(&mut self, val: bool) {
+    self.write_u8(val as u8);
+  }
+
+  pub fn write_u32(&mut self, val: u32) {
+    self.data.extend_from_slice(&val.to_le_bytes());
+  }
+
+  /// Write a length prefixed block of bytes
+  pub fn write_bytes(&mut self, bytes: &[u8]) {
+    self.write_u32(bytes.len() as u32);
+    self.data.extend_from_slice(bytes);
+  }
+
+  /// Write a list of equally sized banks
+  pub fn write_banks<const N: usize>(&mut self, banks: &[[u8; N]]) {
+    self.write_u32(banks.len() as u32);
+    for bank in banks {
+      self.write_bytes(bank);
+    }
+  }
+
+  pub fn into_bytes(self) -> Vec<u8> {
+    self.data
+  }
+}
+
+impl Default for SnapshotWriter {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+pub struct SnapshotReader<'a> {
+  data: &'a [u8],
+  offset: usize,
+}
+
+impl<'a> SnapshotReader<'a> {
+  pub fn new(data: &'a [u8]) -> SnapshotReader<'a> {
+    SnapshotReader { data, offset: 0 }
+  }
+
+  fn take(&mut self, len: usize) -> GbResult<&'a [u8]> {
+    if self.offset + len > self.data.len() {
+      error!(
+        "Snapshot truncated: wanted {} bytes at offset {}, only {} left",
+        len,
+        self.offset,
+        self.data.len() - self.offset
+      );
+      return gb_err!(GbErrorType::OutOfBounds);
+    }
+    let bytes = &self.data[self.offset..self.offset + len];
+    self.offset += len;
+    Ok(bytes)
+  }
+
+  pub fn read_u8(&mut self) -> GbResult<u8> {
+    Ok(self.take(1)?[0])
+  }
+
+  pub fn read_bool(&mut self) -> GbResult<bool> {
+    Ok(self.read_u8()? > 0)
+  }
+
+  pub fn read_u32(&mut self) -> GbResult<u32> {
+    let bytes = self.take(4)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+  }
+
+  /// Read a length prefixed block of bytes
+  pub fn read_bytes(&mut self) -> GbResult<&'a [u8]> {
+    let len = self.read_u32()? as usize;
+    self.take(len)
+  }
+
+  /// Read a length prefixed block of bytes into a buffer of the same size
+  pub fn read_bytes_into(&mut self, buf: &mut [u8]) -> GbResult<()> {
+    let bytes = self.read_bytes()?;
+    if bytes.len() != buf.len() {
+      error!(
+        "Snapshot block has {} bytes, expected {}",
+        bytes.len(),
+        buf.len()
+      );
+      return gb_err!(GbErrorType::BadValue);
+    }
+    buf.copy_from_slice(bytes);
+    Ok(())
+  }
+
+  /// Read a list of banks into banks of the same count and size
+  pub fn read_banks_into<const N: usize>(&mut self, banks: &mut [[u8; N]]) -> GbResult<()> {
+    let count = self.read_u32()? as usize;
+    if count != banks.len() {
+      error!("Snapshot has {} banks, expected {}", count, banks.len());
+      return gb_err!(GbErrorType::BadValue);
+    }
+    for bank in banks.iter_mut() {
+      self.read_bytes_into(bank)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_round_trip() {
+    let mut writer = SnapshotWriter::new();
+    writer.write_u8(0x12);
+    writer.write_bool(true);
+    writer.write_u32(0xdeadbeef);
+    writer.write_bytes(&[1, 2, 3]);
+    let bytes = writer.into_bytes();
+
+    let mut reader = SnapshotReader::new(&bytes);
+    assert_eq!(reader.read_u8().unwrap(), 0x12);
+    assert!(reader.read_bool().unwrap());
+    assert_eq!(reader.read_u32().unwrap(), 0xdeadbeef);
+    let mut buf = [0; 3];
+    reader.read_bytes_into(&mut buf).unwrap();
+    assert_eq!(buf, [1, 2, 3]);
+
+    // nothing left
+    let err = reader.read_u8().unwrap_err();
+    assert_eq!(*err.error_type(), GbErrorType::OutOfBounds);
+  }
+
+  #[test]
+  fn test_block_size_mismatch() {
+    let mut writer = SnapshotWriter::new();
+    writer.write_bytes(&[1, 2, 3]);
+    let bytes = writer.into_bytes();
+
+    let mut buf = [0; 4];
+    let err = SnapshotReader::new(&bytes)
+      .read_bytes_into(&mut buf)
+      .unwrap_err();
+    assert_eq!(*err.error_type(), GbErrorType::BadValue);
+  }
+}
@@ -0,0 +1,98 @@
+//! CGB speed switch (KEY1). The cpu can run at double speed while the ppu
+//! keeps its normal rate.
+
+use crate::err::{GbError, GbErrorType, GbResult};
+use crate::gb_err;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+use log::{error, info};
+
+pub const KEY1_ADDR: u16 = 0xff4d;
+
+pub struct SpeedSwitch {
+  /// Only CGB mode has KEY1, a DMG reads $FF and ignores writes
+  pub enabled: bool,
+  /// Switch speeds on the next STOP
+  pub armed: bool,
+  /// Cpu is running at double speed
+  pub double_speed: bool,
+}
+
+impl SpeedSwitch {
+  pub fn new() -> SpeedSwitch {
+    SpeedSwitch {
+      enabled: false,
+      armed: false,
+      double_speed: false,
+    }
+  }
+
+  /// Called when the cpu executes STOP. Returns true if the speed changed.
+  pub fn stop(&mut self) -> bool {
+    if !self.armed {
+      return false;
+    }
+    self.armed = false;
+    self.double_speed = !self.double_speed;
+    info!(
+      "Switched to {} speed",
+      if self.double_speed {
+        "double"
+      } else {
+        "normal"
+      }
+    );
+    true
+  }
+
+  /// Cpu clock multiplier
+  pub fn factor(&self) -> u32 {
+    if self.double_speed {
+      2
+    } else {
+      1
+    }
+  }
+
+  pub fn read(&self, addr: u16) -> GbResult<u8> {
+    match addr {
+      KEY1_ADDR if !self.enabled => Ok(0xff),
+      KEY1_ADDR => Ok((self.double_speed as u8) << 7 | self.armed as u8),
+      _ => {
+        error!("Unknown read from addr ${:04X}", addr);
+        gb_err!(GbErrorType::OutOfBounds)
+      }
+    }
+  }
+
+  pub fn write(&mut self, addr: u16, data: u8) -> GbResult<()> {
+    match addr {
+      KEY1_ADDR if !self.enabled => {}
+      // only the armed bit is writable
+      KEY1_ADDR => self.armed = data & 0x1 > 0,
+      _ => {
+        error!("Unknown write: 0x{:02X} -> ${:04X}", data, addr);
+        return gb_err!(GbErrorType::OutOfBounds);
+      }
+    }
+    Ok(())
+  }
+}
+
+impl Default for SpeedSwitch {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Snapshot for SpeedSwitch {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    writer.write_bool(self.armed);
+    writer.write_bool(self.double_speed);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    self.armed = reader.read_bool()?;
+    self.double_speed = reader.read_bool()?;
+    Ok(())
+  }
+}
@@ -1,28 +1,75 @@
 //! Gameboy state
 
-use egui_winit::winit::event_loop::EventLoopProxy;
+use std::fs;
+use std::path::PathBuf;
 use std::{cell::RefCell, rc::Rc};
 
+use crate::apu::Apu;
+use crate::audio::{AudioOutput, DEFAULT_OUTPUT_RATE};
+use crate::breakpoint::Breakpoint;
+use crate::cheat::Cheat;
+use crate::crash;
+use crate::input_script::{InputScript, ScriptedInput};
 use crate::int::Interrupts;
 use crate::screen::Screen;
-use crate::tick_counter::TickCounter;
+use crate::serial::Serial;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+use crate::speed::SpeedSwitch;
+use crate::tick_counter::{FrameTimes, TickCounter};
 use crate::timer::Timer;
 use crate::{
-  bus::Bus, cart::Cartridge, cpu, cpu::Cpu, err::GbResult, joypad::Joypad, ppu::Ppu, ram::Ram,
+  bus::Bus,
+  cart::{Cartridge, GBCSupport, RtcTime},
+  cpu::{self, Cpu},
+  err::{GbError, GbErrorType, GbResult},
+  gb_err,
+  joypad::{Joypad, JoypadInput},
+  ppu::Ppu,
+  ram::{Ram, RamInit, Wram},
 };
 
-use crate::event::UserEvent;
-use log::{error, warn};
+use log::{error, info, warn};
 
 /// Alpha used when calculating the rolling average
 const CLOCK_RATE_ALPHA: f32 = 0.9;
 const GB_FPS_ALPHA: f32 = 0.9;
+// 0xff80-0xfffe
+const HRAM_SIZE: u16 = 127;
+/// Number of frames the frame time stats and graph cover
+const FRAME_TIMES_WINDOW: usize = 240;
 
-#[derive(Copy, Clone)]
+/// Number of save state slots per rom
+pub const STATE_SLOTS: u8 = 4;
+// save state file header
+const STATE_MAGIC: &[u8] = b"GBSS";
+const STATE_VERSION: u8 = 13;
+
+/// Range of the free form speed controls, as a fraction of full speed
+pub const MIN_SPEED: f32 = 0.1;
+pub const MAX_SPEED: f32 = 8.0;
+/// How much the speed hotkeys change the speed by
+pub const SPEED_STEP: f32 = 0.1;
+pub use crate::ppu::DOTS_PER_FRAME;
+/// Frames per second at normal speed, about 59.73 and not the host's 60
+pub const FRAME_RATE: f64 = cpu::CLOCK_RATE as f64 / DOTS_PER_FRAME as f64;
+
+#[derive(Clone)]
 pub struct EmuFlow {
   pub paused: bool,
   pub step: bool,
   pub speed: f32,
+  /// Temporary breakpoint, cleared once the cpu reaches it
+  pub run_to: Option<u16>,
+  /// Pause when the cpu reaches one of these and its condition holds
+  pub breakpoints: Vec<Breakpoint>,
+  /// Pause when the cpu enters the handler of one of these interrupts, as IE
+  /// bits
+  pub break_on_interrupt: u8,
+  /// Run as fast as possible, ignoring the speed
+  pub uncapped: bool,
+  /// Advance DOTS_PER_FRAME per frame instead of following the wall clock,
+  /// so runs with the same rom and input repeat exactly
+  pub deterministic: bool,
 }
 
 impl EmuFlow {
@@ -31,13 +78,28 @@ impl EmuFlow {
       paused,
       step,
       speed,
+      run_to: None,
+      breakpoints: Vec::new(),
+      break_on_interrupt: 0,
+      uncapped: false,
+      deterministic: false,
     }
   }
+
+  /// Set the speed, clamped to the range of the speed slider
+  pub fn set_speed(&mut self, speed: f32) {
+    self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+  }
+
+  /// Change the speed by a number of hotkey steps
+  pub fn nudge_speed(&mut self, steps: i32) {
+    self.set_speed(self.speed + steps as f32 * SPEED_STEP);
+  }
 }
 
 pub struct GbState {
   pub bus: Rc<RefCell<Bus>>,
-  pub wram: Rc<RefCell<Ram>>,
+  pub wram: Rc<RefCell<Wram>>,
   pub hram: Rc<RefCell<Ram>>,
   pub cart: Rc<RefCell<Cartridge>>,
   pub cpu: Rc<RefCell<Cpu>>,
@@ -45,38 +107,75 @@ pub struct GbState {
   pub ic: Rc<RefCell<Interrupts>>,
   pub timer: Rc<RefCell<Timer>>,
   pub joypad: Rc<RefCell<Joypad>>,
+  pub speed_switch: Rc<RefCell<SpeedSwitch>>,
+  pub serial: Rc<RefCell<Serial>>,
+  pub apu: Rc<RefCell<Apu>>,
+  /// Apu samples resampled for the output device
+  pub audio: AudioOutput,
   pub flow: EmuFlow,
   pub cycles: TickCounter,
   pub gb_fps: TickCounter,
+  pub frame_times: FrameTimes,
   pub clock_rate: f32,
-  pub event_loop_proxy: Option<EventLoopProxy<UserEvent>>,
+  /// Called at the end of every frame, a frontend hooks in here to redraw
+  pub on_frame: Option<Box<dyn FnMut()>>,
+  /// Power up contents of wram and hram
+  pub ram_init: RamInit,
+  /// Run every cart as a DMG, CGB carts included
+  pub force_dmg: bool,
+  /// Fixed frames run so far, used to time recorded inputs. Keeps counting
+  /// across resets so recordings stay in order.
+  pub frame: u64,
+  /// Joypad changes are recorded here while set
+  pub recording: Option<InputScript>,
+  /// Joypad changes are played from here while set, live input is ignored
+  pub replay: Option<InputScript>,
+  /// Frozen memory, written after every frame
+  pub cheats: Vec<Cheat>,
+  // dots the last fixed frame ran past its end, taken off the next one
+  fixed_overrun: u32,
+  // dots run into a fixed frame that stopped part way for the debugger
+  fixed_ran: Option<u32>,
+  // leftover cpu cycle not yet given to the ppu in double speed mode
+  odd_cycle: u32,
 }
 
 impl GbState {
   pub fn new(flow: EmuFlow) -> GbState {
     GbState {
       bus: Rc::new(RefCell::new(Bus::new())),
-      wram: Rc::new(RefCell::new(Ram::new(8 * 1024))),
-      hram: Rc::new(RefCell::new(Ram::new(127))),
+      wram: Rc::new(RefCell::new(Wram::new())),
+      hram: Rc::new(RefCell::new(Ram::new(HRAM_SIZE))),
       cart: Rc::new(RefCell::new(Cartridge::new())),
       cpu: Rc::new(RefCell::new(Cpu::new())),
       ppu: Rc::new(RefCell::new(Ppu::new())),
       ic: Rc::new(RefCell::new(Interrupts::new())),
       timer: Rc::new(RefCell::new(Timer::new())),
       joypad: Rc::new(RefCell::new(Joypad::new())),
+      speed_switch: Rc::new(RefCell::new(SpeedSwitch::new())),
+      serial: Rc::new(RefCell::new(Serial::new())),
+      apu: Rc::new(RefCell::new(Apu::new())),
+      audio: AudioOutput::new(DEFAULT_OUTPUT_RATE),
       flow,
       cycles: TickCounter::new(CLOCK_RATE_ALPHA),
       gb_fps: TickCounter::new(GB_FPS_ALPHA),
+      frame_times: FrameTimes::new(FRAME_TIMES_WINDOW),
       clock_rate: 0.0,
-      event_loop_proxy: None,
+      on_frame: None,
+      ram_init: RamInit::Zeros,
+      force_dmg: false,
+      frame: 0,
+      recording: None,
+      replay: None,
+      cheats: Vec::new(),
+      fixed_overrun: 0,
+      fixed_ran: None,
+      odd_cycle: 0,
     }
   }
 
-  pub fn init(
-    &mut self,
-    screen: Rc<RefCell<Screen>>,
-    event_loop_proxy: EventLoopProxy<UserEvent>,
-  ) -> GbResult<()> {
+  /// Connect the components and the screen frames are drawn to
+  pub fn init(&mut self, screen: Rc<RefCell<Screen>>) -> GbResult<()> {
     // TODO: load cartridge
 
     // connect PPU to screen
@@ -93,72 +192,1029 @@ impl GbState {
     self.bus.borrow_mut().connect_ic(self.ic.clone())?;
     self.bus.borrow_mut().connect_timer(self.timer.clone())?;
     self.bus.borrow_mut().connect_joypad(self.joypad.clone())?;
+    self
+      .bus
+      .borrow_mut()
+      .connect_speed_switch(self.speed_switch.clone())?;
+    self.bus.borrow_mut().connect_serial(self.serial.clone())?;
+    self.bus.borrow_mut().connect_apu(self.apu.clone())?;
 
     // connect modules to bus
     self.cpu.borrow_mut().connect_bus(self.bus.clone())?;
+    self
+      .cpu
+      .borrow_mut()
+      .connect_speed_switch(self.speed_switch.clone())?;
+
+    // dump this cpu if we crash
+    crash::register_cpu(&self.cpu);
 
     // connect modules to interrupt controller
     self.timer.borrow_mut().connect_ic(self.ic.clone())?;
+    self.timer.borrow_mut().connect_apu(self.apu.clone())?;
+    self
+      .timer
+      .borrow_mut()
+      .connect_speed_switch(self.speed_switch.clone())?;
     self.ppu.borrow_mut().connect_ic(self.ic.clone())?;
+    self.serial.borrow_mut().connect_ic(self.ic.clone())?;
+
+    Ok(())
+  }
+
+  /// Return every component to its power-up state. The components keep
+  /// their allocations and connections, and the cartridge is unloaded.
+  pub fn reset(&mut self) -> GbResult<()> {
+    *self.cart.borrow_mut() = Cartridge::new();
+    self.reset_system()
+  }
+
+  /// Like pressing the console's reset. Everything goes back to power-up but
+  /// the loaded rom and battery ram are kept.
+  pub fn soft_reset(&mut self) -> GbResult<()> {
+    self.cart.borrow_mut().soft_reset();
+    self.reset_system()
+  }
+
+  /// Reset everything other than the cartridge
+  fn reset_system(&mut self) -> GbResult<()> {
+    self.ppu.borrow_mut().reset(true);
+    self.timer.borrow_mut().reset();
+    self.serial.borrow_mut().reset();
+    self.apu.borrow_mut().reset();
+    self.init_ram();
+    *self.joypad.borrow_mut() = Joypad::new();
+    *self.speed_switch.borrow_mut() = SpeedSwitch::new();
+
+    // fresh cpu and interrupt controller need their handles again
+    let strict_opcodes = self.cpu.borrow().strict_opcodes;
+    *self.cpu.borrow_mut() = Cpu::new();
+    self.cpu.borrow_mut().strict_opcodes = strict_opcodes;
+    self.cpu.borrow_mut().connect_bus(self.bus.clone())?;
+    self
+      .cpu
+      .borrow_mut()
+      .connect_speed_switch(self.speed_switch.clone())?;
+    *self.ic.borrow_mut() = Interrupts::new();
+    self.ic.borrow_mut().connect_cpu(self.cpu.clone())?;
+
+    self.odd_cycle = 0;
+    self.fixed_overrun = 0;
+    self.fixed_ran = None;
+    self.apply_model();
+    Ok(())
+  }
+
+  /// Load a rom and switch to the model its header asks for
+  pub fn load_cart(&mut self, path: PathBuf) -> GbResult<()> {
+    let cart = self.open_cart(path)?;
+    self.insert_cart(cart);
+    Ok(())
+  }
+
+  /// Read a rom into a new cartridge without touching the loaded one, so a
+  /// file that fails to load leaves the running game alone
+  pub fn open_cart(&self, path: PathBuf) -> GbResult<Cartridge> {
+    let mut cart = self.cart.borrow().empty_like();
+    cart.set_rtc_time(self.rtc_time());
+    cart.load(path)?;
+    Ok(cart)
+  }
+
+  /// Swap in a cartridge from `open_cart` and switch to the model it asks
+  /// for. The rom cheats carry over to it.
+  pub fn insert_cart(&mut self, cart: Cartridge) {
+    *self.cart.borrow_mut() = cart;
+    self.sync_rom_patches();
+    self.cart_loaded();
+  }
+
+  /// Same as `load_cart` for a rom image in memory, which has no battery
+  /// save
+  pub fn load_rom(&mut self, rom: &[u8]) -> GbResult<()> {
+    self.cart.borrow_mut().set_rtc_time(self.rtc_time());
+    self.cart.borrow_mut().load_rom(rom.to_vec())?;
+    self.cart_loaded();
+    Ok(())
+  }
+
+  /// Deterministic runs give the cartridge clock the fixed frames run so far
+  /// instead of the wall clock
+  fn rtc_time(&self) -> RtcTime {
+    if !self.flow.deterministic {
+      return RtcTime::Wall;
+    }
+    let cycles = self.frame * DOTS_PER_FRAME as u64;
+    RtcTime::Emulated(cycles / cpu::CLOCK_RATE as u64)
+  }
+
+  fn cart_loaded(&mut self) {
+    self.apply_model();
+    if let Some(warning) = self.model_warning() {
+      warn!("{}", warning);
+    }
+  }
+
+  /// CGB carts run in CGB mode unless the DMG is forced
+  fn apply_model(&mut self) {
+    let cgb = !self.force_dmg && self.cart.borrow().header.gbc_support.cgb_mode();
+    self.set_cgb_mode(cgb);
+  }
+
+  /// Turn the CGB only features on or off: the color palettes, object
+  /// priority and vram bank in the ppu, the wram bank and the double speed
+  /// switch
+  pub fn set_cgb_mode(&mut self, cgb: bool) {
+    self.ppu.borrow_mut().cgb_mode = cgb;
+    self.speed_switch.borrow_mut().enabled = cgb;
+    self.wram.borrow_mut().cgb_mode = cgb;
+  }
+
+  pub fn cgb_mode(&self) -> bool {
+    self.ppu.borrow().cgb_mode
+  }
+
+  /// Set when the cart needs a CGB but is running as a DMG
+  pub fn model_warning(&self) -> Option<String> {
+    let cart = self.cart.borrow();
+    if self.cgb_mode() || !matches!(cart.header.gbc_support, GBCSupport::GBCOnly) {
+      return None;
+    }
+    Some(format!(
+      "{} only runs on a CGB, it may not work as a DMG",
+      cart.header.title.trim_end_matches('\0')
+    ))
+  }
+
+  /// Change the power up ram pattern and refill wram and hram with it
+  pub fn set_ram_init(&mut self, init: RamInit) {
+    self.ram_init = init;
+    self.init_ram();
+  }
 
-    // connect proxy
-    self.event_loop_proxy = Some(event_loop_proxy);
+  fn init_ram(&mut self) {
+    let cgb_mode = self.wram.borrow().cgb_mode;
+    *self.wram.borrow_mut() = Wram::with_init(self.ram_init);
+    self.wram.borrow_mut().cgb_mode = cgb_mode;
+    *self.hram.borrow_mut() = Ram::hram_with_init(HRAM_SIZE, self.ram_init);
+  }
+
+  /// Snapshot of the whole system. The loaded rom is not included.
+  pub fn save_state(&self) -> Vec<u8> {
+    let mut writer = SnapshotWriter::new();
+    writer.write_bytes(STATE_MAGIC);
+    writer.write_u8(STATE_VERSION);
+    self.save_components(&mut writer);
+    writer.into_bytes()
+  }
+
+  /// Restore a snapshot from `save_state`. The state is left untouched if the
+  /// snapshot can not be loaded.
+  pub fn load_state(&mut self, bytes: &[u8]) -> GbResult<()> {
+    let mut reader = SnapshotReader::new(bytes);
+    if reader.read_bytes()? != STATE_MAGIC || reader.read_u8()? != STATE_VERSION {
+      error!("Not a save state or from an unsupported version");
+      return gb_err!(GbErrorType::BadValue);
+    }
+
+    let mut backup = SnapshotWriter::new();
+    self.save_components(&mut backup);
+    if let Err(e) = self.load_components(&mut reader) {
+      let backup = backup.into_bytes();
+      self.load_components(&mut SnapshotReader::new(&backup))?;
+      return Err(e);
+    }
+    Ok(())
+  }
+
+  /// Save state file for a slot, stored next to the loaded rom
+  pub fn state_slot_path(&self, slot: u8) -> Option<PathBuf> {
+    let rom = self.cart.borrow().cart_path()?;
+    Some(rom.with_extension(format!("ss{}", slot)))
+  }
+
+  pub fn save_state_slot(&self, slot: u8) -> GbResult<PathBuf> {
+    let path = match self.state_slot_path(slot) {
+      Some(path) => path,
+      None => {
+        error!("Can not save state without a rom loaded");
+        return gb_err!(GbErrorType::NotInitialized);
+      }
+    };
+    if let Err(why) = fs::write(&path, self.save_state()) {
+      error!("Failed to write {}: {}", path.display(), why);
+      return gb_err!(GbErrorType::FileError);
+    }
+    Ok(path)
+  }
+
+  pub fn load_state_slot(&mut self, slot: u8) -> GbResult<PathBuf> {
+    let path = match self.state_slot_path(slot) {
+      Some(path) => path,
+      None => {
+        error!("Can not load state without a rom loaded");
+        return gb_err!(GbErrorType::NotInitialized);
+      }
+    };
+    let bytes = match fs::read(&path) {
+      Ok(bytes) => bytes,
+      Err(why) => {
+        error!("Failed to read {}: {}", path.display(), why);
+        return gb_err!(GbErrorType::FileError);
+      }
+    };
+    self.load_state(&bytes)?;
+    Ok(path)
+  }
+
+  fn save_components(&self, writer: &mut SnapshotWriter) {
+    self.cpu.borrow().save(writer);
+    self.ic.borrow().save(writer);
+    self.wram.borrow().save(writer);
+    self.hram.borrow().save(writer);
+    self.cart.borrow().save(writer);
+    self.ppu.borrow().save(writer);
+    self.timer.borrow().save(writer);
+    self.speed_switch.borrow().save(writer);
+    self.serial.borrow().save(writer);
+    self.apu.borrow().save(writer);
+    writer.write_u32(self.odd_cycle);
+  }
 
+  fn load_components(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    self.cpu.borrow_mut().load(reader)?;
+    self.ic.borrow_mut().load(reader)?;
+    self.wram.borrow_mut().load(reader)?;
+    self.hram.borrow_mut().load(reader)?;
+    // the cartridge has its own rom loading `load`
+    Snapshot::load(&mut *self.cart.borrow_mut(), reader)?;
+    self.ppu.borrow_mut().load(reader)?;
+    self.timer.borrow_mut().load(reader)?;
+    self.speed_switch.borrow_mut().load(reader)?;
+    self.serial.borrow_mut().load(reader)?;
+    self.apu.borrow_mut().load(reader)?;
+    self.odd_cycle = reader.read_u32()?;
     Ok(())
   }
 
-  pub fn step(&mut self) -> GbResult<()> {
+  /// Run the emulator for about `dots` ppu dots of wall clock time. Returns
+  /// how many dots were run, which can go over by the last instruction. While
+  /// paused only a requested single step runs.
+  pub fn run(&mut self, dots: u32) -> GbResult<u32> {
     if self.flow.paused && !self.flow.step {
       self.clock_rate = 0.0;
-      return Ok(());
+      self.frame_times.pause();
+      return Ok(0);
     }
 
     if self.flow.step {
+      self.flow.step = false;
       self.clock_rate = 0.0;
-      self.step_one()?;
-    } else {
-      self.step_chunk()?;
+      let (_, ran) = self.step_one_dots()?;
+      return Ok(ran);
     }
 
-    self.flow.step = false;
-    Ok(())
+    let mut ran = 0;
+    while ran < dots {
+      ran += self.step_one_dots()?.1;
+      if self.hit_run_to() || self.hit_breakpoint()? || self.flow.paused {
+        break;
+      }
+    }
+    // only show clock rate when we are doing work
+    self.clock_rate = self.cycles.tps();
+    Ok(ran)
+  }
+
+  /// Pause and clear the temporary breakpoint if the cpu is sitting on it
+  fn hit_run_to(&mut self) -> bool {
+    match self.flow.run_to {
+      Some(pc) if self.cpu.borrow().pc == pc => {
+        self.flow.run_to = None;
+        self.flow.paused = true;
+        true
+      }
+      _ => false,
+    }
+  }
+
+  /// Pause if the cpu is on a breakpoint whose condition holds
+  fn hit_breakpoint(&mut self) -> GbResult<bool> {
+    let cpu = self.cpu.borrow();
+    let bus = self.bus.borrow();
+    for breakpoint in &self.flow.breakpoints {
+      if breakpoint.hit(&cpu, |addr| bus.read8(addr))? {
+        info!("Hit breakpoint {}", breakpoint);
+        self.flow.paused = true;
+        return Ok(true);
+      }
+    }
+    Ok(false)
   }
 
-  fn step_chunk(&mut self) -> GbResult<()> {
-    // if we are running too fast, skip
-    let clock_rate = self.cycles.tps();
-    let target_pace = cpu::CLOCK_RATE * self.flow.speed;
-    if clock_rate > target_pace {
+  /// Run one frame of the fixed deterministic schedule. Frames are always
+  /// DOTS_PER_FRAME long, running over by the last instruction makes the next
+  /// frame that much shorter. A breakpoint or pause stops the frame part way,
+  /// the next call runs the rest of it.
+  pub fn run_fixed_frame(&mut self) -> GbResult<()> {
+    let mut ran = match self.fixed_ran.take() {
+      // the inputs for this frame are already in
+      Some(ran) => ran,
+      None => {
+        if let Some(replay) = &self.replay {
+          for scripted in replay.inputs_at(self.frame) {
+            Self::apply_input(
+              &mut self.joypad.borrow_mut(),
+              scripted.input,
+              scripted.pressed,
+            );
+          }
+        }
+        0
+      }
+    };
+    while ran + self.fixed_overrun < DOTS_PER_FRAME {
+      ran += self.step_one_dots()?.1;
+      if self.hit_run_to() || self.hit_breakpoint()? || self.flow.paused {
+        break;
+      }
+    }
+    if ran + self.fixed_overrun < DOTS_PER_FRAME {
+      self.fixed_ran = Some(ran);
       return Ok(());
     }
-    // only show clock rate when we are doing work
-    self.clock_rate = clock_rate;
+    self.fixed_overrun = ran + self.fixed_overrun - DOTS_PER_FRAME;
+    self.frame += 1;
+    self.cart.borrow_mut().set_rtc_time(self.rtc_time());
+    Ok(())
+  }
 
-    // how many steps in a chunk
-    const CHUNK_SIZE: u32 = 4;
+  /// Replay `script` for `frames` fixed frames, counting from the current
+  /// frame
+  pub fn run_script(&mut self, frames: u64, script: &[ScriptedInput]) -> GbResult<()> {
+    self.replay = Some(InputScript::from(script.to_vec()));
+    for _ in 0..frames {
+      self.run_fixed_frame()?;
+    }
+    Ok(())
+  }
 
-    for _ in 0..CHUNK_SIZE {
-      self.step_one()?;
+  /// Press or release a button for the player. The change is recorded while
+  /// recording and dropped while a replay is driving the joypad.
+  pub fn joypad_input(&mut self, input: JoypadInput, pressed: bool) {
+    if self.replay.is_some() {
+      return;
+    }
+    if let Some(recording) = &mut self.recording {
+      recording.record(self.frame, input, pressed);
     }
+    Self::apply_input(&mut self.joypad.borrow_mut(), input, pressed);
+  }
 
+  fn apply_input(joypad: &mut Joypad, input: JoypadInput, pressed: bool) {
+    if pressed {
+      joypad.set_input(input);
+    } else {
+      joypad.clear_input(input);
+    }
+  }
+
+  /// Run as fast as possible until the ppu finishes the current frame
+  pub fn step_frame(&mut self) -> GbResult<()> {
+    while !self.step_one()? {}
     Ok(())
   }
 
+  /// Step a single cpu instruction. Returns true when a new frame was finished.
+  #[inline]
+  fn step_one(&mut self) -> GbResult<bool> {
+    Ok(self.step_one_dots()?.0)
+  }
+
+  /// Step a single cpu instruction. Returns true when a new frame was
+  /// finished, along with the ppu dots the instruction took.
   #[inline]
-  fn step_one(&mut self) -> GbResult<()> {
+  fn step_one_dots(&mut self) -> GbResult<(bool, u32)> {
     let cycle_budget = self.cpu.borrow_mut().step()?;
     for _ in 0..cycle_budget {
       self.cycles.tick();
     }
-    if self.ppu.borrow_mut().step(cycle_budget)? {
+    // the ppu always runs at normal speed
+    let ppu_budget = self.ppu_cycles(cycle_budget);
+    let is_new_frame = self.ppu.borrow_mut().step(ppu_budget)?;
+    if is_new_frame {
       self.gb_fps.tick();
-      match &self.event_loop_proxy {
-        Some(elp) => elp.send_event(UserEvent::RequestRender).unwrap(),
-        None => panic!(),
+      self.frame_times.frame();
+      if let Some(on_frame) = &mut self.on_frame {
+        on_frame();
+      }
+    }
+    let interrupt = self.ic.borrow_mut().step()?;
+    if let Some(interrupt) = interrupt {
+      if self.flow.break_on_interrupt & interrupt as u8 > 0 {
+        info!("Break on {} interrupt", interrupt.name());
+        self.flow.paused = true;
       }
     }
-    self.ic.borrow_mut().step();
     self.timer.borrow_mut().step(cycle_budget);
+    self.serial.borrow_mut().step(cycle_budget);
+    // the apu runs at the same pace as the ppu
+    self.apu.borrow_mut().step(ppu_budget);
+    if is_new_frame {
+      self.audio.push(self.apu.borrow_mut().drain_samples());
+      self.apply_cheats()?;
+    }
+    Ok((is_new_frame, ppu_budget))
+  }
+
+  /// Hand the enabled rom cheats to the cartridge. Call this after changing
+  /// `cheats` so rom patches take effect right away.
+  pub fn sync_rom_patches(&mut self) {
+    self.cart.borrow_mut().patches = self
+      .cheats
+      .iter()
+      .filter_map(Cheat::rom_patch_entry)
+      .collect();
+  }
+
+  /// Write the ram cheats and sync the rom ones, so cheats switched on or
+  /// off in the ui take effect by the next frame at the latest
+  fn apply_cheats(&mut self) -> GbResult<()> {
+    self.sync_rom_patches();
+    let mut bus = self.bus.borrow_mut();
+    for cheat in &self.cheats {
+      cheat.apply(&mut bus)?;
+    }
     Ok(())
   }
+
+  /// Convert cpu cycles to ppu cycles for the current cpu speed. Timers are
+  /// clocked by the cpu so they use the cpu cycles directly.
+  fn ppu_cycles(&mut self, cpu_cycles: u32) -> u32 {
+    let factor = self.speed_switch.borrow().factor();
+    let total = cpu_cycles + self.odd_cycle;
+    self.odd_cycle = total % factor;
+    total / factor
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::int::Interrupt;
+  use crate::ram::SVBK_ADDR;
+  use crate::screen::Screen;
+
+  #[test]
+  fn test_double_speed_switch() {
+    let mut state = GbState::new(EmuFlow::new(false, false, 1.0));
+    state
+      .init(Rc::new(RefCell::new(Screen::headless())))
+      .unwrap();
+    assert_eq!(state.ppu_cycles(4), 4);
+    // a DMG has no KEY1
+    assert_eq!(state.bus.borrow().read8(0xff4d).unwrap(), 0xff);
+    state.set_cgb_mode(true);
+
+    // arm the switch and run STOP from wram
+    state.bus.borrow_mut().write8(0xff4d, 0x01).unwrap();
+    assert_eq!(state.bus.borrow().read8(0xff4d).unwrap(), 0x7f);
+    state.bus.borrow_mut().write8(0xc000, 0x10).unwrap();
+    state.bus.borrow_mut().write8(0xc001, 0x00).unwrap();
+    state.cpu.borrow_mut().pc = 0xc000;
+    state.step_one().unwrap();
+
+    assert!(state.speed_switch.borrow().double_speed);
+    assert!(!state.speed_switch.borrow().armed);
+    assert_eq!(state.bus.borrow().read8(0xff4d).unwrap(), 0xfe);
+    assert_eq!(state.cpu.borrow().pc, 0xc002);
+
+    // the ppu now gets half of the cpu cycles
+    assert_eq!(state.ppu_cycles(8), 4);
+    assert_eq!(state.ppu_cycles(12), 6);
+    assert_eq!(state.ppu_cycles(4), 2);
+  }
+
+  #[test]
+  fn test_speed_clamp() {
+    let mut flow = EmuFlow::new(false, false, 1.0);
+    flow.set_speed(20.0);
+    assert_eq!(flow.speed, MAX_SPEED);
+    flow.set_speed(0.0);
+    assert_eq!(flow.speed, MIN_SPEED);
+    flow.set_speed(1.5);
+    assert_eq!(flow.speed, 1.5);
+
+    flow.nudge_speed(1);
+    assert!((flow.speed - 1.6).abs() < 1e-6);
+    flow.nudge_speed(-100);
+    assert_eq!(flow.speed, MIN_SPEED);
+    flow.nudge_speed(100);
+    assert_eq!(flow.speed, MAX_SPEED);
+
+    // the menu presets below the slider range nudge back into it
+    flow.speed = 0.0001;
+    flow.nudge_speed(1);
+    assert!(flow.speed >= MIN_SPEED);
+  }
+
+  /// Deterministic state running a program that reads the buttons, sends
+  /// them over serial and writes them into the tile data the background shows
+  fn button_echo_state() -> (GbState, Rc<RefCell<Screen>>) {
+    #[rustfmt::skip]
+    let program = [
+      0x21, 0x00, 0x80, // ld hl, $8000
+      0xf0, 0x00,       // ldh a, (JOYP)
+      0xe0, 0x01,       // ldh (SB), a
+      0x77,             // ld (hl), a
+      0x2c,             // inc l
+      0x3e, 0x81,       // ld a, $81
+      0xe0, 0x02,       // ldh (SC), a
+      0xf0, 0x02,       // ldh a, (SC)
+      0x87,             // add a, a
+      0x38, 0xfb,       // jr c, -5 until the transfer is done
+      0x18, 0xef,       // jr -17
+    ];
+    let mut state = GbState::new(EmuFlow::new(false, false, 1.0));
+    state.flow.deterministic = true;
+    let screen = Rc::new(RefCell::new(Screen::headless()));
+    state.init(screen.clone()).unwrap();
+    let mut bus = state.bus.borrow_mut();
+    for (offset, byte) in program.iter().enumerate() {
+      bus.write8(0xc000 + offset as u16, *byte).unwrap();
+    }
+    // select the buttons, lcd on with tile data at $8000
+    bus.write8(0xff00, 0x10).unwrap();
+    bus.write8(0xff40, 0x91).unwrap();
+    bus.write8(0xff47, 0xe4).unwrap();
+    drop(bus);
+    state.cpu.borrow_mut().pc = 0xc000;
+    (state, screen)
+  }
+
+  #[test]
+  fn test_deterministic_runs_match() {
+    let script = [
+      ScriptedInput {
+        frame: 2,
+        input: JoypadInput::A,
+        pressed: true,
+      },
+      ScriptedInput {
+        frame: 5,
+        input: JoypadInput::A,
+        pressed: false,
+      },
+      ScriptedInput {
+        frame: 7,
+        input: JoypadInput::Start,
+        pressed: true,
+      },
+    ];
+    let run = |script: &[ScriptedInput]| {
+      let (mut state, screen) = button_echo_state();
+      state.run_script(10, script).unwrap();
+      let hash = screen.borrow().framebuffer_hash();
+      let output = state.serial.borrow().output.clone();
+      (hash, output, state.fixed_overrun)
+    };
+
+    let (hash, output, overrun) = run(&script);
+    assert!(!output.is_empty());
+    // the schedule doesn't drift by more than an instruction
+    assert!(overrun < 24);
+    assert_eq!(run(&script), (hash, output.clone(), overrun));
+
+    // the input made it into the run
+    let (_, unscripted_output, _) = run(&[]);
+    assert_ne!(unscripted_output, output);
+  }
+
+  #[test]
+  fn test_record_and_replay() {
+    // play a few frames by "hand" while recording
+    let (mut state, _) = button_echo_state();
+    state.recording = Some(InputScript::new());
+    let presses = [
+      (1, JoypadInput::B, true),
+      (3, JoypadInput::Select, true),
+      (3, JoypadInput::B, false),
+      (6, JoypadInput::Select, false),
+    ];
+    for frame in 0..8 {
+      for (_, input, pressed) in presses.iter().filter(|press| press.0 == frame) {
+        state.joypad_input(*input, *pressed);
+      }
+      state.run_fixed_frame().unwrap();
+    }
+    let recorded = state.save_state();
+
+    let path = std::env::temp_dir().join("gb_test_record_and_replay.txt");
+    state.recording.as_ref().unwrap().save(&path).unwrap();
+    let script = InputScript::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(script.inputs().len(), presses.len());
+
+    // replaying ends in the same state, live input is ignored
+    let (mut state, _) = button_echo_state();
+    state.replay = Some(script);
+    for _ in 0..8 {
+      state.joypad_input(JoypadInput::Start, true);
+      state.run_fixed_frame().unwrap();
+    }
+    assert!(state.save_state() == recorded);
+  }
+
+  #[test]
+  fn test_run_to() {
+    let mut state = GbState::new(EmuFlow::new(true, false, 1.0));
+    state
+      .init(Rc::new(RefCell::new(Screen::headless())))
+      .unwrap();
+    // wram is cleared to nops, stop part way into a run of 4 nops
+    state.cpu.borrow_mut().pc = 0xc000;
+    state.flow.run_to = Some(0xc006);
+    state.flow.paused = false;
+    for _ in 0..100 {
+      state.run(16).unwrap();
+    }
+    assert_eq!(state.cpu.borrow().pc, 0xc006);
+    assert!(state.flow.paused);
+    assert_eq!(state.flow.run_to, None);
+
+    // resuming runs past the old target
+    state.flow.paused = false;
+    assert!(state.run(16).unwrap() >= 16);
+    assert!(state.cpu.borrow().pc > 0xc006);
+    assert!(!state.flow.paused);
+  }
+
+  /// State spinning in wram with the timer interrupt enabled and the timer
+  /// ticking every 16 cycles. The handler returns straight away.
+  fn timer_spin_state(break_on_interrupt: u8) -> GbState {
+    let mut state = GbState::new(EmuFlow::new(false, false, 1.0));
+    state
+      .init(Rc::new(RefCell::new(Screen::headless())))
+      .unwrap();
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x50] = 0xd9; // reti
+    *state.cart.borrow_mut() = Cartridge::from_bytes(&rom).unwrap();
+    state.bus.borrow_mut().write8(0xff50, 0x01).unwrap();
+    #[rustfmt::skip]
+    let program = [
+      0x3e, 0x04,       // ld a, $04
+      0xea, 0xff, 0xff, // ld ($ffff), a
+      0x3e, 0x05,       // ld a, $05
+      0xe0, 0x07,       // ldh ($07), a
+      0xfb,             // ei
+      0x18, 0xfe,       // jr -2
+    ];
+    for (i, byte) in program.iter().enumerate() {
+      state
+        .bus
+        .borrow_mut()
+        .write8(0xc000 + i as u16, *byte)
+        .unwrap();
+    }
+    state.cpu.borrow_mut().pc = 0xc000;
+    state.cpu.borrow_mut().sp = 0xdff0;
+    state.flow.break_on_interrupt = break_on_interrupt;
+    state
+  }
+
+  #[test]
+  fn test_break_on_interrupt() {
+    // TIMA overflows after 256 ticks and the run stops on the handler
+    let mut state = timer_spin_state(Interrupt::Vblank as u8 | Interrupt::Timer as u8);
+    state.run(DOTS_PER_FRAME).unwrap();
+    assert!(state.flow.paused);
+    assert_eq!(state.cpu.borrow().pc, 0x0050);
+    let sp = state.cpu.borrow().sp;
+    assert_eq!(state.bus.borrow().read16(sp).unwrap(), 0xc00a);
+    assert_eq!(state.ic.borrow().pending() & Interrupt::Timer as u8, 0);
+
+    // without the break the handler runs like normal
+    let mut state = timer_spin_state(Interrupt::Vblank as u8);
+    assert!(state.run(DOTS_PER_FRAME).unwrap() >= DOTS_PER_FRAME);
+    assert!(!state.flow.paused);
+  }
+
+  #[test]
+  fn test_conditional_breakpoint() {
+    let mut state = GbState::new(EmuFlow::new(false, false, 1.0));
+    state
+      .init(Rc::new(RefCell::new(Screen::headless())))
+      .unwrap();
+    // inc a, jr -3
+    for (i, byte) in [0x3c, 0x18, 0xfd].iter().enumerate() {
+      state
+        .bus
+        .borrow_mut()
+        .write8(0xc000 + i as u16, *byte)
+        .unwrap();
+    }
+    state.cpu.borrow_mut().pc = 0xc000;
+    state.flow.breakpoints = vec!["c000 if A == 5".parse().unwrap()];
+
+    // the loop passes the breakpoint four times before it pauses
+    state.run(DOTS_PER_FRAME).unwrap();
+    assert!(state.flow.paused);
+    assert_eq!(state.cpu.borrow().pc, 0xc000);
+    assert_eq!(state.cpu.borrow().af.hi, 5);
+
+    // resuming goes around the loop without stopping again
+    state.flow.paused = false;
+    state.run(1000).unwrap();
+    assert!(!state.flow.paused);
+    assert!(state.cpu.borrow().af.hi > 5);
+  }
+
+  #[test]
+  fn test_breakpoint_stops_fixed_frame() {
+    let run = |breakpoint: Option<&str>| {
+      let mut state = GbState::new(EmuFlow::new(false, false, 1.0));
+      state
+        .init(Rc::new(RefCell::new(Screen::headless())))
+        .unwrap();
+      // inc a, jr -3
+      for (i, byte) in [0x3c, 0x18, 0xfd].iter().enumerate() {
+        state
+          .bus
+          .borrow_mut()
+          .write8(0xc000 + i as u16, *byte)
+          .unwrap();
+      }
+      state.cpu.borrow_mut().pc = 0xc000;
+      state.flow.breakpoints = breakpoint.iter().map(|b| b.parse().unwrap()).collect();
+      state
+    };
+
+    let mut state = run(Some("c000 if A == 5"));
+    state.run_fixed_frame().unwrap();
+    assert!(state.flow.paused);
+    assert_eq!(state.cpu.borrow().af.hi, 5);
+    assert_eq!(state.frame, 0);
+
+    // resuming runs the rest of the frame, so the schedule matches a run
+    // that never stopped
+    state.flow.paused = false;
+    state.flow.breakpoints.clear();
+    state.run_fixed_frame().unwrap();
+    let mut reference = run(None);
+    reference.run_fixed_frame().unwrap();
+    assert_eq!(state.frame, 1);
+    assert_eq!(state.fixed_overrun, reference.fixed_overrun);
+    assert_eq!(state.cpu.borrow().pc, reference.cpu.borrow().pc);
+    assert_eq!(state.cpu.borrow().af.hi, reference.cpu.borrow().af.hi);
+  }
+
+  #[test]
+  fn test_frozen_cheat() {
+    let mut state = GbState::new(EmuFlow::new(false, false, 1.0));
+    state
+      .init(Rc::new(RefCell::new(Screen::headless())))
+      .unwrap();
+    // turn the lcd on so frames finish, then keep writing 9 lives
+    #[rustfmt::skip]
+    let program = [
+      0x3e, 0x80,       // ld a, $80
+      0xe0, 0x40,       // ldh ($40), a
+      0x3e, 0x09,       // ld a, $09
+      0xea, 0xa0, 0xc0, // ld ($c0a0), a
+      0x18, 0xfb,       // jr -5
+    ];
+    for (i, byte) in program.iter().enumerate() {
+      state
+        .bus
+        .borrow_mut()
+        .write8(0xc000 + i as u16, *byte)
+        .unwrap();
+    }
+    state.cpu.borrow_mut().pc = 0xc000;
+    state.cheats.push(Cheat::freeze(0xc0a0, 0x63).unwrap());
+
+    for _ in 0..3 {
+      state.step_frame().unwrap();
+      assert_eq!(state.bus.borrow().read8(0xc0a0).unwrap(), 0x63);
+    }
+
+    // switched off the game's writes stick
+    state.cheats[0].enabled = false;
+    state.step_frame().unwrap();
+    assert_eq!(state.bus.borrow().read8(0xc0a0).unwrap(), 0x09);
+
+    // the same freeze as a GameShark code
+    state.cheats[0] = "0163A0C0".parse().unwrap();
+    state.step_frame().unwrap();
+    assert_eq!(state.bus.borrow().read8(0xc0a0).unwrap(), 0x63);
+  }
+
+  #[test]
+  fn test_game_genie_patch() {
+    let mut state = GbState::new(EmuFlow::new(false, false, 1.0));
+    state
+      .init(Rc::new(RefCell::new(Screen::headless())))
+      .unwrap();
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x4a17] = 0xc8;
+    rom[0x4a18] = 0x11;
+    rom[0x5f29] = 0x22;
+    *state.cart.borrow_mut() = Cartridge::from_bytes(&rom).unwrap();
+    state.bus.borrow_mut().write8(0xff50, 0x01).unwrap();
+    // spin in wram so frames finish without touching the rom
+    #[rustfmt::skip]
+    let program = [
+      0x3e, 0x80, // ld a, $80
+      0xe0, 0x40, // ldh ($40), a
+      0x18, 0xfe, // jr -2
+    ];
+    for (i, byte) in program.iter().enumerate() {
+      state
+        .bus
+        .borrow_mut()
+        .write8(0xc000 + i as u16, *byte)
+        .unwrap();
+    }
+    state.cpu.borrow_mut().pc = 0xc000;
+    for code in ["00A-17B-C49", "3EF-29A", "99A-18B-C49"] {
+      state.cheats.push(code.parse().unwrap());
+    }
+    state.step_frame().unwrap();
+
+    let read = |addr| state.bus.borrow().read8(addr).unwrap();
+    assert_eq!(read(0x4a17), 0x00);
+    assert_eq!(read(0x5f29), 0x3e);
+    // the compare byte doesn't match what the rom holds there
+    assert_eq!(read(0x4a18), 0x11);
+
+    // changes take effect as soon as the list is synced, not just at the end
+    // of the next frame
+    state.cheats[0].enabled = false;
+    state.sync_rom_patches();
+    assert_eq!(state.bus.borrow().read8(0x4a17).unwrap(), 0xc8);
+  }
+
+  #[test]
+  fn test_ei_delay() {
+    let run = |program: &[u8]| {
+      let mut state = GbState::new(EmuFlow::new(false, false, 1.0));
+      state
+        .init(Rc::new(RefCell::new(Screen::headless())))
+        .unwrap();
+      for (offset, byte) in program.iter().enumerate() {
+        state
+          .bus
+          .borrow_mut()
+          .write8(0xc000 + offset as u16, *byte)
+          .unwrap();
+      }
+      // vblank is already pending when EI runs
+      state.bus.borrow_mut().write8(0xffff, 0x01).unwrap();
+      state.ic.borrow_mut().raise(Interrupt::Vblank);
+      state.cpu.borrow_mut().sp = 0xdff0;
+      state.cpu.borrow_mut().pc = 0xc000;
+      state
+    };
+
+    // ei, nop: the nop runs before the handler
+    let mut state = run(&[0xfb, 0x00, 0x00]);
+    state.step_one().unwrap();
+    assert_eq!(state.cpu.borrow().pc, 0xc001);
+    state.step_one().unwrap();
+    assert_eq!(state.cpu.borrow().pc, 0x0040);
+    // returns past the nop
+    assert_eq!(state.bus.borrow().read16(0xdfee).unwrap(), 0xc002);
+
+    // ei, di: the interrupt never fires
+    let mut state = run(&[0xfb, 0xf3, 0x00]);
+    state.step_one().unwrap();
+    state.step_one().unwrap();
+    state.step_one().unwrap();
+    assert_eq!(state.cpu.borrow().pc, 0xc003);
+
+    // ei with ime already on: the interrupt fires right after it and the
+    // handler runs with ime off
+    let mut state = run(&[0xfb, 0x00, 0x00]);
+    state.cpu.borrow_mut().ime = true;
+    state.step_one().unwrap();
+    assert_eq!(state.cpu.borrow().pc, 0x0040);
+    state.step_one().unwrap();
+    assert!(!state.cpu.borrow().ime);
+  }
+
+  #[test]
+  fn test_soft_reset() {
+    let mut state = GbState::new(EmuFlow::new(false, false, 1.0));
+    state
+      .init(Rc::new(RefCell::new(Screen::headless())))
+      .unwrap();
+    // 128KiB mbc1 rom with ram where each bank starts with its number
+    let mut rom = vec![0u8; 0x20000];
+    rom[0x147] = 0x02;
+    rom[0x148] = 0x02;
+    rom[0x149] = 0x02;
+    for bank in 0..8 {
+      rom[bank * 0x4000] = bank as u8;
+    }
+    rom[0x150] = 0x3c;
+    *state.cart.borrow_mut() = Cartridge::from_bytes(&rom).unwrap();
+
+    // run a bit and leave some state behind everywhere
+    let bus = state.bus.clone();
+    bus.borrow_mut().write8(0xff50, 0x01).unwrap();
+    bus.borrow_mut().write8(0x0000, 0x0a).unwrap();
+    bus.borrow_mut().write8(0xa000, 0x5a).unwrap();
+    bus.borrow_mut().write8(0x2000, 0x03).unwrap();
+    bus.borrow_mut().write8(0xc000, 0x42).unwrap();
+    state.cpu.borrow_mut().pc = 0xc001;
+    for _ in 0..100 {
+      state.step_one().unwrap();
+    }
+
+    state.soft_reset().unwrap();
+    assert_eq!(state.cpu.borrow().pc, 0);
+    assert_eq!(state.cpu.borrow().sp, 0);
+    assert_eq!(state.ppu.borrow().ly, 0);
+    assert_eq!(bus.borrow().read8(0xc000).unwrap(), 0);
+    assert!(state.cart.borrow().loaded);
+    assert!(state.cart.borrow().boot_mode);
+
+    // rom is still there with the banks back at power-up
+    bus.borrow_mut().write8(0xff50, 0x01).unwrap();
+    assert_eq!(bus.borrow().read8(0x0150).unwrap(), 0x3c);
+    assert_eq!(bus.borrow().read8(0x4000).unwrap(), 1);
+
+    // ram is disabled but keeps its contents
+    assert_eq!(bus.borrow().read8(0xa000).unwrap(), 0xff);
+    bus.borrow_mut().write8(0x0000, 0x0a).unwrap();
+    assert_eq!(bus.borrow().read8(0xa000).unwrap(), 0x5a);
+  }
+
+  #[test]
+  fn test_reset_keeps_connections() {
+    let mut state = GbState::new(EmuFlow::new(false, false, 1.0));
+    state
+      .init(Rc::new(RefCell::new(Screen::headless())))
+      .unwrap();
+    state.set_cgb_mode(true);
+    state.bus.borrow_mut().write8(0xc000, 0x42).unwrap();
+    state.bus.borrow_mut().write8(0xff4d, 0x01).unwrap();
+    state.bus.borrow_mut().write8(0xff06, 0x12).unwrap();
+    state.cpu.borrow_mut().pc = 0xc000;
+    for _ in 0..100 {
+      state.step_one().unwrap();
+    }
+
+    state.reset().unwrap();
+    assert_eq!(state.cpu.borrow().pc, 0);
+    assert_eq!(state.ppu.borrow().ly, 0);
+    let bus = state.bus.clone();
+    assert_eq!(bus.borrow().read8(0xc000).unwrap(), 0);
+    assert_eq!(bus.borrow().read8(0xff06).unwrap(), 0);
+    // no cart left, so back to a DMG without KEY1
+    assert_eq!(bus.borrow().read8(0xff4d).unwrap(), 0xff);
+    assert!(!state.cgb_mode());
+
+    // the new cpu and interrupt controller are wired up
+    bus.borrow_mut().write8(0xffff, 0x01).unwrap();
+    state.cpu.borrow_mut().ime = true;
+    state.cpu.borrow_mut().sp = 0xdff0;
+    state.ic.borrow_mut().raise(Interrupt::Vblank);
+    state.cpu.borrow_mut().pc = 0xc000;
+    state.step_one().unwrap();
+    assert_eq!(state.cpu.borrow().pc, 0x0040);
+  }
+
+  #[test]
+  fn test_cgb_only_cart() {
+    let mut state = GbState::new(EmuFlow::new(false, false, 1.0));
+    state
+      .init(Rc::new(RefCell::new(Screen::headless())))
+      .unwrap();
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x134..0x138].copy_from_slice(b"TEST");
+    rom[0x143] = 0xc0;
+    *state.cart.borrow_mut() = Cartridge::from_bytes(&rom).unwrap();
+    state.soft_reset().unwrap();
+    assert!(matches!(
+      state.cart.borrow().header.gbc_support,
+      GBCSupport::GBCOnly
+    ));
+    assert!(state.cgb_mode());
+    assert!(state.ppu.borrow().cgb_mode);
+    assert_eq!(state.bus.borrow().read8(0xff4d).unwrap(), 0x7e);
+    assert_eq!(state.bus.borrow().read8(SVBK_ADDR).unwrap(), 0xf9);
+    assert!(state.model_warning().is_none());
+
+    // forced to a DMG the cart still loads, with a warning
+    state.force_dmg = true;
+    state.soft_reset().unwrap();
+    assert!(!state.cgb_mode());
+    assert_eq!(state.bus.borrow().read8(0xff4d).unwrap(), 0xff);
+    assert_eq!(state.bus.borrow().read8(SVBK_ADDR).unwrap(), 0xff);
+    assert_eq!(
+      state.model_warning().unwrap(),
+      "TEST only runs on a CGB, it may not work as a DMG"
+    );
+  }
 }
@@ -1,6 +1,16 @@
 use log::{error, info};
+use std::collections::VecDeque;
+use std::hint;
+use std::thread;
 use std::time::{Duration, Instant};
 
+/// Longest stretch of wall clock time the emulator catches up on after
+/// falling behind
+pub const MAX_CATCH_UP: Duration = Duration::from_millis(50);
+
+// sleeping can overshoot by about this much, so spin for the rest
+const SPIN_MARGIN: Duration = Duration::from_millis(1);
+
 pub struct TickCounter {
   ticks: u64,
   avg_tps: f32,
@@ -42,3 +52,246 @@ impl TickCounter {
     self.avg_tps
   }
 }
+
+/// Durations of the most recent frames
+pub struct FrameTimes {
+  durations: VecDeque<Duration>,
+  cap: usize,
+  // end of the last frame, None until the first frame or after a pause
+  last_frame: Option<Instant>,
+}
+
+impl FrameTimes {
+  pub fn new(cap: usize) -> FrameTimes {
+    FrameTimes {
+      durations: VecDeque::with_capacity(cap),
+      cap,
+      last_frame: None,
+    }
+  }
+
+  /// Mark the end of a frame
+  pub fn frame(&mut self) {
+    let now = Instant::now();
+    if let Some(last) = self.last_frame {
+      self.record(now - last);
+    }
+    self.last_frame = Some(now);
+  }
+
+  /// Forget when the last frame ended so time spent paused is not counted
+  pub fn pause(&mut self) {
+    self.last_frame = None;
+  }
+
+  pub fn record(&mut self, duration: Duration) {
+    if self.durations.len() == self.cap {
+      self.durations.pop_front();
+    }
+    self.durations.push_back(duration);
+  }
+
+  /// Recorded frames from oldest to newest
+  pub fn iter(&self) -> impl Iterator<Item = Duration> + '_ {
+    self.durations.iter().copied()
+  }
+
+  /// Most frames kept at once
+  pub fn cap(&self) -> usize {
+    self.cap
+  }
+
+  pub fn len(&self) -> usize {
+    self.durations.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.durations.is_empty()
+  }
+
+  pub fn min(&self) -> Option<Duration> {
+    self.durations.iter().min().copied()
+  }
+
+  pub fn max(&self) -> Option<Duration> {
+    self.durations.iter().max().copied()
+  }
+
+  pub fn avg(&self) -> Option<Duration> {
+    if self.durations.is_empty() {
+      return None;
+    }
+    let total: Duration = self.durations.iter().sum();
+    Some(total / self.durations.len() as u32)
+  }
+}
+
+/// Turns wall clock time into cycle budgets for the emulator. Time that was
+/// not run yet carries over, so a late frame is caught up on the next call.
+pub struct CycleClock {
+  rate: f64,
+  last: Instant,
+  // cycles owed, negative after running past the budget
+  credit: f64,
+}
+
+impl CycleClock {
+  pub fn new(rate: f64) -> CycleClock {
+    CycleClock {
+      rate,
+      last: Instant::now(),
+      credit: 0.0,
+    }
+  }
+
+  /// Cycles owed for the time up to `now` at the given speed. Falling more
+  /// than MAX_CATCH_UP behind is forgotten, so a host that can't keep up
+  /// doesn't fall further behind trying.
+  pub fn advance(&mut self, now: Instant, speed: f32) -> u32 {
+    self.advance_for(now, speed, 0)
+  }
+
+  /// Like `advance`, for a caller that only runs whole chunks of `chunk`
+  /// cycles. The catch up limit never drops below one chunk, otherwise a slow
+  /// enough speed would never owe a whole one.
+  pub fn advance_for(&mut self, now: Instant, speed: f32, chunk: u32) -> u32 {
+    let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+    self.last = now;
+    let cycles_per_sec = self.rate * speed as f64;
+    let max_credit = (MAX_CATCH_UP.as_secs_f64() * cycles_per_sec).max(chunk as f64);
+    self.credit = (self.credit + elapsed * cycles_per_sec).min(max_credit);
+    self.credit.max(0.0) as u32
+  }
+
+  /// Take the cycles that were run out of the budget. Running over is paid
+  /// back by the next budget.
+  pub fn spend(&mut self, cycles: u32) {
+    self.credit -= cycles as f64;
+  }
+
+  /// When `cycles` will be owed at the given speed, going by the last
+  /// advance
+  pub fn due(&self, speed: f32, cycles: u32) -> Instant {
+    let missing = (cycles as f64 - self.credit).max(0.0);
+    self.last + Duration::from_secs_f64(missing / (self.rate * speed as f64))
+  }
+
+  /// Start over from `now` with nothing owed, like after a pause
+  pub fn resync(&mut self, now: Instant) {
+    self.last = now;
+    self.credit = 0.0;
+  }
+}
+
+/// Block until `deadline`, but no longer than `max_wait` so the caller stays
+/// responsive. Waiting against a deadline instead of for a fixed time keeps
+/// sleep overshoot from adding up.
+pub fn wait_until(deadline: Instant, max_wait: Duration) {
+  let now = Instant::now();
+  if now >= deadline {
+    return;
+  }
+  let remaining = deadline - now;
+  if remaining > max_wait {
+    thread::sleep(max_wait);
+    return;
+  }
+  if remaining > SPIN_MARGIN {
+    thread::sleep(remaining - SPIN_MARGIN);
+  }
+  while Instant::now() < deadline {
+    hint::spin_loop();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_frame_times() {
+    let mut times = FrameTimes::new(3);
+    assert_eq!(times.avg(), None);
+    for ms in [10, 30, 20] {
+      times.record(Duration::from_millis(ms));
+    }
+    assert_eq!(times.min(), Some(Duration::from_millis(10)));
+    assert_eq!(times.max(), Some(Duration::from_millis(30)));
+    assert_eq!(times.avg(), Some(Duration::from_millis(20)));
+
+    // only the most recent frames count
+    times.record(Duration::from_millis(50));
+    assert_eq!(times.min(), Some(Duration::from_millis(20)));
+    assert_eq!(times.max(), Some(Duration::from_millis(50)));
+    assert_eq!(times.avg(), Some(Duration::from_millis(100) / 3));
+    let ms: Vec<u128> = times.iter().map(|d| d.as_millis()).collect();
+    assert_eq!(ms, [30, 20, 50]);
+  }
+
+  #[test]
+  fn test_cycle_clock() {
+    let rate = 1000.0;
+    let start = Instant::now();
+    let mut clock = CycleClock::new(rate);
+    clock.resync(start);
+
+    // 10ms is 10 cycles, 2x speed runs twice as many
+    assert_eq!(clock.advance(start + Duration::from_millis(10), 1.0), 10);
+    clock.spend(10);
+    assert_eq!(clock.advance(start + Duration::from_millis(20), 2.0), 20);
+
+    // leftovers carry over and overshooting is paid back
+    clock.spend(15);
+    assert_eq!(clock.advance(start + Duration::from_millis(20), 1.0), 5);
+    clock.spend(8);
+    assert_eq!(clock.advance(start + Duration::from_millis(30), 1.0), 7);
+
+    // 3 more cycles are owed 3ms after the last advance, sooner when faster
+    let last = start + Duration::from_millis(30);
+    assert_eq!(clock.due(1.0, 10), last + Duration::from_millis(3));
+    assert_eq!(clock.due(3.0, 10), last + Duration::from_millis(1));
+    assert_eq!(clock.due(1.0, 5), last);
+  }
+
+  #[test]
+  fn test_wait_until() {
+    // a close deadline is met without oversleeping much
+    let deadline = Instant::now() + Duration::from_millis(5);
+    wait_until(deadline, Duration::from_millis(10));
+    let late = Instant::now() - deadline;
+    assert!(late < Duration::from_millis(10), "{:?} late", late);
+
+    // a far one only waits as long as allowed
+    let start = Instant::now();
+    wait_until(start + Duration::from_secs(10), Duration::from_millis(5));
+    let waited = start.elapsed();
+    assert!(waited >= Duration::from_millis(5), "{:?}", waited);
+    assert!(waited < Duration::from_secs(1), "{:?}", waited);
+  }
+
+  #[test]
+  fn test_cycle_clock_catch_up_clamp() {
+    let rate = 1000.0;
+    let max_cycles = (MAX_CATCH_UP.as_secs_f64() * rate) as u32;
+    let start = Instant::now();
+    let mut clock = CycleClock::new(rate);
+    clock.resync(start);
+
+    // a long stall only catches up on MAX_CATCH_UP
+    let now = start + Duration::from_secs(10);
+    assert_eq!(clock.advance(now, 1.0), max_cycles);
+    // not running any of it doesn't keep piling up
+    let now = now + Duration::from_secs(10);
+    assert_eq!(clock.advance(now, 1.0), max_cycles);
+    assert_eq!(clock.advance(now, 4.0), max_cycles);
+
+    // a chunk bigger than the limit can still be owed in full
+    clock.resync(now);
+    let now = now + Duration::from_secs(10);
+    assert_eq!(clock.advance_for(now, 1.0, max_cycles * 3), max_cycles * 3);
+
+    // time going backwards owes nothing
+    clock.resync(now);
+    assert_eq!(clock.advance(start, 1.0), 0);
+  }
+}
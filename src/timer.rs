@@ -1,17 +1,30 @@
 //! Timer for the Gameboy system.
 
+use crate::apu::Apu;
 use crate::err::{GbError, GbErrorType, GbResult};
 use crate::int::{Interrupt, Interrupts};
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+use crate::speed::SpeedSwitch;
 use crate::util::LazyDref;
 use crate::{cpu, gb_err};
 use log::error;
 use std::cell::RefCell;
 use std::rc::Rc;
 
-const DIV_ADDR: u16 = 0xff04;
-const TIMA_ADDR: u16 = 0xff05;
-const TMA_ADDR: u16 = 0xff06;
-const TAC_ADDR: u16 = 0xff07;
+pub const DIV_ADDR: u16 = 0xff04;
+pub const TIMA_ADDR: u16 = 0xff05;
+pub const TMA_ADDR: u16 = 0xff06;
+pub const TAC_ADDR: u16 = 0xff07;
+
+// cycles between TIMA overflowing and TMA being loaded, and how long the
+// reload takes effect afterwards
+const RELOAD_DELAY: u8 = 4;
+
+// the apu frame sequencer steps on the falling edge of DIV bit 4, which is
+// this bit of the counter behind DIV. In double speed the CGB uses DIV bit 5
+// so the sequencer keeps its rate.
+const FRAME_SEQUENCER_BIT: u32 = 1 << 12;
+const FRAME_SEQUENCER_BIT_DOUBLE_SPEED: u32 = 1 << 13;
 
 #[derive(Copy, Clone)]
 pub enum ClockRate {
@@ -79,9 +92,18 @@ pub struct Timer {
 
   /// interrupt controller handle
   ic: Option<Rc<RefCell<Interrupts>>>,
+  /// apu handle for the frame sequencer, the timer runs fine without one
+  apu: Option<Rc<RefCell<Apu>>>,
+  /// speed switch handle, without one the timer stays at normal speed
+  speed_switch: Option<Rc<RefCell<SpeedSwitch>>>,
 
   /// keep track of cpu ticks
   master_clock: u32,
+  /// cycles left until an overflowed TIMA is reloaded from TMA
+  overflow_delay: u8,
+  /// cycles left in which TIMA was just reloaded. TIMA writes are ignored and
+  /// TMA writes also go to TIMA.
+  reload_window: u8,
 }
 
 impl Timer {
@@ -92,10 +114,26 @@ impl Timer {
       tma: 0,
       tac: Tac::from(0),
       ic: None,
+      apu: None,
+      speed_switch: None,
       master_clock: 0,
+      overflow_delay: 0,
+      reload_window: 0,
     }
   }
 
+  /// Return the registers to their power-up values. The interrupt controller
+  /// and apu stay connected.
+  pub fn reset(&mut self) {
+    self.div = 0;
+    self.tima = 0;
+    self.tma = 0;
+    self.tac = Tac::from(0);
+    self.master_clock = 0;
+    self.overflow_delay = 0;
+    self.reload_window = 0;
+  }
+
   /// Adds a reference to the interrupt controller to the timer
   pub fn connect_ic(&mut self, ic: Rc<RefCell<Interrupts>>) -> GbResult<()> {
     match self.ic {
@@ -105,20 +143,64 @@ impl Timer {
     Ok(())
   }
 
+  /// Adds a reference to the apu so DIV can drive its frame sequencer
+  pub fn connect_apu(&mut self, apu: Rc<RefCell<Apu>>) -> GbResult<()> {
+    match self.apu {
+      None => self.apu = Some(apu),
+      Some(_) => return gb_err!(GbErrorType::AlreadyInitialized),
+    }
+    Ok(())
+  }
+
+  /// Adds a reference to the speed switch, the frame sequencer runs off a
+  /// different DIV bit in double speed
+  pub fn connect_speed_switch(&mut self, speed_switch: Rc<RefCell<SpeedSwitch>>) -> GbResult<()> {
+    match self.speed_switch {
+      None => self.speed_switch = Some(speed_switch),
+      Some(_) => return gb_err!(GbErrorType::AlreadyInitialized),
+    }
+    Ok(())
+  }
+
   /// Step the timer. Will tick as many times as budget allows.
   pub fn step(&mut self, cycle_budget: u32) {
+    // the speed only changes on STOP, never in the middle of a step
+    let sequencer_bit = self.frame_sequencer_bit();
     for cycle in 0..cycle_budget {
-      self.step_one();
+      self.step_one(sequencer_bit);
+    }
+  }
+
+  fn frame_sequencer_bit(&self) -> u32 {
+    let double_speed = self
+      .speed_switch
+      .as_ref()
+      .is_some_and(|speed_switch| speed_switch.borrow().double_speed);
+    if double_speed {
+      FRAME_SEQUENCER_BIT_DOUBLE_SPEED
+    } else {
+      FRAME_SEQUENCER_BIT
     }
   }
 
-  fn step_one(&mut self) {
+  fn step_one(&mut self, sequencer_bit: u32) {
     self.master_clock = self.master_clock.wrapping_add(1);
 
+    self.reload_window = self.reload_window.saturating_sub(1);
+    if self.overflow_delay > 0 {
+      self.overflow_delay -= 1;
+      if self.overflow_delay == 0 {
+        self.reload();
+      }
+    }
+
     // DIV clock rate is always Div256
     if self.master_clock % ClockRate::Div256.as_div() == 0 {
       self.div = self.div.wrapping_add(1);
     }
+    if self.master_clock.is_multiple_of(sequencer_bit * 2) {
+      self.clock_frame_sequencer();
+    }
 
     // TIMA checks
     if self.tac.enable && self.master_clock % self.tac.clock_rate.as_div() == 0 {
@@ -126,16 +208,45 @@ impl Timer {
     }
   }
 
-  /// Increment the TIMA register. If overflow occurs, reset to TMA register
-  /// value.
+  /// Increment the TIMA register. On overflow TIMA stays 0 for a few cycles
+  /// before it is reloaded from TMA.
   fn tick(&mut self) {
     self.tima = self.tima.wrapping_add(1);
     if self.tima == 0 {
-      self.ic.lazy_dref_mut().raise(Interrupt::Timer);
-      self.tima = self.tma;
+      self.overflow_delay = RELOAD_DELAY;
     }
   }
 
+  /// Load TMA into an overflowed TIMA and request the timer interrupt
+  fn reload(&mut self) {
+    self.ic.lazy_dref_mut().raise(Interrupt::Timer);
+    self.tima = self.tma;
+    self.reload_window = RELOAD_DELAY;
+  }
+
+  fn clock_frame_sequencer(&mut self) {
+    if let Some(apu) = &self.apu {
+      apu.borrow_mut().clock_frame_sequencer();
+    }
+  }
+
+  /// Clear DIV and the internal counter behind it. TIMA and the frame
+  /// sequencer count on the falling edge of a counter bit, so clearing it
+  /// while that bit is set clocks them once.
+  fn reset_div(&mut self) {
+    // TIMA ticks when the counter passes a multiple of the clock rate, which
+    // is the falling edge of the bit just below it
+    let tima_bit = self.tac.clock_rate.as_div() / 2;
+    if self.tac.enable && self.master_clock & tima_bit != 0 {
+      self.tick();
+    }
+    if self.master_clock & self.frame_sequencer_bit() != 0 {
+      self.clock_frame_sequencer();
+    }
+    self.master_clock = 0;
+    self.div = 0;
+  }
+
   pub fn read(&self, addr: u16) -> GbResult<u8> {
     match addr {
       DIV_ADDR => Ok(self.div),
@@ -152,9 +263,20 @@ impl Timer {
   pub fn write(&mut self, addr: u16, data: u8) -> GbResult<()> {
     match addr {
       // writing any value to DIV resets to 0
-      DIV_ADDR => self.div = 0,
-      TIMA_ADDR => self.tima = data,
-      TMA_ADDR => self.tma = data,
+      DIV_ADDR => self.reset_div(),
+      // the reload wins over writes right after it
+      TIMA_ADDR if self.reload_window > 0 => {}
+      TIMA_ADDR => {
+        // writing before the reload cancels it along with the interrupt
+        self.overflow_delay = 0;
+        self.tima = data;
+      }
+      TMA_ADDR => {
+        self.tma = data;
+        if self.reload_window > 0 {
+          self.tima = data;
+        }
+      }
       TAC_ADDR => self.tac = Tac::from(data),
       _ => {
         error!("Unknown write: 0x{:02X} -> ${:04X}", data, addr);
@@ -164,3 +286,273 @@ impl Timer {
     Ok(())
   }
 }
+
+impl Default for Timer {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// The interrupt controller and apu handles are not part of the snapshot
+impl Snapshot for Timer {
+  fn save(&self, writer: &mut SnapshotWriter) {
+    writer.write_u8(self.div);
+    writer.write_u8(self.tima);
+    writer.write_u8(self.tma);
+    writer.write_u8(self.tac.into());
+    writer.write_u32(self.master_clock);
+    writer.write_u8(self.overflow_delay);
+    writer.write_u8(self.reload_window);
+  }
+
+  fn load(&mut self, reader: &mut SnapshotReader) -> GbResult<()> {
+    self.div = reader.read_u8()?;
+    self.tima = reader.read_u8()?;
+    self.tma = reader.read_u8()?;
+    self.tac = Tac::from(reader.read_u8()? & 0x7);
+    self.master_clock = reader.read_u32()?;
+    self.overflow_delay = reader.read_u8()?;
+    self.reload_window = reader.read_u8()?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_reset() {
+    let mut timer = Timer::new();
+    timer
+      .connect_ic(Rc::new(RefCell::new(Interrupts::new())))
+      .unwrap();
+    timer.write(TIMA_ADDR, 0x12).unwrap();
+    timer.write(TMA_ADDR, 0x34).unwrap();
+    timer.write(TAC_ADDR, 0x05).unwrap();
+    timer.step(1000);
+    assert_ne!(timer.read(DIV_ADDR).unwrap(), 0);
+
+    timer.reset();
+    for addr in [DIV_ADDR, TIMA_ADDR, TMA_ADDR, TAC_ADDR] {
+      assert_eq!(timer.read(addr).unwrap(), 0, "${:04X}", addr);
+    }
+
+    // the divider starts counting from scratch
+    timer.step(255);
+    assert_eq!(timer.read(DIV_ADDR).unwrap(), 0);
+    timer.step(1);
+    assert_eq!(timer.read(DIV_ADDR).unwrap(), 1);
+  }
+
+  #[test]
+  fn test_div_write_falling_edge() {
+    let mut timer = Timer::new();
+    timer
+      .connect_ic(Rc::new(RefCell::new(Interrupts::new())))
+      .unwrap();
+    // 16 cycles per tick, fed by bit 3 of the counter
+    timer.write(TAC_ADDR, 0x05).unwrap();
+
+    // bit 3 is low, no extra tick
+    timer.step(4);
+    timer.write(DIV_ADDR, 0).unwrap();
+    assert_eq!(timer.read(TIMA_ADDR).unwrap(), 0);
+
+    // bit 3 is high, resetting the counter ticks TIMA
+    timer.step(8);
+    timer.write(DIV_ADDR, 0).unwrap();
+    assert_eq!(timer.read(TIMA_ADDR).unwrap(), 1);
+
+    // the counter starts over, so the next tick is a full period away
+    timer.step(15);
+    assert_eq!(timer.read(TIMA_ADDR).unwrap(), 1);
+    timer.step(1);
+    assert_eq!(timer.read(TIMA_ADDR).unwrap(), 2);
+
+    // no extra tick while the timer is stopped
+    timer.write(TAC_ADDR, 0x01).unwrap();
+    timer.step(8);
+    timer.write(DIV_ADDR, 0).unwrap();
+    assert_eq!(timer.read(TIMA_ADDR).unwrap(), 2);
+  }
+
+  /// Timer at 16 cycles per tick with TMA = $10 that has just overflowed
+  fn overflowed_timer() -> (Timer, Rc<RefCell<Interrupts>>) {
+    let ic = Rc::new(RefCell::new(Interrupts::new()));
+    let mut timer = Timer::new();
+    timer.connect_ic(ic.clone()).unwrap();
+    timer.write(TMA_ADDR, 0x10).unwrap();
+    timer.write(TIMA_ADDR, 0xff).unwrap();
+    timer.write(TAC_ADDR, 0x05).unwrap();
+    timer.step(16);
+    (timer, ic)
+  }
+
+  #[test]
+  fn test_overflow_reload_delay() {
+    let (mut timer, ic) = overflowed_timer();
+    // TIMA reads 0 until the reload
+    timer.step(3);
+    assert_eq!(timer.read(TIMA_ADDR).unwrap(), 0);
+    assert_eq!(ic.borrow().pending(), 0);
+    timer.step(1);
+    assert_eq!(timer.read(TIMA_ADDR).unwrap(), 0x10);
+    assert_eq!(ic.borrow().pending(), Interrupt::Timer as u8);
+
+    // writing TIMA before the reload cancels it
+    let (mut timer, ic) = overflowed_timer();
+    timer.step(2);
+    timer.write(TIMA_ADDR, 0x55).unwrap();
+    timer.step(4);
+    assert_eq!(timer.read(TIMA_ADDR).unwrap(), 0x55);
+    assert_eq!(ic.borrow().pending(), 0);
+  }
+
+  #[test]
+  fn test_tma_write_during_reload() {
+    let (mut timer, _ic) = overflowed_timer();
+    timer.step(4);
+    // TMA written right after the reload is loaded into TIMA as well
+    timer.write(TMA_ADDR, 0x20).unwrap();
+    assert_eq!(timer.read(TIMA_ADDR).unwrap(), 0x20);
+    // once the window passes only TMA changes
+    timer.step(4);
+    timer.write(TMA_ADDR, 0x30).unwrap();
+    assert_eq!(timer.read(TIMA_ADDR).unwrap(), 0x20);
+    assert_eq!(timer.read(TMA_ADDR).unwrap(), 0x30);
+  }
+
+  #[test]
+  fn test_tima_write_during_reload_ignored() {
+    let (mut timer, _ic) = overflowed_timer();
+    timer.step(4);
+    timer.write(TIMA_ADDR, 0x55).unwrap();
+    assert_eq!(timer.read(TIMA_ADDR).unwrap(), 0x10);
+    // once the window passes writes go through again
+    timer.step(4);
+    timer.write(TIMA_ADDR, 0x55).unwrap();
+    assert_eq!(timer.read(TIMA_ADDR).unwrap(), 0x55);
+  }
+
+  #[test]
+  fn test_frame_sequencer_double_speed() {
+    let apu = Rc::new(RefCell::new(Apu::new()));
+    let speed_switch = Rc::new(RefCell::new(SpeedSwitch::new()));
+    speed_switch.borrow_mut().double_speed = true;
+    let mut timer = Timer::new();
+    timer
+      .connect_ic(Rc::new(RefCell::new(Interrupts::new())))
+      .unwrap();
+    timer.connect_apu(apu.clone()).unwrap();
+    timer.connect_speed_switch(speed_switch).unwrap();
+    {
+      let mut apu = apu.borrow_mut();
+      apu.write(0xff26, 0x80).unwrap();
+      apu.write(0xff11, 64 - 10).unwrap();
+      apu.write(0xff12, 0xf0).unwrap();
+      apu.write(0xff14, 0xc0).unwrap();
+    }
+
+    // twice the cpu cycles per step, so half the length clocks of normal
+    // speed in the same number of cycles
+    timer.step(8192 * 8);
+    assert_eq!(apu.borrow().ch1.length.counter(), 8);
+    timer.step(8192 * 8);
+    assert_eq!(apu.borrow().ch1.length.counter(), 6);
+  }
+
+  #[test]
+  fn test_frame_sequencer_length_clock() {
+    let apu = Rc::new(RefCell::new(Apu::new()));
+    let mut timer = Timer::new();
+    timer
+      .connect_ic(Rc::new(RefCell::new(Interrupts::new())))
+      .unwrap();
+    timer.connect_apu(apu.clone()).unwrap();
+    {
+      let mut apu = apu.borrow_mut();
+      apu.write(0xff26, 0x80).unwrap();
+      // channel 1 with a length of 10 and the length counter on
+      apu.write(0xff11, 64 - 10).unwrap();
+      apu.write(0xff12, 0xf0).unwrap();
+      apu.write(0xff14, 0xc0).unwrap();
+      assert!(apu.ch1.enabled);
+    }
+
+    // the sequencer steps every 8192 cycles and clocks lengths every other
+    // step, 256 Hz
+    timer.step(8192 * 8);
+    assert_eq!(apu.borrow().ch1.length.counter(), 6);
+
+    // writing DIV while bit 4 is set steps the sequencer early
+    timer.step(4096);
+    timer.write(DIV_ADDR, 0).unwrap();
+    assert_eq!(apu.borrow().ch1.length.counter(), 5);
+    // the counter starts over, the next step is a full period later
+    timer.step(8191);
+    assert_eq!(apu.borrow().ch1.length.counter(), 5);
+    timer.step(1);
+    // that was step 1, step 2 clocks the lengths again
+    timer.step(8192);
+    assert_eq!(apu.borrow().ch1.length.counter(), 4);
+
+    // running out turns the channel off
+    timer.step(8192 * 8);
+    assert_eq!(apu.borrow().ch1.length.counter(), 0);
+    assert!(!apu.borrow().ch1.enabled);
+    assert_eq!(apu.borrow().read(0xff26).unwrap(), 0x80);
+  }
+
+  #[test]
+  fn test_tac_write_toggles_counting() {
+    let mut timer = Timer::new();
+    timer
+      .connect_ic(Rc::new(RefCell::new(Interrupts::new())))
+      .unwrap();
+    // stopped timers don't count
+    timer.step(64);
+    assert_eq!(timer.read(TIMA_ADDR).unwrap(), 0);
+
+    // enabled at 16 cycles per tick
+    timer.write(TAC_ADDR, 0x05).unwrap();
+    timer.step(64);
+    assert_eq!(timer.read(TIMA_ADDR).unwrap(), 4);
+
+    // same clock with the enable bit cleared
+    timer.write(TAC_ADDR, 0x01).unwrap();
+    timer.step(64);
+    assert_eq!(timer.read(TIMA_ADDR).unwrap(), 4);
+  }
+
+  #[test]
+  fn test_snapshot_round_trip() {
+    let mut timer = Timer::new();
+    timer
+      .connect_ic(Rc::new(RefCell::new(Interrupts::new())))
+      .unwrap();
+    timer.write(TMA_ADDR, 0xf0).unwrap();
+    timer.write(TAC_ADDR, 0x05).unwrap();
+    timer.step(1234);
+    let mut writer = SnapshotWriter::new();
+    timer.save(&mut writer);
+    let bytes = writer.into_bytes();
+
+    // a fresh timer picks up exactly where the first one left off
+    let mut restored = Timer::new();
+    restored
+      .connect_ic(Rc::new(RefCell::new(Interrupts::new())))
+      .unwrap();
+    restored.load(&mut SnapshotReader::new(&bytes)).unwrap();
+    timer.step(5000);
+    restored.step(5000);
+    for addr in [DIV_ADDR, TIMA_ADDR, TMA_ADDR, TAC_ADDR] {
+      assert_eq!(
+        restored.read(addr).unwrap(),
+        timer.read(addr).unwrap(),
+        "${:04X}",
+        addr
+      );
+    }
+  }
+}
@@ -1,19 +1,93 @@
 //! Debug ui for the emulator
 
 use egui::{
-  self, epaint::Shadow, Align2, Color32, Context, FullOutput, RawInput, RichText, Style, Visuals,
+  self, epaint::Shadow, Align2, Color32, Context, FullOutput, RawInput, RichText, Sense, Style,
+  Visuals,
 };
 use egui_winit::winit::event_loop::EventLoopProxy;
+use log::{error, info};
 use rfd::FileDialog;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use crate::bus::Bus;
-use crate::cart::Cartridge;
-use crate::dasm::Dasm;
-use crate::ppu::{self, ObjectAttribute, Ppu, OAM_SIZE};
-use crate::timer::Timer;
-use crate::util::LazyDref;
-use crate::{cpu, cpu::Cpu, event::UserEvent, state::GbState};
+use crate::apu::{self, Apu, ChannelStatus};
+use crate::breakpoint::Breakpoint;
+use crate::bus::{self, Bus, MCycle};
+use crate::cart::{self, Cartridge};
+use crate::cheat::{Cheat, CheatKind};
+use crate::dasm::{self, Dasm, DasmView};
+use crate::err::{GbError, GbErrorType, GbResult};
+use crate::int::INTERRUPTS;
+use crate::ppu::{self, ObjectAttribute, PaletteKind, Ppu, OAM_SIZE};
+use crate::recent::RecentRoms;
+use crate::screen;
+use crate::search::{self, MemSearch, ScanFilter};
+use crate::serial::{self, Serial};
+use crate::state::{self, STATE_SLOTS};
+use crate::tick_counter::FrameTimes;
+use crate::timer::{self, Timer};
+use crate::util::{home_file, LazyDref};
+use crate::{cpu, cpu::Cpu, event::UserEvent, gb_err, state::GbState};
+
+// number of instructions written when exporting the disassembly
+const DASM_EXPORT_INSTRS: usize = 1024;
+
+// number of instructions listed when the disassembly isn't following pc
+const DASM_VIEW_INSTRS: usize = 256;
+
+// how long a toast message stays on screen
+const TOAST_DURATION: Duration = Duration::from_secs(2);
+// frame time of a real gameboy, drawn as a guide on the frame graph
+const GB_FRAME_MS: f32 = 1000.0 / 59.73;
+const FRAME_GRAPH_SIZE: [f32; 2] = [300.0, 60.0];
+const APU_GRAPH_SIZE: [f32; 2] = [300.0, 40.0];
+const APU_METER_WIDTH: f32 = 8.0;
+/// Memory search matches and cheat scan candidates listed as buttons, the
+/// count still shows them all
+const MEM_SEARCH_SHOWN: usize = 64;
+// file the custom palette is kept in, relative to the home directory
+const CUSTOM_PALETTE_FILE: &str = ".gb_palette";
+
+/// Colors for a palette, reading the saved colors for the custom one
+pub fn palette_colors(kind: PaletteKind) -> [screen::Color; 4] {
+  match kind {
+    PaletteKind::Custom => load_custom_palette().unwrap_or(ppu::PALETTE_GRAY),
+    _ => kind.colors(),
+  }
+}
+
+/// Read the saved custom palette, one rrggbb hex color per line
+fn load_custom_palette() -> Option<[screen::Color; 4]> {
+  let text = fs::read_to_string(home_file(CUSTOM_PALETTE_FILE)).ok()?;
+  ppu::parse_palette(&text)
+}
+
+/// Save the custom palette so it is used by later runs
+fn save_custom_palette(palette: &[screen::Color; 4]) -> GbResult<()> {
+  let path = home_file(CUSTOM_PALETTE_FILE);
+  if let Err(why) = fs::write(&path, ppu::format_palette(palette)) {
+    error!("Failed to save palette to {}: {}", path.display(), why);
+    return gb_err!(GbErrorType::FileError);
+  }
+  Ok(())
+}
+
+/// Ask for a file and write raw memory to it. Failures are logged.
+fn dump_bytes(file_name: &str, bytes: &[u8]) {
+  if let Some(path) = FileDialog::new().set_file_name(file_name).save_file() {
+    let _ = write_dump(&path, bytes);
+  }
+}
+
+/// Write raw memory to a file
+fn write_dump(path: &Path, bytes: &[u8]) -> GbResult<()> {
+  if let Err(why) = fs::write(path, bytes) {
+    error!("Failed to dump to {}: {}", path.display(), why);
+    return gb_err!(GbErrorType::FileError);
+  }
+  Ok(())
+}
 
 pub struct UiState {
   pub show_menu_bar: bool,
@@ -21,12 +95,37 @@ pub struct UiState {
   pub show_cpu_dasm_window: bool,
   pub show_mem_window: bool,
   pub show_stat_window: bool,
+  /// Frame time graph in the stats window
+  pub show_frame_graph: bool,
   pub show_ppu_reg_window: bool,
   pub show_ppu_palette_window: bool,
   pub show_ppu_oam_window: bool,
+  pub show_ppu_vram_window: bool,
+  /// Boxes around the objects drawn over the screen
+  pub show_sprite_overlay: bool,
   pub show_timer_window: bool,
+  pub show_int_window: bool,
+  pub show_cheat_window: bool,
   pub show_cart_info_window: bool,
+  pub show_cart_ram_window: bool,
   pub show_joypad_window: bool,
+  pub show_serial_window: bool,
+  pub show_apu_window: bool,
+  /// Short message shown over the screen and when it was posted
+  pub toast: Option<(String, Instant)>,
+  /// Recently loaded roms for the menu
+  pub recent_roms: RecentRoms,
+  /// Disassembly window position
+  pub dasm_view: DasmView,
+  pub mem_search: MemSearch,
+  /// Contents of the new cheat box
+  pub cheat_text: String,
+  /// Bank shown in the VRAM window
+  pub vram_bank: usize,
+  /// M-cycle of the last instruction the registers window is replaying
+  pub mcycle_pos: usize,
+  /// Edited custom palette waiting to be saved once the edit is done
+  pub unsaved_palette: Option<[screen::Color; 4]>,
 }
 
 impl UiState {
@@ -37,18 +136,54 @@ impl UiState {
       show_cpu_dasm_window: false,
       show_mem_window: false,
       show_stat_window: false,
+      show_frame_graph: false,
       show_ppu_reg_window: false,
       show_ppu_palette_window: false,
       show_ppu_oam_window: false,
+      show_ppu_vram_window: false,
+      show_sprite_overlay: false,
       show_timer_window: false,
+      show_int_window: false,
+      show_cheat_window: false,
       show_cart_info_window: false,
+      show_cart_ram_window: false,
       show_joypad_window: false,
+      show_serial_window: false,
+      show_apu_window: false,
+      toast: None,
+      recent_roms: RecentRoms::load(),
+      dasm_view: DasmView::new(),
+      mem_search: MemSearch::new(),
+      cheat_text: String::new(),
+      vram_bank: 0,
+      mcycle_pos: 0,
+      unsaved_palette: None,
     }
   }
 
+  /// Briefly show a message over the screen
+  pub fn show_toast(&mut self, msg: String) {
+    self.toast = Some((msg, Instant::now()));
+  }
+
   pub fn hide_all(&mut self) {
+    self.save_palette();
     *self = UiState::new();
   }
+
+  /// Write out the custom palette if it was edited
+  pub fn save_palette(&mut self) {
+    if let Some(palette) = self.unsaved_palette.take() {
+      // failures are logged, the palette still applies for this run
+      let _ = save_custom_palette(&palette);
+    }
+  }
+}
+
+impl Default for UiState {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 pub struct Ui {
@@ -120,23 +255,88 @@ impl Ui {
                 ui_state.show_ppu_oam_window = !ui_state.show_ppu_oam_window;
                 ui.close_menu();
               }
+              if ui.button("VRAM").clicked() {
+                ui_state.show_ppu_vram_window = !ui_state.show_ppu_vram_window;
+                ui.close_menu();
+              }
+              ui.checkbox(&mut ui_state.show_sprite_overlay, "Sprite Overlay");
+              ui.separator();
+              if ui.button("Dump OAM").clicked() {
+                dump_bytes("oam.bin", &gb_state.ppu.borrow().oam);
+                ui.close_menu();
+              }
+              // the bank picked in the vram viewer
+              if ui.button("Dump VRAM").clicked() {
+                let file_name = format!("vram{}.bin", ui_state.vram_bank);
+                let ppu = gb_state.ppu.borrow();
+                dump_bytes(&file_name, ppu.vram_bank(ui_state.vram_bank));
+                ui.close_menu();
+              }
             });
             if ui.button("Memory").clicked() {
               ui_state.show_mem_window = !ui_state.show_mem_window;
               ui.close_menu();
             }
+            if ui.button("Cheats").clicked() {
+              ui_state.show_cheat_window = !ui_state.show_cheat_window;
+              ui.close_menu();
+            }
             if ui.button("Timer").clicked() {
               ui_state.show_timer_window = !ui_state.show_timer_window;
               ui.close_menu();
             }
-            if ui.button("Cartridge Info").clicked() {
-              ui_state.show_cart_info_window = !ui_state.show_cart_info_window;
+            if ui.button("Interrupts").clicked() {
+              ui_state.show_int_window = !ui_state.show_int_window;
               ui.close_menu();
             }
+            ui.menu_button("Cartridge", |ui| {
+              if ui.button("Cartridge Info").clicked() {
+                ui_state.show_cart_info_window = !ui_state.show_cart_info_window;
+                ui.close_menu();
+              }
+              if ui.button("Cartridge RAM").clicked() {
+                ui_state.show_cart_ram_window = !ui_state.show_cart_ram_window;
+                ui.close_menu();
+              }
+            });
             if ui.button("Joypad").clicked() {
               ui_state.show_joypad_window = !ui_state.show_joypad_window;
               ui.close_menu();
             }
+            if ui.button("Serial Console").clicked() {
+              ui_state.show_serial_window = !ui_state.show_serial_window;
+              ui.close_menu();
+            }
+            if ui.button("Audio Channels").clicked() {
+              ui_state.show_apu_window = !ui_state.show_apu_window;
+              ui.close_menu();
+            }
+          });
+
+          // save states
+          ui.menu_button("State", |ui| {
+            ui.menu_button("Save State", |ui| {
+              for slot in 1..=STATE_SLOTS {
+                if ui.button(format!("Slot {}", slot)).clicked() {
+                  self
+                    .event_loop_proxy
+                    .send_event(UserEvent::SaveState(slot))
+                    .unwrap();
+                  ui.close_menu();
+                }
+              }
+            });
+            ui.menu_button("Load State", |ui| {
+              for slot in 1..=STATE_SLOTS {
+                if ui.button(format!("Slot {}", slot)).clicked() {
+                  self
+                    .event_loop_proxy
+                    .send_event(UserEvent::LoadState(slot))
+                    .unwrap();
+                  ui.close_menu();
+                }
+              }
+            });
           });
 
           if ui.button("Load Cartridge").clicked() {
@@ -150,6 +350,25 @@ impl Ui {
                 .unwrap();
             }
           }
+          ui.menu_button("Recent", |ui| {
+            if ui_state.recent_roms.paths().is_empty() {
+              ui.label("No recent roms");
+            }
+            for rom in ui_state.recent_roms.paths() {
+              let name = rom.file_name().unwrap_or_default().to_string_lossy();
+              if ui
+                .button(name)
+                .on_hover_text(rom.display().to_string())
+                .clicked()
+              {
+                self
+                  .event_loop_proxy
+                  .send_event(UserEvent::EmuReset(Some(rom.clone())))
+                  .unwrap();
+                ui.close_menu();
+              }
+            }
+          });
 
           // control flow buttons
           ui.monospace("  |  ");
@@ -165,6 +384,12 @@ impl Ui {
               .send_event(UserEvent::EmuStep)
               .unwrap();
           }
+          if gb_state.flow.paused && ui.button("Step Frame").clicked() {
+            self
+              .event_loop_proxy
+              .send_event(UserEvent::EmuStepFrame)
+              .unwrap();
+          }
           if !gb_state.flow.paused && ui.button("Pause").clicked() {
             self
               .event_loop_proxy
@@ -177,6 +402,12 @@ impl Ui {
               .send_event(UserEvent::EmuReset(gb_state.cart.borrow().cart_path()))
               .unwrap();
           }
+          if ui.button("Soft Reset").clicked() {
+            self
+              .event_loop_proxy
+              .send_event(UserEvent::EmuSoftReset)
+              .unwrap();
+          }
           ui.menu_button("Speed", |ui| {
             if ui.button(".01%").clicked() {
               gb_state.flow.speed = 0.0001;
@@ -214,6 +445,28 @@ impl Ui {
               gb_state.flow.speed = 8.00;
               ui.close_menu();
             }
+            ui.separator();
+            let mut percent = gb_state.flow.speed * 100.0;
+            let slider = egui::Slider::new(
+              &mut percent,
+              state::MIN_SPEED * 100.0..=state::MAX_SPEED * 100.0,
+            )
+            .suffix("%");
+            if ui.add(slider).changed() {
+              gb_state.flow.set_speed(percent / 100.0);
+            }
+            ui.separator();
+            // for benchmarking
+            ui.checkbox(&mut gb_state.flow.uncapped, "Uncapped");
+            let scanline_render = &mut gb_state.ppu.borrow_mut().scanline_render;
+            ui.checkbox(scanline_render, "Scanline Render");
+          });
+          ui.menu_button("Volume", |ui| {
+            let mut percent = gb_state.audio.queue.volume() * 100.0;
+            let slider = egui::Slider::new(&mut percent, 0.0..=100.0).suffix("%");
+            if ui.add(slider).changed() {
+              gb_state.audio.queue.set_volume(percent / 100.0);
+            }
           });
           ui.monospace("  |  ");
 
@@ -233,38 +486,84 @@ impl Ui {
 
     // show debug windows
     if ui_state.show_cpu_reg_window {
-      self.ui_cpu_reg(ctx, &mut gb_state.cpu.borrow_mut());
+      self.ui_cpu_reg(
+        ctx,
+        &mut gb_state.cpu.borrow_mut(),
+        &mut ui_state.mcycle_pos,
+      );
     }
     if ui_state.show_cpu_dasm_window {
-      self.ui_cpu_dasm(ctx, &gb_state.cpu.borrow());
+      self.ui_cpu_dasm(
+        ctx,
+        &gb_state.cpu.borrow(),
+        &mut ui_state.dasm_view,
+        &mut gb_state.flow.breakpoints,
+      );
     }
     if ui_state.show_mem_window {
-      self.ui_mem(ctx, &mut gb_state.bus.borrow_mut());
+      self.ui_mem(
+        ctx,
+        &gb_state.bus.borrow(),
+        &mut ui_state.mem_search,
+        &mut gb_state.cheats,
+      );
+    }
+    if ui_state.show_cheat_window
+      && self.ui_cheats(ctx, &mut gb_state.cheats, &mut ui_state.cheat_text)
+    {
+      gb_state.sync_rom_patches();
     }
     if ui_state.show_stat_window {
-      self.ui_stat(ctx, fps, gb_state);
+      self.ui_stat(ctx, fps, gb_state, &mut ui_state.show_frame_graph);
     }
     if ui_state.show_ppu_reg_window {
       self.ui_ppu_reg(ctx, &mut gb_state.ppu.borrow_mut());
     }
     if ui_state.show_ppu_palette_window {
-      self.ui_ppu_palettes(ctx, &mut gb_state.ppu.borrow_mut());
+      self.ui_ppu_palettes(
+        ctx,
+        &mut gb_state.ppu.borrow_mut(),
+        &mut ui_state.unsaved_palette,
+      );
+      // save once the color picker is let go instead of on every drag step
+      if !ctx.input(|i| i.pointer.any_down()) {
+        ui_state.save_palette();
+      }
     }
     if ui_state.show_ppu_oam_window {
       self.ui_ppu_oam(ctx, &mut gb_state.ppu.borrow_mut());
     }
+    if ui_state.show_sprite_overlay {
+      self.ui_sprite_overlay(ctx, &gb_state.ppu.borrow());
+    }
+    if ui_state.show_ppu_vram_window {
+      self.ui_ppu_vram(ctx, &gb_state.ppu.borrow(), &mut ui_state.vram_bank);
+    }
     if ui_state.show_timer_window {
       self.ui_timer(ctx, &mut gb_state.timer.borrow_mut());
     }
+    if ui_state.show_int_window {
+      self.ui_interrupts(ctx, gb_state);
+    }
     if ui_state.show_cart_info_window {
-      self.ui_cart_info(ctx, &mut gb_state.cart.borrow_mut());
+      self.ui_cart_info(ctx, gb_state);
+    }
+    if ui_state.show_cart_ram_window {
+      self.ui_cart_ram(ctx, &gb_state.cart.borrow());
     }
     if ui_state.show_joypad_window {
       self.ui_joypad(ctx, gb_state);
     }
+    if ui_state.show_serial_window {
+      self.ui_serial(ctx, &mut gb_state.serial.borrow_mut());
+    }
+    if ui_state.show_apu_window {
+      self.ui_apu(ctx, &mut gb_state.apu.borrow_mut());
+    }
+    self.ui_toast(ctx, ui_state);
   }
 
-  fn ui_stat(&self, ctx: &Context, fps: f32, gb_state: &mut GbState) {
+  fn ui_stat(&self, ctx: &Context, fps: f32, gb_state: &mut GbState, show_graph: &mut bool) {
     ctx.style_mut(|style| {
       style.visuals.window_fill = Color32::BLACK.gamma_multiply(0.50);
       style.visuals.window_stroke = egui::Stroke::new(0.0, Color32::TRANSPARENT);
@@ -283,12 +582,67 @@ impl Ui {
         ));
         ui.monospace(format!("UI FPS: {:.0}", fps));
         ui.monospace(format!("GB FPS: {:.0}", gb_state.gb_fps.tps()));
+        let times = &gb_state.frame_times;
+        if let (Some(min), Some(max), Some(avg)) = (times.min(), times.max(), times.avg()) {
+          ui.monospace(format!(
+            "Frame Time: {:.2} ms avg ({:.2} min, {:.2} max)",
+            avg.as_secs_f32() * 1000.0,
+            min.as_secs_f32() * 1000.0,
+            max.as_secs_f32() * 1000.0
+          ));
+        }
+        ui.checkbox(show_graph, "Frame Graph");
+        if *show_graph {
+          self.ui_frame_graph(ui, times);
+        }
       });
 
     // reset style
     Self::set_default_style(ctx);
   }
 
+  /// Rolling line graph of recent frame times, newest on the right. The
+  /// dashed line is the frame time of real hardware.
+  fn ui_frame_graph(&self, ui: &mut egui::Ui, times: &FrameTimes) {
+    let (response, painter) = ui.allocate_painter(FRAME_GRAPH_SIZE.into(), Sense::hover());
+    let rect = response.rect;
+    painter.rect_stroke(rect, 0.0, (1.0, Color32::GRAY));
+
+    // leave room above the slowest frame, but always show the guide line
+    let max_ms = times
+      .max()
+      .map_or(0.0, |max| max.as_secs_f32() * 1000.0)
+      .max(GB_FRAME_MS * 2.0);
+    let to_y = |ms: f32| rect.bottom() - (ms / max_ms) * rect.height();
+    let guide_y = to_y(GB_FRAME_MS);
+    painter.extend(egui::Shape::dashed_line(
+      &[
+        egui::pos2(rect.left(), guide_y),
+        egui::pos2(rect.right(), guide_y),
+      ],
+      (1.0, Color32::DARK_GREEN),
+      4.0,
+      4.0,
+    ));
+
+    let step = rect.width() / (times.cap().max(2) - 1) as f32;
+    // right align so the newest frame is always at the edge
+    let first_x = rect.right() - step * (times.len().max(1) - 1) as f32;
+    let points: Vec<egui::Pos2> = times
+      .iter()
+      .enumerate()
+      .map(|(i, time)| egui::pos2(first_x + step * i as f32, to_y(time.as_secs_f32() * 1000.0)))
+      .collect();
+    painter.add(egui::Shape::line(points, (1.0, Color32::YELLOW)));
+    painter.text(
+      rect.left_top() + egui::vec2(2.0, 2.0),
+      Align2::LEFT_TOP,
+      format!("{:.0} ms", max_ms),
+      egui::FontId::monospace(10.0),
+      Color32::GRAY,
+    );
+  }
+
   fn ui_joypad(&self, ctx: &Context, gb_state: &mut GbState) {
     egui::Window::new("Joypad").show(ctx, |ui| {
       ui.monospace(format!(
@@ -304,11 +658,17 @@ impl Ui {
     });
   }
 
-  fn ui_cart_info(&self, ctx: &Context, cart: &mut Cartridge) {
+  fn ui_cart_info(&self, ctx: &Context, gb_state: &GbState) {
+    let cart = gb_state.cart.borrow();
     egui::Window::new("Cartridge Info")
       .resizable(false)
       .show(ctx, |ui| {
         ui.monospace(format!("Loaded: {}", cart.loaded));
+        let mode = if gb_state.cgb_mode() { "CGB" } else { "DMG" };
+        ui.monospace(format!("Running As: {}", mode));
+        if let Some(warning) = gb_state.model_warning() {
+          ui.colored_label(Color32::YELLOW, warning);
+        }
         ui.monospace("--- Header ---");
         ui.monospace(format!("Title: {}", cart.header.title));
         ui.monospace(format!(
@@ -335,7 +695,7 @@ impl Ui {
       });
   }
 
-  fn ui_cpu_reg(&self, ctx: &Context, cpu: &mut Cpu) {
+  fn ui_cpu_reg(&self, ctx: &Context, cpu: &mut Cpu, mcycle_pos: &mut usize) {
     egui::Window::new("CPU Registers")
       .resizable(false)
       .show(ctx, |ui| {
@@ -353,13 +713,84 @@ impl Ui {
         let h = if f & crate::cpu::FLAG_H > 0 { 1 } else { 0 };
         let c = if f & crate::cpu::FLAG_C > 0 { 1 } else { 0 };
         ui.monospace(format!("Z:{}  N:{}  H:{}  C:{}", z, n, h, c));
+        if cpu.locked {
+          ui.monospace("");
+          ui.colored_label(Color32::RED, "Locked up on an illegal opcode");
+        }
+        egui::CollapsingHeader::new("M-cycles").show(ui, |ui| {
+          self.ui_mcycles(ui, cpu, mcycle_pos);
+        });
       });
   }
 
-  fn ui_cpu_dasm(&self, ctx: &Context, cpu: &Cpu) {
+  /// Step back and forth through the memory cycles of the last instruction,
+  /// with the ones done so far highlighted
+  fn ui_mcycles(&self, ui: &mut egui::Ui, cpu: &mut Cpu, pos: &mut usize) {
+    ui.checkbox(&mut cpu.record_mcycles, "Record");
+    if cpu.mcycles.is_empty() {
+      ui.label("Nothing recorded");
+      return;
+    }
+    *pos = (*pos).min(cpu.mcycles.len() - 1);
+    ui.horizontal(|ui| {
+      if ui.small_button("<").clicked() {
+        *pos = pos.saturating_sub(1);
+      }
+      ui.monospace(format!("M{}/{}", *pos + 1, cpu.mcycles.len()));
+      if ui.small_button(">").clicked() && *pos + 1 < cpu.mcycles.len() {
+        *pos += 1;
+      }
+    });
+    for (i, cycle) in cpu.mcycles.iter().enumerate() {
+      let text = match cycle {
+        MCycle::Read { addr, val } => format!("M{} read  ${:04X} -> {:02X}", i + 1, addr, val),
+        MCycle::Write { addr, val } => format!("M{} write ${:04X} <- {:02X}", i + 1, addr, val),
+        MCycle::Internal => format!("M{} internal", i + 1),
+      };
+      let color = if i <= *pos {
+        Color32::LIGHT_YELLOW
+      } else {
+        Color32::DARK_GRAY
+      };
+      ui.colored_label(color, egui::RichText::new(text).monospace());
+    }
+  }
+
+  fn ui_cpu_dasm(
+    &self,
+    ctx: &Context,
+    cpu: &Cpu,
+    view: &mut DasmView,
+    breakpoints: &mut Vec<Breakpoint>,
+  ) {
     egui::Window::new("Disassembly")
       .resizable(false)
       .show(ctx, |ui| {
+        ui.horizontal(|ui| {
+          if ui.button("Export").clicked() {
+            self.export_dasm(cpu);
+          }
+          let mut follow_pc = view.follow_pc;
+          if ui.checkbox(&mut follow_pc, "Follow PC").changed() {
+            view.set_follow_pc(follow_pc, cpu.pc);
+          }
+        });
+        ui.horizontal(|ui| {
+          let goto_box =
+            ui.add(egui::TextEdit::singleline(&mut view.goto_text).desired_width(60.0));
+          let entered = goto_box.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+          if (ui.button("Goto").clicked() || entered) && !view.goto() {
+            error!("Invalid disassembly address: {}", view.goto_text);
+          }
+        });
+        self.ui_breakpoints(ui, view, breakpoints);
+        ui.separator();
+
+        if !view.follow_pc {
+          self.ui_dasm_scroll(ui, cpu, view.start_addr(cpu.pc));
+          return;
+        }
+
         let mut vpc = cpu.pc;
         let mut dasm = Dasm::new();
 
@@ -369,19 +800,131 @@ impl Ui {
           ui.monospace("");
         }
         for pc in cpu.history.entries() {
-          let output = self.build_dasm_line(cpu, &mut pc.clone(), &mut dasm);
-          ui.monospace(RichText::from(output).color(Color32::DARK_GRAY));
+          self.ui_dasm_line(ui, cpu, &mut pc.clone(), &mut dasm, Color32::DARK_GRAY);
         }
 
         // print current instruction
-        let output = self.build_dasm_line(cpu, &mut vpc, &mut dasm);
-        ui.monospace(RichText::from(output).color(Color32::LIGHT_YELLOW));
+        self.ui_dasm_line(ui, cpu, &mut vpc, &mut dasm, Color32::LIGHT_YELLOW);
+
+        for _ in 0..cpu.history.cap() {
+          self.ui_dasm_line(ui, cpu, &mut vpc, &mut dasm, Color32::DARK_GRAY);
+        }
+      });
+  }
 
-        for i in 0..cpu.history.cap() {
-          let output = self.build_dasm_line(cpu, &mut vpc, &mut dasm);
-          ui.monospace(RichText::from(output).color(Color32::DARK_GRAY));
+  /// Add breakpoints like `c100 if A == 5` and list the ones set
+  fn ui_breakpoints(
+    &self,
+    ui: &mut egui::Ui,
+    view: &mut DasmView,
+    breakpoints: &mut Vec<Breakpoint>,
+  ) {
+    ui.horizontal(|ui| {
+      let bp_box = ui.add(
+        egui::TextEdit::singleline(&mut view.breakpoint_text)
+          .hint_text("addr [if A == 5]")
+          .desired_width(160.0),
+      );
+      let entered = bp_box.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+      if ui.button("Break").clicked() || entered {
+        match view.breakpoint_text.parse() {
+          Ok(breakpoint) => {
+            breakpoints.push(breakpoint);
+            view.breakpoint_text.clear();
+          }
+          Err(e) => error!("{}", e),
         }
+      }
+    });
+    let mut remove = None;
+    for (i, breakpoint) in breakpoints.iter().enumerate() {
+      ui.horizontal(|ui| {
+        if ui.small_button("x").clicked() {
+          remove = Some(i);
+        }
+        ui.monospace(breakpoint.to_string());
       });
+    }
+    if let Some(i) = remove {
+      breakpoints.remove(i);
+    }
+  }
+
+  /// List instructions from a fixed address in a scroll area
+  fn ui_dasm_scroll(&self, ui: &mut egui::Ui, cpu: &Cpu, start: u16) {
+    let bus = cpu.bus.lazy_dref();
+    let lines = match dasm::disassemble(start, DASM_VIEW_INSTRS, |addr| bus.read8(addr)) {
+      Ok(lines) => lines,
+      Err(e) => {
+        error!("Failed to disassemble from ${:04X}: {}", start, e);
+        return;
+      }
+    };
+    // same height as the view that follows pc
+    let rows = (cpu.history.cap() * 2 + 1) as f32;
+    egui::ScrollArea::vertical()
+      .max_height(ui.text_style_height(&egui::TextStyle::Monospace) * rows)
+      .show(ui, |ui| {
+        for line in lines {
+          let color = if line.addr == cpu.pc {
+            Color32::LIGHT_YELLOW
+          } else {
+            Color32::DARK_GRAY
+          };
+          self.ui_dasm_label(ui, line.addr, format!(" PC:{}", line), color);
+        }
+      });
+  }
+
+  /// Write a listing starting at the current pc to a file picked by the user
+  fn export_dasm(&self, cpu: &Cpu) {
+    let file_option = FileDialog::new().set_file_name("dasm.txt").save_file();
+    let path = match file_option {
+      Some(path) => path,
+      None => return,
+    };
+    let bus = cpu.bus.lazy_dref();
+    let listing = match dasm::disassemble(cpu.pc, DASM_EXPORT_INSTRS, |addr| bus.read8(addr)) {
+      Ok(lines) => dasm::format_listing(&lines),
+      Err(e) => {
+        error!("Failed to disassemble from ${:04X}: {}", cpu.pc, e);
+        return;
+      }
+    };
+    match fs::write(&path, listing) {
+      Ok(_) => info!("Exported disassembly to {}", path.display()),
+      Err(why) => error!(
+        "Failed to export disassembly to {}: {}",
+        path.display(),
+        why
+      ),
+    }
+  }
+
+  /// Show a disassembled line, clicking it runs until the cpu reaches it
+  fn ui_dasm_line(
+    &self,
+    ui: &mut egui::Ui,
+    cpu: &Cpu,
+    vpc: &mut u16,
+    dasm: &mut Dasm,
+    color: Color32,
+  ) {
+    let line_pc = *vpc;
+    let output = self.build_dasm_line(cpu, vpc, dasm);
+    self.ui_dasm_label(ui, line_pc, output, color);
+  }
+
+  /// Clickable disassembly text that runs until the cpu reaches `line_pc`
+  fn ui_dasm_label(&self, ui: &mut egui::Ui, line_pc: u16, output: String, color: Color32) {
+    let label =
+      egui::Label::new(RichText::from(output).monospace().color(color)).sense(Sense::click());
+    if ui.add(label).on_hover_text("Run to here").clicked() {
+      self
+        .event_loop_proxy
+        .send_event(UserEvent::EmuRunTo(line_pc))
+        .unwrap();
+    }
   }
 
   fn build_dasm_line(&self, cpu: &Cpu, vpc: &mut u16, dasm: &mut Dasm) -> String {
@@ -403,16 +946,40 @@ impl Ui {
     }
   }
 
-  fn ui_ppu_palettes(&self, ctx: &Context, ppu: &mut Ppu) {
+  fn ui_ppu_palettes(
+    &self,
+    ctx: &Context,
+    ppu: &mut Ppu,
+    unsaved: &mut Option<[screen::Color; 4]>,
+  ) {
     egui::Window::new("Palettes").show(ctx, |ui| {
       if ui.button("GRAY").clicked() {
-        ppu.palette = ppu::PALETTE_GRAY;
+        ppu.set_palette(ppu::PALETTE_GRAY);
       }
       if ui.button("GREEN").clicked() {
-        ppu.palette = ppu::PALETTE_GREEN;
+        ppu.set_palette(ppu::PALETTE_GREEN);
       }
       if ui.button("BLUE").clicked() {
-        ppu.palette = ppu::PALETTE_BLUE;
+        ppu.set_palette(ppu::PALETTE_BLUE);
+      }
+      if ui.button("CUSTOM").clicked() {
+        ppu.set_palette(palette_colors(PaletteKind::Custom));
+      }
+      ui.separator();
+      // edits apply live and become the custom palette
+      let mut changed = false;
+      for (index, name) in ["White", "Light", "Dark", "Black"].iter().enumerate() {
+        ui.horizontal(|ui| {
+          let mut rgb = ppu.palette()[index].to_rgb8();
+          if ui.color_edit_button_srgb(&mut rgb).changed() {
+            ppu.set_palette_color(index, rgb);
+            changed = true;
+          }
+          ui.label(*name);
+        });
+      }
+      if changed {
+        *unsaved = Some(*ppu.palette());
       }
     });
   }
@@ -445,11 +1012,50 @@ impl Ui {
     });
   }
 
+  /// Box every object on the screen, labeled with its OAM index. Objects the
+  /// 10 per line limit drops on some line are orange instead of red.
+  fn ui_sprite_overlay(&self, ctx: &Context, ppu: &Ppu) {
+    // the gameboy screen is stretched over the whole window, below the windows
+    let screen = ctx.screen_rect();
+    let painter = ctx.layer_painter(egui::LayerId::background());
+    let scale = egui::vec2(
+      screen.width() / ppu::SCREEN_WIDTH as f32,
+      screen.height() / ppu::SCREEN_HEIGHT as f32,
+    );
+    let to_screen =
+      |x: i32, y: i32| screen.min + egui::vec2(x as f32 * scale.x, y as f32 * scale.y);
+    for bounds in ppu.object_bounds() {
+      let color = if bounds.over_limit {
+        Color32::from_rgb(255, 140, 0)
+      } else {
+        Color32::RED
+      };
+      let rect = egui::Rect::from_two_pos(
+        to_screen(bounds.x, bounds.y),
+        to_screen(bounds.x + 8, bounds.y + bounds.height as i32),
+      );
+      painter.rect_stroke(rect, 0.0, (1.0, color));
+      painter.text(
+        rect.left_top(),
+        Align2::LEFT_BOTTOM,
+        format!("{}", bounds.index),
+        egui::FontId::monospace(10.0),
+        color,
+      );
+    }
+  }
+
   fn ui_ppu_reg(&self, ctx: &Context, ppu: &mut Ppu) {
     egui::Window::new("PPU Registers").show(ctx, |ui| {
       ui.monospace(format!("LY: {}", ppu.ly));
-      ui.monospace(format!("SCX: {}", ppu.scx));
-      ui.monospace(format!("SCY: {}", ppu.scy));
+      for (name, addr, value) in [
+        ("LCDC", ppu::LCDC_ADDR, u8::from(ppu.lcdc)),
+        ("SCX", ppu::SCX_ADDR, ppu.scx),
+        ("SCY", ppu::SCY_ADDR, ppu.scy),
+        ("LYC", ppu::LYC_ADDR, ppu.lyc),
+      ] {
+        self.ui_io_reg(ui, name, addr, value, |addr, data| ppu.io_write(addr, data));
+      }
       ui.monospace(format!("LCDC.BG_WIN_PRIORITY: {}", ppu.lcdc.bg_win_enable));
       ui.monospace(format!("LCDC.OBJ_ENABLE: {}", ppu.lcdc.obj_enabled));
       ui.monospace(format!("LCDC.LARGE_OBJ_SIZE: {}", ppu.lcdc.obj_size_large));
@@ -464,60 +1070,479 @@ impl Ui {
         ppu.lcdc.win_tile_map_hi
       ));
       ui.monospace(format!("LCDC.LCD_ENABLE: {}", ppu.lcdc.ppu_enabled));
+      ui.separator();
+      let bgp = self.ui_palette_reg(ui, "BGP", ppu.bgp());
+      ppu.set_bgp(bgp);
+      for index in 0..2 {
+        let obp = self.ui_palette_reg(ui, &format!("OBP{}", index), ppu.obp(index));
+        ppu.set_obp(index, obp);
+      }
+    });
+  }
+
+  /// Editable palette register with the shade of each color index. Returns the
+  /// possibly edited value.
+  fn ui_palette_reg(&self, ui: &mut egui::Ui, name: &str, mut reg: u8) -> u8 {
+    ui.horizontal(|ui| {
+      ui.monospace(format!("{:4}:", name));
+      ui.add(egui::DragValue::new(&mut reg).hexadecimal(2, false, true));
+      let shades = ppu::palette_shades(reg);
+      ui.monospace(format!(
+        "0->{} 1->{} 2->{} 3->{}",
+        shades[0], shades[1], shades[2], shades[3]
+      ));
     });
+    reg
   }
 
-  fn ui_mem(&self, ctx: &Context, bus: &mut Bus) {
+  fn ui_mem(&self, ctx: &Context, bus: &Bus, search: &mut MemSearch, cheats: &mut Vec<Cheat>) {
     egui::Window::new("Memory Dump")
       .resizable(true)
       .show(ctx, |ui| {
-        // set up starting state
-        let num_cols = 8;
-        let total_mem_size = 0x1_0000;
-
-        let text_style = egui::TextStyle::Monospace;
-        let row_height = ui.text_style_height(&text_style);
-        let num_rows = total_mem_size / num_cols;
-        egui::ScrollArea::both().auto_shrink(false).show_rows(
-          ui,
-          row_height,
-          num_rows,
-          |ui, row_range| {
-            ui.style_mut().wrap = Some(false);
-            // memory dump
-            for row in row_range {
-              let row_addr = row * num_cols;
-              let mut row_str = String::from(format!("{:04X}  ", row_addr));
-              let mut as_char_str = String::from(" | ");
-              for col in 0..num_cols {
-                let addr = row_addr + col;
-                let byte = bus.read8(addr as u16).unwrap();
-                row_str.push_str(format!("{:02X} ", byte).as_str());
-                let c = if (33..126).contains(&byte) {
-                  byte as char
-                } else {
-                  '.'
-                };
-                as_char_str.push(c);
+        ui.horizontal(|ui| {
+          let search_box = ui.add(
+            egui::TextEdit::singleline(&mut search.text)
+              .hint_text("3e 05, $c100 or 42")
+              .desired_width(120.0),
+          );
+          let entered = search_box.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+          if ui.button("Search").clicked() || entered {
+            if let Err(e) = search.run(|addr| bus.read8(addr).unwrap_or(0xff)) {
+              error!("{}", e);
+            }
+          }
+          ui.monospace(format!("{} found", search.results.len()));
+        });
+        // clicking a match scrolls the dump to it
+        if !search.results.is_empty() {
+          egui::ScrollArea::horizontal()
+            .id_source("mem search results")
+            .show(ui, |ui| {
+              ui.horizontal(|ui| {
+                for &addr in search.results.iter().take(MEM_SEARCH_SHOWN) {
+                  if ui.small_button(format!("{:04X}", addr)).clicked() {
+                    search.jump_to = Some(addr);
+                  }
+                }
+              });
+            });
+        }
+        egui::CollapsingHeader::new("Cheat Scan").show(ui, |ui| {
+          self.ui_cheat_scan(ui, bus, search, cheats);
+        });
+        ui.separator();
+        let scroll_to = search.jump_to.take().map(|addr| addr as usize);
+        self.ui_hex_dump(ui, 0, 0x1_0000, scroll_to, |addr| {
+          bus.read8(addr as u16).unwrap()
+        });
+      });
+  }
+
+  /// Frozen addresses and rom patches, applied after every frame while
+  /// enabled
+  ///
+  /// Returns true when a cheat was added, removed or edited
+  fn ui_cheats(&self, ctx: &Context, cheats: &mut Vec<Cheat>, text: &mut String) -> bool {
+    let mut changed = false;
+    egui::Window::new("Cheats").show(ctx, |ui| {
+      ui.horizontal(|ui| {
+        let cheat_box = ui.add(
+          egui::TextEdit::singleline(text)
+            .hint_text("c0a0=63 or a code")
+            .desired_width(140.0),
+        );
+        let entered = cheat_box.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+        if ui.button("Add").clicked() || entered {
+          match text.parse() {
+            Ok(cheat) => {
+              cheats.push(cheat);
+              text.clear();
+              changed = true;
+            }
+            Err(e) => error!("{}", e),
+          }
+        }
+      });
+      let mut remove = None;
+      for (i, cheat) in cheats.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+          if ui.small_button("x").clicked() {
+            remove = Some(i);
+          }
+          changed |= ui.checkbox(&mut cheat.enabled, "").changed();
+          let rom = if matches!(cheat.kind, CheatKind::RomPatch { .. }) {
+            "ROM "
+          } else {
+            ""
+          };
+          ui.monospace(format!("{}{:04X} =", rom, cheat.addr));
+          let value = egui::DragValue::new(&mut cheat.value).hexadecimal(2, false, true);
+          changed |= ui.add(value).changed();
+          if let CheatKind::RomPatch {
+            compare: Some(compare),
+          } = cheat.kind
+          {
+            ui.monospace(format!("if {:02X}", compare));
+          }
+        });
+      }
+      if let Some(i) = remove {
+        cheats.remove(i);
+        changed = true;
+      }
+    });
+    changed
+  }
+
+  /// Scan memory, then keep narrowing down the candidates by how they
+  /// changed since the scan before
+  fn ui_cheat_scan(
+    &self,
+    ui: &mut egui::Ui,
+    bus: &Bus,
+    search: &mut MemSearch,
+    cheats: &mut Vec<Cheat>,
+  ) {
+    let read = |addr| bus.read8(addr).unwrap_or(0xff);
+    let scanner = &mut search.scanner;
+    ui.horizontal(|ui| {
+      if ui.button("New Scan").clicked() {
+        scanner.start(read);
+      }
+      ui.add_enabled_ui(scanner.started(), |ui| {
+        for (name, filter) in [
+          ("Increased", ScanFilter::Increased),
+          ("Decreased", ScanFilter::Decreased),
+          ("Unchanged", ScanFilter::Unchanged),
+          ("Changed", ScanFilter::Changed),
+        ] {
+          if ui.button(name).clicked() {
+            scanner.scan(filter, read);
+          }
+        }
+      });
+    });
+    ui.add_enabled_ui(scanner.started(), |ui| {
+      ui.horizontal(|ui| {
+        ui.add(egui::TextEdit::singleline(&mut search.scan_value).desired_width(40.0));
+        if ui.button("Equals").clicked() {
+          match search::parse_pattern(&search.scan_value).as_deref() {
+            Ok([val]) => scanner.scan(ScanFilter::Equals(*val), read),
+            _ => error!("Scan value has to be one byte: {}", search.scan_value),
+          }
+        }
+        ui.monospace(format!("{} candidates", scanner.candidates().len()));
+      });
+    });
+    egui::ScrollArea::vertical()
+      .id_source("cheat scan candidates")
+      .max_height(120.0)
+      .show(ui, |ui| {
+        for &addr in scanner.candidates().iter().take(MEM_SEARCH_SHOWN) {
+          let val = scanner.value(addr).unwrap_or(0);
+          ui.horizontal(|ui| {
+            let text = format!("{:04X}: {:02X} ({})", addr, val, val);
+            if ui.small_button(text).clicked() {
+              search.jump_to = Some(addr);
+            }
+            if ui.small_button("Freeze").clicked() {
+              match Cheat::freeze(addr, val) {
+                Ok(cheat) => cheats.push(cheat),
+                Err(e) => error!("{}", e),
               }
-              as_char_str.push_str(" |");
-              row_str.push_str(as_char_str.as_str());
-              ui.monospace(row_str);
             }
-          },
+          });
+        }
+      });
+  }
+
+  /// Scrollable hex dump of `size` bytes labeled from `base_addr`. `read`
+  /// gets the offset of the byte from `base_addr`, and so does `scroll_to`
+  /// which brings that row to the top.
+  fn ui_hex_dump<F>(
+    &self,
+    ui: &mut egui::Ui,
+    base_addr: usize,
+    size: usize,
+    scroll_to: Option<usize>,
+    read: F,
+  ) where
+    F: Fn(usize) -> u8,
+  {
+    // set up starting state
+    let num_cols = 8;
+
+    let text_style = egui::TextStyle::Monospace;
+    let row_height = ui.text_style_height(&text_style);
+    let num_rows = size / num_cols;
+    let mut scroll_area = egui::ScrollArea::both().auto_shrink(false);
+    if let Some(offset) = scroll_to {
+      let spaced_row = row_height + ui.spacing().item_spacing.y;
+      scroll_area = scroll_area.vertical_scroll_offset((offset / num_cols) as f32 * spaced_row);
+    }
+    scroll_area.show_rows(ui, row_height, num_rows, |ui, row_range| {
+      ui.style_mut().wrap = Some(false);
+      // memory dump
+      for row in row_range {
+        let row_offset = row * num_cols;
+        let mut row_str = String::from(format!("{:04X}  ", base_addr + row_offset));
+        let mut as_char_str = String::from(" | ");
+        for col in 0..num_cols {
+          let byte = read(row_offset + col);
+          row_str.push_str(format!("{:02X} ", byte).as_str());
+          let c = if (33..126).contains(&byte) {
+            byte as char
+          } else {
+            '.'
+          };
+          as_char_str.push(c);
+        }
+        as_char_str.push_str(" |");
+        row_str.push_str(as_char_str.as_str());
+        ui.monospace(row_str);
+      }
+    });
+  }
+
+  /// VRAM read straight from the ppu, so it shows up even while the cpu
+  /// would be locked out of it
+  fn ui_ppu_vram(&self, ctx: &Context, ppu: &Ppu, bank: &mut usize) {
+    egui::Window::new("VRAM").resizable(true).show(ctx, |ui| {
+      ui.horizontal(|ui| {
+        ui.monospace("Bank:");
+        for b in 0..ppu::VRAM_BANKS {
+          ui.radio_value(bank, b, format!("{}", b));
+        }
+        if !ppu.cgb_mode {
+          ui.monospace("(bank 1 is CGB only)");
+        }
+      });
+      ui.separator();
+      let bank_vram = ppu.vram_bank(*bank);
+      self.ui_hex_dump(
+        ui,
+        bus::PPU_START as usize,
+        ppu::VRAM_SIZE,
+        None,
+        |offset| bank_vram[offset],
+      );
+    });
+  }
+
+  /// External ram of the bank currently mapped at $A000
+  fn ui_cart_ram(&self, ctx: &Context, cart: &Cartridge) {
+    egui::Window::new("Cartridge RAM")
+      .resizable(true)
+      .show(ctx, |ui| {
+        let mbc = match &cart.mbc {
+          Some(mbc) if cart.loaded => mbc,
+          _ => {
+            ui.monospace("No cartridge loaded");
+            return;
+          }
+        };
+        let ram = mbc.ram();
+        if ram.is_empty() {
+          ui.monospace("No cartridge RAM");
+          return;
+        }
+        let bank = match mbc.ram_bank() {
+          Some(bank) => {
+            ui.monospace(format!("RAM Bank: {}", bank));
+            bank
+          }
+          None => {
+            ui.monospace("RAM Bank: none, RTC register mapped");
+            return;
+          }
+        };
+        if !mbc.ram_enabled() {
+          ui.label(
+            RichText::from("RAM disabled, reads return 0xFF")
+              .monospace()
+              .color(Color32::LIGHT_RED),
+          );
+        }
+        ui.separator();
+
+        // small rams like the mbc2's don't fill a whole bank
+        let start = (bank * cart::RAM_BANK_SIZE).min(ram.len());
+        let end = (start + cart::RAM_BANK_SIZE).min(ram.len());
+        let bank_ram = &ram[start..end];
+        self.ui_hex_dump(
+          ui,
+          cart::ERAM_START as usize,
+          bank_ram.len(),
+          None,
+          |offset| bank_ram[offset],
         );
       });
   }
 
   fn ui_timer(&self, ctx: &Context, timer: &mut Timer) {
     egui::Window::new("Timer Registers").show(ctx, |ui| {
-      ui.monospace(format!("DIV: 0x{:02X}", timer.div));
-      ui.monospace(format!("TIMA: 0x{:02X}", timer.tima));
-      ui.monospace(format!("TMA: 0x{:02X}", timer.tma));
-      ui.monospace(format!("TAC: 0x{:02X}", u8::from(timer.tac)));
+      for (name, addr, value) in [
+        ("DIV", timer::DIV_ADDR, timer.div),
+        ("TIMA", timer::TIMA_ADDR, timer.tima),
+        ("TMA", timer::TMA_ADDR, timer.tma),
+        ("TAC", timer::TAC_ADDR, u8::from(timer.tac)),
+      ] {
+        self.ui_io_reg(ui, name, addr, value, |addr, data| timer.write(addr, data));
+      }
+    });
+  }
+
+  fn ui_interrupts(&self, ctx: &Context, gb_state: &mut GbState) {
+    let ic = gb_state.ic.borrow();
+    egui::Window::new("Interrupts")
+      .resizable(false)
+      .show(ctx, |ui| {
+        ui.monospace(format!("IME: {}", gb_state.cpu.borrow().ime));
+        ui.monospace(format!(
+          "IE: {:02x}  IF: {:02x}",
+          ic.enabled(),
+          ic.pending()
+        ));
+        ui.monospace("");
+        egui::Grid::new("interrupts").show(ui, |ui| {
+          ui.monospace("");
+          ui.monospace("IE");
+          ui.monospace("IF");
+          ui.monospace("Break");
+          ui.end_row();
+          for interrupt in INTERRUPTS {
+            let bit = interrupt as u8;
+            ui.monospace(interrupt.name());
+            ui.monospace(format!("{}", (ic.enabled() & bit > 0) as u8));
+            ui.monospace(format!("{}", (ic.pending() & bit > 0) as u8));
+            // pauses when the cpu enters the handler
+            let mut brk = gb_state.flow.break_on_interrupt & bit > 0;
+            if ui.checkbox(&mut brk, "").changed() {
+              gb_state.flow.break_on_interrupt ^= bit;
+            }
+            ui.end_row();
+          }
+        });
+      });
+  }
+
+  /// Editable io register. Changes go through `write` so the register's
+  /// side effects happen like a write from the cpu.
+  fn ui_io_reg<F>(&self, ui: &mut egui::Ui, name: &str, addr: u16, value: u8, mut write: F)
+  where
+    F: FnMut(u16, u8) -> GbResult<()>,
+  {
+    ui.horizontal(|ui| {
+      ui.monospace(format!("{:4}:", name));
+      let mut data = value;
+      let response = ui.add(egui::DragValue::new(&mut data).hexadecimal(2, false, true));
+      if response.changed() && data != value {
+        if let Err(err) = write(addr, data) {
+          error!("Failed to write {} = 0x{:02X}: {}", name, data, err);
+        }
+      }
+    });
+  }
+
+  fn ui_toast(&self, ctx: &Context, ui_state: &mut UiState) {
+    let msg = match &ui_state.toast {
+      Some((msg, posted)) if posted.elapsed() < TOAST_DURATION => msg,
+      Some(_) => {
+        ui_state.toast = None;
+        return;
+      }
+      None => return,
+    };
+    egui::Area::new("toast")
+      .anchor(Align2::CENTER_BOTTOM, [0.0, -20.0])
+      .interactable(false)
+      .show(ctx, |ui| {
+        egui::Frame::popup(ui.style()).show(ui, |ui| {
+          ui.monospace(msg);
+        });
+      });
+  }
+
+  fn ui_apu(&self, ctx: &Context, apu: &mut Apu) {
+    egui::Window::new("Audio Channels").show(ctx, |ui| {
+      if !apu.powered {
+        ui.colored_label(Color32::RED, "APU off");
+      }
+      for (i, status) in apu.channel_status().iter().enumerate() {
+        ui.separator();
+        ui.horizontal(|ui| {
+          ui.monospace(format!("{} {:8}", i + 1, apu::CHANNEL_NAMES[i]));
+          ui.checkbox(&mut apu.muted[i], "Mute");
+        });
+        ui.monospace(format!(
+          "On: {:5}  DAC: {:5}  {:8.1} Hz  Vol: {:3.0}%",
+          status.enabled,
+          status.dac_on,
+          status.frequency,
+          status.volume * 100.0
+        ));
+        self.ui_apu_channel_graph(ui, status);
+      }
     });
   }
 
+  /// One period of a channel's waveform with a volume meter beside it. Both
+  /// are dimmed while the channel is off.
+  fn ui_apu_channel_graph(&self, ui: &mut egui::Ui, status: &ChannelStatus) {
+    let (response, painter) = ui.allocate_painter(APU_GRAPH_SIZE.into(), Sense::hover());
+    let rect = response.rect;
+    let color = if status.enabled {
+      Color32::YELLOW
+    } else {
+      Color32::DARK_GRAY
+    };
+    painter.rect_stroke(rect, 0.0, (1.0, Color32::GRAY));
+
+    let inner = rect.shrink(2.0);
+    let meter_left = inner.right() - APU_METER_WIDTH;
+    let wave_rect = egui::Rect::from_min_max(
+      inner.left_top(),
+      egui::pos2(meter_left - 4.0, inner.bottom()),
+    );
+    if !status.waveform.is_empty() {
+      let step = wave_rect.width() / status.waveform.len() as f32;
+      let to_y = |level: f32| wave_rect.bottom() - level * wave_rect.height();
+      // held steps, like the channel plays them
+      let mut points = Vec::with_capacity(status.waveform.len() * 2);
+      for (i, &level) in status.waveform.iter().enumerate() {
+        let x = wave_rect.left() + step * i as f32;
+        points.push(egui::pos2(x, to_y(level)));
+        points.push(egui::pos2(x + step, to_y(level)));
+      }
+      painter.add(egui::Shape::line(points, (1.0, color)));
+    }
+
+    let level_top = inner.bottom() - status.volume * inner.height();
+    let level = egui::Rect::from_min_max(egui::pos2(meter_left, level_top), inner.right_bottom());
+    painter.rect_filled(level, 0.0, color);
+  }
+
+  fn ui_serial(&self, ctx: &Context, serial: &mut Serial) {
+    egui::Window::new("Serial Console")
+      .resizable(true)
+      .show(ctx, |ui| {
+        ui.horizontal(|ui| {
+          ui.monospace(format!("{} bytes", serial.output.len()));
+          if ui.button("Clear").clicked() {
+            serial.output.clear();
+          }
+        });
+        ui.separator();
+        // follow new output as long as the view is scrolled to the bottom
+        egui::ScrollArea::vertical()
+          .auto_shrink(false)
+          .stick_to_bottom(true)
+          .show(ui, |ui| {
+            ui.monospace(serial::escape_output(&serial.output));
+          });
+      });
+  }
+
   fn ui_reso(&self, ui: &mut egui::Ui) {
     ui.menu_button("Screen Size", |ui| {
       if ui.button("160 x 144 (x1)").clicked() {
@@ -577,3 +1602,28 @@ impl Ui {
     });
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ppu::Ppu;
+
+  #[test]
+  fn test_write_dump() {
+    let mut ppu = Ppu::new();
+    ppu.oam[ppu::OAM_SIZE - 1] = 0x12;
+    ppu.vram[ppu::VRAM_SIZE] = 0x34;
+    let path = std::env::temp_dir().join("gb_test_write_dump.bin");
+
+    write_dump(&path, &ppu.oam).unwrap();
+    let oam = fs::read(&path).unwrap();
+    assert_eq!(oam.len(), 160);
+    assert_eq!(oam[159], 0x12);
+
+    write_dump(&path, ppu.vram_bank(1)).unwrap();
+    let vram = fs::read(&path).unwrap();
+    assert_eq!(vram.len(), 8192);
+    assert_eq!(vram[0], 0x34);
+    let _ = fs::remove_file(&path);
+  }
+}
@@ -2,13 +2,33 @@
 
 use std::{
   cell::{Ref, RefCell, RefMut},
+  env,
+  path::PathBuf,
   rc::Rc,
 };
 
+use crate::err::{GbError, GbErrorType, GbResult};
+use crate::gb_err;
+
+/// Path to a settings file in the user's home directory. Falls back to the
+/// working directory when there is no home.
+pub fn home_file(name: &str) -> PathBuf {
+  let home = env::var_os("HOME").unwrap_or_default();
+  PathBuf::from(home).join(name)
+}
+
 pub trait LazyDref<T> {
   fn lazy_dref(&self) -> Ref<T>;
 
   fn lazy_dref_mut(&self) -> RefMut<T>;
+
+  /// Like `lazy_dref` but gives NotInitialized instead of panicking when
+  /// nothing is connected
+  fn try_dref(&self) -> GbResult<Ref<'_, T>>;
+
+  /// Like `lazy_dref_mut` but gives NotInitialized instead of panicking when
+  /// nothing is connected
+  fn try_dref_mut(&self) -> GbResult<RefMut<'_, T>>;
 }
 
 impl<T> LazyDref<T> for Option<Rc<RefCell<T>>> {
@@ -19,4 +39,36 @@ impl<T> LazyDref<T> for Option<Rc<RefCell<T>>> {
   fn lazy_dref_mut(&self) -> RefMut<T> {
     self.as_ref().unwrap().borrow_mut()
   }
+
+  fn try_dref(&self) -> GbResult<Ref<'_, T>> {
+    match self {
+      Some(rc) => Ok(rc.borrow()),
+      None => gb_err!(GbErrorType::NotInitialized),
+    }
+  }
+
+  fn try_dref_mut(&self) -> GbResult<RefMut<'_, T>> {
+    match self {
+      Some(rc) => Ok(rc.borrow_mut()),
+      None => gb_err!(GbErrorType::NotInitialized),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_try_dref() {
+    let mut handle: Option<Rc<RefCell<u8>>> = None;
+    let err = handle.try_dref().err().unwrap();
+    assert_eq!(*err.error_type(), GbErrorType::NotInitialized);
+    let err = handle.try_dref_mut().err().unwrap();
+    assert_eq!(*err.error_type(), GbErrorType::NotInitialized);
+
+    handle = Some(Rc::new(RefCell::new(1)));
+    *handle.try_dref_mut().unwrap() += 1;
+    assert_eq!(*handle.try_dref().unwrap(), 2);
+  }
 }
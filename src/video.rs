@@ -9,6 +9,7 @@ use egui_winit::winit;
 use egui_winit::winit::event::WindowEvent;
 use egui_winit::winit::window::Window;
 use std::cell::RefCell;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
 use crate::screen::{Color, Pos, Resolution, Screen};
@@ -225,6 +226,29 @@ impl Video {
     &self.window
   }
 
+  /// Briefly show a message over the screen
+  pub fn show_toast(&mut self, msg: String) {
+    self.ui_state.show_toast(msg);
+  }
+
+  /// Save anything the ui still holds before the emulator exits
+  pub fn on_close(&mut self) {
+    self.ui_state.save_palette();
+  }
+
+  /// Remember a loaded rom in the recent roms menu
+  pub fn add_recent_rom(&mut self, rom: PathBuf) {
+    self.ui_state.recent_roms.push(rom);
+    // failures are logged, the menu still has the rom for this run
+    let _ = self.ui_state.recent_roms.save();
+  }
+
+  /// Drop a rom that failed to load from the recent roms menu
+  pub fn remove_recent_rom(&mut self, rom: &Path) {
+    self.ui_state.recent_roms.remove(rom);
+    let _ = self.ui_state.recent_roms.save();
+  }
+
   pub fn screen(&self) -> Rc<RefCell<Screen>> {
     self.screen.clone()
   }
@@ -250,7 +274,10 @@ impl Video {
     self.fps.tick();
 
     // update screen colors from its buffer state
-    self.screen.borrow_mut().write_buffer(&mut self.queue);
+    self
+      .screen
+      .borrow_mut()
+      .write_buffer(&self.device, &mut self.queue);
 
     // first grab a frame to render
     let output = self.surface.get_current_texture()?;
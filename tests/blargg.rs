@@ -0,0 +1,34 @@
+//! Blargg test roms, run through the public `GameBoy` like a frontend would.
+//! The roms are not included, see the README.
+
+use gb::serial::escape_output;
+use gb::GameBoy;
+use std::fs;
+use std::path::PathBuf;
+
+/// Run a blargg test rom until it reports a result over serial, or until
+/// `max_frames` have run. Returns the serial output.
+fn run_blargg_rom(rom: &[u8], max_frames: u32) -> String {
+  let mut gb = GameBoy::new().unwrap();
+  gb.load_rom(rom).unwrap();
+  for _ in 0..max_frames {
+    gb.step_frame().unwrap();
+    let output = escape_output(&gb.serial_output());
+    if output.contains("Passed") || output.contains("Failed") {
+      return output;
+    }
+  }
+  escape_output(&gb.serial_output())
+}
+
+#[test]
+#[ignore = "needs the blargg cpu_instrs rom, set GB_CPU_INSTRS_ROM to its path"]
+fn test_blargg_cpu_instrs() {
+  let path = std::env::var_os("GB_CPU_INSTRS_ROM")
+    .map(PathBuf::from)
+    .unwrap_or_else(|| PathBuf::from("roms/cpu_instrs.gb"));
+  let rom = fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+  // all 11 tests take just under a minute of emulated time
+  let output = run_blargg_rom(&rom, 60 * 90);
+  assert!(output.contains("Passed"), "cpu_instrs output:\n{}", output);
+}
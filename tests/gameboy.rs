@@ -0,0 +1,57 @@
+//! Drives the public `GameBoy` facade like an embedding frontend would
+
+use gb::joypad::JoypadInput;
+use gb::GameBoy;
+
+/// 32K rom that turns the lcd on, then keeps sending the button half of the
+/// joypad register out over serial
+fn joypad_echo_rom() -> Vec<u8> {
+  let mut rom = vec![0u8; 0x8000];
+  #[rustfmt::skip]
+  let program = [
+    0x3e, 0x91, // ld a, $91
+    0xe0, 0x40, // ldh ($40), a
+    0x3e, 0x10, // ld a, $10     select the buttons
+    0xe0, 0x00, // ldh ($00), a
+    0xf0, 0x00, // ldh a, ($00)
+    0xe0, 0x01, // ldh ($01), a  send it
+    0x3e, 0x81, // ld a, $81
+    0xe0, 0x02, // ldh ($02), a
+    0xf0, 0x02, // ldh a, ($02)  wait for the transfer
+    0x87,       // add a
+    0x38, 0xfb, // jr c, -5
+    0x18, 0xed, // jr -19
+  ];
+  rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+  rom
+}
+
+#[test]
+fn test_facade_runs_headless() {
+  let mut gb = GameBoy::new().unwrap();
+  gb.load_rom(&joypad_echo_rom()).unwrap();
+  // start at the cart's entry point, the boot rom takes a few hundred frames
+  {
+    let state = gb.state();
+    state.bus.borrow_mut().write8(0xff50, 0x01).unwrap();
+    let mut cpu = state.cpu.borrow_mut();
+    cpu.pc = 0x0100;
+    cpu.sp = 0xfffe;
+  }
+  let blank = gb.framebuffer();
+  gb.step_frame().unwrap();
+
+  let res = gb.resolution();
+  let framebuffer = gb.framebuffer();
+  assert_eq!(framebuffer.len(), (res.width * res.height * 3) as usize);
+  assert_ne!(framebuffer, blank);
+
+  // nothing pressed reads as all 1s, A pulls bit 0 low
+  assert_eq!(gb.serial_output().last().map(|b| b & 0x0f), Some(0x0f));
+  gb.set_button(JoypadInput::A, true);
+  gb.step_frame().unwrap();
+  assert_eq!(gb.serial_output().last().map(|b| b & 0x0f), Some(0x0e));
+  gb.set_button(JoypadInput::A, false);
+  gb.step_frame().unwrap();
+  assert_eq!(gb.serial_output().last().map(|b| b & 0x0f), Some(0x0f));
+}